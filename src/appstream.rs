@@ -0,0 +1,122 @@
+use std::fs;
+use std::path::Path;
+use crate::{filelists, layering, load_installed_packages, run_command, OVERLAY_DIR};
+
+// Catalog of the components layered packages ship, where AppStream looks for distribution
+// catalogs. It is generated rather than owned by a package.
+pub const CATALOG: &str = "/usr/share/swcatalog/xml/hacker-ostree.xml";
+// Where packages install their component metadata, current and legacy
+const METAINFO_DIRS: [&str; 2] = ["/usr/share/metainfo/", "/usr/share/appdata/"];
+const APPLICATIONS_DIR: &str = "/usr/share/applications";
+
+// Text between `open` and `close` in `xml`
+fn element<'a>(xml: &'a str, open: &str, close: &str) -> Option<&'a str> {
+    let start = xml.find(open)? + open.len();
+    let end = xml[start..].find(close)? + start;
+    Some(xml[start..end].trim())
+}
+
+// Icon= of a desktop entry
+fn desktop_icon(entry: &str) -> Option<String> {
+    entry
+        .lines()
+        .find_map(|line| line.strip_prefix("Icon="))
+        .map(|icon| icon.trim().to_string())
+        .filter(|icon| !icon.is_empty())
+}
+
+// The <component> element of a metainfo file as a catalog entry: catalogs name the package
+// providing each component, and software centers only show an icon the catalog names.
+// `icon` looks up the icon of a desktop entry by its id.
+fn catalog_component(metainfo: &str, package: &str, icon: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let start = metainfo.find("<component")?;
+    let end = metainfo.rfind("</component>")?;
+    let mut component = metainfo[start..end].trim_end().to_string();
+    if !component.contains("<pkgname>") {
+        component.push_str(&format!("\n  <pkgname>{}</pkgname>", package));
+    }
+    if !component.contains("<icon") {
+        let found = element(&component, "<launchable type=\"desktop-id\">", "</launchable>").and_then(&icon);
+        if let Some(found) = found {
+            component.push_str(&format!("\n  <icon type=\"stock\">{}</icon>", found));
+        }
+    }
+    component.push_str("\n</component>");
+    Some(component)
+}
+
+// Regenerate the catalog from the metainfo files of the layered packages, and when layering
+// live refresh AppStream's cache so software centers pick the change up right away. A
+// composed deployment gets its cache rebuilt on first use after the reboot.
+pub fn generate() -> Result<(), String> {
+    let mut components = Vec::new();
+    for package in load_installed_packages()? {
+        for file in filelists::load_package_files(&package)?.unwrap_or_default() {
+            if !METAINFO_DIRS.iter().any(|dir| file.starts_with(dir)) || !file.ends_with(".xml") {
+                continue;
+            }
+            let Ok(metainfo) = fs::read_to_string(format!("{}{}", OVERLAY_DIR, file)) else {
+                continue;
+            };
+            let icon = |id: &str| {
+                let entry = fs::read_to_string(format!("{}{}/{}", OVERLAY_DIR, APPLICATIONS_DIR, id)).ok()?;
+                desktop_icon(&entry)
+            };
+            match catalog_component(&metainfo, &package, icon) {
+                Some(component) => components.push(component),
+                None => eprintln!("Warning: no AppStream component in {} of {}", file, package),
+            }
+        }
+    }
+
+    let path = format!("{}{}", OVERLAY_DIR, CATALOG);
+    let existing = fs::read_to_string(&path).ok();
+    let catalog = if components.is_empty() {
+        None
+    } else {
+        Some(format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<components version=\"1.0\" origin=\"hacker-ostree\">\n{}\n</components>\n",
+            components.join("\n")
+        ))
+    };
+    if catalog == existing {
+        return Ok(());
+    }
+    match &catalog {
+        Some(catalog) => {
+            if let Some(parent) = Path::new(&path).parent() {
+                fs::create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+            }
+            fs::write(&path, catalog).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+        }
+        None => fs::remove_file(&path).map_err(|e| format!("Failed to remove {}: {}", path, e))?,
+    }
+    if !layering::enabled()? {
+        // Software centers still work off the stale cache; not a reason to fail the transaction
+        if let Err(e) = run_command("appstreamcli", &["refresh-cache", "--force"]) {
+            eprintln!("Warning: failed to refresh the AppStream cache: {}", e.trim());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metainfo_becomes_a_catalog_component_with_package_and_icon() {
+        let metainfo = r#"<?xml version="1.0" encoding="UTF-8"?>
+<component type="desktop-application">
+  <id>org.example.Editor</id>
+  <name>Editor</name>
+  <launchable type="desktop-id">org.example.Editor.desktop</launchable>
+</component>
+"#;
+        let icon = |id: &str| (id == "org.example.Editor.desktop").then(|| "accessories-text-editor".to_string());
+        let component = catalog_component(metainfo, "editor", icon).unwrap();
+        assert!(component.starts_with("<component type=\"desktop-application\">"));
+        assert!(component.ends_with("  <pkgname>editor</pkgname>\n  <icon type=\"stock\">accessories-text-editor</icon>\n</component>"));
+        assert!(catalog_component("<?xml version=\"1.0\"?>", "editor", icon).is_none());
+    }
+}
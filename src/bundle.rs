@@ -0,0 +1,200 @@
+use std::fs;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use tempfile::TempDir;
+use crate::fleet::{self, StateManifest};
+use crate::{fetch, history, ostree, run_command};
+
+// Describes the contents of a bundle; stored as bundle.json at its root
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BundleInfo {
+    pub created: u64,
+    pub manifest: StateManifest,
+    // Base commit the delta starts from (None for a full delta) and the commit it yields
+    pub base_from: Option<String>,
+    pub base_to: Option<String>,
+    // (package, file under debs/)
+    pub debs: Vec<(String, String)>,
+}
+
+const INFO_FILE: &str = "bundle.json";
+const BASE_DELTA: &str = "base.delta";
+const CHECKSUMS: &str = "SHA256SUMS";
+const SIGNATURE: &str = "SHA256SUMS.asc";
+// Keyring trusted to sign bundles unless another one is given
+const BUNDLE_KEYRING: &str = "/etc/hacker-ostree/keyrings/bundles.gpg";
+
+fn temp_dir() -> Result<TempDir, String> {
+    TempDir::new().map_err(|e| format!("Failed to create temp dir: {}", e))
+}
+
+fn path_str(path: &Path) -> Result<&str, String> {
+    path.to_str().ok_or_else(|| format!("Non UTF-8 path {}", path.display()))
+}
+
+// Pack what a node needs to reach `manifest_path` offline: a base delta when the manifest
+// asks for a system update, the overlay .debs, and signed checksums over all of it
+pub fn create(out: &str, manifest_path: &str, from: Option<&str>, sign_key: Option<&str>) -> Result<(), String> {
+    let manifest = fleet::load_manifest(manifest_path)?;
+    let staging = temp_dir()?;
+    let root = path_str(staging.path())?.to_string();
+    let mut files = Vec::new();
+
+    let (mut base_from, mut base_to) = (None, None);
+    if manifest.system_update {
+        let to = ostree::rev_parse("origin:main")?;
+        let from = from.map(ostree::rev_parse).transpose()?;
+        println!("Generating base delta {} -> {}", from.as_deref().unwrap_or("(empty)"), to);
+        ostree::generate_delta(from.as_deref(), &to, &format!("{}/{}", root, BASE_DELTA))?;
+        files.push(BASE_DELTA.to_string());
+        base_from = from;
+        base_to = Some(to);
+    }
+
+    if !manifest.packages.is_empty() {
+        crate::refresh_indexes()?;
+    }
+    fs::create_dir_all(format!("{}/debs", root)).map_err(|e| format!("Failed to create {}/debs: {}", root, e))?;
+    let mut debs = Vec::new();
+    for package in &manifest.packages {
+        let deb_path = crate::download_package(package, None)?;
+        let name = Path::new(&deb_path).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let relative = format!("debs/{}", name);
+        fs::copy(&deb_path, format!("{}/{}", root, relative)).map_err(|e| format!("Failed to copy {}: {}", deb_path, e))?;
+        files.push(relative.clone());
+        debs.push((package.clone(), relative));
+    }
+
+    let info = BundleInfo { created: history::now(), manifest, base_from, base_to, debs };
+    let info_json = serde_json::to_string_pretty(&info).map_err(|e| format!("Failed to serialize bundle info: {}", e))?;
+    fs::write(format!("{}/{}", root, INFO_FILE), info_json).map_err(|e| format!("Failed to write {}: {}", INFO_FILE, e))?;
+    files.push(INFO_FILE.to_string());
+
+    let mut sums = String::new();
+    for file in &files {
+        sums.push_str(&format!("{}  {}\n", fetch::sha256_file(&format!("{}/{}", root, file))?, file));
+    }
+    let sums_path = format!("{}/{}", root, CHECKSUMS);
+    fs::write(&sums_path, sums).map_err(|e| format!("Failed to write {}: {}", sums_path, e))?;
+    if let Some(key) = sign_key {
+        let sig_path = format!("{}/{}", root, SIGNATURE);
+        run_command("gpg", &["--batch", "--yes", "--armor", "--local-user", key, "--output", &sig_path, "--detach-sign", &sums_path])?;
+    }
+
+    run_command("tar", &["-cf", out, "-C", &root, "."])?;
+    println!("Wrote bundle {} ({} packages{})", out, info.debs.len(), if info.base_to.is_some() { ", base delta" } else { "" });
+    Ok(())
+}
+
+// Unpack a bundle and check its signature and checksums; the directory lives as long as
+// the returned TempDir
+pub fn open(file: &str, keyring: Option<&str>, allow_unsigned: bool) -> Result<(TempDir, BundleInfo), String> {
+    let dir = temp_dir()?;
+    let root = path_str(dir.path())?.to_string();
+    run_command("tar", &["-xf", file, "-C", &root])?;
+
+    let sums_path = format!("{}/{}", root, CHECKSUMS);
+    let sig_path = format!("{}/{}", root, SIGNATURE);
+    if Path::new(&sig_path).exists() {
+        let keyring = keyring.unwrap_or(BUNDLE_KEYRING);
+        run_command("gpgv", &["--keyring", keyring, &sig_path, &sums_path])
+            .map_err(|e| format!("Bundle signature verification failed against {}: {}", keyring, e))?;
+        println!("Bundle signature verified");
+    } else if !allow_unsigned {
+        return Err(format!("{} is not signed; pass --allow-unsigned to apply it anyway", file));
+    }
+
+    let sums = fs::read_to_string(&sums_path).map_err(|e| format!("Failed to read {}: {}", CHECKSUMS, e))?;
+    let mut covered = Vec::new();
+    for line in sums.lines().filter(|line| !line.trim().is_empty()) {
+        let (expected, name) = line.split_once("  ").ok_or_else(|| format!("Malformed {} line: {}", CHECKSUMS, line))?;
+        if name.contains("..") || name.starts_with('/') {
+            return Err(format!("Refusing suspicious path {} in bundle", name));
+        }
+        let actual = fetch::sha256_file(&format!("{}/{}", root, name))?;
+        if actual != expected {
+            return Err(format!("Checksum mismatch for {} in bundle", name));
+        }
+        covered.push(name);
+    }
+    // Every file the bundle refers to must be covered by the checksums, bundle.json before
+    // anything in it is believed
+    let require = |name: &str| {
+        if covered.contains(&name) {
+            Ok(())
+        } else {
+            Err(format!("{} is not covered by the bundle checksums", name))
+        }
+    };
+    require(INFO_FILE)?;
+
+    let info_path = format!("{}/{}", root, INFO_FILE);
+    let text = fs::read_to_string(&info_path).map_err(|e| format!("Failed to read {}: {}", INFO_FILE, e))?;
+    let info: BundleInfo = serde_json::from_str(&text).map_err(|e| format!("Failed to parse {}: {}", INFO_FILE, e))?;
+    if info.base_to.is_some() {
+        require(BASE_DELTA)?;
+    }
+    for (_, deb) in &info.debs {
+        require(deb)?;
+    }
+    Ok((dir, info))
+}
+
+// Import the base delta of an opened bundle, if it carries one
+pub fn import_base(root: &Path, info: &BundleInfo) -> Result<bool, String> {
+    let to = match &info.base_to {
+        Some(to) => to,
+        None => return Ok(false),
+    };
+    if let Some(from) = &info.base_from {
+        if ostree::rev_parse(from).is_err() {
+            return Err(format!("The bundle's base delta starts from {}, which this node does not have", from));
+        }
+    }
+    ostree::apply_delta(&format!("{}/{}", path_str(root)?, BASE_DELTA), "origin:main", to)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pack `files` with checksums over `summed` only into a bundle, and open it unsigned
+    fn open_packed(files: &[(&str, &str)], summed: &[&str]) -> Result<BundleInfo, String> {
+        let staging = temp_dir()?;
+        let root = path_str(staging.path())?;
+        let mut sums = String::new();
+        for (name, content) in files {
+            fs::write(format!("{}/{}", root, name), content).map_err(|e| e.to_string())?;
+            if summed.contains(name) {
+                sums.push_str(&format!("{}  {}\n", fetch::sha256_file(&format!("{}/{}", root, name))?, name));
+            }
+        }
+        fs::write(format!("{}/{}", root, CHECKSUMS), sums).map_err(|e| e.to_string())?;
+        let out = temp_dir()?;
+        let file = format!("{}/bundle.tar", path_str(out.path())?);
+        run_command("tar", &["-cf", &file, "-C", root, "."])?;
+        open(&file, None, true).map(|(_, info)| info)
+    }
+
+    #[test]
+    fn files_outside_the_checksums_are_refused() {
+        let info = |base_to: Option<&str>| {
+            let manifest = serde_json::json!({ "packages": [], "remove": [], "system_update": base_to.is_some() });
+            serde_json::json!({ "created": 0, "manifest": manifest, "base_from": null, "base_to": base_to, "debs": [] }).to_string()
+        };
+        let with_delta = info(Some("abc"));
+        let files = [(INFO_FILE, with_delta.as_str()), (BASE_DELTA, "delta")];
+        assert_eq!(
+            open_packed(&files, &[BASE_DELTA]).unwrap_err(),
+            "bundle.json is not covered by the bundle checksums"
+        );
+        assert_eq!(
+            open_packed(&files, &[INFO_FILE]).unwrap_err(),
+            "base.delta is not covered by the bundle checksums"
+        );
+        assert!(open_packed(&files, &[INFO_FILE, BASE_DELTA]).is_ok());
+        let without_delta = info(None);
+        assert!(open_packed(&[(INFO_FILE, without_delta.as_str())], &[INFO_FILE]).is_ok());
+    }
+}
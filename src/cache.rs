@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use crate::{fetch, CACHE_DIR};
+
+// SHA256 of every cached .deb as it was when downloaded and verified, by path. Kept inside
+// the archives so cleaning the cache clears it too.
+fn integrity_file() -> String {
+    format!("{}/archives/integrity.json", CACHE_DIR)
+}
+
+fn load() -> Result<BTreeMap<String, String>, String> {
+    let path = integrity_file();
+    match fs::read_to_string(&path) {
+        Ok(text) => serde_json::from_str(&text).map_err(|e| format!("Failed to parse {}: {}", path, e)),
+        Err(_) => Ok(BTreeMap::new()),
+    }
+}
+
+fn save(hashes: &BTreeMap<String, String>) -> Result<(), String> {
+    let path = integrity_file();
+    if let Some(parent) = Path::new(&path).parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    let text = serde_json::to_string_pretty(hashes).map_err(|e| format!("Failed to serialize cache hashes: {}", e))?;
+    fs::write(&path, text).map_err(|e| format!("Failed to write {}: {}", path, e))
+}
+
+// Remember the hash of freshly downloaded and verified files
+pub fn record(paths: &[String]) -> Result<(), String> {
+    let mut hashes = load()?;
+    for path in paths {
+        hashes.insert(path.clone(), fetch::sha256_file(path)?);
+    }
+    save(&hashes)
+}
+
+// Hash of a cached file, or None after evicting it because it no longer matches the hash
+// recorded when it was downloaded
+pub fn intact_hash(path: &str) -> Result<Option<String>, String> {
+    let actual = fetch::sha256_file(path)?;
+    let mut hashes = load()?;
+    match hashes.get(path) {
+        Some(recorded) if *recorded != actual => {
+            eprintln!("Warning: evicting {} from the cache, it changed since it was downloaded", path);
+            let _ = fs::remove_file(path);
+            hashes.remove(path);
+            save(&hashes)?;
+            Ok(None)
+        }
+        _ => Ok(Some(actual)),
+    }
+}
+
+// Files below `dir`, recursively
+fn files(dir: &Path, found: &mut Vec<String>) {
+    for entry in fs::read_dir(dir).into_iter().flatten().filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if path.is_dir() {
+            files(&path, found);
+        } else {
+            found.push(path.display().to_string());
+        }
+    }
+}
+
+// Check every cached .deb against the hash recorded when it was downloaded, evicting the
+// ones that changed. Fails if any did, so bit-rot or tampering doesn't go unnoticed.
+pub fn verify() -> Result<(), String> {
+    let mut hashes = load()?;
+    let mut corrupt = Vec::new();
+    let recorded = hashes.len();
+    hashes.retain(|path, expected| match fetch::sha256_file(path) {
+        Ok(actual) if actual == *expected => true,
+        Ok(_) => {
+            corrupt.push(path.clone());
+            let _ = fs::remove_file(path);
+            false
+        }
+        // Already removed, e.g. by clean
+        Err(_) => false,
+    });
+    save(&hashes)?;
+
+    let mut cached = Vec::new();
+    files(Path::new(&format!("{}/archives", CACHE_DIR)), &mut cached);
+    let unrecorded: Vec<&String> = cached
+        .iter()
+        .filter(|path| path.ends_with(".deb") && !hashes.contains_key(*path))
+        .collect();
+    println!("Checked {} cached packages", recorded);
+    for path in &corrupt {
+        println!("  corrupt, evicted: {}", path);
+    }
+    if !unrecorded.is_empty() {
+        // Still checked against the index before they are installed
+        println!("{} cached packages predate the integrity records and were not checked", unrecorded.len());
+    }
+    if !corrupt.is_empty() {
+        return Err(format!("{} cached packages changed since they were downloaded and were evicted", corrupt.len()));
+    }
+    println!("All recorded packages are intact");
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use std::cmp::Ordering;
+use crate::compose::{self, CommitMetadata};
+use crate::version::compare_versions;
+use crate::{dpkgdb, ostree};
+
+// Directories whose added and removed files are worth pointing out after a base update
+const KEY_PATHS: [&str; 4] = ["/usr/bin/", "/usr/sbin/", "/usr/lib/systemd/system/", "/etc/"];
+// Kernel argument drop-ins shipped in the image
+const KARGS_DIR: &str = "/usr/lib/bootc/kargs.d/";
+
+// Layered packages a base ships at the same or a newer version, as (name, layered, base)
+pub fn absorbed_packages(installed: &[String], base: &CommitMetadata) -> Result<Vec<(String, String, String)>, String> {
+    let mut absorbed = Vec::new();
+    for name in installed {
+        let (layered, shipped) = match (dpkgdb::installed_version(name)?, base.packages.get(name)) {
+            (Some(layered), Some(shipped)) => (layered, shipped),
+            _ => continue,
+        };
+        if compare_versions(shipped, &layered) != Ordering::Less {
+            absorbed.push((name.clone(), layered, shipped.clone()));
+        }
+    }
+    Ok(absorbed)
+}
+
+// Print what a new base commit changes compared to the booted one
+pub fn print_report(from: &str, to: &str, installed: &[String]) -> Result<(), String> {
+    let old = CommitMetadata::load(from)?;
+    let new = CommitMetadata::load(to)?;
+    println!("Changes in the new base image:");
+    compose::print_diff(&old, &new);
+
+    let paths = ostree::diff_paths(from, to)?;
+    let key_files: Vec<&(char, String)> = paths
+        .iter()
+        .filter(|(status, path)| matches!(status, 'A' | 'D') && KEY_PATHS.iter().any(|dir| path.starts_with(dir)))
+        .collect();
+    if !key_files.is_empty() {
+        println!("Files in key paths:");
+        for (status, path) in key_files {
+            println!("  {} {}", if *status == 'A' { '+' } else { '-' }, path);
+        }
+    }
+
+    let kargs: Vec<&(char, String)> = paths.iter().filter(|(_, path)| path.starts_with(KARGS_DIR)).collect();
+    if !kargs.is_empty() {
+        println!("Kernel arguments:");
+        for (status, path) in kargs {
+            println!("  {} {}", status, path);
+            if *status != 'D' {
+                for line in ostree::cat(to, path)?.lines().filter(|line| !line.trim().is_empty()) {
+                    println!("      {}", line);
+                }
+            }
+        }
+    }
+
+    let absorbed = absorbed_packages(installed, &new)?;
+    if !absorbed.is_empty() {
+        println!("Layered packages now shipped by the base (can be removed from the overlay):");
+        for (name, layered, shipped) in absorbed {
+            println!("  {} {} (base has {})", name, layered, shipped);
+        }
+    }
+    Ok(())
+}
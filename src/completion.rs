@@ -0,0 +1,124 @@
+use clap::{Arg, Command};
+use crate::{dpkgdb, history, index, ostree, repos};
+
+// Shell glue calling back into `hacker-ostree __complete <words>`
+pub fn script(shell: &str) -> String {
+    let bash = r#"_hacker_ostree() {
+    local IFS=$'\n'
+    COMPREPLY=($(hacker-ostree __complete "${COMP_WORDS[@]:1:COMP_CWORD}" 2>/dev/null))
+}
+complete -o default -F _hacker_ostree hacker-ostree
+"#;
+    match shell {
+        "zsh" => format!("autoload -U +X bashcompinit && bashcompinit\n{}", bash),
+        "fish" => "complete -c hacker-ostree -f -a '(hacker-ostree __complete (commandline -opc)[2..-1] (commandline -ct))'\n".to_string(),
+        _ => bash.to_string(),
+    }
+}
+
+fn find_long<'a>(cmd: &'a Command, name: &str) -> Option<&'a Arg> {
+    cmd.get_arguments().find(|arg| arg.get_long() == Some(name))
+}
+
+fn find_short(cmd: &Command, short: char) -> Option<&Arg> {
+    cmd.get_arguments().find(|arg| arg.get_short() == Some(short))
+}
+
+fn takes_value(arg: &Arg) -> bool {
+    arg.get_action().takes_values()
+}
+
+// Values for an argument, read from the live system where the argument names something
+// that exists on it
+fn values(path: &[String], arg: &Arg) -> Vec<String> {
+    let possible: Vec<String> = arg.get_possible_values().iter().map(|v| v.get_name().to_string()).collect();
+    if !possible.is_empty() {
+        return possible;
+    }
+    let command = path.join(" ");
+    match (command.as_str(), arg.get_id().as_str()) {
+        ("unhold", "PACKAGE") => crate::load_held().unwrap_or_default(),
+        ("remove" | "hold" | "files" | "verify", "PACKAGE") => dpkgdb::installed_versions()
+            .map(|installed| installed.into_iter().map(|(name, _)| name).collect())
+            .unwrap_or_default(),
+        (_, "PACKAGE") => {
+            let mut names: Vec<String> = index::load_all_packages()
+                .map(|packages| packages.iter().map(|p| p.name().to_string()).collect())
+                .unwrap_or_default();
+            names.sort();
+            names.dedup();
+            names
+        }
+        (_, "REPO") => repos::load_repos()
+            .map(|repos| repos.into_iter().map(|r| r.name).collect())
+            .unwrap_or_default(),
+        ("undo", "ID") | ("history diff", "FROM" | "TO") => history::load()
+            .map(|entries| entries.into_iter().map(|e| e.id.to_string()).collect())
+            .unwrap_or_default(),
+        ("note", "INDEX") => ostree::deployments()
+            .map(|deployments| (0..deployments.len()).map(|i| i.to_string()).collect())
+            .unwrap_or_default(),
+        // Commits of the deployments, the booted one and those rollback can return to
+        ("system-update", "commit") | ("db diff", "FROM" | "TO") => ostree::deployments()
+            .map(|deployments| deployments.into_iter().map(|d| d.checksum).collect())
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+// Candidates for the last of `words` (the word being completed), given the words before it
+pub fn complete(mut root: Command, words: &[String]) -> Vec<String> {
+    // Propagates global arguments such as --timing into the subcommands
+    root.build();
+    let (current, done) = match words.split_last() {
+        Some((current, done)) => (current.as_str(), done),
+        None => ("", &[][..]),
+    };
+    let mut cmd = &root;
+    let mut path: Vec<String> = Vec::new();
+    let mut positionals = 0;
+    let mut pending: Option<&Arg> = None;
+    for word in done {
+        if pending.take().is_some() {
+            continue;
+        }
+        if let Some(long) = word.strip_prefix("--") {
+            if !long.contains('=') {
+                pending = find_long(cmd, long).filter(|arg| takes_value(arg));
+            }
+        } else if let Some(short) = word.strip_prefix('-').filter(|s| !s.is_empty()) {
+            if short.len() == 1 {
+                pending = short.chars().next().and_then(|c| find_short(cmd, c)).filter(|arg| takes_value(arg));
+            }
+        } else if let Some(sub) = cmd.find_subcommand(word) {
+            cmd = sub;
+            path.push(sub.get_name().to_string());
+            positionals = 0;
+        } else {
+            positionals += 1;
+        }
+    }
+
+    let candidates = if let Some(arg) = pending {
+        values(&path, arg)
+    } else if current.starts_with('-') {
+        cmd.get_arguments()
+            .filter(|arg| !arg.is_hide_set())
+            .filter_map(|arg| arg.get_long().map(|long| format!("--{}", long)))
+            .collect()
+    } else if cmd.has_subcommands() {
+        cmd.get_subcommands()
+            .filter(|sub| !sub.is_hide_set())
+            .flat_map(|sub| std::iter::once(sub.get_name()).chain(sub.get_visible_aliases()))
+            .map(str::to_string)
+            .collect()
+    } else {
+        let mut positional: Vec<&Arg> = cmd.get_positionals().collect();
+        positional.sort_by_key(|arg| arg.get_index());
+        // The last positional may take any number of values, like install's packages
+        let arg = positional.get(positionals).or_else(|| positional.last().filter(|arg| arg.get_num_args().is_some_and(|n| n.max_values() > 1)));
+        arg.map(|arg| values(&path, arg)).unwrap_or_default()
+    };
+    candidates.into_iter().filter(|c| c.starts_with(current)).collect()
+}
+
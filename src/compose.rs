@@ -0,0 +1,656 @@
+use std::collections::BTreeMap;
+use std::cmp::Ordering;
+use std::fs::{self, File};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::version::compare_versions;
+use crate::{fetch, ostree, repos, run_command, run_command_streamed};
+
+// Commit metadata keys written by compose; "version" is the one ostree itself displays
+pub const METADATA_VERSION: &str = "version";
+pub const METADATA_PACKAGES: &str = "hackeros.packages";
+pub const METADATA_ADVISORIES: &str = "hackeros.advisories";
+// Release series of a base image, e.g. "2025"; moving between series needs --major
+pub const METADATA_SERIES: &str = "hackeros.series";
+// Written on layered commits: the base commit and the overlay packages on top of it
+pub const METADATA_BASE: &str = "hackeros.base";
+pub const METADATA_LAYERED: &str = "hackeros.layered";
+// Written on composed commits: version and .deb hash of every package, as in a lockfile
+const METADATA_LOCKED: &str = "hackeros.locked";
+// Written on composed commits: the suite they were bootstrapped from. The next compose of the
+// branch only builds on the commit's tree when its suite is the same.
+const METADATA_SUITE: &str = "hackeros.suite";
+// Root filesystems are bootstrapped here rather than in a possibly small /tmp
+const COMPOSE_TMP: &str = "/var/tmp";
+// .debs of the last compose of each branch as <sha256>/<file name>, reused by the next one
+const DEB_CACHE: &str = "/var/cache/hacker-ostree/compose";
+// Mounted into a tree being updated for apt and maintainer scripts
+const CHROOT_MOUNTS: [&str; 3] = ["proc", "sys", "dev"];
+// Image references already naming a transport; anything else is pushed to a registry
+const IMAGE_TRANSPORTS: [&str; 6] = ["docker://", "oci:", "oci-archive:", "docker-archive:", "containers-storage:", "dir:"];
+
+// Security fix shipped by a package version, e.g.
+// {"id": "DSA-5532-1", "package": "openssl", "fixed-version": "3.0.11-1~deb12u2", "severity": "high"}
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Advisory {
+    pub id: String,
+    pub package: String,
+    pub fixed_version: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub severity: String,
+}
+
+// Description of a base image build
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Treefile {
+    // Branch the commit is written to, e.g. "hackeros/stable/x86_64"
+    #[serde(rename = "ref")]
+    pub branch: String,
+    pub version: String,
+    // Release series the image belongs to; updates only cross series when asked to
+    #[serde(default)]
+    pub series: Option<String>,
+    pub suite: String,
+    // Source lines the root filesystem is bootstrapped from
+    pub repos: Vec<String>,
+    pub packages: Vec<String>,
+    // Advisories to record when the composed versions include their fix
+    #[serde(default)]
+    pub advisories: Vec<Advisory>,
+}
+
+// Exact outcome of a compose: replaying it with `compose tree --lockfile` installs the same
+// versions from the same archive snapshots, or fails
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Lockfile {
+    #[serde(rename = "ref")]
+    pub branch: String,
+    pub suite: String,
+    // Source lines, pinned to archive snapshots where a snapshot service is known
+    pub repos: Vec<String>,
+    pub packages: LockedPackages,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LockedPackage {
+    pub version: String,
+    // Of the .deb the package was installed from
+    pub sha256: String,
+}
+
+// Locked packages by name
+type LockedPackages = BTreeMap<String, LockedPackage>;
+
+// Structured metadata of a base commit, empty for commits not built by compose
+#[derive(Debug, Default)]
+pub struct CommitMetadata {
+    pub version: Option<String>,
+    pub series: Option<String>,
+    pub packages: BTreeMap<String, String>,
+    pub advisories: Vec<Advisory>,
+    // Base commit a layered commit was composed on, None for base commits
+    pub base: Option<String>,
+    pub layered: BTreeMap<String, String>,
+}
+
+impl CommitMetadata {
+    // Read the metadata of a commit in the system repository
+    pub fn load(rev: &str) -> Result<Self, String> {
+        let version = ostree::metadata_string(ostree::OSTREE_REPO, rev, METADATA_VERSION)?;
+        let series = ostree::metadata_string(ostree::OSTREE_REPO, rev, METADATA_SERIES)?;
+        let packages = match ostree::metadata_string(ostree::OSTREE_REPO, rev, METADATA_PACKAGES)? {
+            Some(json) => serde_json::from_str(&json)
+                .map_err(|e| format!("Failed to parse package manifest of {}: {}", rev, e))?,
+            None => BTreeMap::new(),
+        };
+        let advisories = match ostree::metadata_string(ostree::OSTREE_REPO, rev, METADATA_ADVISORIES)? {
+            Some(json) => serde_json::from_str(&json)
+                .map_err(|e| format!("Failed to parse advisories of {}: {}", rev, e))?,
+            None => Vec::new(),
+        };
+        let base = ostree::metadata_string(ostree::OSTREE_REPO, rev, METADATA_BASE)?;
+        let layered = match ostree::metadata_string(ostree::OSTREE_REPO, rev, METADATA_LAYERED)? {
+            Some(json) => serde_json::from_str(&json)
+                .map_err(|e| format!("Failed to parse layered packages of {}: {}", rev, e))?,
+            None => BTreeMap::new(),
+        };
+        Ok(CommitMetadata { version, series, packages, advisories, base, layered })
+    }
+
+    pub fn version_label(&self) -> &str {
+        self.version.as_deref().unwrap_or("unknown")
+    }
+}
+
+fn load_treefile(path: &str) -> Result<Treefile, String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+    serde_json::from_reader(file).map_err(|e| format!("Failed to parse {}: {}", path, e))
+}
+
+fn load_lockfile(path: &str) -> Result<Lockfile, String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+    serde_json::from_reader(file).map_err(|e| format!("Failed to parse {}: {}", path, e))
+}
+
+// (name, version) of every package installed in a bootstrapped root
+fn rootfs_packages(rootfs: &str) -> Result<BTreeMap<String, String>, String> {
+    let admindir = format!("--admindir={}/var/lib/dpkg", rootfs);
+    let output = run_command("dpkg-query", &[&admindir, "-W", "-f", "${Package}\t${Version}\n"])?;
+    Ok(output
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(name, version)| (name.to_string(), version.to_string()))
+        .collect())
+}
+
+// Advisories whose fixed version is at or below what was composed
+fn included_advisories(advisories: &[Advisory], packages: &BTreeMap<String, String>) -> Vec<Advisory> {
+    advisories
+        .iter()
+        .filter(|advisory| {
+            packages
+                .get(&advisory.package)
+                .is_some_and(|version| compare_versions(version, &advisory.fixed_version) != Ordering::Less)
+        })
+        .cloned()
+        .collect()
+}
+
+// Versions and hashes of the .debs a bootstrap downloaded, by package name, moving them
+// from the root into the cache
+fn take_downloaded_debs(rootfs: &str) -> Result<BTreeMap<String, (String, String)>, String> {
+    let archives = format!("{}/var/cache/apt/archives", rootfs);
+    let entries = fs::read_dir(&archives).map_err(|e| format!("Failed to read {}: {}", archives, e))?;
+    let mut debs = BTreeMap::new();
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path().display().to_string();
+        if !path.ends_with(".deb") {
+            continue;
+        }
+        let fields = run_command("dpkg-deb", &["-f", &path, "Package", "Version"])?;
+        let field = |name: &str| {
+            fields
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                .map(|value| value.trim().to_string())
+                .unwrap_or_default()
+        };
+        let sha256 = fetch::sha256_file(&path)?;
+        let cached = format!("{}/{}", DEB_CACHE, sha256);
+        fs::create_dir_all(&cached).map_err(|e| format!("Failed to create {}: {}", cached, e))?;
+        let dest = format!("{}/{}", cached, entry.file_name().to_string_lossy());
+        // The root usually lives on the same filesystem as the cache; copy when it doesn't
+        if fs::rename(&path, &dest).is_err() {
+            fs::copy(&path, &dest).map_err(|e| format!("Failed to copy {} to {}: {}", path, dest, e))?;
+            fs::remove_file(&path).map_err(|e| format!("Failed to remove {}: {}", path, e))?;
+        }
+        debs.insert(field("Package"), (field("Version"), sha256));
+    }
+    Ok(debs)
+}
+
+// Directory of the cached .debs with the given hashes, under their original file names,
+// for a bootstrap to start from; apt still checks each against the index before using it
+fn stage_cached_debs(hashes: &[&str], staging: &str) -> Result<usize, String> {
+    let mut staged = 0;
+    for sha256 in hashes {
+        let cached = format!("{}/{}", DEB_CACHE, sha256);
+        let entries = match fs::read_dir(&cached) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let dest = format!("{}/{}", staging, entry.file_name().to_string_lossy());
+            fs::hard_link(entry.path(), &dest)
+                .or_else(|_| fs::copy(entry.path(), &dest).map(|_| ()))
+                .map_err(|e| format!("Failed to stage {}: {}", entry.path().display(), e))?;
+            staged += 1;
+        }
+    }
+    Ok(staged)
+}
+
+// Drop cached .debs that no branch's last compose in `repo` uses
+fn prune_deb_cache(repo: &str) -> Result<(), String> {
+    let entries = match fs::read_dir(DEB_CACHE) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(()),
+    };
+    let mut keep = Vec::new();
+    for branch in run_command("ostree", &["refs", &format!("--repo={}", repo)])?.lines() {
+        if let Some(build) = previous_build(repo, branch.trim())? {
+            keep.extend(build.locked.into_values().map(|locked| locked.sha256));
+        }
+    }
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let sha256 = entry.file_name().to_string_lossy().to_string();
+        if !keep.contains(&sha256) {
+            fs::remove_dir_all(entry.path()).map_err(|e| format!("Failed to remove {}: {}", entry.path().display(), e))?;
+        }
+    }
+    Ok(())
+}
+
+// The last compose of a branch
+struct PreviousBuild {
+    rev: String,
+    locked: LockedPackages,
+    suite: Option<String>,
+}
+
+// Versions and hashes recorded on the last compose of a branch, if there is one
+fn previous_build(repo: &str, branch: &str) -> Result<Option<PreviousBuild>, String> {
+    let rev = match run_command("ostree", &["rev-parse", "--repo", repo, branch]) {
+        Ok(rev) => rev.trim().to_string(),
+        Err(_) => return Ok(None),
+    };
+    let locked = match ostree::metadata_string(repo, &rev, METADATA_LOCKED)? {
+        Some(json) => serde_json::from_str(&json).map_err(|e| format!("Failed to parse locked packages of {}: {}", rev, e))?,
+        None => return Ok(None),
+    };
+    let suite = ostree::metadata_string(repo, &rev, METADATA_SUITE)?;
+    Ok(Some(PreviousBuild { rev, locked, suite }))
+}
+
+// Bootstrap packages from source lines into a fresh root, returning every installed package
+// with the hash of the .deb it came from. Cached .debs with the `reuse` hashes are handed to
+// the bootstrap so only the others are downloaded.
+fn bootstrap(
+    tree: &Treefile,
+    repos: &[String],
+    include: &[String],
+    reuse: &[&str],
+    rootfs: &str,
+) -> Result<LockedPackages, String> {
+    // Keep the downloaded .debs so they can be hashed
+    let mut args = vec!["--variant=minbase".to_string(), "--skip=download/empty".to_string()];
+    let staging = tempfile::Builder::new()
+        .prefix("hacker-ostree-debs-")
+        .tempdir_in(COMPOSE_TMP)
+        .map_err(|e| format!("Failed to create staging directory: {}", e))?;
+    let staged = stage_cached_debs(reuse, &staging.path().display().to_string())?;
+    if staged > 0 {
+        println!("Reusing {} cached packages", staged);
+        args.push(format!("--setup-hook=sync-in {} /var/cache/apt/archives/", staging.path().display()));
+    }
+    if !include.is_empty() {
+        args.push(format!("--include={}", include.join(",")));
+    }
+    args.push(tree.suite.clone());
+    args.push(rootfs.to_string());
+    for line in repos {
+        args.push(repos::parse_line(line, None)?.resolved()?.to_line());
+    }
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    println!("Bootstrapping {} for {}", tree.suite, tree.branch);
+    run_command_streamed("mmdebstrap", &args)?;
+
+    let mut debs = take_downloaded_debs(rootfs)?;
+    let mut packages = BTreeMap::new();
+    for (name, version) in rootfs_packages(rootfs)? {
+        let sha256 = match debs.remove(&name) {
+            Some((deb_version, sha256)) if deb_version == version => sha256,
+            _ => return Err(format!("No downloaded .deb found for {} {}", name, version)),
+        };
+        packages.insert(name, LockedPackage { version, sha256 });
+    }
+    Ok(packages)
+}
+
+// Run apt-get in `rootfs` the way mmdebstrap does: without recommends and without asking
+fn chroot_apt(rootfs: &str, args: &[&str]) -> Result<(), String> {
+    let mut all = vec![rootfs, "env", "DEBIAN_FRONTEND=noninteractive", "apt-get", "-y", "-o", "APT::Install-Recommends=false"];
+    all.extend(args);
+    run_command_streamed("chroot", &all)
+}
+
+// Packages the way update_tree() wants them: `include` installed and kept, everything else
+// at its newest version with `upgrade`, and what nothing needs any more removed
+fn apt_update_tree(rootfs: &str, include: &[String], upgrade: bool) -> Result<(), String> {
+    chroot_apt(rootfs, &["update"])?;
+    // Packages the previous treefile asked for but this one doesn't become removable
+    let names: Vec<&str> = include.iter().map(|spec| spec.split('=').next().unwrap_or(spec)).collect();
+    let manual = run_command("chroot", &[rootfs, "apt-mark", "showmanual"])?;
+    let dropped: Vec<&str> = manual.lines().map(str::trim).filter(|name| !name.is_empty() && *name != "apt" && !names.contains(name)).collect();
+    if !dropped.is_empty() {
+        let mut args = vec![rootfs, "apt-mark", "auto"];
+        args.extend(dropped);
+        run_command("chroot", &args)?;
+    }
+    if !include.is_empty() {
+        let mut args = vec!["install"];
+        args.extend(include.iter().map(String::as_str));
+        chroot_apt(rootfs, &args)?;
+    }
+    if upgrade {
+        chroot_apt(rootfs, &["dist-upgrade"])?;
+    }
+    chroot_apt(rootfs, &["autoremove", "--purge"])
+}
+
+// Where a compose builds its tree inside its work directory
+fn rootfs_of(workdir: &tempfile::TempDir) -> String {
+    format!("{}/rootfs", workdir.path().display())
+}
+
+// Build on the previous compose's tree instead of bootstrapping: check it out and let apt
+// install, upgrade and remove only what differs, so unchanged packages are neither
+// downloaded nor extracted nor configured again. Returns every installed package with the
+// hash of its .deb like bootstrap(), taking unchanged ones' hashes from the previous build.
+fn update_tree(
+    previous: &PreviousBuild,
+    repos: &[String],
+    include: &[String],
+    upgrade: bool,
+    reuse: &[&str],
+    repo: &str,
+    workdir: &mut tempfile::TempDir,
+) -> Result<LockedPackages, String> {
+    let rootfs = &rootfs_of(workdir);
+    println!("Updating the tree of the previous build {}", previous.rev);
+    // A copy: maintainer scripts may write to files in place, which would change hardlinked objects
+    run_command("ostree", &["checkout", &format!("--repo={}", repo), "--force-copy", &previous.rev, rootfs])?;
+    let usr_etc = format!("{}/usr/etc", rootfs);
+    let etc = format!("{}/etc", rootfs);
+    fs::rename(&usr_etc, &etc).map_err(|e| format!("Failed to move {} to {}: {}", usr_etc, etc, e))?;
+
+    let mut sources = String::new();
+    for line in repos {
+        sources.push_str(&format!("{}\n", repos::parse_line(line, None)?.resolved()?.to_line()));
+    }
+    let sources_list = format!("{}/apt/sources.list", etc);
+    fs::write(&sources_list, sources).map_err(|e| format!("Failed to write {}: {}", sources_list, e))?;
+    let archives = format!("{}/var/cache/apt/archives", rootfs);
+    fs::create_dir_all(format!("{}/partial", archives)).map_err(|e| format!("Failed to create {}: {}", archives, e))?;
+    let staged = stage_cached_debs(reuse, &archives)?;
+    if staged > 0 {
+        println!("Reusing {} cached packages", staged);
+    }
+
+    // Keep maintainer scripts from starting services, and give apt name resolution
+    let policy = format!("{}/usr/sbin/policy-rc.d", rootfs);
+    fs::write(&policy, "#!/bin/sh\nexit 101\n").map_err(|e| format!("Failed to write {}: {}", policy, e))?;
+    fs::set_permissions(&policy, fs::Permissions::from_mode(0o755)).map_err(|e| format!("Failed to make {} executable: {}", policy, e))?;
+    let resolv = format!("{}/resolv.conf", etc);
+    let resolv_aside = format!("{}.hacker-ostree", resolv);
+    let had_resolv = fs::rename(&resolv, &resolv_aside).is_ok();
+    fs::copy("/etc/resolv.conf", &resolv).map_err(|e| format!("Failed to copy /etc/resolv.conf: {}", e))?;
+    let mut mounted = Vec::new();
+    let mut result = Ok(());
+    for dir in CHROOT_MOUNTS {
+        let target = format!("{}/{}", rootfs, dir);
+        result = fs::create_dir_all(&target)
+            .map_err(|e| format!("Failed to create {}: {}", target, e))
+            .and_then(|_| run_command("mount", &["--rbind", &format!("/{}", dir), &target]))
+            .map(|_| ());
+        if result.is_err() {
+            break;
+        }
+        // Unmounting a shared bind would propagate to the host's own /proc, /sys and /dev
+        if let Err(e) = run_command("mount", &["--make-rslave", &target]) {
+            workdir.disable_cleanup(true);
+            return Err(format!(
+                "Failed to make {} a slave mount: {}; keeping {} so nothing is removed through it",
+                target,
+                e.trim(),
+                workdir.path().display()
+            ));
+        }
+        mounted.push(target);
+    }
+    if result.is_ok() {
+        result = apt_update_tree(rootfs, include, upgrade);
+    }
+    let mut stuck = Vec::new();
+    for target in mounted.iter().rev() {
+        if let Err(e) = run_command("umount", &["-R", target]) {
+            stuck.push(format!("{} ({})", target, e.trim()));
+        }
+    }
+    if !stuck.is_empty() {
+        workdir.disable_cleanup(true);
+        return Err(format!(
+            "Failed to unmount {}; keeping {} so nothing is removed through it",
+            stuck.join(", "),
+            workdir.path().display()
+        ));
+    }
+    result?;
+    fs::remove_file(&policy).map_err(|e| format!("Failed to remove {}: {}", policy, e))?;
+    fs::remove_file(&resolv).map_err(|e| format!("Failed to remove {}: {}", resolv, e))?;
+    if had_resolv {
+        fs::rename(&resolv_aside, &resolv).map_err(|e| format!("Failed to restore {}: {}", resolv, e))?;
+    }
+    // Like mmdebstrap, don't ship the package lists
+    let lists = format!("{}/var/lib/apt/lists", rootfs);
+    fs::remove_dir_all(&lists).map_err(|e| format!("Failed to remove {}: {}", lists, e))?;
+    fs::create_dir_all(format!("{}/partial", lists)).map_err(|e| format!("Failed to create {}: {}", lists, e))?;
+
+    let mut debs = take_downloaded_debs(rootfs)?;
+    let mut packages = BTreeMap::new();
+    for (name, version) in rootfs_packages(rootfs)? {
+        let sha256 = match (debs.remove(&name), previous.locked.get(&name)) {
+            (Some((deb_version, sha256)), _) if deb_version == version => sha256,
+            (_, Some(locked)) if locked.version == version => locked.sha256.clone(),
+            _ => return Err(format!("No .deb found for {} {}", name, version)),
+        };
+        packages.insert(name, LockedPackage { version, sha256 });
+    }
+    Ok(packages)
+}
+
+// Differences between a lockfile and what a build installed, one line each
+fn drift(locked: &LockedPackages, built: &LockedPackages) -> Vec<String> {
+    let mut lines = Vec::new();
+    for (name, lock) in locked {
+        match built.get(name) {
+            None => lines.push(format!("{} {} is locked but was not installed", name, lock.version)),
+            Some(got) if got.version != lock.version => lines.push(format!("{} {} is locked but {} was installed", name, lock.version, got.version)),
+            Some(got) if got.sha256 != lock.sha256 => {
+                lines.push(format!("{} {}: .deb hash {} does not match the locked {}", name, got.version, got.sha256, lock.sha256))
+            }
+            Some(_) => {}
+        }
+    }
+    for (name, got) in built.iter().filter(|(name, _)| !locked.contains_key(*name)) {
+        lines.push(format!("{} {} was installed but is not locked", name, got.version));
+    }
+    lines
+}
+
+// Bootstrap the treefile's packages into a fresh root and commit it with its metadata. With
+// a lockfile the build uses its repos and versions, and fails on any difference from it.
+// Unless `no_cache` is set, the branch's previous compose from the same suite is updated
+// instead of bootstrapping from scratch, reusing its cached packages, and a lockfile matching
+// it exactly reuses its whole tree.
+pub fn compose_tree(treefile: &str, repo: &str, lockfile: Option<&str>, no_cache: bool) -> Result<String, String> {
+    let tree = load_treefile(treefile)?;
+    let previous = if no_cache { None } else { previous_build(repo, &tree.branch)? };
+    let lock = match lockfile {
+        Some(path) => {
+            let lock = load_lockfile(path)?;
+            if lock.branch != tree.branch || lock.suite != tree.suite {
+                return Err(format!("{} locks {} ({}), not {} ({})", path, lock.branch, lock.suite, tree.branch, tree.suite));
+            }
+            Some((path, lock))
+        }
+        None => None,
+    };
+    let mut workdir = tempfile::Builder::new()
+        .prefix("hacker-ostree-compose-")
+        .tempdir_in(COMPOSE_TMP)
+        .map_err(|e| format!("Failed to create compose directory: {}", e))?;
+    let rootfs = rootfs_of(&workdir);
+
+    let unchanged = match (&lock, &previous) {
+        (Some((_, lock)), Some(previous)) if lock.packages == previous.locked => Some(previous.rev.clone()),
+        _ => None,
+    };
+    // A tree from another suite is bootstrapped again rather than upgraded across releases
+    let updatable = previous.as_ref().filter(|previous| previous.suite.as_deref() == Some(tree.suite.as_str()));
+    let built = match (&lock, &unchanged) {
+        (Some((path, _)), Some(rev)) => {
+            println!("{} matches the previous build {}; reusing its tree", path, rev);
+            previous.as_ref().map(|previous| previous.locked.clone()).unwrap_or_default()
+        }
+        (Some((path, lock)), None) => {
+            let include: Vec<String> = lock.packages.iter().map(|(name, locked)| format!("{}={}", name, locked.version)).collect();
+            let reuse: Vec<&str> = lock.packages.values().map(|locked| locked.sha256.as_str()).collect();
+            let built = match updatable {
+                Some(previous) => update_tree(previous, &lock.repos, &include, false, &reuse, repo, &mut workdir)?,
+                None => bootstrap(&tree, &lock.repos, &include, &reuse, &rootfs)?,
+            };
+            let drift = drift(&lock.packages, &built);
+            if !drift.is_empty() {
+                return Err(format!("The build drifted from {}:\n  {}", path, drift.join("\n  ")));
+            }
+            println!("All {} packages match {}", built.len(), path);
+            built
+        }
+        (None, _) => {
+            let reuse: Vec<&str> = previous.iter().flat_map(|previous| previous.locked.values().map(|l| l.sha256.as_str())).collect();
+            match updatable {
+                Some(previous) => update_tree(previous, &tree.repos, &tree.packages, true, &reuse, repo, &mut workdir)?,
+                None => bootstrap(&tree, &tree.repos, &tree.packages, &reuse, &rootfs)?,
+            }
+        }
+    };
+    let packages: BTreeMap<String, String> = built.iter().map(|(name, locked)| (name.clone(), locked.version.clone())).collect();
+    let advisories = included_advisories(&tree.advisories, &packages);
+
+    // ostree deployments carry the default /etc as /usr/etc and merge it at deploy time
+    let etc = format!("{}/etc", rootfs);
+    if Path::new(&etc).is_dir() {
+        let usr_etc = format!("{}/usr/etc", rootfs);
+        fs::rename(&etc, &usr_etc).map_err(|e| format!("Failed to move {} to {}: {}", etc, usr_etc, e))?;
+    }
+
+    let mut metadata = vec![
+        (METADATA_VERSION, tree.version.clone()),
+        (METADATA_PACKAGES, serde_json::to_string(&packages).map_err(|e| format!("Failed to serialize package manifest: {}", e))?),
+        (METADATA_ADVISORIES, serde_json::to_string(&advisories).map_err(|e| format!("Failed to serialize advisories: {}", e))?),
+        (METADATA_LOCKED, serde_json::to_string(&built).map_err(|e| format!("Failed to serialize locked packages: {}", e))?),
+        (METADATA_SUITE, tree.suite.clone()),
+    ];
+    if let Some(series) = &tree.series {
+        metadata.push((METADATA_SERIES, series.clone()));
+    }
+    let source = match &unchanged {
+        Some(rev) => format!("ref={}", rev),
+        None => rootfs,
+    };
+    let checksum = ostree::commit_tree(repo, &tree.branch, &source, &tree.version, &metadata)?;
+    prune_deb_cache(repo)?;
+    println!("Committed {} to {} as {}", tree.version, tree.branch, checksum);
+    println!("{} packages, {} advisories included", packages.len(), advisories.len());
+    Ok(checksum)
+}
+
+// Compose a treefile and push the commit as an OSTree-native container image, so test
+// machines can rebase onto it from a registry instead of a hosted ostree repository
+pub fn compose_container(treefile: &str, repo: &str, lockfile: Option<&str>, no_cache: bool, tag: &str) -> Result<(), String> {
+    let checksum = compose_tree(treefile, repo, lockfile, no_cache)?;
+    let version = load_treefile(treefile)?.version;
+    let image = if IMAGE_TRANSPORTS.iter().any(|transport| tag.starts_with(transport)) {
+        tag.to_string()
+    } else {
+        format!("docker://{}", tag)
+    };
+    let repo_arg = format!("--repo={}", repo);
+    let label = format!("--label=version={}", version);
+    println!("Pushing {} to {}", checksum, image);
+    run_command_streamed("ostree", &["container", "encapsulate", &repo_arg, &label, &checksum, &image])?;
+    println!("Published {} as {}", version, image);
+    Ok(())
+}
+
+// Resolve a treefile against the archives as they are now and write the result as a
+// lockfile: repos pinned to the current snapshot, exact versions and .deb hashes
+pub fn write_lockfile(treefile: &str, output: &str) -> Result<(), String> {
+    let tree = load_treefile(treefile)?;
+    let timestamp = run_command("date", &["-u", "+%Y%m%dT%H%M%SZ"])?.trim().to_string();
+    let mut pinned = Vec::new();
+    for line in &tree.repos {
+        let mut repo = repos::parse_line(line, None)?;
+        if repo.snapshot.is_none() {
+            match repos::snapshot_uri(&repo.uri, &timestamp) {
+                Ok(_) => repo.snapshot = Some(timestamp.clone()),
+                Err(e) => eprintln!("Warning: {}; it is locked unpinned, so only drift is detected", e),
+            }
+        }
+        pinned.push(repo.resolved()?.to_line());
+    }
+
+    let workdir = tempfile::Builder::new()
+        .prefix("hacker-ostree-lock-")
+        .tempdir_in(COMPOSE_TMP)
+        .map_err(|e| format!("Failed to create compose directory: {}", e))?;
+    let rootfs = format!("{}/rootfs", workdir.path().display());
+    let packages = bootstrap(&tree, &pinned, &tree.packages, &[], &rootfs)?;
+
+    let lock = Lockfile { branch: tree.branch, suite: tree.suite, repos: pinned, packages };
+    let file = File::create(output).map_err(|e| format!("Failed to create {}: {}", output, e))?;
+    serde_json::to_writer_pretty(file, &lock).map_err(|e| format!("Failed to write to {}: {}", output, e))?;
+    println!("Locked {} packages from {} repos at {} in {}", lock.packages.len(), lock.repos.len(), timestamp, output);
+    Ok(())
+}
+
+// Print version, package and advisory changes between two commits' metadata
+pub fn print_diff(old: &CommitMetadata, new: &CommitMetadata) {
+    println!("Version: {} -> {}", old.version_label(), new.version_label());
+    if old.packages.is_empty() || new.packages.is_empty() {
+        println!("No package manifest recorded in one of the commits");
+        return;
+    }
+    let mut upgraded = Vec::new();
+    let mut downgraded = Vec::new();
+    let mut added = Vec::new();
+    for (name, version) in &new.packages {
+        match old.packages.get(name) {
+            None => added.push(format!("{} {}", name, version)),
+            Some(previous) => match compare_versions(version, previous) {
+                Ordering::Greater => upgraded.push(format!("{} {} -> {}", name, previous, version)),
+                Ordering::Less => downgraded.push(format!("{} {} -> {}", name, previous, version)),
+                Ordering::Equal => {}
+            },
+        }
+    }
+    let removed: Vec<String> = old
+        .packages
+        .iter()
+        .filter(|(name, _)| !new.packages.contains_key(*name))
+        .map(|(name, version)| format!("{} {}", name, version))
+        .collect();
+    let fixed: Vec<String> = new
+        .advisories
+        .iter()
+        .filter(|advisory| !old.advisories.iter().any(|a| a.id == advisory.id))
+        .map(|advisory| match advisory.severity.as_str() {
+            "" => format!("{} ({} {})", advisory.id, advisory.package, advisory.fixed_version),
+            severity => format!("{} ({} {}, {})", advisory.id, advisory.package, advisory.fixed_version, severity),
+        })
+        .collect();
+
+    for (title, lines) in [
+        ("Upgraded", &upgraded),
+        ("Downgraded", &downgraded),
+        ("Added", &added),
+        ("Removed", &removed),
+        ("Security advisories", &fixed),
+    ] {
+        if lines.is_empty() {
+            continue;
+        }
+        println!("{}:", title);
+        for line in lines {
+            println!("  {}", line);
+        }
+    }
+    if upgraded.is_empty() && downgraded.is_empty() && added.is_empty() && removed.is_empty() {
+        println!("No package changes");
+    }
+}
@@ -0,0 +1,199 @@
+use std::fs::{self, create_dir_all, File};
+use std::path::Path;
+use std::process::Command as ProcessCommand;
+use serde::{Deserialize, Serialize};
+use crate::{dpkgdb, run_command, OVERLAY_DIR};
+
+// New package versions of conffiles the user edited, kept aside until reviewed
+pub const CONFFILES_DIR: &str = "/var/lib/hacker-ostree/conffiles";
+const PENDING_FILE: &str = "/var/lib/hacker-ostree/conffiles/pending.json";
+
+// Where the user's edited copy of a conffile lives
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Location {
+    // The copy dpkg installed into the overlay
+    Overlay,
+    // The live /etc of the deployment
+    Etc,
+}
+
+// Conffile whose new package version was not installed because the user had edited it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Pending {
+    pub package: String,
+    // Version of the package that shipped the candidate
+    pub version: String,
+    pub path: String,
+    pub edited_in: Location,
+}
+
+impl Pending {
+    // The user's copy, which stays in effect until the candidate is taken
+    pub fn current_path(&self) -> String {
+        match self.edited_in {
+            Location::Overlay => format!("{}{}", OVERLAY_DIR, self.path),
+            Location::Etc => self.path.clone(),
+        }
+    }
+
+    pub fn candidate_path(&self) -> String {
+        format!("{}{}.dpkg-new", CONFFILES_DIR, self.path)
+    }
+}
+
+// Conffile the user changed since dpkg installed it: (path, where, md5sum dpkg recorded)
+pub struct Edited {
+    path: String,
+    location: Location,
+    recorded: String,
+}
+
+fn md5(path: &str) -> Option<String> {
+    let output = run_command("md5sum", &["--", path]).ok()?;
+    output.split_whitespace().next().map(str::to_string)
+}
+
+// Conffiles of an installed overlay package that were edited in the overlay or in /etc
+pub fn edited(package: &str) -> Result<Vec<Edited>, String> {
+    let mut edited = Vec::new();
+    for (path, recorded) in dpkgdb::conffiles(package)? {
+        let location = if md5(&format!("{}{}", OVERLAY_DIR, path)).is_some_and(|sum| sum != recorded) {
+            Location::Overlay
+        } else if md5(&path).is_some_and(|sum| sum != recorded) {
+            Location::Etc
+        } else {
+            continue;
+        };
+        edited.push(Edited { path, location, recorded });
+    }
+    Ok(edited)
+}
+
+pub fn load_pending() -> Result<Vec<Pending>, String> {
+    if !Path::new(PENDING_FILE).exists() {
+        return Ok(Vec::new());
+    }
+    let file = File::open(PENDING_FILE).map_err(|e| format!("Failed to open {}: {}", PENDING_FILE, e))?;
+    serde_json::from_reader(file).map_err(|e| format!("Failed to parse {}: {}", PENDING_FILE, e))
+}
+
+fn save_pending(pending: &[Pending]) -> Result<(), String> {
+    create_dir_all(CONFFILES_DIR).map_err(|e| format!("Failed to create {}: {}", CONFFILES_DIR, e))?;
+    let file = File::create(PENDING_FILE).map_err(|e| format!("Failed to create {}: {}", PENDING_FILE, e))?;
+    serde_json::to_writer_pretty(file, pending).map_err(|e| format!("Failed to write {}: {}", PENDING_FILE, e))
+}
+
+// After an upgrade ran with --force-confold, set the package's new versions of edited
+// conffiles aside as candidates; returns how many were recorded
+pub fn set_aside(package: &str, edited: &[Edited]) -> Result<usize, String> {
+    let version = dpkgdb::installed_version(package)?.unwrap_or_default();
+    let mut pending = load_pending()?;
+    let mut recorded = 0;
+    for conffile in edited {
+        let overlay_path = format!("{}{}", OVERLAY_DIR, conffile.path);
+        let entry = Pending {
+            package: package.to_string(),
+            version: version.clone(),
+            path: conffile.path.clone(),
+            edited_in: conffile.location,
+        };
+        let candidate = entry.candidate_path();
+        match conffile.location {
+            Location::Overlay => {
+                // dpkg kept the edited file and wrote the package's version next to it
+                let dist = format!("{}.dpkg-dist", overlay_path);
+                if !Path::new(&dist).exists() {
+                    continue;
+                }
+                create_parent(&candidate)?;
+                fs::rename(&dist, &candidate).map_err(|e| format!("Failed to move {}: {}", dist, e))?;
+            }
+            Location::Etc => {
+                // The untouched overlay copy was replaced; it is news only if the package changed it
+                if md5(&overlay_path).is_none_or(|sum| sum == conffile.recorded) {
+                    continue;
+                }
+                create_parent(&candidate)?;
+                fs::copy(&overlay_path, &candidate).map_err(|e| format!("Failed to copy {}: {}", overlay_path, e))?;
+            }
+        }
+        pending.retain(|p| p.path != entry.path);
+        pending.push(entry);
+        recorded += 1;
+    }
+    if recorded > 0 {
+        save_pending(&pending)?;
+    }
+    Ok(recorded)
+}
+
+fn create_parent(path: &str) -> Result<(), String> {
+    match Path::new(path).parent() {
+        Some(parent) => create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e)),
+        None => Ok(()),
+    }
+}
+
+fn find(pending: &[Pending], path: &str) -> Result<usize, String> {
+    pending
+        .iter()
+        .position(|p| p.path == path)
+        .ok_or_else(|| format!("No pending configuration update for {}", path))
+}
+
+// Print the pending candidates
+pub fn list() -> Result<(), String> {
+    let pending = load_pending()?;
+    if pending.is_empty() {
+        println!("No configuration updates to review");
+        return Ok(());
+    }
+    for entry in &pending {
+        let location = match entry.edited_in {
+            Location::Overlay => "overlay",
+            Location::Etc => "/etc",
+        };
+        println!("{} ({} {}, edited in {})", entry.path, entry.package, entry.version, location);
+    }
+    Ok(())
+}
+
+// Show how the package's new version differs from the user's copy
+pub fn diff(path: Option<&str>) -> Result<(), String> {
+    let pending = load_pending()?;
+    let selected: Vec<&Pending> = match path {
+        Some(path) => vec![&pending[find(&pending, path)?]],
+        None => pending.iter().collect(),
+    };
+    for entry in selected {
+        // diff exits with 1 when the files differ, which is the expected case here
+        let output = ProcessCommand::new("diff")
+            .args(["-u", &entry.current_path(), &entry.candidate_path()])
+            .output()
+            .map_err(|e| format!("Failed to execute diff: {}", e))?;
+        print!("{}", String::from_utf8_lossy(&output.stdout));
+    }
+    Ok(())
+}
+
+// Settle a candidate: install the package's version (saving the user's as .dpkg-old) or keep the user's
+pub fn resolve(path: &str, take_new: bool) -> Result<(), String> {
+    let mut pending = load_pending()?;
+    let entry = pending.remove(find(&pending, path)?);
+    let current = entry.current_path();
+    let candidate = entry.candidate_path();
+    if take_new {
+        let old = format!("{}.dpkg-old", current);
+        if Path::new(&current).exists() {
+            fs::copy(&current, &old).map_err(|e| format!("Failed to back up {}: {}", current, e))?;
+        }
+        fs::copy(&candidate, &current).map_err(|e| format!("Failed to install {}: {}", current, e))?;
+        println!("Installed the {} {} version of {}; yours is saved as {}", entry.package, entry.version, path, old);
+    } else {
+        println!("Kept your version of {}", path);
+    }
+    fs::remove_file(&candidate).map_err(|e| format!("Failed to remove {}: {}", candidate, e))?;
+    save_pending(&pending)
+}
@@ -0,0 +1,204 @@
+use std::fs::File;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+const CONFIG_FILE: &str = "/etc/hacker-ostree/config.json";
+
+// How committed overlay generations are stored on disk
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum StorageBackend {
+    // Plain directory under /var/lib/hacker-ostree/overlay
+    #[default]
+    Directory,
+    // Compressed squashfs image per generation, mounted as an overlayfs lower layer
+    Squashfs,
+    // composefs/erofs image per generation backed by a content-addressed object store
+    Composefs,
+}
+
+// What system-update does with layered packages the new base already ships
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnlayerPolicy {
+    // Offer to remove them when running interactively, otherwise only report them
+    #[default]
+    Ask,
+    // Remove them from the overlay without asking
+    Auto,
+    // Keep them layered
+    Never,
+}
+
+// Where package transactions take effect
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Layering {
+    // Compose base and overlay into a new OSTree deployment that takes effect on reboot
+    #[default]
+    Deployment,
+    // Change the overlay of the running system in place
+    Live,
+}
+
+// What a dpkg or maintainer script run does when it asks a question no other setting answers
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum PromptPolicy {
+    // Fail the transaction: without prompt-answers dpkg finds its input closed, otherwise it
+    // is aborted with the question
+    #[default]
+    Abort,
+    // Answer with an empty line, taking the default the question offers
+    Default,
+}
+
+// Answer typed into prompts matching a glob, e.g. {"prompt": "*Restart services*", "answer": "yes"}
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PromptAnswer {
+    pub prompt: String,
+    pub answer: String,
+}
+
+// Command run after packages matching a glob are installed, e.g.
+// {"package": "wireshark-common", "run": ["setcap", "cap_net_raw+ep", "{root}/usr/bin/dumpcap"]}
+// "{root}" expands to the overlay root and "{package}" to the installed package
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PostAction {
+    pub package: String,
+    pub run: Vec<String>,
+}
+
+// Global settings loaded from config.json
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Config {
+    pub storage_backend: StorageBackend,
+    pub squashfs_compression: String,
+    pub keep_generations: usize,
+    // Require fs-verity digests to match when mounting composefs images
+    pub composefs_verity: bool,
+    // Parent commits to fetch on base pulls: 0 for only the newest, -1 for full history
+    pub pull_depth: i32,
+    // How often a failed base pull is retried before giving up
+    pub network_retries: u32,
+    // Delay before the first retry, doubled after every failed attempt
+    pub retry_delay_secs: u64,
+    // Translation-<lang> indexes to fetch; "environment" expands to the locale's language
+    pub languages: Vec<String>,
+    // Refuse transactions downloading more than this many bytes; 0 disables the check
+    pub max_download_size: u64,
+    // Refuse transactions leaving less than this many bytes free under /var; 0 disables the check
+    pub min_free_space_after: u64,
+    // Abort a transaction step (download or dpkg run) after this many seconds without progress; 0 disables
+    pub stall_timeout_secs: u64,
+    // Automatic updates wait while discharging below this charge in percent; 0 disables
+    pub auto_update_min_battery: u32,
+    // Let automatic updates download over connections NetworkManager reports as metered
+    pub auto_update_on_metered: bool,
+    // Automatic updates wait up to this many seconds before fetching, each machine a fixed
+    // share derived from its machine id, so a fleet doesn't hit the mirror all at once; 0 disables
+    pub auto_update_jitter_secs: u64,
+    // When automatic updates may be applied, e.g. "Mon-Fri 02:00-05:00"; empty means any time
+    pub maintenance_windows: Vec<String>,
+    // URL receiving a JSON POST for transaction results, available updates and rollbacks
+    pub notify_webhook: Option<String>,
+    // Address mailed the same events through the local sendmail
+    pub notify_email: Option<String>,
+    // Remote syslog server, "host[:port]" (6514 by default), receiving every event plus
+    // repository and key changes as RFC 5424 messages over TLS, for central change tracking
+    pub syslog_server: Option<String>,
+    // CA certificates to verify the syslog server with instead of the system's
+    pub syslog_ca: Option<String>,
+    // HTTP collector receiving the same events as syslog-server, as JSON POSTs
+    pub audit_collector: Option<String>,
+    pub prompt_policy: PromptPolicy,
+    // Checked before prompt-policy, first match wins
+    pub prompt_answers: Vec<PromptAnswer>,
+    // Integration steps run inside install transactions; a failing step rolls the transaction back
+    pub post_install: Vec<PostAction>,
+    // Refuse to deploy base commits older than the booted one unless --allow-downgrade is given
+    pub downgrade_protection: bool,
+    // Handling of layered packages absorbed into a new base image
+    pub unlayer_absorbed: UnlayerPolicy,
+    pub layering: Layering,
+    // When check-update finds a newer base, pull it and download the layered packages it
+    // needs reapplied in the background, so applying it later needs no network
+    pub prewarm_updates: bool,
+    // dpkg force options used on top of the essential ones, e.g. "overwrite"; conflicts
+    // they would hide fail the transaction otherwise
+    pub force_options: Vec<String>,
+    // Suite or codename, e.g. "bookworm-backports", whose versions are preferred over every
+    // other repo's like APT::Default-Release; -t overrides it for one run
+    pub default_release: Option<String>,
+    // Keyserver `repo refresh-keys` fetches keys not added from a URL from
+    // (keyserver.ubuntu.com by default)
+    pub keyserver: Option<String>,
+    // Base URL `self-update` checks for newer releases of this tool: it serves release.json,
+    // its detached signature release.json.sig and the packages the manifest lists
+    pub self_update_channel: Option<String>,
+    // Limits of the systemd scope transactions run in, so downloads and dpkg runs don't
+    // degrade interactive use: relative CPU and IO weights (1-10000, 100 is the default
+    // every other service gets) and a memory ceiling in bytes; 0 leaves each unlimited
+    pub cpu_weight: u64,
+    pub io_weight: u64,
+    pub memory_max: u64,
+    // Enable the `ex` commands: features still being iterated on, which may change or go away
+    pub experimental: bool,
+    // Operations hard to undo that need a typed confirmation token, or --i-know-what-im-doing
+    // when run without a terminal: rollback, prune, clean-orphans and diff-fix
+    pub confirm_tokens: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            storage_backend: StorageBackend::Directory,
+            squashfs_compression: "zstd".to_string(),
+            keep_generations: 3,
+            composefs_verity: true,
+            pull_depth: 0,
+            network_retries: 5,
+            retry_delay_secs: 5,
+            languages: vec!["environment".to_string()],
+            max_download_size: 0,
+            min_free_space_after: 0,
+            stall_timeout_secs: 600,
+            auto_update_min_battery: 30,
+            auto_update_on_metered: false,
+            auto_update_jitter_secs: 1800,
+            maintenance_windows: Vec::new(),
+            notify_webhook: None,
+            notify_email: None,
+            syslog_server: None,
+            syslog_ca: None,
+            audit_collector: None,
+            prompt_policy: PromptPolicy::Abort,
+            prompt_answers: Vec::new(),
+            post_install: Vec::new(),
+            downgrade_protection: true,
+            unlayer_absorbed: UnlayerPolicy::Ask,
+            layering: Layering::Deployment,
+            prewarm_updates: false,
+            force_options: Vec::new(),
+            default_release: None,
+            keyserver: None,
+            self_update_channel: None,
+            cpu_weight: 0,
+            io_weight: 0,
+            memory_max: 0,
+            experimental: false,
+            confirm_tokens: ["rollback", "prune", "clean-orphans", "diff-fix"].map(String::from).to_vec(),
+        }
+    }
+}
+
+// Load config.json, falling back to defaults when it does not exist
+pub fn load_config() -> Result<Config, String> {
+    let path = Path::new(CONFIG_FILE);
+    if !path.exists() {
+        return Ok(Config::default());
+    }
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", CONFIG_FILE, e))?;
+    serde_json::from_reader(file).map_err(|e| format!("Failed to parse {}: {}", CONFIG_FILE, e))
+}
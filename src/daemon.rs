@@ -0,0 +1,594 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::process::{Command as ProcessCommand, Stdio};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use crate::dbus::{self, Arg};
+use crate::{history, transaction};
+
+// Where the daemon accepts clients; only root may connect
+pub const SOCKET: &str = "/run/hacker-ostree/daemon.sock";
+// Name, object and interface the daemon exports on the system bus for software centers
+const BUS_NAME: &str = "org.hackeros.OSTree1";
+const OBJECT_PATH: &str = "/org/hackeros/OSTree1";
+const INTERFACE: &str = "org.hackeros.OSTree1";
+const INTROSPECTABLE: &str = "org.freedesktop.DBus.Introspectable";
+// polkit action every method changing the system needs, see data/org.hackeros.OSTree1.policy
+const ACTION: &str = "org.hackeros.OSTree1.manage";
+const INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.hackeros.OSTree1">
+    <method name="Install"><arg name="packages" type="as" direction="in"/><arg name="job" type="t" direction="out"/></method>
+    <method name="Remove"><arg name="packages" type="as" direction="in"/><arg name="job" type="t" direction="out"/></method>
+    <method name="Upgrade"><arg name="job" type="t" direction="out"/></method>
+    <method name="SystemUpdate"><arg name="job" type="t" direction="out"/></method>
+    <method name="Cancel"><arg name="job" type="t" direction="in"/></method>
+    <signal name="Output"><arg name="job" type="t"/><arg name="line" type="s"/></signal>
+    <signal name="Progress"><arg name="job" type="t"/><arg name="phase" type="s"/><arg name="percent" type="u"/></signal>
+    <signal name="Finished"><arg name="job" type="t"/><arg name="success" type="b"/></signal>
+    <signal name="Cancelled"><arg name="job" type="t"/></signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect"><arg name="xml" type="s" direction="out"/></method>
+  </interface>
+</node>
+"#;
+
+// A client that takes longer than this to accept a message is dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Connection to the system bus, when the daemon could claim its name there
+static BUS: OnceLock<dbus::Bus> = OnceLock::new();
+
+// One line of JSON sent by a client
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+enum Request {
+    // Queue a command line and stream its output once it runs
+    Submit { args: Vec<String> },
+    List,
+    // Drop a queued job; running jobs finish so the overlay is never left half-changed
+    Cancel { id: u64 },
+    // Stream a job's output from the start; the running job without an id
+    Attach { id: Option<u64> },
+}
+
+// One line of JSON sent back to a client
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum Message {
+    Queued { id: u64, position: usize },
+    Output { id: u64, line: String },
+    // How far a phase of the job (download, unpack) has come
+    Progress { id: u64, phase: String, percent: u32 },
+    Finished { id: u64, success: bool },
+    Cancelled { id: u64 },
+    Jobs { jobs: Vec<JobInfo> },
+    Error { message: String },
+}
+
+// Job as shown by `queue list`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+struct JobInfo {
+    id: u64,
+    command: String,
+    running: bool,
+    submitted: u64,
+}
+
+struct Job {
+    id: u64,
+    args: Vec<String>,
+    submitted: u64,
+    // Clients streaming this job's output
+    subscribers: Vec<Sender<Message>>,
+    // Output so far, replayed to clients attaching late
+    log: Vec<String>,
+}
+
+impl Job {
+    fn info(&self, running: bool) -> JobInfo {
+        JobInfo { id: self.id, command: self.args.join(" "), running, submitted: self.submitted }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<Job>,
+    running: Option<Job>,
+    next_id: u64,
+}
+
+type Shared = Arc<(Mutex<State>, Condvar)>;
+
+fn send(stream: &mut UnixStream, message: &Message) -> bool {
+    match serde_json::to_string(message) {
+        Ok(mut line) => {
+            line.push('\n');
+            stream.write_all(line.as_bytes()).is_ok()
+        }
+        Err(_) => false,
+    }
+}
+
+// Hand a client to a thread of its own that writes the messages sent to it, so a client that
+// stops reading holds up nobody else; once a write fails or times out the client is dropped
+fn subscribe(mut stream: UnixStream) -> Sender<Message> {
+    let (client, messages) = mpsc::channel();
+    let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+    thread::spawn(move || {
+        for message in messages {
+            if !send(&mut stream, &message) {
+                return;
+            }
+        }
+    });
+    client
+}
+
+// Mirror a job event as a signal on the bus
+fn signal(message: &Message) {
+    let bus = match BUS.get() {
+        Some(bus) => bus,
+        None => return,
+    };
+    let (member, args) = match message {
+        Message::Output { id, line } => ("Output", vec![Arg::U64(*id), Arg::Str(line.clone())]),
+        Message::Progress { id, phase, percent } => ("Progress", vec![Arg::U64(*id), Arg::Str(phase.clone()), Arg::U32(*percent)]),
+        Message::Finished { id, success } => ("Finished", vec![Arg::U64(*id), Arg::Bool(*success)]),
+        Message::Cancelled { id } => ("Cancelled", vec![Arg::U64(*id)]),
+        _ => return,
+    };
+    if let Err(e) = bus.signal(OBJECT_PATH, INTERFACE, member, &args) {
+        eprintln!("Failed to emit {} signal: {}", member, e);
+    }
+}
+
+// Queue a message for every subscriber of a job, forgetting clients that went away; the
+// matching signal is left to the caller, to emit once it released the state
+fn broadcast(job: &mut Job, message: &Message) {
+    job.subscribers.retain(|client| client.send(message.clone()).is_ok());
+}
+
+// Record and forward one line of output of the running job; progress lines are forwarded
+// as progress only
+fn publish(shared: &Shared, id: u64, line: String) {
+    let message = {
+        let mut state = shared.0.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(job) = state.running.as_mut().filter(|job| job.id == id) else {
+            return;
+        };
+        let progress = line
+            .strip_prefix(transaction::PROGRESS_PREFIX)
+            .and_then(|rest| rest.split_once(' '))
+            .and_then(|(phase, percent)| Some((phase.to_string(), percent.parse().ok()?)));
+        let message = match progress {
+            Some((phase, percent)) => Message::Progress { id, phase, percent },
+            None => Message::Output { id, line: line.clone() },
+        };
+        broadcast(job, &message);
+        if matches!(message, Message::Output { .. }) {
+            job.log.push(line);
+        }
+        message
+    };
+    signal(&message);
+}
+
+// Add a command line to the queue, returning its id and the number of jobs ahead of it
+fn enqueue(state: &mut State, args: Vec<String>) -> (u64, usize) {
+    state.next_id += 1;
+    let id = state.next_id;
+    let position = state.queue.len() + usize::from(state.running.is_some());
+    state.queue.push_back(Job { id, args, submitted: history::now(), subscribers: Vec::new(), log: Vec::new() });
+    (id, position)
+}
+
+// Drop a job that hasn't started yet
+fn cancel_queued(state: &mut State, id: u64) -> Result<(), String> {
+    if state.running.as_ref().is_some_and(|job| job.id == id) {
+        return Err(format!("Job {} is already running and can't be cancelled", id));
+    }
+    match state.queue.iter().position(|job| job.id == id).and_then(|index| state.queue.remove(index)) {
+        Some(mut job) => {
+            broadcast(&mut job, &Message::Cancelled { id });
+            Ok(())
+        }
+        None => Err(format!("No queued job {}", id)),
+    }
+}
+
+// Run queued jobs one at a time, each as a separate invocation of this binary
+fn worker(shared: Shared) {
+    let exe = std::env::current_exe().unwrap_or_else(|_| "hacker-ostree".into());
+    loop {
+        let (id, args) = {
+            let (lock, ready) = &*shared;
+            let mut state = lock.lock().unwrap_or_else(|e| e.into_inner());
+            while state.queue.is_empty() {
+                state = ready.wait(state).unwrap_or_else(|e| e.into_inner());
+            }
+            let job = state.queue.pop_front().expect("queue is not empty");
+            let started = (job.id, job.args.clone());
+            state.running = Some(job);
+            started
+        };
+
+        // A job must never wait on itself when it finds the lock taken
+        let success = match ProcessCommand::new(&exe)
+            .arg("--no-attach")
+            .args(&args)
+            .env(transaction::PROGRESS_ENV, "1")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+        {
+            Ok(mut child) => {
+                let readers: Vec<_> = [
+                    child.stdout.take().map(|out| Box::new(out) as Box<dyn Read + Send>),
+                    child.stderr.take().map(|err| Box::new(err) as Box<dyn Read + Send>),
+                ]
+                .into_iter()
+                .flatten()
+                .map(|pipe| {
+                    let shared = Arc::clone(&shared);
+                    thread::spawn(move || {
+                        for line in BufReader::new(pipe).lines().map_while(Result::ok) {
+                            publish(&shared, id, line);
+                        }
+                    })
+                })
+                .collect();
+                for reader in readers {
+                    let _ = reader.join();
+                }
+                child.wait().is_ok_and(|status| status.success())
+            }
+            Err(e) => {
+                publish(&shared, id, format!("Failed to execute {}: {}", exe.display(), e));
+                false
+            }
+        };
+
+        let finished = shared.0.lock().unwrap_or_else(|e| e.into_inner()).running.take();
+        if let Some(mut job) = finished {
+            let message = Message::Finished { id, success };
+            broadcast(&mut job, &message);
+            signal(&message);
+        }
+    }
+}
+
+// Answer one client connection
+fn handle(shared: Shared, mut stream: UnixStream) {
+    let mut line = String::new();
+    let read = stream.try_clone().map(BufReader::new).and_then(|mut reader| reader.read_line(&mut line));
+    if read.is_err() {
+        return;
+    }
+    let request: Request = match serde_json::from_str(&line) {
+        Ok(request) => request,
+        Err(e) => {
+            send(&mut stream, &Message::Error { message: format!("Invalid request: {}", e) });
+            return;
+        }
+    };
+    // Replies are written once the state is released, like every other message
+    let (lock, ready) = &*shared;
+    let reply = {
+        let mut state = lock.lock().unwrap_or_else(|e| e.into_inner());
+        match request {
+            Request::Submit { args } => {
+                let (id, position) = enqueue(&mut state, args);
+                let client = subscribe(stream);
+                let _ = client.send(Message::Queued { id, position });
+                if let Some(job) = state.queue.back_mut() {
+                    job.subscribers.push(client);
+                }
+                ready.notify_one();
+                return;
+            }
+            Request::List => {
+                let mut jobs: Vec<JobInfo> = state.running.iter().map(|job| job.info(true)).collect();
+                jobs.extend(state.queue.iter().map(|job| job.info(false)));
+                Message::Jobs { jobs }
+            }
+            Request::Cancel { id } => match cancel_queued(&mut state, id) {
+                Ok(()) => Message::Cancelled { id },
+                Err(message) => Message::Error { message },
+            },
+            Request::Attach { id } => {
+                let State { queue, running, .. } = &mut *state;
+                let job = running
+                    .iter_mut()
+                    .chain(queue.iter_mut())
+                    .find(|job| id.is_none_or(|id| job.id == id));
+                match job {
+                    Some(job) => {
+                        let client = subscribe(stream);
+                        for line in &job.log {
+                            let _ = client.send(Message::Output { id: job.id, line: line.clone() });
+                        }
+                        job.subscribers.push(client);
+                        return;
+                    }
+                    None => Message::Error {
+                        message: match id {
+                            Some(id) => format!("No running or queued job {}", id),
+                            None => "No transaction is running".to_string(),
+                        },
+                    },
+                }
+            }
+        }
+    };
+    if let Message::Cancelled { .. } = reply {
+        signal(&reply);
+    }
+    send(&mut stream, &reply);
+}
+
+// Answer one method call on the bus, with the reply arguments or an error name and message
+fn answer(shared: &Shared, call: &dbus::Incoming) -> Result<Vec<Arg>, (&'static str, String)> {
+    let args = call.args.clone().map_err(|e| (dbus::ERROR_FAILED, e))?;
+    if call.path.as_deref() != Some(OBJECT_PATH) {
+        return Err((dbus::ERROR_UNKNOWN_METHOD, format!("No object at {}", call.path.as_deref().unwrap_or_default())));
+    }
+    let member = call.member.as_deref().unwrap_or_default();
+    match (call.interface.as_deref(), member, args.as_slice()) {
+        (Some(INTROSPECTABLE), "Introspect", []) => return Ok(vec![Arg::Str(INTROSPECTION.to_string())]),
+        (Some(INTERFACE) | None, "Cancel", [Arg::U64(id)]) => {
+            call.authorize(ACTION).map_err(|e| (dbus::ERROR_ACCESS_DENIED, e))?;
+            let cancelled = cancel_queued(&mut shared.0.lock().unwrap_or_else(|e| e.into_inner()), *id);
+            cancelled.map_err(|e| (dbus::ERROR_FAILED, e))?;
+            signal(&Message::Cancelled { id: *id });
+            return Ok(Vec::new());
+        }
+        _ => {}
+    }
+    let command = command_line(call.interface.as_deref(), member, &args)?;
+    call.authorize(ACTION).map_err(|e| (dbus::ERROR_ACCESS_DENIED, e))?;
+    let (lock, ready) = &**shared;
+    let mut state = lock.lock().unwrap_or_else(|e| e.into_inner());
+    let (id, _) = enqueue(&mut state, command);
+    ready.notify_one();
+    Ok(vec![Arg::U64(id)])
+}
+
+// Command line of the job a method call queues
+fn command_line(interface: Option<&str>, member: &str, args: &[Arg]) -> Result<Vec<String>, (&'static str, String)> {
+    let command = match (interface, member, args) {
+        (Some(INTERFACE) | None, "Install", [Arg::Strs(packages)]) => [vec!["install".to_string()], packages.clone()].concat(),
+        (Some(INTERFACE) | None, "Remove", [Arg::Strs(packages)]) => [vec!["remove".to_string()], packages.clone()].concat(),
+        (Some(INTERFACE) | None, "Upgrade", []) => vec!["upgrade".to_string()],
+        (Some(INTERFACE) | None, "SystemUpdate", []) => vec!["system-update".to_string()],
+        _ => return Err((dbus::ERROR_UNKNOWN_METHOD, format!("Unknown method {} or wrong arguments", member))),
+    };
+    if matches!(member, "Install" | "Remove") && command.len() == 1 {
+        return Err((dbus::ERROR_FAILED, format!("{} needs at least one package", member)));
+    }
+    // Package names become arguments of the command line; don't let them pass as options
+    if let Some(option) = command.iter().skip(1).find(|arg| arg.is_empty() || arg.starts_with('-')) {
+        return Err((dbus::ERROR_FAILED, format!("Invalid package name '{}'", option)));
+    }
+    Ok(command)
+}
+
+// Answer method calls on the bus until the connection drops
+fn serve_bus(shared: Shared, bus: &dbus::Bus) {
+    loop {
+        let call = match bus.next_call() {
+            Ok(call) => call,
+            Err(e) => {
+                eprintln!("Lost the system bus connection: {}", e);
+                return;
+            }
+        };
+        let sent = match answer(&shared, &call) {
+            Ok(args) => bus.reply(&call, &args),
+            Err((name, message)) => bus.error(&call, name, &message),
+        };
+        if let Err(e) = sent {
+            eprintln!("Failed to answer a D-Bus call: {}", e);
+        }
+    }
+}
+
+// Claim the daemon's name on the system bus; the socket keeps working without it
+fn connect_bus() -> Result<&'static dbus::Bus, String> {
+    let bus = dbus::Bus::system()?;
+    bus.request_name(BUS_NAME)?;
+    Ok(BUS.get_or_init(|| bus))
+}
+
+// Serve clients on the socket until killed
+pub fn serve() -> Result<(), String> {
+    if let Some(parent) = Path::new(SOCKET).parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    if UnixStream::connect(SOCKET).is_ok() {
+        return Err(format!("A daemon is already listening on {}", SOCKET));
+    }
+    let _ = fs::remove_file(SOCKET);
+    let listener = UnixListener::bind(SOCKET).map_err(|e| format!("Failed to bind {}: {}", SOCKET, e))?;
+    fs::set_permissions(SOCKET, fs::Permissions::from_mode(0o600))
+        .map_err(|e| format!("Failed to set permissions on {}: {}", SOCKET, e))?;
+    println!("Listening on {}", SOCKET);
+
+    let shared: Shared = Arc::new((Mutex::new(State::default()), Condvar::new()));
+    let worker_state = Arc::clone(&shared);
+    thread::spawn(move || worker(worker_state));
+    match connect_bus() {
+        Ok(bus) => {
+            println!("Serving {} on the system bus", BUS_NAME);
+            let bus_state = Arc::clone(&shared);
+            thread::spawn(move || serve_bus(bus_state, bus));
+        }
+        Err(e) => eprintln!("Warning: D-Bus interface unavailable: {}", e),
+    }
+    for stream in listener.incoming().map_while(Result::ok) {
+        let shared = Arc::clone(&shared);
+        thread::spawn(move || handle(shared, stream));
+    }
+    Ok(())
+}
+
+// Send a request and return the daemon's replies
+fn request(request: &Request) -> Result<impl Iterator<Item = Result<Message, String>>, String> {
+    let mut stream = UnixStream::connect(SOCKET).map_err(|e| format!("Failed to connect to the daemon at {}: {}", SOCKET, e))?;
+    let mut line = serde_json::to_string(request).map_err(|e| format!("Failed to encode request: {}", e))?;
+    line.push('\n');
+    stream.write_all(line.as_bytes()).map_err(|e| format!("Failed to send request: {}", e))?;
+    Ok(BufReader::new(stream).lines().map(|line| {
+        let line = line.map_err(|e| format!("Failed to read from the daemon: {}", e))?;
+        serde_json::from_str(&line).map_err(|e| format!("Invalid reply from the daemon: {}", e))
+    }))
+}
+
+// Print a job's output until it ends; fails if the job failed or was cancelled
+fn stream_job(replies: impl Iterator<Item = Result<Message, String>>) -> Result<(), String> {
+    for reply in replies {
+        match reply? {
+            Message::Queued { id, position } if position > 0 => println!("Queued as job {} behind {} other jobs", id, position),
+            Message::Output { line, .. } => println!("{}", line),
+            Message::Finished { id, success } => {
+                return if success { Ok(()) } else { Err(format!("Job {} failed", id)) };
+            }
+            Message::Cancelled { id } => return Err(format!("Job {} was cancelled", id)),
+            Message::Error { message } => return Err(message),
+            _ => {}
+        }
+    }
+    Err("Lost connection to the daemon".to_string())
+}
+
+// Queue a command line with the daemon and follow its output
+pub fn submit(args: &[String]) -> Result<(), String> {
+    stream_job(request(&Request::Submit { args: args.to_vec() })?)
+}
+
+// Follow the output of a running or queued job
+pub fn attach(id: Option<u64>) -> Result<(), String> {
+    stream_job(request(&Request::Attach { id })?)
+}
+
+pub fn list() -> Result<(), String> {
+    for reply in request(&Request::List)? {
+        match reply? {
+            Message::Jobs { jobs } if jobs.is_empty() => println!("No transactions running or queued"),
+            Message::Jobs { jobs } => {
+                for job in jobs {
+                    println!(
+                        "{}: {} ({}, submitted {})",
+                        job.id,
+                        job.command,
+                        if job.running { "running" } else { "queued" },
+                        history::format_time(job.submitted)
+                    );
+                }
+            }
+            Message::Error { message } => return Err(message),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Id and command line of the job the daemon is running, None if there is none or no daemon
+pub fn running_job() -> Option<(u64, String)> {
+    for reply in request(&Request::List).ok()? {
+        if let Ok(Message::Jobs { jobs }) = reply {
+            return jobs.into_iter().find(|job| job.running).map(|job| (job.id, job.command));
+        }
+    }
+    None
+}
+
+// Number of jobs the daemon is running or has queued, 0 if there is no daemon
+pub fn pending_jobs() -> usize {
+    for reply in request(&Request::List).into_iter().flatten() {
+        if let Ok(Message::Jobs { jobs }) = reply {
+            return jobs.len();
+        }
+    }
+    0
+}
+
+pub fn cancel(id: u64) -> Result<(), String> {
+    for reply in request(&Request::Cancel { id })? {
+        match reply? {
+            Message::Cancelled { id } => println!("Cancelled job {}", id),
+            Message::Error { message } => return Err(message),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strs(values: &[&str]) -> Arg {
+        Arg::Strs(values.iter().map(|v| v.to_string()).collect())
+    }
+
+    #[test]
+    fn method_calls_queue_command_lines_the_cli_accepts() {
+        for (member, args) in [
+            ("Install", vec![strs(&["a", "b=1.0"])]),
+            ("Remove", vec![strs(&["a", "b"])]),
+            ("Upgrade", vec![]),
+            ("SystemUpdate", vec![]),
+        ] {
+            let command = command_line(Some(INTERFACE), member, &args).unwrap();
+            let argv = std::iter::once("hacker-ostree".to_string()).chain(command);
+            assert!(crate::build_cli().try_get_matches_from(argv).is_ok(), "{}", member);
+        }
+        assert_eq!(command_line(None, "Install", &[strs(&["a", "b"])]).unwrap(), ["install", "a", "b"]);
+    }
+
+    #[test]
+    fn method_calls_with_bad_arguments_are_refused() {
+        let refused = |member: &str, args: &[Arg]| command_line(Some(INTERFACE), member, args).unwrap_err().0;
+        assert_eq!(refused("Install", &[strs(&["--force-reinstall"])]), dbus::ERROR_FAILED);
+        assert_eq!(refused("Remove", &[strs(&["a", ""])]), dbus::ERROR_FAILED);
+        assert_eq!(refused("Install", &[strs(&[])]), dbus::ERROR_FAILED);
+        assert_eq!(refused("Install", &[Arg::Str("a".to_string())]), dbus::ERROR_UNKNOWN_METHOD);
+        assert_eq!(refused("Upgrade", &[Arg::Bool(true)]), dbus::ERROR_UNKNOWN_METHOD);
+        assert_eq!(refused("Reboot", &[]), dbus::ERROR_UNKNOWN_METHOD);
+        assert!(command_line(Some("org.example.Other"), "Upgrade", &[]).is_err());
+    }
+
+    #[test]
+    fn broadcast_does_not_wait_for_clients_that_stop_reading() {
+        let (stalled, _peer) = UnixStream::pair().unwrap();
+        let (gone, peer) = UnixStream::pair().unwrap();
+        drop(peer);
+        let mut job = Job { id: 1, args: Vec::new(), submitted: 0, subscribers: vec![subscribe(stalled), subscribe(gone)], log: Vec::new() };
+        // Far more than a socket buffer holds
+        let line = "x".repeat(64 * 1024);
+        for _ in 0..64 {
+            broadcast(&mut job, &Message::Output { id: 1, line: line.clone() });
+        }
+        // The client that went away is forgotten once its writer noticed
+        for _ in 0..100 {
+            if job.subscribers.len() == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+            broadcast(&mut job, &Message::Finished { id: 1, success: true });
+        }
+        assert_eq!(job.subscribers.len(), 1);
+    }
+}
@@ -0,0 +1,531 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use crate::run_command;
+
+// Minimal D-Bus client: enough of the wire protocol to own a name on the system bus, answer
+// method calls and emit signals with the few argument types the daemon's interface uses
+
+const SYSTEM_BUS: &str = "unix:path=/run/dbus/system_bus_socket";
+const BUS_NAME: &str = "org.freedesktop.DBus";
+const BUS_PATH: &str = "/org/freedesktop/DBus";
+// The bus refuses anything larger
+const MAX_MESSAGE: usize = 128 * 1024 * 1024;
+
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+const SIGNAL: u8 = 4;
+const NO_REPLY_EXPECTED: u8 = 0x1;
+
+// Header field codes
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SENDER: u8 = 7;
+const FIELD_SIGNATURE: u8 = 8;
+
+// DO_NOT_QUEUE: fail instead of waiting for the current owner to go away
+const NAME_FLAGS: u32 = 0x4;
+const PRIMARY_OWNER: u32 = 1;
+
+pub const ERROR_FAILED: &str = "org.freedesktop.DBus.Error.Failed";
+pub const ERROR_UNKNOWN_METHOD: &str = "org.freedesktop.DBus.Error.UnknownMethod";
+pub const ERROR_ACCESS_DENIED: &str = "org.freedesktop.DBus.Error.AccessDenied";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arg {
+    Bool(bool),
+    U32(u32),
+    U64(u64),
+    Str(String),
+    Strs(Vec<String>),
+}
+
+impl Arg {
+    fn signature(&self) -> &'static str {
+        match self {
+            Arg::Bool(_) => "b",
+            Arg::U32(_) => "u",
+            Arg::U64(_) => "t",
+            Arg::Str(_) => "s",
+            Arg::Strs(_) => "as",
+        }
+    }
+}
+
+// A message received from the bus
+pub struct Incoming {
+    kind: u8,
+    flags: u8,
+    serial: u32,
+    pub path: Option<String>,
+    pub interface: Option<String>,
+    pub member: Option<String>,
+    error_name: Option<String>,
+    reply_serial: Option<u32>,
+    sender: Option<String>,
+    // Arguments, or why they couldn't be decoded
+    pub args: Result<Vec<Arg>, String>,
+}
+
+impl Incoming {
+    // Ask polkit whether the caller may perform `action`, letting its authentication agent
+    // ask for a password where the action's policy wants one
+    pub fn authorize(&self, action: &str) -> Result<(), String> {
+        let sender = self.sender.as_deref().ok_or("Method call without a sender")?;
+        run_command("pkcheck", &["--action-id", action, "--system-bus-name", sender, "--allow-user-interaction"])
+            .map(|_| ())
+            .map_err(|_| format!("{} is not authorized for {}", sender, action))
+    }
+}
+
+// Marshals values with the alignment rules of the wire format, little-endian
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn align(&mut self, n: usize) {
+        while !self.buf.len().is_multiple_of(n) {
+            self.buf.push(0);
+        }
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.align(4);
+        self.buf.extend(value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.align(8);
+        self.buf.extend(value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.buf.extend(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    fn signature(&mut self, value: &str) {
+        self.u8(value.len() as u8);
+        self.buf.extend(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    // Write an array length placeholder, returning where it is and where the elements start
+    fn begin_array(&mut self, element_align: usize) -> (usize, usize) {
+        self.u32(0);
+        let at = self.buf.len() - 4;
+        self.align(element_align);
+        (at, self.buf.len())
+    }
+
+    fn end_array(&mut self, (at, start): (usize, usize)) {
+        let len = (self.buf.len() - start) as u32;
+        self.buf[at..at + 4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    fn arg(&mut self, arg: &Arg) {
+        match arg {
+            Arg::Bool(value) => self.u32(u32::from(*value)),
+            Arg::U32(value) => self.u32(*value),
+            Arg::U64(value) => self.u64(*value),
+            Arg::Str(value) => self.string(value),
+            Arg::Strs(values) => {
+                let array = self.begin_array(4);
+                for value in values {
+                    self.string(value);
+                }
+                self.end_array(array);
+            }
+        }
+    }
+}
+
+// Unmarshals values; positions are relative to the start of the message or body, which
+// are both 8-aligned
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+
+impl Reader<'_> {
+    fn align(&mut self, n: usize) {
+        self.pos = self.pos.div_ceil(n) * n;
+    }
+
+    fn take(&mut self, n: usize) -> Result<&[u8], String> {
+        let bytes = self.data.get(self.pos..self.pos + n).ok_or("Truncated D-Bus message")?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        self.align(4);
+        let bytes: [u8; 4] = self.take(4)?.try_into().map_err(|_| "Truncated D-Bus message")?;
+        Ok(if self.big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        self.align(8);
+        let bytes: [u8; 8] = self.take(8)?.try_into().map_err(|_| "Truncated D-Bus message")?;
+        Ok(if self.big_endian { u64::from_be_bytes(bytes) } else { u64::from_le_bytes(bytes) })
+    }
+
+    fn text(&mut self, len: usize) -> Result<String, String> {
+        let bytes = self.take(len + 1)?;
+        String::from_utf8(bytes[..len].to_vec()).map_err(|_| "Invalid UTF-8 in D-Bus message".to_string())
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        self.text(len)
+    }
+
+    fn signature(&mut self) -> Result<String, String> {
+        let len = self.u8()? as usize;
+        self.text(len)
+    }
+
+    // Decode a body made of the types in Arg only
+    fn args(&mut self, signature: &str) -> Result<Vec<Arg>, String> {
+        let mut args = Vec::new();
+        let mut types = signature.chars();
+        while let Some(code) = types.next() {
+            let arg = match code {
+                'b' => Arg::Bool(self.u32()? != 0),
+                'u' => Arg::U32(self.u32()?),
+                't' => Arg::U64(self.u64()?),
+                's' | 'o' => Arg::Str(self.string()?),
+                'a' if types.next() == Some('s') => {
+                    let len = self.u32()? as usize;
+                    let end = self.pos + len;
+                    let mut values = Vec::new();
+                    while self.pos < end {
+                        values.push(self.string()?);
+                    }
+                    Arg::Strs(values)
+                }
+                _ => return Err(format!("Unsupported D-Bus signature {}", signature)),
+            };
+            args.push(arg);
+        }
+        Ok(args)
+    }
+}
+
+// Header field value with its type code
+enum Field<'a> {
+    Str(char, &'a str),
+    U32(u32),
+}
+
+pub struct Bus {
+    writer: Mutex<UnixStream>,
+    reader: Mutex<BufReader<UnixStream>>,
+    serial: AtomicU32,
+}
+
+// Socket path of the system bus, honouring DBUS_SYSTEM_BUS_ADDRESS
+fn system_bus_path() -> Result<String, String> {
+    let address = std::env::var("DBUS_SYSTEM_BUS_ADDRESS").unwrap_or_else(|_| SYSTEM_BUS.to_string());
+    address
+        .split(';')
+        .filter_map(|entry| entry.strip_prefix("unix:"))
+        .flat_map(|params| params.split(','))
+        .find_map(|param| param.strip_prefix("path="))
+        .map(str::to_string)
+        .ok_or_else(|| format!("Unsupported D-Bus address {}", address))
+}
+
+// Our uid as the bus expects it for EXTERNAL authentication
+fn uid() -> Result<String, String> {
+    let status = fs::read_to_string("/proc/self/status").map_err(|e| format!("Failed to read /proc/self/status: {}", e))?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Uid:"))
+        .and_then(|ids| ids.split_whitespace().next())
+        .map(str::to_string)
+        .ok_or_else(|| "Failed to determine our uid".to_string())
+}
+
+impl Bus {
+    // Connect and authenticate to the system bus and register with it
+    pub fn system() -> Result<Bus, String> {
+        let path = system_bus_path()?;
+        let stream = UnixStream::connect(&path).map_err(|e| format!("Failed to connect to the system bus at {}: {}", path, e))?;
+        let writer = stream.try_clone().map_err(|e| format!("Failed to clone D-Bus socket: {}", e))?;
+        let bus = Bus { writer: Mutex::new(writer), reader: Mutex::new(BufReader::new(stream)), serial: AtomicU32::new(0) };
+
+        let hex_uid: String = uid()?.bytes().map(|b| format!("{:02x}", b)).collect();
+        bus.write(format!("\0AUTH EXTERNAL {}\r\n", hex_uid).as_bytes())?;
+        let mut answer = String::new();
+        bus.reader
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .read_line(&mut answer)
+            .map_err(|e| format!("Failed to read from the system bus: {}", e))?;
+        if !answer.starts_with("OK ") {
+            return Err(format!("System bus rejected authentication: {}", answer.trim()));
+        }
+        bus.write(b"BEGIN\r\n")?;
+        bus.call("Hello", &[])?;
+        Ok(bus)
+    }
+
+    fn write(&self, bytes: &[u8]) -> Result<(), String> {
+        self.writer
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .write_all(bytes)
+            .map_err(|e| format!("Failed to write to the system bus: {}", e))
+    }
+
+    fn send(&self, kind: u8, flags: u8, fields: &[(u8, Field)], args: &[Arg]) -> Result<u32, String> {
+        let serial = self.serial.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut body = Writer::default();
+        for arg in args {
+            body.arg(arg);
+        }
+        let signature: String = args.iter().map(Arg::signature).collect();
+
+        let mut message = Writer::default();
+        for byte in [b'l', kind, flags, 1] {
+            message.u8(byte);
+        }
+        message.u32(body.buf.len() as u32);
+        message.u32(serial);
+        let array = message.begin_array(8);
+        let signature_field = (FIELD_SIGNATURE, Field::Str('g', &signature));
+        for (code, value) in fields.iter().chain((!signature.is_empty()).then_some(&signature_field)) {
+            message.align(8);
+            message.u8(*code);
+            match value {
+                Field::Str(kind, value) => {
+                    message.signature(&kind.to_string());
+                    if *kind == 'g' {
+                        message.signature(value);
+                    } else {
+                        message.string(value);
+                    }
+                }
+                Field::U32(value) => {
+                    message.signature("u");
+                    message.u32(*value);
+                }
+            }
+        }
+        message.end_array(array);
+        message.align(8);
+        message.buf.extend(body.buf);
+        self.write(&message.buf)?;
+        Ok(serial)
+    }
+
+    fn receive(&self) -> Result<Incoming, String> {
+        let mut reader = self.reader.lock().unwrap_or_else(|e| e.into_inner());
+        let mut fixed = [0u8; 16];
+        reader.read_exact(&mut fixed).map_err(|e| format!("Failed to read from the system bus: {}", e))?;
+        let big_endian = match fixed[0] {
+            b'l' => false,
+            b'B' => true,
+            other => return Err(format!("Invalid D-Bus endianness marker {}", other)),
+        };
+        let mut header = Reader { data: &fixed, pos: 4, big_endian };
+        let body_len = header.u32()? as usize;
+        let serial = header.u32()?;
+        let fields_len = header.u32()? as usize;
+        let header_len = (16 + fields_len).div_ceil(8) * 8;
+        if header_len + body_len > MAX_MESSAGE {
+            return Err("D-Bus message too large".to_string());
+        }
+        let mut data = fixed.to_vec();
+        data.resize(header_len + body_len, 0);
+        reader.read_exact(&mut data[16..]).map_err(|e| format!("Failed to read from the system bus: {}", e))?;
+
+        let mut incoming = Incoming {
+            kind: fixed[1],
+            flags: fixed[2],
+            serial,
+            path: None,
+            interface: None,
+            member: None,
+            error_name: None,
+            reply_serial: None,
+            sender: None,
+            args: Ok(Vec::new()),
+        };
+        let mut signature = String::new();
+        let mut fields = Reader { data: &data[..16 + fields_len], pos: 16, big_endian };
+        while fields.pos < 16 + fields_len {
+            fields.align(8);
+            let code = fields.u8()?;
+            let value = match fields.signature()?.as_str() {
+                "s" | "o" => Some(fields.string()?),
+                "g" => Some(fields.signature()?),
+                "u" => {
+                    let value = fields.u32()?;
+                    if code == FIELD_REPLY_SERIAL {
+                        incoming.reply_serial = Some(value);
+                    }
+                    None
+                }
+                other => return Err(format!("Unsupported D-Bus header field type {}", other)),
+            };
+            match code {
+                FIELD_PATH => incoming.path = value,
+                FIELD_INTERFACE => incoming.interface = value,
+                FIELD_MEMBER => incoming.member = value,
+                FIELD_ERROR_NAME => incoming.error_name = value,
+                FIELD_SENDER => incoming.sender = value,
+                FIELD_SIGNATURE => signature = value.unwrap_or_default(),
+                _ => {}
+            }
+        }
+        let mut body = Reader { data: &data[header_len..], pos: 0, big_endian };
+        incoming.args = body.args(&signature);
+        Ok(incoming)
+    }
+
+    // Call a method of the bus itself and wait for its reply; only used before serving
+    fn call(&self, member: &str, args: &[Arg]) -> Result<Vec<Arg>, String> {
+        let fields = [
+            (FIELD_PATH, Field::Str('o', BUS_PATH)),
+            (FIELD_INTERFACE, Field::Str('s', BUS_NAME)),
+            (FIELD_MEMBER, Field::Str('s', member)),
+            (FIELD_DESTINATION, Field::Str('s', BUS_NAME)),
+        ];
+        let serial = self.send(METHOD_CALL, 0, &fields, args)?;
+        loop {
+            let reply = self.receive()?;
+            if reply.reply_serial != Some(serial) {
+                continue;
+            }
+            return match reply.kind {
+                ERROR => {
+                    let detail = match reply.args {
+                        Ok(args) => match args.first() {
+                            Some(Arg::Str(message)) => message.clone(),
+                            _ => String::new(),
+                        },
+                        Err(e) => e,
+                    };
+                    Err(format!("{} failed: {} {}", member, reply.error_name.unwrap_or_default(), detail))
+                }
+                _ => reply.args,
+            };
+        }
+    }
+
+    // Become the only owner of a well-known name
+    pub fn request_name(&self, name: &str) -> Result<(), String> {
+        match self.call("RequestName", &[Arg::Str(name.to_string()), Arg::U32(NAME_FLAGS)])?.first() {
+            Some(Arg::U32(PRIMARY_OWNER)) => Ok(()),
+            _ => Err(format!("{} is already owned on the system bus", name)),
+        }
+    }
+
+    // Wait for the next method call addressed to us; pings are answered here
+    pub fn next_call(&self) -> Result<Incoming, String> {
+        loop {
+            let call = self.receive()?;
+            if call.kind != METHOD_CALL {
+                continue;
+            }
+            if call.interface.as_deref() == Some("org.freedesktop.DBus.Peer") && call.member.as_deref() == Some("Ping") {
+                self.reply(&call, &[])?;
+                continue;
+            }
+            return Ok(call);
+        }
+    }
+
+    fn reply_fields(call: &Incoming) -> Vec<(u8, Field<'_>)> {
+        let mut fields = vec![(FIELD_REPLY_SERIAL, Field::U32(call.serial))];
+        if let Some(sender) = &call.sender {
+            fields.push((FIELD_DESTINATION, Field::Str('s', sender)));
+        }
+        fields
+    }
+
+    pub fn reply(&self, call: &Incoming, args: &[Arg]) -> Result<(), String> {
+        if call.flags & NO_REPLY_EXPECTED != 0 {
+            return Ok(());
+        }
+        self.send(METHOD_RETURN, 0, &Self::reply_fields(call), args).map(|_| ())
+    }
+
+    pub fn error(&self, call: &Incoming, name: &str, message: &str) -> Result<(), String> {
+        if call.flags & NO_REPLY_EXPECTED != 0 {
+            return Ok(());
+        }
+        let mut fields = Self::reply_fields(call);
+        fields.push((FIELD_ERROR_NAME, Field::Str('s', name)));
+        self.send(ERROR, 0, &fields, &[Arg::Str(message.to_string())]).map(|_| ())
+    }
+
+    pub fn signal(&self, path: &str, interface: &str, member: &str, args: &[Arg]) -> Result<(), String> {
+        let fields = [
+            (FIELD_PATH, Field::Str('o', path)),
+            (FIELD_INTERFACE, Field::Str('s', interface)),
+            (FIELD_MEMBER, Field::Str('s', member)),
+        ];
+        self.send(SIGNAL, NO_REPLY_EXPECTED, &fields, args).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arguments_survive_a_round_trip_aligned() {
+        let args = vec![
+            Arg::Bool(true),
+            Arg::U64(1 << 40),
+            Arg::Str("héllo".to_string()),
+            Arg::Strs(vec!["a".to_string(), String::new(), "ccc".to_string()]),
+            Arg::U32(7),
+        ];
+        let mut writer = Writer::default();
+        for arg in &args {
+            writer.arg(arg);
+        }
+        // The u64 after the 4-byte bool starts on the next multiple of 8
+        assert_eq!(writer.buf[4..16], [0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0]);
+        let signature: String = args.iter().map(Arg::signature).collect();
+        assert_eq!(signature, "btsasu");
+        let mut reader = Reader { data: &writer.buf, pos: 0, big_endian: false };
+        assert_eq!(reader.args(&signature), Ok(args));
+        assert_eq!(reader.pos, writer.buf.len());
+    }
+
+    #[test]
+    fn big_endian_bodies_are_read() {
+        let mut data = vec![0, 0, 0, 1, 0, 0, 0, 0];
+        data.extend(3u64.to_be_bytes());
+        data.extend(2u32.to_be_bytes());
+        data.extend(b"hi\0");
+        let mut reader = Reader { data: &data, pos: 0, big_endian: true };
+        assert_eq!(reader.args("bts"), Ok(vec![Arg::Bool(true), Arg::U64(3), Arg::Str("hi".to_string())]));
+        let mut reader = Reader { data: &data, pos: 0, big_endian: true };
+        assert!(reader.args("bv").is_err());
+    }
+}
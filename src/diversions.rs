@@ -0,0 +1,116 @@
+use std::fs;
+use crate::dpkgdb::{self, ADMIN_DIR};
+use crate::run_command;
+
+// A dpkg diversion in the overlay database: other packages' versions of `path` are installed
+// as `divert_to` instead
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diversion {
+    pub path: String,
+    pub divert_to: String,
+    // Package whose own version stays at `path`, None for a local diversion of every package
+    pub package: Option<String>,
+}
+
+// Parse dpkg's diversions file: the path, where it is diverted to and the diverting package
+// (":" when local), one line each
+pub fn parse(text: &str) -> Vec<Diversion> {
+    let lines: Vec<&str> = text.lines().collect();
+    lines
+        .chunks_exact(3)
+        .map(|entry| Diversion {
+            path: entry[0].to_string(),
+            divert_to: entry[1].to_string(),
+            package: Some(entry[2]).filter(|package| *package != ":").map(str::to_string),
+        })
+        .collect()
+}
+
+// Diversions in the overlay, whether packages declared them in their maintainer scripts or
+// they were added with `divert add`
+pub fn load() -> Result<Vec<Diversion>, String> {
+    let path = format!("{}/diversions", ADMIN_DIR);
+    match fs::read_to_string(&path) {
+        Ok(text) => Ok(parse(&text)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(format!("Failed to read {}: {}", path, e)),
+    }
+}
+
+// Where `package`'s version of `path` actually lives in the overlay
+pub fn locate(diversions: &[Diversion], package: &str, path: &str) -> String {
+    diversions
+        .iter()
+        .find(|d| d.path == path && d.package.as_deref() != Some(package))
+        .map(|d| d.divert_to.clone())
+        .unwrap_or_else(|| path.to_string())
+}
+
+fn divert(args: &[&str]) -> Result<String, String> {
+    dpkgdb::ensure_admindir()?;
+    let mut all: Vec<String> = dpkgdb::dpkg_target_args();
+    // Move a file already in the overlay along with its diversion
+    all.push("--rename".to_string());
+    all.extend(args.iter().map(|arg| arg.to_string()));
+    let all: Vec<&str> = all.iter().map(String::as_str).collect();
+    run_command("dpkg-divert", &all)
+}
+
+// Divert every package's version of `path` to `divert_to` (`<path>.distrib` by default), or
+// with `package` every version except that package's own
+pub fn add(path: &str, divert_to: Option<&str>, package: Option<&str>) -> Result<(), String> {
+    let mut args = match package {
+        Some(package) => vec!["--package", package],
+        None => vec!["--local"],
+    };
+    if let Some(divert_to) = divert_to {
+        args.extend(["--divert", divert_to]);
+    }
+    args.extend(["--add", path]);
+    print!("{}", divert(&args)?);
+    Ok(())
+}
+
+pub fn remove(path: &str) -> Result<(), String> {
+    let diversions = load()?;
+    let diversion = diversions
+        .iter()
+        .find(|d| d.path == path)
+        .ok_or_else(|| format!("{} is not diverted in the overlay", path))?;
+    let mut args = match &diversion.package {
+        Some(package) => vec!["--package", package.as_str()],
+        None => vec!["--local"],
+    };
+    args.extend(["--divert", diversion.divert_to.as_str(), "--remove", path]);
+    print!("{}", divert(&args)?);
+    Ok(())
+}
+
+pub fn list() -> Result<(), String> {
+    let diversions = load()?;
+    if diversions.is_empty() {
+        println!("No diversions in the overlay");
+    }
+    for diversion in diversions {
+        match diversion.package {
+            Some(package) => println!("{} -> {} (by {})", diversion.path, diversion.divert_to, package),
+            None => println!("{} -> {} (local)", diversion.path, diversion.divert_to),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn other_packages_versions_are_located_at_the_diversion() {
+        let diversions = parse("/usr/bin/foo\n/usr/bin/foo.real\nbar\n/usr/bin/baz\n/usr/bin/baz.distrib\n:\n");
+        assert_eq!(diversions[1].package, None);
+        assert_eq!(locate(&diversions, "bar", "/usr/bin/foo"), "/usr/bin/foo");
+        assert_eq!(locate(&diversions, "other", "/usr/bin/foo"), "/usr/bin/foo.real");
+        assert_eq!(locate(&diversions, "bar", "/usr/bin/baz"), "/usr/bin/baz.distrib");
+        assert_eq!(locate(&diversions, "bar", "/usr/bin/qux"), "/usr/bin/qux");
+    }
+}
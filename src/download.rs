@@ -0,0 +1,97 @@
+use std::path::Path;
+use crate::index::{self, Package};
+use crate::policy::Policy;
+use crate::repos::Repo;
+use crate::{cache, fault, fetch, keys, mirrors, resolve, timing, transaction, CACHE_DIR};
+
+// A .deb chosen from the index
+struct Pending<'a> {
+    package: &'a Package,
+    repo: &'a Repo,
+    // Path below the repo's base URI, from the Filename field
+    pool_path: &'a str,
+    path: String,
+    // Already in the cache with the hash the index lists
+    cached: bool,
+}
+
+fn is_cached(path: &str, package: &Package) -> Result<bool, String> {
+    if !Path::new(path).exists() {
+        return Ok(false);
+    }
+    let actual = cache::intact_hash(path)?;
+    Ok(package
+        .field("SHA256")
+        .is_some_and(|expected| actual.is_some_and(|actual| actual.eq_ignore_ascii_case(expected))))
+}
+
+// Download the .debs of packages into the cache and return their paths, in order: the given
+// versions, or the candidates. Cached files with the right hash are reused; the others are
+// fetched concurrently in one batch per repo, resuming partial downloads, checked against
+// the signed index and their hashes recorded for `cache verify`.
+pub fn debs(order: &[resolve::Selection]) -> Result<Vec<String>, String> {
+    let (packages, policy) = timing::phase("resolution", || -> Result<_, String> {
+        Ok((index::load_all_packages()?, Policy::load()?))
+    })?;
+    let mut pending = Vec::with_capacity(order.len());
+    for (name, version) in order {
+        let package = policy.select(name, version.as_deref(), &packages).ok_or_else(|| match version {
+            Some(version) => format!("{} {} is not available from any repository", name, version),
+            None => format!("{} is not available from any repository; run 'hacker-ostree update'", name),
+        })?;
+        let repo = policy
+            .repo(package)
+            .ok_or_else(|| format!("{} comes from unknown repository {}", name, package.repo))?;
+        let pool_path = package
+            .field("Filename")
+            .ok_or_else(|| format!("The index of {} lists no file for {} {}", repo.name, name, package.version()))?;
+        println!("Selected {} {} from {} (priority {})", name, package.version(), repo.name, policy.priority(package));
+        // Repos may ship different files under the same name, so each repo, suite and
+        // architecture gets its own directory
+        let filename = pool_path.rsplit('/').next().unwrap_or(pool_path);
+        let arch = package.field("Architecture").unwrap_or("all");
+        let path = format!("{}/archives/{}/{}/{}", CACHE_DIR, repo.cache_path(), arch, filename);
+        let cached = is_cached(&path, package)?;
+        pending.push(Pending { package, repo, pool_path, path, cached });
+    }
+
+    let mut repos: Vec<&Repo> = Vec::new();
+    for entry in &pending {
+        if !repos.iter().any(|repo| repo.name == entry.repo.name) {
+            repos.push(entry.repo);
+        }
+    }
+    let mut done = pending.iter().filter(|entry| entry.cached).count();
+    timing::phase("download", || -> Result<(), String> {
+        for repo in repos {
+            let batch: Vec<&Pending> = pending.iter().filter(|entry| entry.repo.name == repo.name && !entry.cached).collect();
+            if batch.is_empty() {
+                continue;
+            }
+            mirrors::with_mirrors(repo, |uri| {
+                let downloads: Vec<fetch::Download> = batch
+                    .iter()
+                    .map(|entry| fetch::Download {
+                        url: format!("{}/{}", uri.trim_end_matches('/'), entry.pool_path),
+                        dest: entry.path.clone(),
+                    })
+                    .collect();
+                fetch::fetch_all(&downloads, false)
+            })?;
+            done += batch.len();
+            transaction::progress("download", done, pending.len());
+        }
+        Ok(())
+    })?;
+
+    for entry in &pending {
+        // trusted=yes vouches for a repo the way a good signature would
+        let verified = entry.repo.option("trusted") == Some("yes")
+            || index::load_record(entry.repo)?.is_some_and(|record| record.verified);
+        keys::verify_deb(&entry.path, entry.package.field("SHA256"), verified)?;
+    }
+    let downloaded: Vec<String> = pending.iter().filter(|entry| !entry.cached).map(|entry| entry.path.clone()).collect();
+    cache::record(&downloaded)?;
+    fault::point("download")?;
+    Ok(pending.into_iter().map(|entry| entry.path).collect())
+}
@@ -0,0 +1,204 @@
+use std::fs::{self, create_dir_all, OpenOptions};
+use std::path::Path;
+use std::process::Command as ProcessCommand;
+use crate::{diversions, run_command, OVERLAY_DIR};
+
+// dpkg database describing exactly what is installed in the overlay
+pub const ADMIN_DIR: &str = "/var/lib/hacker-ostree/dpkg";
+
+// Present once the packages layered before the overlay had its own database are in it
+const MIGRATED_FILE: &str = "/var/lib/hacker-ostree/dpkg/migrated";
+
+// Create an empty dpkg database the first time it is needed
+pub fn ensure_admindir() -> Result<(), String> {
+    for sub in ["info", "updates", "triggers"] {
+        let dir = format!("{}/{}", ADMIN_DIR, sub);
+        create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {}", dir, e))?;
+    }
+    for file in ["status", "available"] {
+        let path = format!("{}/{}", ADMIN_DIR, file);
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("Failed to create {}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+// Whether packages layered before this database existed may still be missing from it
+pub fn needs_migration() -> bool {
+    !Path::new(MIGRATED_FILE).exists()
+}
+
+// Note that every layered package is in the database; kept with it, so a transaction
+// rolled back takes the note back too
+pub fn mark_migrated() -> Result<(), String> {
+    ensure_admindir()?;
+    fs::write(MIGRATED_FILE, "").map_err(|e| format!("Failed to write {}: {}", MIGRATED_FILE, e))
+}
+
+// Arguments pointing dpkg at the overlay and its own database
+pub fn dpkg_target_args() -> Vec<String> {
+    vec![
+        format!("--admindir={}", ADMIN_DIR),
+        format!("--instdir={}", OVERLAY_DIR),
+    ]
+}
+
+// (name, version) of every package installed in the overlay database
+pub fn installed_versions() -> Result<Vec<(String, String)>, String> {
+    if !fs::metadata(format!("{}/status", ADMIN_DIR)).is_ok_and(|m| m.len() > 0) {
+        return Ok(Vec::new());
+    }
+    let admindir = format!("--admindir={}", ADMIN_DIR);
+    let output = run_command("dpkg-query", &[&admindir, "-W", "-f", "${Package}\t${Version}\t${db:Status-Abbrev}\n"])?;
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let (name, version, status) = (fields.next()?, fields.next()?, fields.next()?);
+            // "ii " = desired install, currently installed
+            status.starts_with("ii").then(|| (name.to_string(), version.to_string()))
+        })
+        .collect())
+}
+
+// Installed version of a package in the overlay, if any
+pub fn installed_version(package: &str) -> Result<Option<String>, String> {
+    Ok(installed_versions()?
+        .into_iter()
+        .find(|(name, _)| name == package)
+        .map(|(_, version)| version))
+}
+
+// Check overlay files against the checksums dpkg recorded; returns the problem lines
+pub fn verify(package: Option<&str>) -> Result<Vec<String>, String> {
+    let mut args = dpkg_target_args();
+    args.push("--verify".to_string());
+    if let Some(package) = package {
+        args.push(package.to_string());
+    }
+    let output = ProcessCommand::new("dpkg")
+        .args(&args)
+        .output()
+        .map_err(|e| format!("Failed to execute dpkg: {}", e))?;
+    // dpkg --verify exits with 1 when it found problems and reports them on stdout
+    if !matches!(output.status.code(), Some(0) | Some(1)) {
+        return Err(format!(
+            "Command failed: dpkg\nStderr: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).lines().map(str::to_string).collect())
+}
+
+// Installed-Size (KiB) dpkg recorded for a package in the overlay, if installed
+pub fn installed_size(package: &str) -> Result<Option<u64>, String> {
+    if installed_version(package)?.is_none() {
+        return Ok(None);
+    }
+    let admindir = format!("--admindir={}", ADMIN_DIR);
+    let output = run_command("dpkg-query", &[&admindir, "-W", "-f", "${Installed-Size}", package])?;
+    Ok(output.trim().parse().ok())
+}
+
+// Names of the packages a package in the overlay depends on, alternatives included
+pub fn dependencies(package: &str) -> Result<Vec<String>, String> {
+    let admindir = format!("--admindir={}", ADMIN_DIR);
+    let output = run_command("dpkg-query", &[&admindir, "-W", "-f", "${Depends}, ${Pre-Depends}", package])?;
+    Ok(output
+        .split([',', '|'])
+        .filter_map(|relation| relation.split_whitespace().next())
+        .map(|name| name.split(':').next().unwrap_or(name).to_string())
+        .collect())
+}
+
+// Files of a package below `root` whose content no longer matches the md5sums dpkg recorded,
+// as absolute paths; files that are gone are left to the file list checks
+pub fn modified_files(package: &str, root: &str) -> Result<Vec<String>, String> {
+    let sums_path = format!("{}/info/{}.md5sums", ADMIN_DIR, package);
+    let sums = match fs::read_to_string(&sums_path) {
+        Ok(sums) => sums,
+        Err(_) => return Ok(Vec::new()),
+    };
+    // md5sums name the paths in the package; diverted files are checked where they went
+    let diversions = diversions::load()?;
+    let located: Vec<(&str, String)> = sums
+        .lines()
+        .filter_map(|line| line.split_once("  "))
+        .map(|(sum, path)| {
+            let path = diversions::locate(&diversions, package, &format!("/{}", path));
+            (sum, path.trim_start_matches('/').to_string())
+        })
+        .collect();
+    let expected: Vec<(&str, &str)> = located
+        .iter()
+        .map(|(sum, path)| (*sum, path.as_str()))
+        .filter(|(_, path)| fs::symlink_metadata(format!("{}/{}", root, path)).is_ok())
+        .collect();
+    if expected.is_empty() {
+        return Ok(Vec::new());
+    }
+    let output = ProcessCommand::new("md5sum")
+        .arg("--")
+        .args(expected.iter().map(|(_, path)| path))
+        .current_dir(root)
+        .output()
+        .map_err(|e| format!("Failed to execute md5sum: {}", e))?;
+    let actual = String::from_utf8_lossy(&output.stdout);
+    let actual: std::collections::HashMap<&str, &str> =
+        actual.lines().filter_map(|line| line.split_once("  ")).map(|(sum, path)| (path, sum)).collect();
+    Ok(expected
+        .into_iter()
+        .filter(|(sum, path)| actual.get(path) != Some(sum))
+        .map(|(_, path)| format!("/{}", path))
+        .collect())
+}
+
+// Conffiles of a package in the overlay with the md5sum dpkg recorded when installing them
+pub fn conffiles(package: &str) -> Result<Vec<(String, String)>, String> {
+    if installed_version(package)?.is_none() {
+        return Ok(Vec::new());
+    }
+    let admindir = format!("--admindir={}", ADMIN_DIR);
+    let output = run_command("dpkg-query", &[&admindir, "-W", "-f", "${Conffiles}", package])?;
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (path, sum) = (fields.next()?, fields.next()?);
+            // Conffiles the package no longer ships are flagged "obsolete"
+            (fields.next() != Some("obsolete")).then(|| (path.to_string(), sum.to_string()))
+        })
+        .collect())
+}
+
+// (name, version, provided names) of every package installed in the base image,
+// or in the overlay when `base` is false
+pub fn installed_with_provides(base: bool) -> Result<Vec<(String, String, Vec<String>)>, String> {
+    let mut args = vec!["-W".to_string(), "-f".to_string(), "${Package}\t${Version}\t${db:Status-Abbrev}\t${Provides}\n".to_string()];
+    if !base {
+        if !fs::metadata(format!("{}/status", ADMIN_DIR)).is_ok_and(|m| m.len() > 0) {
+            return Ok(Vec::new());
+        }
+        args.insert(0, format!("--admindir={}", ADMIN_DIR));
+    }
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let output = run_command("dpkg-query", &args)?;
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let (name, version, status) = (fields.next()?, fields.next()?, fields.next()?);
+            let provides = fields
+                .next()
+                .unwrap_or_default()
+                .split(',')
+                .filter_map(|relation| relation.split_whitespace().next())
+                .map(str::to_string)
+                .collect();
+            status.starts_with("ii").then(|| (name.to_string(), version.to_string(), provides))
+        })
+        .collect())
+}
@@ -0,0 +1,72 @@
+use crate::{dpkgdb, filelists};
+
+// Differences between the overlay on disk and what the package database says it holds
+#[derive(Debug, Default)]
+pub struct Drift {
+    // (package, path) of files whose content changed since installation
+    pub modified: Vec<(String, String)>,
+    // (package, path) of recorded files that no longer exist
+    pub missing: Vec<(String, String)>,
+    // Files no layered package owns
+    pub unowned: Vec<String>,
+}
+
+impl Drift {
+    pub fn is_clean(&self) -> bool {
+        self.modified.is_empty() && self.missing.is_empty() && self.unowned.is_empty()
+    }
+
+    // Packages that have to be reinstalled to restore their files, sorted
+    pub fn damaged_packages(&self) -> Vec<String> {
+        let mut packages: Vec<String> = self
+            .modified
+            .iter()
+            .chain(&self.missing)
+            .map(|(package, _)| package.clone())
+            .collect();
+        packages.sort();
+        packages.dedup();
+        packages
+    }
+}
+
+// Compare the overlay below `root` against the recorded file lists and checksums
+pub fn scan(packages: &[String], root: &str) -> Result<Drift, String> {
+    let mut drift = Drift::default();
+    for package in packages {
+        let files = filelists::load_package_files(package)?.unwrap_or_default();
+        for file in files {
+            if std::fs::symlink_metadata(format!("{}{}", root, file)).is_err() {
+                drift.missing.push((package.clone(), file));
+            }
+        }
+        for file in dpkgdb::modified_files(package, root)? {
+            drift.modified.push((package.clone(), file));
+        }
+    }
+    drift.unowned = filelists::unowned_files(packages, root)?;
+    Ok(drift)
+}
+
+// Print a drift report in diff-like form: M modified, D missing, A unowned
+pub fn print(drift: &Drift) {
+    if drift.is_clean() {
+        println!("Overlay matches the package database");
+        return;
+    }
+    for (package, path) in &drift.modified {
+        println!("M {} ({})", path, package);
+    }
+    for (package, path) in &drift.missing {
+        println!("D {} ({})", path, package);
+    }
+    for path in &drift.unowned {
+        println!("A {}", path);
+    }
+    println!(
+        "{} modified, {} missing, {} not owned by any package",
+        drift.modified.len(),
+        drift.missing.len(),
+        drift.unowned.len()
+    );
+}
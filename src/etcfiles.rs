@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::{fetch, filelists, layering, load_installed_packages, run_command, OVERLAY_DIR};
+
+// Files layered packages ship in /etc that were copied into the real /etc, by path. When
+// layering live nothing reads the overlay's /etc; composed deployments carry it as /usr/etc.
+pub const TRACKING_FILE: &str = "/var/lib/hacker-ostree/etc_files.json";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Managed {
+    pub package: String,
+    // SHA256 of the copy put into /etc; a different file there means the user changed it
+    pub sha256: String,
+}
+
+// What bringing one /etc path in line with the layered packages takes
+#[derive(Debug, PartialEq)]
+enum Action {
+    // Copy the package's version over the unchanged or missing file
+    Copy,
+    // The file already is the package's version; only start tracking it
+    Adopt,
+    // A file nobody manages is in the way
+    Conflict,
+    // Its package is gone and the file is unchanged
+    Remove,
+    // Its package is gone but the user changed the file, which stays
+    Release,
+}
+
+fn load() -> Result<BTreeMap<String, Managed>, String> {
+    match fs::read_to_string(TRACKING_FILE) {
+        Ok(text) => serde_json::from_str(&text).map_err(|e| format!("Failed to parse {}: {}", TRACKING_FILE, e)),
+        Err(_) => Ok(BTreeMap::new()),
+    }
+}
+
+fn save(tracked: &BTreeMap<String, Managed>) -> Result<(), String> {
+    let text = serde_json::to_string_pretty(tracked).map_err(|e| format!("Failed to serialize managed /etc files: {}", e))?;
+    fs::write(TRACKING_FILE, text).map_err(|e| format!("Failed to write {}: {}", TRACKING_FILE, e))
+}
+
+// Decide every path's action from what the packages ship (path to package and hash), what was
+// copied before, and `live`, the hash of a file in /etc or None when there is none. Files the
+// user changed are never replaced or removed, like dpkg treats conffiles.
+fn plan(
+    shipped: &BTreeMap<String, Managed>,
+    tracked: &BTreeMap<String, Managed>,
+    live: impl Fn(&str) -> Option<String>,
+) -> Vec<(String, Action)> {
+    let mut actions = Vec::new();
+    for (path, new) in shipped {
+        let current = live(path);
+        let action = match tracked.get(path) {
+            Some(old) if current.as_ref() == Some(&old.sha256) && old.sha256 != new.sha256 => Action::Copy,
+            Some(_) => continue,
+            None if current.is_none() => Action::Copy,
+            None if current.as_ref() == Some(&new.sha256) => Action::Adopt,
+            None => Action::Conflict,
+        };
+        actions.push((path.clone(), action));
+    }
+    for (path, old) in tracked.iter().filter(|(path, _)| !shipped.contains_key(*path)) {
+        let action = if live(path).as_ref() == Some(&old.sha256) { Action::Remove } else { Action::Release };
+        actions.push((path.clone(), action));
+    }
+    actions
+}
+
+// Note in `journal` what is at `path` before sync() changes it: a copy of the file, or
+// nothing when there is none
+fn journal(journal: &str, path: &str) -> Result<(), String> {
+    fs::create_dir_all(journal).map_err(|e| format!("Failed to create {}: {}", journal, e))?;
+    let index = format!("{}/paths", journal);
+    let mut paths = fs::read_to_string(&index).unwrap_or_default();
+    if fs::symlink_metadata(path).is_ok() {
+        run_command("cp", &["-a", path, &format!("{}/{}", journal, paths.lines().count())])?;
+    }
+    paths.push_str(&format!("{}\n", path));
+    fs::write(&index, paths).map_err(|e| format!("Failed to write {}: {}", index, e))
+}
+
+// Put back what sync() changed in /etc as noted in `journal`, for a transaction that failed
+pub fn undo(journal: &str) -> Result<(), String> {
+    let Ok(paths) = fs::read_to_string(format!("{}/paths", journal)) else {
+        return Ok(());
+    };
+    let paths: Vec<&str> = paths.lines().collect();
+    for (i, path) in paths.iter().enumerate().rev() {
+        let saved = format!("{}/{}", journal, i);
+        if fs::symlink_metadata(&saved).is_ok() {
+            run_command("cp", &["-a", "--remove-destination", &saved, path])?;
+        } else if fs::symlink_metadata(path).is_ok() {
+            fs::remove_file(path).map_err(|e| format!("Failed to remove {}: {}", path, e))?;
+        }
+    }
+    Ok(())
+}
+
+// Bring /etc in line with the layered packages' /etc files: copy new and updated ones, remove
+// those of removed packages. Fails before changing anything if a file nobody manages is in
+// the way; every file changed is noted in `journal` first, so undo() can put it back.
+pub fn sync(journal_dir: &str) -> Result<(), String> {
+    if layering::enabled()? {
+        return Ok(());
+    }
+    let mut shipped = BTreeMap::new();
+    for package in load_installed_packages()? {
+        for path in filelists::load_package_files(&package)?.unwrap_or_default() {
+            let source = format!("{}{}", OVERLAY_DIR, path);
+            if !path.starts_with("/etc/") || !fs::symlink_metadata(&source).is_ok_and(|m| m.is_file()) {
+                continue;
+            }
+            let sha256 = fetch::sha256_file(&source)?;
+            shipped.insert(path, Managed { package: package.clone(), sha256 });
+        }
+    }
+    let mut tracked = load()?;
+    if shipped.is_empty() && tracked.is_empty() {
+        return Ok(());
+    }
+    let live = |path: &str| fs::symlink_metadata(path).is_ok_and(|m| m.is_file()).then(|| fetch::sha256_file(path).ok()).flatten();
+    let actions = plan(&shipped, &tracked, live);
+
+    let conflicts: Vec<String> = actions
+        .iter()
+        .filter(|(_, action)| *action == Action::Conflict)
+        .map(|(path, _)| format!("{} ({})", path, shipped[path].package))
+        .collect();
+    if !conflicts.is_empty() {
+        return Err(format!(
+            "Layered packages would replace files in /etc that hacker-ostree doesn't manage: {}; move them aside and try again",
+            conflicts.join(", ")
+        ));
+    }
+    for (path, action) in actions {
+        match action {
+            Action::Copy => {
+                if let Some(parent) = Path::new(&path).parent() {
+                    fs::create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+                }
+                journal(journal_dir, &path)?;
+                run_command("cp", &["-a", "--remove-destination", &format!("{}{}", OVERLAY_DIR, path), &path])?;
+                tracked.insert(path.clone(), shipped[&path].clone());
+            }
+            Action::Adopt => {
+                tracked.insert(path.clone(), shipped[&path].clone());
+            }
+            Action::Remove => {
+                journal(journal_dir, &path)?;
+                fs::remove_file(&path).map_err(|e| format!("Failed to remove {}: {}", path, e))?;
+                tracked.remove(&path);
+            }
+            Action::Release => {
+                if let Some(old) = tracked.remove(&path) {
+                    if Path::new(&path).exists() {
+                        println!("Kept {}, which you changed, although {} was removed", path, old.package);
+                    }
+                }
+            }
+            Action::Conflict => {}
+        }
+    }
+    save(&tracked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn managed(package: &str, sha256: &str) -> Managed {
+        Managed { package: package.to_string(), sha256: sha256.to_string() }
+    }
+
+    #[test]
+    fn plans_copies_and_removals_without_touching_edited_files() {
+        let shipped: BTreeMap<String, Managed> = [
+            ("/etc/new.conf", managed("app", "n1")),
+            ("/etc/same.conf", managed("app", "s1")),
+            ("/etc/updated.conf", managed("app", "u2")),
+            ("/etc/edited.conf", managed("app", "e2")),
+            ("/etc/present.conf", managed("app", "p1")),
+            ("/etc/taken.conf", managed("app", "t1")),
+        ]
+        .map(|(path, m)| (path.to_string(), m))
+        .into();
+        let tracked: BTreeMap<String, Managed> = [
+            ("/etc/same.conf", managed("app", "s1")),
+            ("/etc/updated.conf", managed("app", "u1")),
+            ("/etc/edited.conf", managed("app", "e1")),
+            ("/etc/gone.conf", managed("old", "g1")),
+            ("/etc/kept.conf", managed("old", "k1")),
+        ]
+        .map(|(path, m)| (path.to_string(), m))
+        .into();
+        let live = |path: &str| {
+            let sha256 = match path {
+                "/etc/same.conf" => "s1",
+                "/etc/updated.conf" => "u1",
+                "/etc/edited.conf" => "mine",
+                "/etc/present.conf" => "p1",
+                "/etc/taken.conf" => "other",
+                "/etc/gone.conf" => "g1",
+                "/etc/kept.conf" => "mine",
+                _ => return None,
+            };
+            Some(sha256.to_string())
+        };
+        let actions = plan(&shipped, &tracked, live);
+        let actions: Vec<(&str, &Action)> = actions.iter().map(|(path, action)| (path.as_str(), action)).collect();
+        assert_eq!(
+            actions,
+            [
+                ("/etc/new.conf", &Action::Copy),
+                ("/etc/present.conf", &Action::Adopt),
+                ("/etc/taken.conf", &Action::Conflict),
+                ("/etc/updated.conf", &Action::Copy),
+                ("/etc/gone.conf", &Action::Remove),
+                ("/etc/kept.conf", &Action::Release),
+            ]
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use std::fs;
+use std::sync::Mutex;
+use serde::{Deserialize, Serialize};
+
+// Answer commands from a recording instead of running them
+pub const REPLAY_ENV: &str = "HACKER_OSTREE_REPLAY";
+// Append every command run, with what it printed, to a recording
+pub const RECORD_ENV: &str = "HACKER_OSTREE_RECORD";
+
+// One run of an external command and its outcome. In a replayed recording an argument
+// of "*" stands for any single argument.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Call {
+    pub command: String,
+    pub args: Vec<String>,
+    // Captured output; empty for commands whose output went to the terminal
+    #[serde(default)]
+    pub stdout: String,
+    // Error message when the command failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Call {
+    fn new(cmd: &str, args: &[&str], result: &Result<String, String>) -> Call {
+        Call {
+            command: cmd.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            stdout: result.as_ref().cloned().unwrap_or_default(),
+            error: result.as_ref().err().cloned(),
+        }
+    }
+}
+
+// A recording standing in for the system. Commands it has calls for are answered from it:
+// the first unused call with the same arguments, or the last one again once all are used.
+// Commands it never mentions still run, so a test can fake ostree and use the real dpkg.
+struct Replay {
+    calls: Vec<Call>,
+    used: Vec<bool>,
+    // Calls answered, in order
+    log: Vec<Call>,
+}
+
+static REPLAY: Mutex<Option<Replay>> = Mutex::new(None);
+// Serializes appends to the recording; downloads run commands from several threads
+static RECORDING: Mutex<()> = Mutex::new(());
+
+pub fn load(path: &str) -> Result<Vec<Call>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    serde_json::from_str(&text).map_err(|e| format!("Failed to parse {}: {}", path, e))
+}
+
+// Replay the recording named by HACKER_OSTREE_REPLAY, if set
+pub fn init() -> Result<(), String> {
+    if let Ok(path) = std::env::var(REPLAY_ENV) {
+        set_replay(Some(load(&path)?));
+    }
+    Ok(())
+}
+
+// Answer commands from `calls` from now on, or run them all again with None. Returns the
+// calls answered by the previous recording.
+pub fn set_replay(calls: Option<Vec<Call>>) -> Vec<Call> {
+    let replay = calls.map(|calls| Replay { used: vec![false; calls.len()], calls, log: Vec::new() });
+    let previous = std::mem::replace(&mut *REPLAY.lock().unwrap_or_else(|e| e.into_inner()), replay);
+    previous.map(|replay| replay.log).unwrap_or_default()
+}
+
+fn matches(pattern: &[String], args: &[&str]) -> bool {
+    pattern.len() == args.len() && pattern.iter().zip(args).all(|(expected, arg)| expected == "*" || expected == arg)
+}
+
+// The recorded outcome of a command, or None when it should really run. A command the
+// recording knows with arguments it doesn't fails, so unexpected calls show up in tests.
+pub fn replayed(cmd: &str, args: &[&str]) -> Option<Result<String, String>> {
+    let mut guard = REPLAY.lock().unwrap_or_else(|e| e.into_inner());
+    let replay = guard.as_mut()?;
+    if !replay.calls.iter().any(|call| call.command == cmd) {
+        return None;
+    }
+    let matching: Vec<usize> = (0..replay.calls.len())
+        .filter(|&i| replay.calls[i].command == cmd && matches(&replay.calls[i].args, args))
+        .collect();
+    let call = match matching.iter().find(|&&i| !replay.used[i]).or(matching.last()) {
+        Some(&i) => {
+            replay.used[i] = true;
+            replay.calls[i].clone()
+        }
+        None => Call::new(cmd, args, &Err(format!("{} {} is not in the recording", cmd, args.join(" ")))),
+    };
+    replay.log.push(call.clone());
+    Some(match call.error {
+        Some(error) => Err(error),
+        None => Ok(call.stdout),
+    })
+}
+
+// Append a command's outcome to the recording named by HACKER_OSTREE_RECORD, if set
+pub fn record(cmd: &str, args: &[&str], result: &Result<String, String>) {
+    let Ok(path) = std::env::var(RECORD_ENV) else {
+        return;
+    };
+    let _guard = RECORDING.lock().unwrap_or_else(|e| e.into_inner());
+    let mut calls = load(&path).unwrap_or_default();
+    calls.push(Call::new(cmd, args, result));
+    match serde_json::to_string_pretty(&calls) {
+        Ok(json) => {
+            if let Err(e) = fs::write(&path, json) {
+                eprintln!("Warning: failed to write {}: {}", path, e);
+            }
+        }
+        Err(e) => eprintln!("Warning: failed to serialize recording: {}", e),
+    }
+}
+
+// Helpers for tests replacing the system with a recording
+#[cfg(test)]
+pub mod testing {
+    use std::sync::Mutex;
+    use super::{set_replay, Call};
+
+    // The recording is process-wide, so tests replaying one take turns
+    static TURN: Mutex<()> = Mutex::new(());
+
+    pub fn call(command: &str, args: &[&str], stdout: &str) -> Call {
+        Call::new(command, args, &Ok(stdout.to_string()))
+    }
+
+    pub fn failing(command: &str, args: &[&str], error: &str) -> Call {
+        Call::new(command, args, &Err(error.to_string()))
+    }
+
+    // Run `f` with commands answered from `calls`; returns its result and the calls answered
+    pub fn replaying<T>(calls: Vec<Call>, f: impl FnOnce() -> T) -> (T, Vec<Call>) {
+        let _turn = TURN.lock().unwrap_or_else(|e| e.into_inner());
+        set_replay(Some(calls));
+        let result = f();
+        (result, set_replay(None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{call, failing, replaying};
+    use crate::run_command;
+
+    #[test]
+    fn replays_calls_in_order_then_repeats_the_last() {
+        let calls = vec![call("ostree", &["rev-parse", "a"], "1\n"), call("ostree", &["rev-parse", "a"], "2\n")];
+        let (outputs, log) = replaying(calls, || {
+            (0..3).map(|_| run_command("ostree", &["rev-parse", "a"]).unwrap()).collect::<Vec<_>>()
+        });
+        assert_eq!(outputs, ["1\n", "2\n", "2\n"]);
+        assert_eq!(log.len(), 3);
+    }
+
+    #[test]
+    fn wildcard_matches_any_argument() {
+        let calls = vec![failing("ostree", &["show", "*", "abc"], "error: No such metadata key")];
+        let (result, _) = replaying(calls, || run_command("ostree", &["show", "--print-metadata-key=version", "abc"]));
+        assert_eq!(result, Err("error: No such metadata key".to_string()));
+    }
+
+    #[test]
+    fn unexpected_arguments_fail() {
+        let (result, log) = replaying(vec![call("ostree", &["admin", "status"], "")], || {
+            run_command("ostree", &["admin", "undeploy", "1"])
+        });
+        assert_eq!(result, Err("ostree admin undeploy 1 is not in the recording".to_string()));
+        assert_eq!(log[0].args, ["admin", "undeploy", "1"]);
+    }
+
+    #[test]
+    fn commands_not_in_the_recording_run() {
+        let (result, log) = replaying(vec![call("ostree", &["admin", "status"], "")], || run_command("echo", &["hi"]));
+        assert_eq!(result, Ok("hi\n".to_string()));
+        assert!(log.is_empty());
+    }
+}
@@ -0,0 +1,46 @@
+use std::sync::Mutex;
+
+// Must be set to 1 for --inject-fault to be accepted, so it can't be used by accident
+pub const ENABLE_ENV: &str = "HACKER_OSTREE_FAULT_INJECTION";
+
+// Points a transaction can be made to fail at, and what has happened by then
+pub const POINTS: [(&str, &str); 6] = [
+    ("snapshot", "the overlay state was saved, nothing changed yet"),
+    ("download", "packages were downloaded into the cache"),
+    ("extraction", "dpkg unpacked a package, which isn't recorded as installed yet"),
+    ("removal", "dpkg removed a package, which is still recorded as installed"),
+    ("stage", "all packages changed, the new deployment isn't composed yet"),
+    ("commit", "the new deployment was staged, the transaction isn't recorded yet"),
+];
+
+// Points given with --inject-fault that haven't failed yet
+static ARMED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+pub fn names() -> [&'static str; 6] {
+    POINTS.map(|(name, _)| name)
+}
+
+// Arm the given points for this run; refused unless HACKER_OSTREE_FAULT_INJECTION=1
+pub fn set_injected(points: Vec<String>) -> Result<(), String> {
+    if points.is_empty() {
+        return Ok(());
+    }
+    if std::env::var(ENABLE_ENV).as_deref() != Ok("1") {
+        return Err(format!("--inject-fault is a testing facility; set {}=1 to use it", ENABLE_ENV));
+    }
+    *ARMED.lock().unwrap_or_else(|e| e.into_inner()) = points;
+    Ok(())
+}
+
+// Fail the first time an armed point is reached
+pub fn point(name: &str) -> Result<(), String> {
+    let mut armed = ARMED.lock().unwrap_or_else(|e| e.into_inner());
+    match armed.iter().position(|point| point == name) {
+        Some(i) => {
+            armed.remove(i);
+            let (_, state) = POINTS.iter().find(|(point, _)| *point == name).copied().unwrap_or((name, ""));
+            Err(format!("Injected fault at {} ({})", name, state))
+        }
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,160 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+use tempfile::NamedTempFile;
+use crate::config::load_config;
+use crate::run_command;
+
+// Transfers curl keeps in flight at once over the shared connections
+const PARALLEL_MAX: &str = "8";
+// Smoothed download rate in bytes per second, used for time estimates
+const THROUGHPUT_FILE: &str = "/var/lib/hacker-ostree/throughput";
+// Transfers smaller than this say more about latency than bandwidth
+const MIN_SAMPLE_BYTES: u64 = 256 * 1024;
+
+// A single file to fetch
+pub struct Download {
+    pub url: String,
+    pub dest: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchStatus {
+    Downloaded,
+    NotModified,
+}
+
+// Fetch a small text resource such as an API response or key
+pub fn fetch_text(url: &str) -> Result<String, String> {
+    run_command("curl", &["--silent", "--show-error", "--fail", "--location", "--http2", url])
+}
+
+// SHA256 hex digest of a file
+pub fn sha256_file(path: &str) -> Result<String, String> {
+    let output = run_command("sha256sum", &[path])?;
+    output
+        .split_whitespace()
+        .next()
+        .map(str::to_string)
+        .ok_or_else(|| format!("Failed to hash {}", path))
+}
+
+// Last measured download rate in bytes per second
+pub fn measured_throughput() -> Option<f64> {
+    fs::read_to_string(THROUGHPUT_FILE)
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .filter(|rate: &f64| *rate > 0.0)
+}
+
+// Fold a new sample into the stored rate
+fn record_throughput(bytes: u64, secs: f64) {
+    if bytes < MIN_SAMPLE_BYTES || secs <= 0.0 {
+        return;
+    }
+    let sample = bytes as f64 / secs;
+    let rate = match measured_throughput() {
+        Some(previous) => previous * 0.7 + sample * 0.3,
+        None => sample,
+    };
+    // Only an estimate; losing it is harmless
+    let _ = fs::write(THROUGHPUT_FILE, format!("{:.0}\n", rate));
+}
+
+// Quote a value for a curl config file
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// Fetch all downloads through a single curl process so connections are reused and
+// HTTP/2 streams multiplexed. With `conditional`, existing files are revalidated via
+// ETag/If-Modified-Since and left untouched on 304; otherwise partial files are resumed.
+pub fn fetch_all(downloads: &[Download], conditional: bool) -> Result<Vec<FetchStatus>, String> {
+    if downloads.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut config = NamedTempFile::new().map_err(|e| format!("Failed to create temp file: {}", e))?;
+    for (i, download) in downloads.iter().enumerate() {
+        if let Some(parent) = Path::new(&download.dest).parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+        }
+        let partial = format!("{}.part", download.dest);
+        let etag = format!("{}.etag", download.dest);
+        let mut entry = String::new();
+        if i > 0 {
+            entry.push_str("next\n");
+        }
+        entry.push_str(&format!("url = {}\noutput = {}\n", quote(&download.url), quote(&partial)));
+        entry.push_str("location\nremote-time\nretry = 3\n");
+        entry.push_str("write-out = \"%{http_code} %{filename_effective}\\n\"\n");
+        if conditional {
+            if Path::new(&download.dest).exists() {
+                entry.push_str(&format!("time-cond = {}\n", quote(&download.dest)));
+                if Path::new(&etag).exists() {
+                    entry.push_str(&format!("etag-compare = {}\n", quote(&etag)));
+                }
+            }
+            entry.push_str(&format!("etag-save = {}\n", quote(&etag)));
+        } else {
+            entry.push_str("continue-at = \"-\"\n");
+        }
+        config.write_all(entry.as_bytes()).map_err(|e| format!("Failed to write to temp file: {}", e))?;
+    }
+    let config_path = config.path().to_str().ok_or_else(|| "Failed to get temp file path".to_string())?;
+
+    let mut args = vec![
+        "--silent",
+        "--show-error",
+        "--http2",
+        "--parallel",
+        "--parallel-max", PARALLEL_MAX,
+        "--config", config_path,
+    ];
+    // Let curl abort transfers that stall for longer than the watchdog allows
+    let stall_timeout = load_config()?.stall_timeout_secs.to_string();
+    if stall_timeout != "0" {
+        args.extend(["--speed-limit", "1", "--speed-time", &stall_timeout]);
+    }
+    let started = Instant::now();
+    let output = run_command("curl", &args)?;
+
+    let elapsed = started.elapsed().as_secs_f64();
+
+    let mut statuses = Vec::with_capacity(downloads.len());
+    let mut fetched_bytes = 0;
+    for download in downloads {
+        let partial = format!("{}.part", download.dest);
+        let code = output
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .find(|(_, file)| *file == partial)
+            .map(|(code, _)| code.to_string())
+            .unwrap_or_default();
+        match code.as_str() {
+            // file:// and other non-HTTP transfers report no status code
+            "200" | "206" | "000" if Path::new(&partial).exists() => {
+                fetched_bytes += fs::metadata(&partial).map(|m| m.len()).unwrap_or(0);
+                fs::rename(&partial, &download.dest).map_err(|e| format!("Failed to rename {}: {}", partial, e))?;
+                statuses.push(FetchStatus::Downloaded);
+            }
+            "304" | "000" if conditional && Path::new(&download.dest).exists() => {
+                let _ = fs::remove_file(&partial);
+                statuses.push(FetchStatus::NotModified);
+            }
+            // Resuming a file that was already complete
+            "416" if Path::new(&partial).exists() => {
+                fs::rename(&partial, &download.dest).map_err(|e| format!("Failed to rename {}: {}", partial, e))?;
+                statuses.push(FetchStatus::Downloaded);
+            }
+            _ => {
+                let _ = fs::remove_file(&partial);
+                return Err(format!("Failed to fetch {}: HTTP status {}", download.url, if code.is_empty() { "unknown" } else { &code }));
+            }
+        }
+    }
+    record_throughput(fetched_bytes, elapsed);
+    Ok(statuses)
+}
@@ -0,0 +1,218 @@
+use std::collections::HashSet;
+use std::fs::{self, create_dir_all};
+use std::path::Path;
+use std::process::{Command as ProcessCommand, Stdio};
+use crate::{appstream, diversions, run_command, OVERLAY_DIR};
+
+pub const FILELISTS_DIR: &str = "/var/lib/hacker-ostree/filelists";
+// File lists dpkg recorded for the packages of the base image
+const BASE_DPKG_INFO: &str = "/var/lib/dpkg/info";
+// dpkg's own bookkeeping inside the overlay is never owned by a package
+const OVERLAY_ADMIN_PREFIX: &str = "/var/lib/dpkg";
+
+fn list_path(package: &str) -> String {
+    format!("{}/{}.list", FILELISTS_DIR, package)
+}
+
+// Paths contained in a .deb, as absolute paths ("/usr/bin/foo")
+fn deb_contents(deb_path: &str) -> Result<Vec<String>, String> {
+    let mut dpkg_deb = ProcessCommand::new("dpkg-deb")
+        .args(["--fsys-tarfile", deb_path])
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to execute dpkg-deb: {}", e))?;
+    let tarball = dpkg_deb.stdout.take().ok_or_else(|| "Failed to read dpkg-deb output".to_string())?;
+    let output = ProcessCommand::new("tar")
+        .arg("-t")
+        .stdin(Stdio::from(tarball))
+        .output()
+        .map_err(|e| format!("Failed to execute tar: {}", e))?;
+    let status = dpkg_deb.wait().map_err(|e| format!("Failed to wait for dpkg-deb: {}", e))?;
+    if !status.success() || !output.status.success() {
+        return Err(format!("Failed to list contents of {}", deb_path));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| line.trim_start_matches('.').trim_end_matches('/'))
+        .filter(|path| !path.is_empty())
+        .map(|path| if path.starts_with('/') { path.to_string() } else { format!("/{}", path) })
+        .collect())
+}
+
+// Non-directory paths in a .deb (files and symlinks), as absolute paths
+pub fn deb_files(deb_path: &str) -> Result<Vec<String>, String> {
+    let output = run_command("dpkg-deb", &["-c", deb_path])?;
+    // "-rwxr-xr-x root/root 1234 2024-01-01 00:00 ./usr/bin/foo", symlinks add " -> target"
+    Ok(output
+        .lines()
+        .filter(|line| !line.starts_with('d'))
+        .filter_map(|line| line.split_whitespace().nth(5))
+        .map(|path| path.trim_start_matches('.').to_string())
+        .collect())
+}
+
+// Record the files a package installed into the overlay
+pub fn record_package_files(package: &str, deb_path: &str) -> Result<(), String> {
+    create_dir_all(FILELISTS_DIR).map_err(|e| format!("Failed to create {}: {}", FILELISTS_DIR, e))?;
+    let mut contents = deb_contents(deb_path)?.join("\n");
+    contents.push('\n');
+    let path = list_path(package);
+    fs::write(&path, contents).map_err(|e| format!("Failed to write {}: {}", path, e))
+}
+
+// Forget the file list of a removed package
+pub fn remove_package_files(package: &str) -> Result<(), String> {
+    let path = list_path(package);
+    if Path::new(&path).exists() {
+        fs::remove_file(&path).map_err(|e| format!("Failed to remove {}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+// Recorded files of a package where they are in the overlay, diverted ones at the path they
+// were diverted to; None if nothing was recorded for it
+pub fn load_package_files(package: &str) -> Result<Option<Vec<String>>, String> {
+    let path = list_path(package);
+    if !Path::new(&path).exists() {
+        return Ok(None);
+    }
+    let text = fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let diversions = diversions::load()?;
+    Ok(Some(
+        text.lines()
+            .filter(|line| !line.is_empty())
+            .map(|file| diversions::locate(&diversions, package, file))
+            .collect(),
+    ))
+}
+
+// Files of a base image package from dpkg's recorded lists, None if it isn't installed there
+pub fn load_base_package_files(package: &str) -> Result<Option<Vec<String>>, String> {
+    let entries = match fs::read_dir(BASE_DPKG_INFO) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(None),
+    };
+    // Multi-arch packages are recorded as <name>:<arch>.list
+    let plain = format!("{}.list", package);
+    let qualified = format!("{}:", package);
+    let list = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .find(|name| *name == plain || (name.starts_with(&qualified) && name.ends_with(".list")));
+    let list = match list {
+        Some(list) => format!("{}/{}", BASE_DPKG_INFO, list),
+        None => return Ok(None),
+    };
+    let text = fs::read_to_string(&list).map_err(|e| format!("Failed to read {}: {}", list, e))?;
+    Ok(Some(
+        text.lines()
+            .filter(|line| !line.is_empty() && *line != "/.")
+            .map(str::to_string)
+            .collect(),
+    ))
+}
+
+// Packages whose file list in `dir` contains `path`
+fn owners_in(dir: &str, path: &str, strip_arch: bool) -> Vec<String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut owners = Vec::new();
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let package = match name.strip_suffix(".list") {
+            Some(package) => package,
+            None => continue,
+        };
+        let owned = fs::read_to_string(entry.path()).is_ok_and(|text| text.lines().any(|line| line == path));
+        if owned {
+            let package = if strip_arch { package.split(':').next().unwrap_or(package) } else { package };
+            owners.push(package.to_string());
+        }
+    }
+    owners.sort();
+    owners
+}
+
+// Layered and base packages owning a path, overlay database first
+pub fn find_owners(path: &str) -> (Vec<String>, Vec<String>) {
+    let path = path.trim_end_matches('/');
+    let path = if path.is_empty() { "/" } else { path };
+    (owners_in(FILELISTS_DIR, path, false), owners_in(BASE_DPKG_INFO, path, true))
+}
+
+// Collect files and symlinks below `dir`, as paths relative to the overlay `root`
+fn walk_overlay(root: &str, dir: &Path, found: &mut Vec<String>) -> Result<(), String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let file_type = entry.file_type().map_err(|e| format!("Failed to stat {}: {}", path.display(), e))?;
+        let relative = format!("/{}", path.strip_prefix(root).unwrap_or(&path).display());
+        if relative.starts_with(OVERLAY_ADMIN_PREFIX) || relative == appstream::CATALOG {
+            continue;
+        }
+        if file_type.is_dir() {
+            walk_overlay(root, &path, found)?;
+        } else {
+            found.push(relative);
+        }
+    }
+    Ok(())
+}
+
+// Remove directories left empty below `dir`, keeping `dir` itself
+fn prune_empty_dirs(dir: &Path) {
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                prune_empty_dirs(&path);
+                // Only succeeds when empty
+                let _ = fs::remove_dir(&path);
+            }
+        }
+    }
+}
+
+// Files below the overlay `root` not owned by any of the layered packages, sorted
+pub fn unowned_files(packages: &[String], root: &str) -> Result<Vec<String>, String> {
+    let mut owned = HashSet::new();
+    for package in packages {
+        let files = load_package_files(package)?.ok_or_else(|| {
+            format!("No file list recorded for {}; run 'hacker-ostree resync' first", package)
+        })?;
+        owned.extend(files);
+    }
+
+    let mut found = Vec::new();
+    walk_overlay(root, Path::new(root), &mut found)?;
+    let mut orphans: Vec<String> = found.into_iter().filter(|path| !owned.contains(path)).collect();
+    orphans.sort();
+    Ok(orphans)
+}
+
+// Delete unowned files from the working overlay and drop directories left empty
+pub fn remove_unowned(paths: &[String]) -> Result<(), String> {
+    for path in paths {
+        let full = format!("{}{}", OVERLAY_DIR, path);
+        fs::remove_file(&full).map_err(|e| format!("Failed to remove {}: {}", full, e))?;
+    }
+    prune_empty_dirs(Path::new(OVERLAY_DIR));
+    Ok(())
+}
+
+// Find and list the files below `root` (the overlay as it can be read) not owned by any
+// layered package
+pub fn find_orphans(packages: &[String], root: &str) -> Result<Vec<String>, String> {
+    let orphans = unowned_files(packages, root)?;
+    if orphans.is_empty() {
+        println!("No orphaned files in the overlay");
+        return Ok(orphans);
+    }
+    println!("Orphaned overlay files:");
+    for path in &orphans {
+        println!("  {}", path);
+    }
+    Ok(orphans)
+}
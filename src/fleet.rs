@@ -0,0 +1,157 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::process::{Command as ProcessCommand, Stdio};
+use std::thread;
+use std::time::Instant;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use crate::remote;
+
+// Declarative state for a node. Written as JSON, which YAML parsers accept as well.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct StateManifest {
+    // Update the base image before converging the overlay
+    pub system_update: bool,
+    // Packages that must be layered
+    pub packages: Vec<String>,
+    // Packages that must not be layered
+    pub remove: Vec<String>,
+}
+
+// Read a manifest from a file, or from stdin when the path is "-"
+pub fn load_manifest(path: &str) -> Result<StateManifest, String> {
+    let text = if path == "-" {
+        let mut text = String::new();
+        std::io::stdin().read_to_string(&mut text).map_err(|e| format!("Failed to read manifest from stdin: {}", e))?;
+        text
+    } else {
+        fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?
+    };
+    serde_json::from_str(&text).map_err(|e| format!("Failed to parse manifest {} (JSON/YAML flow syntax expected): {}", path, e))
+}
+
+// Hosts file: one user@machine per line, '#' starts a comment
+fn load_hosts(path: &str) -> Result<Vec<String>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    Ok(text
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+// Hosts per batch for a --rolling value such as "20%" or "5"
+fn batch_size(rolling: Option<&str>, hosts: usize) -> Result<usize, String> {
+    let size = match rolling {
+        None => hosts,
+        Some(value) => match value.strip_suffix('%') {
+            Some(percent) => {
+                let percent: usize = percent.parse().map_err(|_| format!("Invalid --rolling value {}", value))?;
+                (hosts * percent).div_ceil(100)
+            }
+            None => value.parse().map_err(|_| format!("Invalid --rolling value {}", value))?,
+        },
+    };
+    Ok(size.clamp(1, hosts.max(1)))
+}
+
+// Outcome of applying the manifest on one node
+#[derive(Serialize)]
+struct HostResult {
+    host: String,
+    status: &'static str,
+    exit_code: Option<i32>,
+    duration_secs: f64,
+    output: String,
+}
+
+// Send the manifest to a node's `apply` over SSH and collect the result
+fn apply_on(host: &str, manifest: &str) -> HostResult {
+    let started = Instant::now();
+    let args = remote::ssh_args(host, &["apply".to_string(), "--yes".to_string(), "--manifest".to_string(), "-".to_string()], false);
+    let result = ProcessCommand::new("ssh")
+        .args(&args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .and_then(|mut child| {
+            if let Some(mut stdin) = child.stdin.take() {
+                // A node that fails early closes stdin; its output explains why
+                let _ = stdin.write_all(manifest.as_bytes());
+            }
+            child.wait_with_output()
+        });
+    let (status, exit_code, output) = match result {
+        Ok(output) => {
+            let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+            text.push_str(&String::from_utf8_lossy(&output.stderr));
+            let status = if output.status.success() { "ok" } else { "failed" };
+            (status, output.status.code(), text)
+        }
+        Err(e) => ("failed", None, format!("Failed to execute ssh: {}", e)),
+    };
+    println!("{}: {}", host, status);
+    HostResult { host: host.to_string(), status, exit_code, duration_secs: started.elapsed().as_secs_f64(), output }
+}
+
+// Apply a manifest across hosts in batches; a batch with failures stops the rollout.
+// Prints (or writes) an aggregate JSON report and fails if any host failed.
+pub fn apply(hosts_file: &str, manifest_path: &str, rolling: Option<&str>, report: Option<&str>) -> Result<(), String> {
+    let hosts = load_hosts(hosts_file)?;
+    let manifest = load_manifest(manifest_path)?;
+    let manifest_json = serde_json::to_string(&manifest).map_err(|e| format!("Failed to serialize manifest: {}", e))?;
+    let size = batch_size(rolling, hosts.len())?;
+
+    let mut results: Vec<HostResult> = Vec::new();
+    let mut halted = false;
+    let batches = hosts.chunks(size).count();
+    for (n, batch) in hosts.chunks(size).enumerate() {
+        if halted {
+            results.extend(batch.iter().map(|host| HostResult {
+                host: host.clone(),
+                status: "skipped",
+                exit_code: None,
+                duration_secs: 0.0,
+                output: String::new(),
+            }));
+            continue;
+        }
+        println!("Batch {}: {}", n + 1, batch.join(", "));
+        let batch_results: Vec<HostResult> = thread::scope(|scope| {
+            let handles: Vec<_> = batch
+                .iter()
+                .map(|host| {
+                    let manifest_json = &manifest_json;
+                    scope.spawn(move || apply_on(host, manifest_json))
+                })
+                .collect();
+            handles.into_iter().filter_map(|handle| handle.join().ok()).collect()
+        });
+        halted = batch_results.iter().any(|r| r.status != "ok");
+        if halted && n + 1 < batches {
+            eprintln!("Batch {} had failures; not starting further batches", n + 1);
+        }
+        results.extend(batch_results);
+    }
+
+    let count = |status: &str| results.iter().filter(|r| r.status == status).count();
+    let (ok, failed, skipped) = (count("ok"), count("failed"), count("skipped"));
+    let aggregate = json!({
+        "manifest": manifest,
+        "batch_size": size,
+        "hosts": results,
+        "summary": { "ok": ok, "failed": failed, "skipped": skipped },
+    });
+    let text = serde_json::to_string_pretty(&aggregate).map_err(|e| format!("Failed to serialize report: {}", e))?;
+    match report {
+        Some(path) => fs::write(path, text + "\n").map_err(|e| format!("Failed to write {}: {}", path, e))?,
+        None => println!("{}", text),
+    }
+    if failed + skipped > 0 {
+        return Err(format!("{} hosts failed, {} skipped", failed, skipped));
+    }
+    Ok(())
+}
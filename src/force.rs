@@ -0,0 +1,75 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use crate::config::load_config;
+
+// dpkg force options every overlay operation needs: the overlay database isn't root's, and
+// dependencies on base image packages live in a database it deliberately can't see
+const ESSENTIAL: [&str; 2] = ["not-root", "depends"];
+
+// Force options that can be enabled in config.json or with --force, and what they risk
+pub const OPTIONAL: [(&str, &str); 6] = [
+    ("overwrite", "files owned by other layered packages are replaced, and packages left with none disappear"),
+    ("overwrite-dir", "directories of other layered packages may be replaced by files"),
+    ("overwrite-diverted", "diverted files are overwritten with the undiverted version"),
+    ("conflicts", "packages conflicting with installed ones are installed anyway"),
+    ("breaks", "packages breaking installed ones are installed anyway"),
+    ("architecture", "packages built for another architecture are installed"),
+];
+
+// Options given with --force for this invocation
+static REQUESTED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+// Enabled options are warned about once per run, not for every package
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_requested(options: Vec<String>) {
+    *REQUESTED.lock().unwrap_or_else(|e| e.into_inner()) = options;
+}
+
+pub fn names() -> [&'static str; 6] {
+    OPTIONAL.map(|(name, _)| name)
+}
+
+// dpkg arguments for the essential force options
+pub fn essential_args() -> Vec<String> {
+    ESSENTIAL.iter().map(|option| format!("--force-{}", option)).collect()
+}
+
+// dpkg arguments for unpacking packages: the essential force options plus those enabled in
+// config.json or with --force
+pub fn install_args() -> Result<Vec<String>, String> {
+    let mut enabled = load_config()?.force_options;
+    for option in REQUESTED.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+        if !enabled.contains(option) {
+            enabled.push(option.clone());
+        }
+    }
+    let warn = !WARNED.swap(true, Ordering::Relaxed);
+    let mut args = essential_args();
+    for option in &enabled {
+        let (_, risk) = OPTIONAL
+            .iter()
+            .find(|(name, _)| name == option)
+            .ok_or_else(|| format!("Unknown force option '{}'; known options are {}", option, names().join(", ")))?;
+        if warn {
+            eprintln!("Warning: forcing {}: {}", option, risk);
+        }
+        args.push(format!("--force-{}", option));
+    }
+    Ok(args)
+}
+
+// Hint for a dpkg failure a force option would get past, so the conflict is shown rather
+// than hidden
+pub fn hint(error: &str) -> Option<&'static str> {
+    if error.contains("trying to overwrite") {
+        Some("another layered package owns the same files; remove it, or pass --force overwrite to replace them")
+    } else if error.contains("conflicting packages") {
+        Some("it conflicts with a layered package; remove that first, or pass --force conflicts")
+    } else if error.contains("would break") {
+        Some("it breaks a layered package; upgrade or remove that first, or pass --force breaks")
+    } else if error.contains("package architecture") {
+        Some("it is built for another architecture; pass --force architecture to install it anyway")
+    } else {
+        None
+    }
+}
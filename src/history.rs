@@ -0,0 +1,272 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use crate::run_command;
+
+const HISTORY_FILE: &str = "/var/lib/hacker-ostree/history.json";
+
+// One finished transaction
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Entry {
+    pub id: u64,
+    // Unix timestamps
+    pub started: u64,
+    pub finished: u64,
+    pub command: String,
+    // Full invocation and the user behind it (the sudo caller where there is one)
+    #[serde(default)]
+    pub command_line: String,
+    #[serde(default)]
+    pub user: String,
+    pub packages: Vec<String>,
+    // Layered packages the transaction added and removed, with their versions; an upgrade
+    // shows up in both
+    #[serde(default)]
+    pub added: BTreeMap<String, String>,
+    #[serde(default)]
+    pub removed: BTreeMap<String, String>,
+    // Deployment the system was on when the transaction started
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<String>,
+    pub success: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// The user who asked for the current operation
+pub fn invoking_user() -> String {
+    std::env::var("SUDO_USER")
+        .or_else(|_| std::env::var("USER"))
+        .ok()
+        .filter(|user| !user.is_empty())
+        .or_else(|| run_command("id", &["-un"]).ok().map(|user| user.trim().to_string()))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+// Newest successful transaction that installed or upgraded a package
+pub fn last_install_of(entries: &[Entry], package: &str) -> Option<Entry> {
+    entries
+        .iter()
+        .rev()
+        .find(|entry| entry.success && entry.command != "remove" && entry.packages.iter().any(|p| p == package))
+        .cloned()
+}
+
+// Packages added and removed between two sets of installed versions
+pub fn changes(before: &[(String, String)], after: &[(String, String)]) -> (BTreeMap<String, String>, BTreeMap<String, String>) {
+    let added = after.iter().filter(|pkg| !before.contains(pkg)).cloned().collect();
+    let removed = before.iter().filter(|pkg| !after.contains(pkg)).cloned().collect();
+    (added, removed)
+}
+
+// One line per package change: upgrades and downgrades as "old -> new"
+fn describe_changes(added: &BTreeMap<String, String>, removed: &BTreeMap<String, String>) -> Vec<String> {
+    let mut lines = Vec::new();
+    for (name, version) in removed {
+        match added.get(name) {
+            Some(new) => lines.push(format!("{} {} -> {}", name, version, new)),
+            None => lines.push(format!("-{} {}", name, version)),
+        }
+    }
+    for (name, version) in added.iter().filter(|(name, _)| !removed.contains_key(*name)) {
+        lines.push(format!("+{} {}", name, version));
+    }
+    lines
+}
+
+// Net package changes between the states after two transactions
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Diff {
+    pub from: u64,
+    pub to: u64,
+    pub added: BTreeMap<String, String>,
+    pub removed: BTreeMap<String, String>,
+    // Successful transactions in between that recorded no package versions (from before
+    // versions were tracked), whose changes are missing
+    pub untracked: Vec<u64>,
+}
+
+// Compare the layered packages after transaction `from` with those after `to`, from what
+// the successful transactions in between added and removed; changes that cancel out are
+// dropped. 0 stands for the state before the first transaction. Going back in time gives
+// the changes that would undo the ones in between.
+pub fn diff(entries: &[Entry], from: u64, to: u64) -> Result<Diff, String> {
+    for id in [from, to] {
+        if id != 0 && !entries.iter().any(|entry| entry.id == id) {
+            return Err(format!("No transaction {} in the history", id));
+        }
+    }
+    let (first, last) = (from.min(to), from.max(to));
+    // Versions of every package touched in between, before and after; None when not layered
+    let mut before: BTreeMap<&String, Option<&String>> = BTreeMap::new();
+    let mut after: BTreeMap<&String, Option<&String>> = BTreeMap::new();
+    let mut untracked = Vec::new();
+    for entry in entries.iter().filter(|entry| entry.success && entry.id > first && entry.id <= last) {
+        // Versions are recorded along with the base, so entries without either are older
+        if entry.added.is_empty() && entry.removed.is_empty() && entry.base.is_none() && !entry.packages.is_empty() {
+            untracked.push(entry.id);
+        }
+        for (name, version) in &entry.removed {
+            before.entry(name).or_insert(Some(version));
+            after.insert(name, None);
+        }
+        for (name, version) in &entry.added {
+            before.entry(name).or_insert(None);
+            after.insert(name, Some(version));
+        }
+    }
+    let net = |from: &BTreeMap<&String, Option<&String>>, to: &BTreeMap<&String, Option<&String>>| -> BTreeMap<String, String> {
+        to.iter()
+            .filter_map(|(name, version)| Some(((*name).clone(), (*version)?)))
+            .filter(|(name, version)| from.get(name).copied().flatten() != Some(*version))
+            .map(|(name, version)| (name, version.clone()))
+            .collect()
+    };
+    let (mut added, mut removed) = (net(&before, &after), net(&after, &before));
+    if from > to {
+        std::mem::swap(&mut added, &mut removed);
+    }
+    Ok(Diff { from, to, added, removed, untracked })
+}
+
+// Print a diff as change lines, or as JSON
+pub fn print_diff(diff: &Diff, json: bool) -> Result<(), String> {
+    if json {
+        let text = serde_json::to_string_pretty(diff).map_err(|e| format!("Failed to serialize diff: {}", e))?;
+        println!("{}", text);
+        return Ok(());
+    }
+    let lines = describe_changes(&diff.added, &diff.removed);
+    if lines.is_empty() {
+        println!("No package changes between transactions {} and {}", diff.from, diff.to);
+    }
+    for line in lines {
+        println!("{}", line);
+    }
+    if !diff.untracked.is_empty() {
+        let ids: Vec<String> = diff.untracked.iter().map(u64::to_string).collect();
+        eprintln!("Note: transactions {} recorded no package versions; their changes are missing", ids.join(", "));
+    }
+    Ok(())
+}
+
+// Print the recorded transactions, newest first, or all of them as JSON
+pub fn print(entries: &[Entry], json: bool) -> Result<(), String> {
+    if json {
+        let text = serde_json::to_string_pretty(entries).map_err(|e| format!("Failed to serialize history: {}", e))?;
+        println!("{}", text);
+        return Ok(());
+    }
+    if entries.is_empty() {
+        println!("No transactions recorded");
+    }
+    for entry in entries.iter().rev() {
+        let outcome = if entry.success { "" } else { " (failed, rolled back)" };
+        println!("{} {} {} by {}{}", entry.id, format_time(entry.started), entry.command, entry.user, outcome);
+        if let Some(base) = &entry.base {
+            println!("    Base: {}", base);
+        }
+        for line in describe_changes(&entry.added, &entry.removed) {
+            println!("    {}", line);
+        }
+        if let Some(error) = &entry.error {
+            println!("    Error: {}", error.lines().last().unwrap_or_default());
+        }
+    }
+    Ok(())
+}
+
+// Render a Unix timestamp as local time
+pub fn format_time(timestamp: u64) -> String {
+    run_command("date", &["-d", &format!("@{}", timestamp), "+%Y-%m-%d %H:%M:%S %Z"])
+        .map(|out| out.trim().to_string())
+        .unwrap_or_else(|_| timestamp.to_string())
+}
+
+// All recorded transactions, oldest first
+pub fn load() -> Result<Vec<Entry>, String> {
+    if !Path::new(HISTORY_FILE).exists() {
+        return Ok(Vec::new());
+    }
+    let file = File::open(HISTORY_FILE).map_err(|e| format!("Failed to open {}: {}", HISTORY_FILE, e))?;
+    serde_json::from_reader(file).map_err(|e| format!("Failed to parse {}: {}", HISTORY_FILE, e))
+}
+
+// Append a transaction, assigning it the next id
+pub fn record(mut entry: Entry) -> Result<u64, String> {
+    let mut entries = load()?;
+    entry.id = entries.last().map_or(1, |last| last.id + 1);
+    let id = entry.id;
+    entries.push(entry);
+    let dir = Path::new(HISTORY_FILE).parent().ok_or("History file has no parent directory")?;
+    fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    // Written aside and renamed over, so a crash never leaves a truncated history
+    let file = NamedTempFile::new_in(dir).map_err(|e| format!("Failed to create temp file: {}", e))?;
+    serde_json::to_writer_pretty(&file, &entries).map_err(|e| format!("Failed to write to temp file: {}", e))?;
+    // Readable by everyone, like the history file it replaces
+    fs::set_permissions(file.path(), fs::Permissions::from_mode(0o644))
+        .map_err(|e| format!("Failed to set permissions of {}: {}", file.path().display(), e))?;
+    file.persist(HISTORY_FILE).map_err(|e| format!("Failed to write {}: {}", HISTORY_FILE, e))?;
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: u64, success: bool, added: &[(&str, &str)], removed: &[(&str, &str)]) -> Entry {
+        let map = |pairs: &[(&str, &str)]| pairs.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect();
+        Entry {
+            id,
+            started: 0,
+            finished: 0,
+            command: "test".to_string(),
+            command_line: String::new(),
+            user: String::new(),
+            packages: vec!["x".to_string()],
+            added: map(added),
+            removed: map(removed),
+            base: None,
+            success,
+            error: None,
+        }
+    }
+
+    fn pairs(map: &BTreeMap<String, String>) -> Vec<(&str, &str)> {
+        map.iter().map(|(n, v)| (n.as_str(), v.as_str())).collect()
+    }
+
+    #[test]
+    fn diff_nets_out_changes_in_between() {
+        let entries = vec![
+            entry(1, true, &[("a", "1"), ("b", "1")], &[]),
+            entry(2, true, &[("a", "2")], &[("a", "1")]),
+            entry(3, false, &[("c", "1")], &[]),
+            entry(4, true, &[("d", "1")], &[("b", "1")]),
+            entry(5, true, &[], &[("d", "1")]),
+        ];
+        let diff = diff(&entries, 1, 5).unwrap();
+        assert_eq!(pairs(&diff.added), [("a", "2")]);
+        assert_eq!(pairs(&diff.removed), [("a", "1"), ("b", "1")]);
+        assert_eq!(describe_changes(&diff.added, &diff.removed), ["a 1 -> 2", "-b 1"]);
+
+        let back = super::diff(&entries, 5, 1).unwrap();
+        assert_eq!((back.added, back.removed), (diff.removed, diff.added));
+        assert_eq!(pairs(&super::diff(&entries, 0, 1).unwrap().added), [("a", "1"), ("b", "1")]);
+    }
+
+    #[test]
+    fn diff_reports_untracked_transactions_and_unknown_ids() {
+        let entries = vec![entry(1, true, &[], &[]), entry(2, true, &[("a", "1")], &[])];
+        assert_eq!(diff(&entries, 0, 2).unwrap().untracked, [1]);
+        assert_eq!(diff(&entries, 1, 3).unwrap_err(), "No transaction 3 in the history");
+    }
+}
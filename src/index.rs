@@ -0,0 +1,547 @@
+use std::collections::HashMap;
+use std::fs::{self, create_dir_all, File};
+use std::path::Path;
+use std::env;
+use std::process::{Command as ProcessCommand, Stdio};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use crate::config::{load_config, Config};
+use crate::fetch::{self, Download, FetchStatus};
+use crate::{keys, mirrors};
+use crate::repos::{self, Repo};
+use crate::run_command;
+
+const INDEX_DIR: &str = "/var/lib/hacker-ostree/index";
+
+// Compressions we can fetch, most preferred first
+const COMPRESSIONS: [&str; 4] = [".xz", ".gz", ".bz2", ""];
+
+// A package stanza from a Packages index
+#[derive(Debug, Clone)]
+pub struct Package {
+    pub repo: String,
+    // Archive component the stanza was listed under, empty for flat repos
+    pub component: String,
+    pub fields: HashMap<String, String>,
+}
+
+impl Package {
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields.get(name).map(String::as_str)
+    }
+
+    pub fn name(&self) -> &str {
+        self.field("Package").unwrap_or_default()
+    }
+
+    pub fn version(&self) -> &str {
+        self.field("Version").unwrap_or_default()
+    }
+}
+
+// One line of a Release file's SHA256 field
+#[derive(Debug, Clone)]
+pub struct ReleaseEntry {
+    pub sha256: String,
+    pub size: u64,
+    pub path: String,
+}
+
+// Parsed Release/InRelease file
+#[derive(Debug, Clone, Default)]
+pub struct Release {
+    pub fields: HashMap<String, String>,
+    pub sha256: Vec<ReleaseEntry>,
+}
+
+// A verified index file stored in the local index database
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IndexedFile {
+    // Path relative to the Release file, e.g. main/binary-amd64/Packages.xz
+    pub source: String,
+    pub sha256: String,
+    // Decompressed copy under the repo's index directory
+    pub local: String,
+}
+
+// Per-repo record describing the last verified refresh
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct IndexRecord {
+    pub repo: String,
+    pub fetched_at: u64,
+    pub origin: Option<String>,
+    pub label: Option<String>,
+    pub suite: Option<String>,
+    pub codename: Option<String>,
+    pub date: Option<String>,
+    pub valid_until: Option<String>,
+    #[serde(default)]
+    pub not_automatic: bool,
+    #[serde(default)]
+    pub but_automatic_upgrades: bool,
+    // Whether the Release file's signature was verified, rather than accepted unsigned
+    #[serde(default)]
+    pub verified: bool,
+    pub files: Vec<IndexedFile>,
+}
+
+// Parse deb822 text (Release, Packages) into paragraphs of field -> value.
+// Continuation lines are joined with newlines; the first line of a multi-line
+// field is kept, empty if the value starts on the next line.
+pub fn parse_deb822(text: &str) -> Vec<HashMap<String, String>> {
+    let mut paragraphs = Vec::new();
+    let mut current: HashMap<String, String> = HashMap::new();
+    let mut last_key: Option<String> = None;
+    for line in text.lines() {
+        if line.trim().is_empty() {
+            if !current.is_empty() {
+                paragraphs.push(std::mem::take(&mut current));
+            }
+            last_key = None;
+            continue;
+        }
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(value) = last_key.as_ref().and_then(|key| current.get_mut(key)) {
+                value.push('\n');
+                value.push_str(line.trim());
+            }
+            continue;
+        }
+        if let Some((key, value)) = line.split_once(':') {
+            current.insert(key.to_string(), value.trim().to_string());
+            last_key = Some(key.to_string());
+        }
+    }
+    if !current.is_empty() {
+        paragraphs.push(current);
+    }
+    paragraphs
+}
+
+// Strip the OpenPGP clearsign armor from an InRelease file
+pub fn strip_clearsign(text: &str) -> String {
+    if !text.starts_with("-----BEGIN PGP SIGNED MESSAGE-----") {
+        return text.to_string();
+    }
+    let body = text.split_once("\n\n").map(|(_, body)| body).unwrap_or_default();
+    let body = body.split("-----BEGIN PGP SIGNATURE-----").next().unwrap_or_default();
+    // Dash-escaped lines start with "- "
+    body.lines()
+        .map(|line| line.strip_prefix("- ").unwrap_or(line))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Parse the content of a Release or InRelease file
+pub fn parse_release(text: &str) -> Release {
+    let fields = parse_deb822(&strip_clearsign(text)).into_iter().next().unwrap_or_default();
+    let sha256 = fields
+        .get("SHA256")
+        .map(|value| {
+            value
+                .lines()
+                .filter_map(|line| {
+                    let mut parts = line.split_whitespace();
+                    Some(ReleaseEntry {
+                        sha256: parts.next()?.to_string(),
+                        size: parts.next()?.parse().ok()?,
+                        path: parts.next()?.to_string(),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    Release { fields, sha256 }
+}
+
+// Directory holding a repo's verified metadata for its current suite
+pub fn repo_index_dir(repo: &Repo) -> String {
+    format!("{}/{}", INDEX_DIR, repo.cache_path())
+}
+
+// Drop a removed repo's metadata of every suite
+pub fn remove_repo_index(repo: &Repo) {
+    let _ = fs::remove_dir_all(format!("{}/{}", INDEX_DIR, repo.name));
+}
+
+// Drop what a repo's index directory holds besides its current suite: indexes of suites
+// it was configured with before, and files of the old layout without suite directories
+fn prune_index_dir(repo: &Repo) -> Result<(), String> {
+    let dir = format!("{}/{}", INDEX_DIR, repo.name);
+    let current = repo_index_dir(repo);
+    let entries = fs::read_dir(&dir).map_err(|e| format!("Failed to read {}: {}", dir, e))?;
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if path.display().to_string() == current {
+            continue;
+        }
+        let removed = if path.is_dir() { fs::remove_dir_all(&path) } else { fs::remove_file(&path) };
+        removed.map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+// Load the index record of a repo, if it has been refreshed before
+pub fn load_record(repo: &Repo) -> Result<Option<IndexRecord>, String> {
+    let path = format!("{}/index.json", repo_index_dir(repo));
+    if !Path::new(&path).exists() {
+        return Ok(None);
+    }
+    let file = File::open(&path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+    serde_json::from_reader(file).map(Some).map_err(|e| format!("Failed to parse {}: {}", path, e))
+}
+
+fn save_record(repo: &Repo, record: &IndexRecord) -> Result<(), String> {
+    let path = format!("{}/index.json", repo_index_dir(repo));
+    let file = File::create(&path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
+    serde_json::to_writer_pretty(file, record).map_err(|e| format!("Failed to write to {}: {}", path, e))
+}
+
+// Verify the stored Release file of a repo and describe the outcome
+pub fn signature_status(repo: &Repo) -> String {
+    if repo.option("trusted") == Some("yes") {
+        return "not checked (trusted=yes)".to_string();
+    }
+    let dir = repo_index_dir(repo);
+    let release = format!("{}/InRelease", dir);
+    if !Path::new(&release).exists() {
+        return "unknown (not refreshed yet)".to_string();
+    }
+    let signature = format!("{}/Release.gpg", dir);
+    let signature = Path::new(&signature).exists().then_some(signature);
+    match keys::verify_release(repo, &release, signature.as_deref()) {
+        Ok(true) => "good signature".to_string(),
+        Ok(false) => "not checked (--allow-unsigned)".to_string(),
+        Err(e) => format!("BAD: {}", e),
+    }
+}
+
+// Architectures to fetch for a repo: its arch= option or the native one
+fn repo_architectures(repo: &Repo) -> Result<Vec<String>, String> {
+    if let Some(archs) = repo.option("arch") {
+        return Ok(archs.split(',').map(str::to_string).collect());
+    }
+    let native = run_command("dpkg", &["--print-architecture"])?;
+    Ok(vec![native.trim().to_string()])
+}
+
+// Index files (without compression suffix) a repo provides for us
+fn wanted_indexes(repo: &Repo) -> Result<Vec<String>, String> {
+    if repo.components.is_empty() {
+        return Ok(vec!["Packages".to_string()]);
+    }
+    let mut wanted = Vec::new();
+    for component in &repo.components {
+        for arch in repo_architectures(repo)? {
+            wanted.push(format!("{}/binary-{}/Packages", component, arch));
+        }
+    }
+    Ok(wanted)
+}
+
+// Languages to fetch translations for, with "environment" resolved from the locale
+pub fn configured_languages(config: &Config) -> Vec<String> {
+    let mut languages = Vec::new();
+    for lang in &config.languages {
+        if lang != "environment" {
+            languages.push(lang.clone());
+            continue;
+        }
+        let locale = ["LC_ALL", "LC_MESSAGES", "LANG"]
+            .iter()
+            .filter_map(|var| env::var(var).ok())
+            .find(|value| !value.is_empty())
+            .unwrap_or_default();
+        // "pt_BR.UTF-8@euro" -> "pt_BR" and "pt"
+        let locale = locale.split(['.', '@']).next().unwrap_or_default().to_string();
+        if locale.is_empty() || locale == "C" || locale == "POSIX" {
+            continue;
+        }
+        if let Some((language, _)) = locale.split_once('_') {
+            languages.push(locale.clone());
+            languages.push(language.to_string());
+        } else {
+            languages.push(locale);
+        }
+    }
+    languages.dedup();
+    languages
+}
+
+// Optional Translation-<lang> indexes (without compression suffix) for a repo
+fn wanted_translations(repo: &Repo, languages: &[String]) -> Vec<String> {
+    let mut wanted = Vec::new();
+    for component in &repo.components {
+        for lang in languages {
+            wanted.push(format!("{}/i18n/Translation-{}", component, lang));
+        }
+    }
+    wanted
+}
+
+// Decompress a downloaded index into place based on the suffix of its Release path
+fn decompress(src: &str, release_path: &str, dest: &str) -> Result<(), String> {
+    let tool = if release_path.ends_with(".xz") {
+        "xz"
+    } else if release_path.ends_with(".gz") {
+        "gzip"
+    } else if release_path.ends_with(".bz2") {
+        "bzip2"
+    } else {
+        return fs::copy(src, dest).map(|_| ()).map_err(|e| format!("Failed to copy {}: {}", src, e));
+    };
+    let out = File::create(dest).map_err(|e| format!("Failed to create {}: {}", dest, e))?;
+    let status = ProcessCommand::new(tool)
+        .args(["-dc", src])
+        .stdout(Stdio::from(out))
+        .status()
+        .map_err(|e| format!("Failed to execute {}: {}", tool, e))?;
+    if !status.success() {
+        return Err(format!("Failed to decompress {}", src));
+    }
+    Ok(())
+}
+
+// Refresh the verified indexes of one repo, failing over between its mirrors
+pub fn refresh_repo(repo: &Repo) -> Result<(), String> {
+    let dir = repo_index_dir(repo);
+    create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {}", dir, e))?;
+    prune_index_dir(repo)?;
+    mirrors::with_mirrors(repo, |uri| refresh_from(repo, uri))
+}
+
+// Fetch InRelease (falling back to Release) and the indexes it lists from one mirror
+fn refresh_from(repo: &Repo, uri: &str) -> Result<(), String> {
+    let dir = repo_index_dir(repo);
+    let dists = repo.dists_url(uri);
+
+    // Repos without an InRelease sign their Release file with a detached Release.gpg
+    let release_path = format!("{}/InRelease", dir);
+    let signature_path = format!("{}/Release.gpg", dir);
+    let in_release = Download { url: format!("{}/InRelease", dists), dest: release_path.clone() };
+    let (release_status, signature) = match fetch::fetch_all(&[in_release], true) {
+        Ok(statuses) => {
+            let _ = fs::remove_file(&signature_path);
+            (statuses[0], None)
+        }
+        Err(_) => {
+            let release = Download { url: format!("{}/Release", dists), dest: release_path.clone() };
+            let status = fetch::fetch_all(&[release], true)?[0];
+            let _ = fs::remove_file(&signature_path);
+            let detached = Download { url: format!("{}/Release.gpg", dists), dest: signature_path.clone() };
+            let signature = fetch::fetch_all(&[detached], false).ok().map(|_| signature_path.as_str());
+            (status, signature)
+        }
+    };
+    let previous = load_record(repo)?;
+    if release_status == FetchStatus::NotModified {
+        if let Some(record) = &previous {
+            let trusted = record.verified || keys::allow_unsigned();
+            if trusted && record.files.iter().all(|file| Path::new(&file.local).exists()) {
+                return Ok(());
+            }
+        }
+    }
+    // Nothing the Release file lists is trusted before its signature is
+    let verified = keys::verify_release(repo, &release_path, signature)?;
+
+    let text = fs::read_to_string(&release_path).map_err(|e| format!("Failed to read {}: {}", release_path, e))?;
+    let release = parse_release(&text);
+    let by_hash = release.fields.get("Acquire-By-Hash").map(String::as_str) == Some("yes");
+
+    let find_entry = |wanted: &str| {
+        COMPRESSIONS.iter().find_map(|ext| {
+            let path = format!("{}{}", wanted, ext);
+            release.sha256.iter().find(|entry| entry.path == path)
+        })
+    };
+    let mut selected = Vec::new();
+    for wanted in wanted_indexes(repo)? {
+        let entry = find_entry(&wanted).ok_or_else(|| format!("{}: Release file does not list {}", repo.name, wanted))?;
+        selected.push(entry.clone());
+    }
+    // Translations are optional; most repos only ship some languages
+    let languages = configured_languages(&load_config()?);
+    for wanted in wanted_translations(repo, &languages) {
+        if let Some(entry) = find_entry(&wanted) {
+            selected.push(entry.clone());
+        }
+    }
+
+    // Content-addressed by-hash paths can't change under us while the mirror syncs
+    let downloads: Vec<Download> = selected
+        .iter()
+        .map(|entry| {
+            let url = match (by_hash, entry.path.rsplit_once('/')) {
+                (true, Some((parent, _))) => format!("{}/{}/by-hash/SHA256/{}", dists, parent, entry.sha256),
+                (true, None) => format!("{}/by-hash/SHA256/{}", dists, entry.sha256),
+                (false, _) => format!("{}/{}", dists, entry.path),
+            };
+            Download { url, dest: format!("{}/{}.download", dir, entry.path.replace('/', "_")) }
+        })
+        .collect();
+    fetch::fetch_all(&downloads, false)?;
+
+    let mut files = Vec::new();
+    for (entry, download) in selected.iter().zip(&downloads) {
+        let size = fs::metadata(&download.dest).map(|m| m.len()).unwrap_or(0);
+        let actual = fetch::sha256_file(&download.dest)?;
+        if size != entry.size || actual != entry.sha256 {
+            let _ = fs::remove_file(&download.dest);
+            return Err(format!("{}: hash mismatch for {} (expected {}, got {})", repo.name, entry.path, entry.sha256, actual));
+        }
+        let base = COMPRESSIONS
+            .iter()
+            .find_map(|ext| entry.path.strip_suffix(ext).filter(|_| !ext.is_empty()))
+            .unwrap_or(&entry.path);
+        let local = format!("{}/{}", dir, base.replace('/', "_"));
+        decompress(&download.dest, &entry.path, &local)?;
+        let _ = fs::remove_file(&download.dest);
+        files.push(IndexedFile { source: entry.path.clone(), sha256: entry.sha256.clone(), local });
+    }
+
+    let field = |name: &str| release.fields.get(name).cloned();
+    let record = IndexRecord {
+        repo: repo.name.clone(),
+        fetched_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        origin: field("Origin"),
+        label: field("Label"),
+        suite: field("Suite"),
+        codename: field("Codename"),
+        date: field("Date"),
+        valid_until: field("Valid-Until"),
+        not_automatic: field("NotAutomatic").as_deref() == Some("yes"),
+        but_automatic_upgrades: field("ButAutomaticUpgrades").as_deref() == Some("yes"),
+        verified,
+        files,
+    };
+    save_record(repo, &record)
+}
+
+// Refresh the index database for every configured repo, all at once: each repo gets its own
+// thread, so a slow or broken one delays and fails only itself. Failures are reported once
+// every repo is done.
+pub fn refresh_all() -> Result<(), String> {
+    let repos: Vec<Repo> = repos::load_repos()?.into_iter().filter(|repo| repo.kind == "deb").collect();
+    let results: Vec<(&str, Result<(), String>)> = thread::scope(|scope| {
+        let handles: Vec<_> = repos.iter().map(|repo| (repo.name.as_str(), scope.spawn(move || refresh_repo(repo)))).collect();
+        handles
+            .into_iter()
+            .map(|(name, handle)| (name, handle.join().unwrap_or_else(|_| Err("refresh thread panicked".to_string()))))
+            .collect()
+    });
+    let mut failed = Vec::new();
+    for (name, result) in results {
+        if let Err(e) = result {
+            eprintln!("Failed to refresh {}: {}", name, e.trim_end());
+            failed.push(name);
+        }
+    }
+    if !failed.is_empty() {
+        return Err(format!("Failed to refresh {} of {} repositories: {}", failed.len(), repos.len(), failed.join(", ")));
+    }
+    Ok(())
+}
+
+// Read all package stanzas from a repo's indexed Packages files
+pub fn load_packages(repo: &Repo) -> Result<Vec<Package>, String> {
+    let record = match load_record(repo)? {
+        Some(record) => record,
+        None => return Ok(Vec::new()),
+    };
+    let mut packages = Vec::new();
+    for file in record.files.iter().filter(|file| !file.source.contains("/i18n/")) {
+        let text = fs::read_to_string(&file.local).map_err(|e| format!("Failed to read {}: {}", file.local, e))?;
+        let component = match file.source.split_once('/') {
+            Some((component, _)) => component.to_string(),
+            None => String::new(),
+        };
+        packages.extend(parse_deb822(&text).into_iter().map(|fields| Package {
+            repo: repo.name.clone(),
+            component: component.clone(),
+            fields,
+        }));
+    }
+    Ok(packages)
+}
+
+// Read packages from every configured repo's index
+pub fn load_all_packages() -> Result<Vec<Package>, String> {
+    let mut packages = Vec::new();
+    for repo in repos::load_repos()? {
+        packages.extend(load_packages(&repo)?);
+    }
+    Ok(packages)
+}
+
+// Map Description-md5 to the localized description, best language first
+pub fn load_translations(languages: &[String]) -> Result<HashMap<String, String>, String> {
+    let mut translations = HashMap::new();
+    for repo in repos::load_repos()? {
+        let record = match load_record(&repo)? {
+            Some(record) => record,
+            None => continue,
+        };
+        // Walk languages from least to most preferred so better matches overwrite
+        for lang in languages.iter().rev() {
+            let suffix = format!("/i18n/Translation-{}", lang);
+            for file in record.files.iter().filter(|file| file.source.contains(&suffix)) {
+                let text = fs::read_to_string(&file.local).map_err(|e| format!("Failed to read {}: {}", file.local, e))?;
+                let key = format!("Description-{}", lang);
+                for mut entry in parse_deb822(&text) {
+                    if let (Some(md5), Some(description)) = (entry.remove("Description-md5"), entry.remove(&key)) {
+                        translations.insert(md5, description);
+                    }
+                }
+            }
+        }
+    }
+    Ok(translations)
+}
+
+// Description of a package, localized when a translation is available
+pub fn description(package: &Package, translations: &HashMap<String, String>) -> String {
+    package
+        .field("Description-md5")
+        .and_then(|md5| translations.get(md5))
+        .map(String::as_str)
+        .or_else(|| package.field("Description"))
+        .unwrap_or_default()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_clearsigned_release() {
+        let text = "-----BEGIN PGP SIGNED MESSAGE-----
+Hash: SHA512
+
+Suite: stable
+Acquire-By-Hash: yes
+SHA256:
+ 0123abcd 1234 main/binary-amd64/Packages.xz
+ 4567ef01 99 main/i18n/Translation-en
+-----BEGIN PGP SIGNATURE-----
+
+iQIzBAEBCgAdFiEE
+-----END PGP SIGNATURE-----
+";
+        let release = parse_release(text);
+        assert_eq!(release.fields.get("Suite").map(String::as_str), Some("stable"));
+        assert_eq!(release.sha256.len(), 2);
+        assert_eq!(release.sha256[0].path, "main/binary-amd64/Packages.xz");
+        assert_eq!(release.sha256[0].size, 1234);
+    }
+
+    #[test]
+    fn parses_paragraphs_with_continuation_lines() {
+        let paragraphs = parse_deb822("Package: a\nDescription: short\n long\n\nPackage: b\n");
+        assert_eq!(paragraphs.len(), 2);
+        assert_eq!(paragraphs[0].get("Description").map(String::as_str), Some("short\nlong"));
+        assert_eq!(paragraphs[1].get("Package").map(String::as_str), Some("b"));
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tempfile::NamedTempFile;
+use crate::config::load_config;
+use crate::repos::{self, Repo, KEYRINGS_DIR};
+use crate::{fetch, history, run_command};
+
+// Keyrings trusted alongside ours for repos without a signed-by keyring file
+const TRUSTED_KEYRINGS_DIR: &str = "/etc/apt/trusted.gpg.d";
+// URLs keys were added from, by keyring name, so they can be fetched again
+const SOURCES_FILE: &str = "/etc/hacker-ostree/keyrings/sources.json";
+// OSTree keeps the keys imported for a remote next to its repo
+const OSTREE_REPO: &str = "/ostree/repo";
+// Keys expiring within this many days are warned about
+const EXPIRY_WARNING_DAYS: u64 = 30;
+
+// Accept repos and packages that can't be verified, for local test repos
+static ALLOW_UNSIGNED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_allow_unsigned(enabled: bool) {
+    ALLOW_UNSIGNED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn allow_unsigned() -> bool {
+    ALLOW_UNSIGNED.load(Ordering::Relaxed)
+}
+
+// A key in one of our keyrings
+pub struct Key {
+    // Keyring file name without .gpg
+    pub name: String,
+    pub fingerprint: String,
+    pub uids: Vec<String>,
+    pub expires: Option<u64>,
+}
+
+// Keys contained in a keyring or key file, from gpg's machine-readable listing
+fn show_keys(path: &str, name: &str) -> Result<Vec<Key>, String> {
+    let listing = run_command("gpg", &["--batch", "--with-colons", "--show-keys", path])?;
+    let mut keys: Vec<Key> = Vec::new();
+    for line in listing.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields.first().copied() {
+            Some("pub") => keys.push(Key {
+                name: name.to_string(),
+                fingerprint: String::new(),
+                uids: Vec::new(),
+                expires: fields.get(6).and_then(|v| v.parse().ok()),
+            }),
+            // The first fpr after pub is the primary key's; later ones belong to subkeys
+            Some("fpr") => {
+                if let Some(key) = keys.last_mut().filter(|key| key.fingerprint.is_empty()) {
+                    key.fingerprint = fields.get(9).unwrap_or(&"").to_string();
+                }
+            }
+            Some("uid") => {
+                if let Some(key) = keys.last_mut() {
+                    key.uids.push(fields.get(9).unwrap_or(&"").replace("\\x3a", ":"));
+                }
+            }
+            _ => {}
+        }
+    }
+    if keys.is_empty() {
+        return Err(format!("No public key found in {}", path));
+    }
+    Ok(keys)
+}
+
+// Keyring name prefix of keys imported for an OSTree remote
+const OSTREE_PREFIX: &str = "ostree:";
+
+fn keyring_path(name: &str) -> String {
+    format!("{}/{}.gpg", KEYRINGS_DIR, name)
+}
+
+// Keyring files in our keyring directory, with their names
+fn keyrings() -> Result<Vec<(String, String)>, String> {
+    let entries = match fs::read_dir(KEYRINGS_DIR) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let mut keyrings: Vec<(String, String)> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path().display().to_string())
+        .filter_map(|path| {
+            let name = Path::new(&path).file_name()?.to_str()?.strip_suffix(".gpg")?.to_string();
+            Some((name, path))
+        })
+        .collect();
+    keyrings.sort();
+    Ok(keyrings)
+}
+
+// Store a key from a file or URL, armored or binary, as KEYRINGS_DIR/<name>.gpg.
+// Without a name the key's long id is used.
+pub fn add_key(source: &str, name: Option<&str>) -> Result<String, String> {
+    let downloaded = NamedTempFile::new().map_err(|e| format!("Failed to create temp file: {}", e))?;
+    let path = if source.contains("://") {
+        let dest = downloaded.path().display().to_string();
+        fetch::fetch_all(&[fetch::Download { url: source.to_string(), dest: dest.clone() }], false)?;
+        dest
+    } else {
+        source.to_string()
+    };
+    let keys = show_keys(&path, "")?;
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => keys[0].fingerprint.chars().skip(keys[0].fingerprint.len().saturating_sub(16)).collect(),
+    };
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c)) || name.starts_with('.') {
+        return Err(format!("Invalid key name '{}'", name));
+    }
+
+    fs::create_dir_all(KEYRINGS_DIR).map_err(|e| format!("Failed to create {}: {}", KEYRINGS_DIR, e))?;
+    let keyring = keyring_path(&name);
+    let content = fs::read(&path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    if content.starts_with(b"-----BEGIN PGP PUBLIC KEY BLOCK-----") {
+        // gpgv only reads binary keyrings
+        run_command("gpg", &["--batch", "--yes", "--dearmor", "-o", &keyring, &path])?;
+    } else {
+        fs::write(&keyring, content).map_err(|e| format!("Failed to write {}: {}", keyring, e))?;
+    }
+    for key in &keys {
+        println!("Added key {} ({}) as {}", key.fingerprint, key.uids.join(", "), keyring);
+    }
+    let mut sources = load_sources()?;
+    if source.contains("://") {
+        sources.insert(name.clone(), source.to_string());
+    } else {
+        sources.remove(&name);
+    }
+    save_sources(&sources)?;
+    Ok(name)
+}
+
+fn load_sources() -> Result<BTreeMap<String, String>, String> {
+    match fs::read_to_string(SOURCES_FILE) {
+        Ok(text) => serde_json::from_str(&text).map_err(|e| format!("Failed to parse {}: {}", SOURCES_FILE, e)),
+        Err(_) => Ok(BTreeMap::new()),
+    }
+}
+
+fn save_sources(sources: &BTreeMap<String, String>) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(sources).map_err(|e| format!("Failed to serialize key sources: {}", e))?;
+    fs::write(SOURCES_FILE, json).map_err(|e| format!("Failed to write {}: {}", SOURCES_FILE, e))
+}
+
+// Every key in our keyrings
+pub fn list_keys() -> Result<Vec<Key>, String> {
+    let mut keys = Vec::new();
+    for (name, path) in keyrings()? {
+        keys.extend(show_keys(&path, &name)?);
+    }
+    Ok(keys)
+}
+
+// Delete a keyring by name, or the keyring holding a key whose fingerprint ends with `selector`
+pub fn remove_key(selector: &str) -> Result<String, String> {
+    let wanted = selector.trim_start_matches("0x").to_uppercase();
+    let keyring = list_keys()?
+        .into_iter()
+        .find(|key| key.name == selector || (wanted.len() >= 8 && key.fingerprint.ends_with(&wanted)))
+        .map(|key| key.name)
+        .ok_or_else(|| format!("No key named or with fingerprint {}", selector))?;
+    let path = keyring_path(&keyring);
+    fs::remove_file(&path).map_err(|e| format!("Failed to remove {}: {}", path, e))?;
+    let mut sources = load_sources()?;
+    if sources.remove(&keyring).is_some() {
+        save_sources(&sources)?;
+    }
+    Ok(keyring)
+}
+
+// Keyrings of OSTree remotes, named ostree:<remote>, with their paths
+fn ostree_keyrings() -> Vec<(String, String)> {
+    let remotes = run_command("ostree", &["remote", "list", &format!("--repo={}", OSTREE_REPO)]).unwrap_or_default();
+    remotes
+        .lines()
+        .map(str::trim)
+        .filter(|remote| !remote.is_empty())
+        .map(|remote| (format!("{}{}", OSTREE_PREFIX, remote), format!("{}/{}.trustedkeys.gpg", OSTREE_REPO, remote)))
+        .filter(|(_, path)| Path::new(path).exists())
+        .collect()
+}
+
+// Keys of our keyrings and of OSTree remotes that have expired or expire within the
+// warning period
+pub fn expiring() -> Result<Vec<Key>, String> {
+    let deadline = history::now() + EXPIRY_WARNING_DAYS * 24 * 60 * 60;
+    let mut keys = list_keys()?;
+    for (name, path) in ostree_keyrings() {
+        keys.extend(show_keys(&path, &name)?);
+    }
+    keys.retain(|key| key.expires.is_some_and(|expires| expires <= deadline));
+    Ok(keys)
+}
+
+// Warn about expired and expiring keys; status and check-update call this so a key
+// running out doesn't first show up as failing signature verification
+pub fn warn_expiring() {
+    let keys = match expiring() {
+        Ok(keys) => keys,
+        Err(e) => return eprintln!("Warning: failed to check key expiry: {}", e),
+    };
+    let now = history::now();
+    for key in keys {
+        let expires = key.expires.unwrap_or_default();
+        let state = if expires <= now { "expired" } else { "expires" };
+        eprintln!(
+            "Warning: key {} ({}) in {} {} {}; run 'hacker-ostree repo refresh-keys'",
+            key.fingerprint,
+            key.uids.join(", "),
+            key.name,
+            state,
+            history::format_time(expires)
+        );
+    }
+}
+
+// Fetch the current version of keys: from the URL a keyring was added from, or from the
+// keyserver by fingerprint. Only keys with the same fingerprints are accepted, so a
+// refresh can extend a key's validity but never swap in someone else's key.
+fn fetch_updated(name: &str, keys: &[Key], url: Option<&String>, keyserver: &str, dest: &str) -> Result<(), String> {
+    let armored = match url {
+        Some(url) => fetch::fetch_text(url)?,
+        None => {
+            let mut armored = String::new();
+            for key in keys {
+                armored.push_str(&fetch::fetch_text(&format!("{}/pks/lookup?op=get&search=0x{}", keyserver, key.fingerprint))?);
+            }
+            armored
+        }
+    };
+    let mut temp = NamedTempFile::new().map_err(|e| format!("Failed to create temp file: {}", e))?;
+    temp.write_all(armored.as_bytes()).map_err(|e| format!("Failed to write to temp file: {}", e))?;
+    let temp_path = temp.path().display().to_string();
+    let fetched = show_keys(&temp_path, name)?;
+    let mut old: Vec<&str> = keys.iter().map(|key| key.fingerprint.as_str()).collect();
+    let mut new: Vec<&str> = fetched.iter().map(|key| key.fingerprint.as_str()).collect();
+    old.sort();
+    new.sort();
+    if old != new {
+        return Err(format!("the fetched keys ({}) are not the ones trusted ({})", new.join(", "), old.join(", ")));
+    }
+    if armored.contains("BEGIN PGP PUBLIC KEY BLOCK") {
+        run_command("gpg", &["--batch", "--yes", "--dearmor", "-o", dest, &temp_path])?;
+    } else {
+        fs::copy(&temp_path, dest).map_err(|e| format!("Failed to write {}: {}", dest, e))?;
+    }
+    Ok(())
+}
+
+// Refresh every key in our keyrings and those of OSTree remotes, reporting how each
+// keyring's expiry changed. Failures are reported per keyring and make the whole fail.
+pub fn refresh_keys() -> Result<(), String> {
+    let keyserver = load_config()?.keyserver.unwrap_or_else(|| repos::KEYSERVER.to_string());
+    let sources = load_sources()?;
+    let mut failed = Vec::new();
+    for (name, path) in keyrings()?.into_iter().chain(ostree_keyrings()) {
+        let keys = show_keys(&path, &name)?;
+        let temp = NamedTempFile::new().map_err(|e| format!("Failed to create temp file: {}", e))?;
+        let dest = temp.path().display().to_string();
+        let refreshed = fetch_updated(&name, &keys, sources.get(&name), &keyserver, &dest).and_then(|_| {
+            match name.strip_prefix(OSTREE_PREFIX) {
+                Some(remote) => run_command("ostree", &["remote", "gpg-import", &format!("--repo={}", OSTREE_REPO), "-k", &dest, remote]),
+                None => fs::copy(&dest, &path).map(|_| String::new()).map_err(|e| format!("Failed to write {}: {}", path, e)),
+            }
+        });
+        if let Err(e) = refreshed {
+            eprintln!("Failed to refresh {}: {}", name, e);
+            failed.push(name);
+            continue;
+        }
+        let describe = |expires: Option<u64>| expires.map(history::format_time).unwrap_or_else(|| "never".to_string());
+        for new in show_keys(&path, &name)? {
+            let old = keys.iter().find(|key| key.fingerprint == new.fingerprint).and_then(|key| key.expires);
+            if old == new.expires {
+                println!("{}: {} unchanged, expires {}", name, new.fingerprint, describe(new.expires));
+            } else {
+                println!("{}: {} now expires {} (was {})", name, new.fingerprint, describe(new.expires), describe(old));
+            }
+        }
+    }
+    if !failed.is_empty() {
+        return Err(format!("Failed to refresh {}", failed.join(", ")));
+    }
+    Ok(())
+}
+
+// Every keyring trusted for repos without a signed-by keyring: ours, then apt's
+fn trusted_keyrings() -> Result<Vec<String>, String> {
+    let mut paths: Vec<String> = keyrings()?.into_iter().map(|(_, path)| path).collect();
+    if let Ok(entries) = fs::read_dir(TRUSTED_KEYRINGS_DIR) {
+        let mut trusted: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path().display().to_string())
+            .filter(|p| p.ends_with(".gpg"))
+            .collect();
+        trusted.sort();
+        paths.extend(trusted);
+    }
+    Ok(paths)
+}
+
+// Keyrings a repo's signatures are checked against: its signed-by keyring, or all trusted ones
+pub fn repo_keyrings(repo: &Repo) -> Result<Vec<String>, String> {
+    match repo.option("signed-by").filter(|v| v.starts_with('/')) {
+        Some(keyring) => Ok(vec![keyring.to_string()]),
+        None => trusted_keyrings(),
+    }
+}
+
+// Check a repo's Release file: clearsigned InRelease, or with a detached Release.gpg.
+// Returns false when an unverifiable file is accepted via trusted=yes or --allow-unsigned.
+pub fn verify_release(repo: &Repo, release: &str, signature: Option<&str>) -> Result<bool, String> {
+    if repo.option("trusted") == Some("yes") {
+        return Ok(false);
+    }
+    let text = fs::read_to_string(release).map_err(|e| format!("Failed to read {}: {}", release, e))?;
+    let clearsigned = text.starts_with("-----BEGIN PGP SIGNED MESSAGE-----");
+    let result = if !clearsigned && signature.is_none() {
+        Err(format!("{}: the repository's Release file is not signed", repo.name))
+    } else {
+        let keyrings = repo_keyrings(repo)?;
+        if keyrings.is_empty() {
+            Err(format!("{}: no keys to verify the repository with; add one with 'hacker-ostree repo add-key'", repo.name))
+        } else {
+            let mut args: Vec<&str> = Vec::new();
+            for keyring in &keyrings {
+                args.extend(["--keyring", keyring.as_str()]);
+            }
+            args.extend(signature.filter(|_| !clearsigned));
+            args.push(release);
+            run_command("gpgv", &args)
+                .map(|_| true)
+                .map_err(|e| format!("{}: signature verification failed: {}", repo.name, e.trim()))
+        }
+    };
+    match result {
+        Err(e) if allow_unsigned() => {
+            eprintln!("Warning: {}; continuing because of --allow-unsigned", e);
+            Ok(false)
+        }
+        result => result,
+    }
+}
+
+// Check a downloaded .deb against the SHA256 the signed index lists for it. A missing hash
+// or an unverified index is only accepted with --allow-unsigned; a mismatch never is.
+pub fn verify_deb(path: &str, expected: Option<&str>, verified_index: bool) -> Result<(), String> {
+    let expected = match expected {
+        Some(expected) if verified_index || allow_unsigned() => expected,
+        Some(_) => return Err(format!("{} comes from an unsigned index; pass --allow-unsigned to install it anyway", path)),
+        None if allow_unsigned() => return Ok(()),
+        None => return Err(format!("No SHA256 listed for {}; pass --allow-unsigned to install it anyway", path)),
+    };
+    let actual = fetch::sha256_file(path)?;
+    if !actual.eq_ignore_ascii_case(expected) {
+        let _ = fs::remove_file(path);
+        return Err(format!("Hash mismatch for {} (expected {}, got {})", path, expected, actual));
+    }
+    Ok(())
+}
@@ -0,0 +1,204 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use crate::compose::{self, CommitMetadata};
+use crate::config::{load_config, Layering};
+use crate::{dpkgdb, ostree, run_command, timing, transaction, OVERLAY_DIR};
+
+// Local branch layered commits are written to; it is never pulled or pushed
+const LAYERED_BRANCH: &str = "hackeros/layered";
+// Overlay state saved for every layered deployment, restored when rolling back to it
+const STATES_DIR: &str = "/var/lib/hacker-ostree/deployments";
+// Layered trees are checked out and composed here
+const COMPOSE_TMP: &str = "/var/tmp";
+
+// Base commit a system update in this transaction pulled, layered on instead of the current one
+static PENDING_BASE: Mutex<Option<String>> = Mutex::new(None);
+
+// Another stateroot's overlay isn't mounted anywhere, so it only takes effect as deployments
+pub fn enabled() -> Result<bool, String> {
+    let deployment = load_config()?.layering == Layering::Deployment;
+    if deployment && ostree::overlay_only() {
+        return Err("This host isn't OSTree-booted, so packages can't be layered into deployments; \
+             set \"layering\": \"live\" in /etc/hacker-ostree/config.json to manage them in an overlay only"
+            .to_string());
+    }
+    Ok(deployment || ostree::targets_other_stateroot()?)
+}
+
+// Use a freshly pulled base commit for the deployment this transaction composes
+pub fn set_base(checksum: &str) {
+    *PENDING_BASE.lock().unwrap_or_else(|e| e.into_inner()) = Some(checksum.to_string());
+}
+
+// Base commit a deployment was composed on; base commits are their own base
+pub fn base_of(checksum: &str) -> Result<String, String> {
+    Ok(CommitMetadata::load(checksum)?.base.unwrap_or_else(|| checksum.to_string()))
+}
+
+// Base commit the next deployment is composed on: the one just pulled by a system update,
+// otherwise the base of the default deployment
+pub fn target_base() -> Result<Option<String>, String> {
+    if let Some(base) = PENDING_BASE.lock().unwrap_or_else(|e| e.into_inner()).clone() {
+        return Ok(Some(base));
+    }
+    match ostree::deployments()?.first() {
+        Some(deployment) => Ok(Some(base_of(&deployment.checksum)?)),
+        None => Ok(None),
+    }
+}
+
+fn state_dir(checksum: &str) -> String {
+    format!("{}/{}", STATES_DIR, checksum)
+}
+
+// Check out the base, put the overlay on top and commit the result with its metadata
+fn compose(base: &str) -> Result<String, String> {
+    let workdir = tempfile::Builder::new()
+        .prefix("hacker-ostree-layer-")
+        .tempdir_in(COMPOSE_TMP)
+        .map_err(|e| format!("Failed to create compose directory: {}", e))?;
+    let rootfs = format!("{}/rootfs", workdir.path().display());
+    let repo = format!("--repo={}", ostree::OSTREE_REPO);
+    run_command("ostree", &["checkout", &repo, base, &rootfs])?;
+    run_command("cp", &["-a", &format!("{}/.", OVERLAY_DIR), &rootfs])?;
+
+    // Deployments carry their default /etc as /usr/etc, merged with the local /etc on deploy
+    let etc = format!("{}/etc", rootfs);
+    if Path::new(&etc).is_dir() {
+        let usr_etc = format!("{}/usr/etc", rootfs);
+        fs::create_dir_all(&usr_etc).map_err(|e| format!("Failed to create {}: {}", usr_etc, e))?;
+        run_command("cp", &["-a", &format!("{}/.", etc), &usr_etc])?;
+        fs::remove_dir_all(&etc).map_err(|e| format!("Failed to remove {}: {}", etc, e))?;
+    }
+
+    let layered: BTreeMap<String, String> = dpkgdb::installed_versions()?.into_iter().collect();
+    let mut metadata = vec![
+        (compose::METADATA_BASE, base.to_string()),
+        (
+            compose::METADATA_LAYERED,
+            serde_json::to_string(&layered).map_err(|e| format!("Failed to serialize layered packages: {}", e))?,
+        ),
+    ];
+    // Keep the base's compose metadata so status, diffs and downgrade checks still see it
+    for key in [compose::METADATA_VERSION, compose::METADATA_SERIES, compose::METADATA_PACKAGES, compose::METADATA_ADVISORIES] {
+        if let Some(value) = ostree::metadata_string(ostree::OSTREE_REPO, base, key)? {
+            metadata.push((key, value));
+        }
+    }
+    let version = CommitMetadata::load(base)?.version_label().to_string();
+    let subject = format!("{} with {} layered packages", version, layered.len());
+    ostree::commit_tree(ostree::OSTREE_REPO, LAYERED_BRANCH, &rootfs, &subject, &metadata)
+}
+
+// Drop saved overlay states of deployments that no longer exist
+fn prune_states() -> Result<(), String> {
+    let deployed: Vec<String> = ostree::deployments()?.into_iter().map(|d| d.checksum).collect();
+    let entries = match fs::read_dir(STATES_DIR) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(()),
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !deployed.contains(&name) {
+            fs::remove_dir_all(entry.path()).map_err(|e| format!("Failed to remove {}: {}", entry.path().display(), e))?;
+        }
+    }
+    Ok(())
+}
+
+// What a layered deployment is composed from besides its base: the layered packages and the
+// overlay tree, down to the inode and change time of every path in it
+#[derive(PartialEq)]
+pub struct Inputs {
+    packages: Vec<(String, String)>,
+    overlay: String,
+}
+
+// The current inputs of a layered deployment, None when layering live
+pub fn inputs() -> Result<Option<Inputs>, String> {
+    if !enabled()? {
+        return Ok(None);
+    }
+    Ok(Some(Inputs {
+        packages: dpkgdb::installed_versions()?,
+        overlay: run_command("find", &[OVERLAY_DIR, "-printf", "%P %i %C@\n"])?,
+    }))
+}
+
+// A deployment stage() added, removed again when the transaction fails after it
+pub struct Staged {
+    // Default deployment it replaced, as checksum and serial
+    replaced: Option<(String, String)>,
+}
+
+// Compose the transaction's result into a new pending deployment when it pulled a new base
+// or changed the inputs from `before`; nothing to do when layering live
+pub fn stage(before: Option<Inputs>) -> Result<Option<Staged>, String> {
+    let Some(before) = before else {
+        return Ok(None);
+    };
+    let new_base = PENDING_BASE.lock().unwrap_or_else(|e| e.into_inner()).is_some();
+    if !new_base && inputs()?.as_ref() == Some(&before) {
+        println!("Nothing layered changed; no new deployment staged");
+        return Ok(None);
+    }
+    let base = match target_base()? {
+        Some(base) => base,
+        None => return Err("No deployment to layer packages on".to_string()),
+    };
+    let checksum = if dpkgdb::installed_versions()?.is_empty() {
+        base
+    } else {
+        timing::phase("layered commit", || compose(&base))?
+    };
+    transaction::save_state(&state_dir(&checksum))?;
+    let staged = Staged {
+        replaced: ostree::deployments()?.first().map(|d| (d.checksum.clone(), d.serial.clone())),
+    };
+    // The deployment may already be written when attaching its note or pruning fails
+    if let Err(e) = timing::phase("ostree deploy", || ostree::deploy(&checksum)).and_then(|_| prune_states()) {
+        if let Err(undo) = unstage(&staged) {
+            eprintln!("Warning: failed to remove the new deployment: {}", undo);
+        }
+        return Err(e);
+    }
+    *PENDING_BASE.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    println!("Staged deployment {}; the changes take effect after a reboot", checksum);
+    Ok(Some(staged))
+}
+
+// Remove the deployment stage() added, so packages of a transaction that failed and was
+// rolled back don't come up on the next boot anyway
+pub fn unstage(staged: &Staged) -> Result<(), String> {
+    let deployments = ostree::deployments()?;
+    let Some(default) = deployments.first() else {
+        return Ok(());
+    };
+    let unchanged = staged.replaced.as_ref().is_some_and(|(checksum, serial)| *checksum == default.checksum && *serial == default.serial);
+    if unchanged || default.booted {
+        return Ok(());
+    }
+    ostree::undeploy(0)?;
+    println!("Removed deployment {} staged by the failed transaction", default.checksum);
+    prune_states()
+}
+
+// Make the previous deployment the default again and bring the overlay state in line with it
+pub fn rollback() -> Result<String, String> {
+    let deployments = ostree::deployments()?;
+    let target = match deployments.get(1) {
+        Some(deployment) => deployment.checksum.clone(),
+        None => return Err("There is no previous deployment to roll back to".to_string()),
+    };
+    ostree::set_default(1)?;
+    let saved = state_dir(&target);
+    // Restoring from a missing copy empties the overlay, which is right for a plain base
+    if Path::new(&saved).is_dir() || CommitMetadata::load(&target)?.layered.is_empty() {
+        transaction::restore_state(&saved, false)?;
+    } else {
+        eprintln!("Warning: no overlay state saved for {}; the overlay database may not match it", target);
+    }
+    Ok(target)
+}
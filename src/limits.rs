@@ -0,0 +1,78 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::config::Config;
+use crate::run_command;
+
+// The process moves into its scope once; everything it starts afterwards inherits it
+static APPLIED: AtomicBool = AtomicBool::new(false);
+
+// Move this process into a transient systemd scope with the configured cpu-weight,
+// io-weight and memory-max, so downloads and dpkg runs started from now on share them and
+// an update in the background can't starve interactive use or exhaust memory
+pub fn apply(config: &Config) -> Result<(), String> {
+    let limits: Vec<(&str, u64)> = [
+        ("CPUWeight", config.cpu_weight),
+        ("IOWeight", config.io_weight),
+        ("MemoryMax", config.memory_max),
+    ]
+    .into_iter()
+    .filter(|(_, value)| *value > 0)
+    .collect();
+    if limits.is_empty() || APPLIED.swap(true, Ordering::Relaxed) {
+        return Ok(());
+    }
+    let pid = std::process::id().to_string();
+    let unit = format!("hacker-ostree-{}.scope", pid);
+    let count = (limits.len() + 1).to_string();
+    let values: Vec<String> = limits.iter().map(|(_, value)| value.to_string()).collect();
+    let mut args = vec![
+        "call",
+        "org.freedesktop.systemd1",
+        "/org/freedesktop/systemd1",
+        "org.freedesktop.systemd1.Manager",
+        "StartTransientUnit",
+        "ssa(sv)a(sa(sv))",
+        &unit,
+        "fail",
+        &count,
+        "PIDs",
+        "au",
+        "1",
+        &pid,
+    ];
+    for ((name, _), value) in limits.iter().zip(&values) {
+        args.extend([*name, "t", value.as_str()]);
+    }
+    args.push("0");
+    match run_command("busctl", &args) {
+        Ok(_) => Ok(()),
+        // Limits are a courtesy to the rest of the system, not a reason to refuse updating
+        Err(e) => {
+            eprintln!("Warning: running without resource limits, failed to create {}: {}", unit, e.trim());
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::testing::{call, replaying};
+
+    #[test]
+    fn moves_into_a_scope_with_the_configured_limits_once() {
+        let pid = std::process::id().to_string();
+        let unit = format!("hacker-ostree-{}.scope", pid);
+        let expected = [
+            "call", "org.freedesktop.systemd1", "/org/freedesktop/systemd1", "org.freedesktop.systemd1.Manager",
+            "StartTransientUnit", "ssa(sv)a(sa(sv))", &unit, "fail", "3", "PIDs", "au", "1", &pid,
+            "IOWeight", "t", "20", "MemoryMax", "t", "536870912", "0",
+        ];
+        let config = Config { io_weight: 20, memory_max: 512 << 20, ..Config::default() };
+        let (results, log) = replaying(vec![call("busctl", &expected, "o \"/org/freedesktop/systemd1/job/1\"\n")], || {
+            (apply(&config), apply(&config))
+        });
+        assert_eq!(results, (Ok(()), Ok(())));
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].error, None);
+    }
+}
@@ -1,34 +1,191 @@
+use std::collections::{BTreeSet, HashSet};
 use std::fs::{create_dir_all, File};
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufRead, BufReader, IsTerminal, Write};
 use std::path::Path;
 use std::process::Command as ProcessCommand;
-use clap::{Arg, Command};
-use serde_json;
-use tempfile::NamedTempFile;
+use std::thread;
+use std::time::{Duration, Instant};
+use clap::{Arg, ArgAction, Command};
+
+mod appstream;
+mod bundle;
+mod cache;
+mod changes;
+mod completion;
+mod compose;
+mod conffiles;
+mod config;
+mod daemon;
+mod dbus;
+mod diversions;
+mod download;
+mod dpkgdb;
+mod drift;
+mod etcfiles;
+mod exec;
+mod fault;
+mod fetch;
+mod filelists;
+mod fleet;
+mod force;
+mod history;
+mod index;
+mod keys;
+mod layering;
+mod limits;
+mod mirrors;
+mod notes;
+mod notify;
+mod ostree;
+mod policy;
+mod power;
+mod release;
+mod remote;
+mod repos;
+mod resolve;
+mod schedule;
+mod schema;
+mod selfupdate;
+mod session;
+mod statusfile;
+mod storage;
+mod suggest;
+mod timing;
+mod transaction;
+mod version;
 
 const CONFIG_DIR: &str = "/etc/hacker-ostree";
-const REPOS_FILE: &str = "/etc/hacker-ostree/repos.json";
 const VAR_DIR: &str = "/var/lib/hacker-ostree";
 const CACHE_DIR: &str = "/var/lib/hacker-ostree/apt-cache";
 const OVERLAY_DIR: &str = "/var/lib/hacker-ostree/overlay";
 const INSTALLED_PKGS_FILE: &str = "/var/lib/hacker-ostree/installed_packages.txt";
+// Layered packages only pulled in as dependencies, removed once nothing needs them
+const AUTO_INSTALLED_FILE: &str = "/var/lib/hacker-ostree/auto_installed.txt";
+// Layered packages `upgrade` leaves at their installed version
+const HELD_FILE: &str = "/var/lib/hacker-ostree/held_packages.txt";
+// Set in a run re-executed inside another stateroot's mount namespace
+const STATEROOT_ENV: &str = "HACKER_OSTREE_STATEROOT";
+// Directory holding the state to use instead of the system's, for tests
+const ROOT_ENV: &str = "HACKER_OSTREE_ROOT";
+// Set in a run re-executed with that state mounted in place
+const ROOT_ENTERED_ENV: &str = "HACKER_OSTREE_ROOT_ENTERED";
+// Fragment shown at login by pam_motd, written by `summary --motd`
+const MOTD_FILE: &str = "/run/motd.d/50-hacker-ostree";
+// Token for shell prompts, cached by `summary` and `check-update` for `prompt-status`
+const PROMPT_STATUS_FILE: &str = "/run/hacker-ostree/prompt-status";
+// Commands that only make sense on an OSTree-booted system
+const OSTREE_COMMANDS: [&str; 11] = [
+    "system-update", "upgrade-release", "auto-update", "bundle", "status", "check-update", "summary", "db", "rollback", "note", "prune",
+];
+// Everything we write outside the OSTree repository
+const STATE_DIRS: [&str; 4] = [CONFIG_DIR, VAR_DIR, "/var/cache/hacker-ostree", "/run/hacker-ostree"];
 
 // Helper function to run shell commands
 fn run_command(cmd: &str, args: &[&str]) -> Result<String, String> {
+    if let Some(result) = exec::replayed(cmd, args) {
+        return result;
+    }
     let output = ProcessCommand::new(cmd)
     .args(args)
     .output()
     .map_err(|e| format!("Failed to execute {}: {}", cmd, e))?;
 
-    if !output.status.success() {
-        return Err(format!(
+    let result = if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    } else {
+        Err(format!(
             "Command failed: {}\nStderr: {}",
             cmd,
             String::from_utf8_lossy(&output.stderr)
-        ));
+        ))
+    };
+    exec::record(cmd, args, &result);
+    result
+}
+
+// Helper function to run commands with output going straight to the terminal
+fn run_command_streamed(cmd: &str, args: &[&str]) -> Result<(), String> {
+    if let Some(result) = exec::replayed(cmd, args) {
+        return result.map(|_| ());
     }
+    let status = ProcessCommand::new(cmd)
+    .args(args)
+    .status()
+    .map_err(|e| format!("Failed to execute {}: {}", cmd, e))?;
 
-    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    let result = if status.success() {
+        Ok(String::new())
+    } else {
+        Err(format!("Command failed: {} ({})", cmd, status))
+    };
+    exec::record(cmd, args, &result);
+    result.map(|_| ())
+}
+
+// Function giving a run aimed at a stateroot that isn't booted that stateroot's own overlay
+// state: it is re-executed in a private mount namespace with the stateroot's
+// /var/lib/hacker-ostree mounted over ours. Returns the exit code of the re-executed run.
+fn enter_stateroot() -> Result<Option<i32>, String> {
+    if !ostree::targets_other_stateroot()? {
+        return Ok(None);
+    }
+    let state = format!("{}/{}{}", ostree::DEPLOY_DIR, ostree::stateroot()?, VAR_DIR);
+    if std::env::var_os(STATEROOT_ENV).is_some() {
+        for dir in [&state, VAR_DIR] {
+            std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir, e))?;
+        }
+        run_command("mount", &["--bind", &state, VAR_DIR])?;
+        return Ok(None);
+    }
+    let exe = std::env::current_exe().map_err(|e| format!("Failed to locate executable: {}", e))?;
+    let status = ProcessCommand::new("unshare")
+        .args(["--mount", "--propagation", "private"])
+        .arg(&exe)
+        .args(std::env::args_os().skip(1))
+        .env(STATEROOT_ENV, "1")
+        .status()
+        .map_err(|e| format!("Failed to execute unshare: {}", e))?;
+    Ok(Some(status.code().unwrap_or(1)))
+}
+
+// Function relocating all our state below $HACKER_OSTREE_ROOT, so tests can install and
+// remove for real without root: the run is re-executed in its own user and mount namespace
+// with the root's copy of each state directory mounted over it. Returns the exit code of the
+// re-executed run.
+fn enter_root() -> Result<Option<i32>, String> {
+    let root = match std::env::var(ROOT_ENV) {
+        Ok(root) if !root.is_empty() => root.trim_end_matches('/').to_string(),
+        _ => return Ok(None),
+    };
+    if std::env::var_os(ROOT_ENTERED_ENV).is_none() {
+        let exe = std::env::current_exe().map_err(|e| format!("Failed to locate executable: {}", e))?;
+        let status = ProcessCommand::new("unshare")
+            .args(["--user", "--map-root-user", "--mount", "--propagation", "private"])
+            .arg(&exe)
+            .args(std::env::args_os().skip(1))
+            .env(ROOT_ENTERED_ENV, "1")
+            .status()
+            .map_err(|e| format!("Failed to execute unshare: {}", e))?;
+        return Ok(Some(status.code().unwrap_or(1)));
+    }
+    for dir in STATE_DIRS {
+        let source = format!("{}{}", root, dir);
+        create_dir_all(&source).map_err(|e| format!("Failed to create {}: {}", source, e))?;
+        if !Path::new(dir).exists() {
+            // Without a mountpoint to mount on, make one in a throwaway overlay of the parent
+            let parent = Path::new(dir).parent().and_then(Path::to_str).unwrap_or("/");
+            let upper = format!("{}/.mountpoints{}/upper", root, parent);
+            let work = format!("{}/.mountpoints{}/work", root, parent);
+            for path in [&upper, &work] {
+                create_dir_all(path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
+            }
+            let options = format!("lowerdir={},upperdir={},workdir={}", parent, upper, work);
+            run_command("mount", &["-t", "overlay", "overlay", "-o", &options, parent])?;
+            create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir, e))?;
+        }
+        run_command("mount", &["--bind", &source, dir])?;
+    }
+    Ok(None)
 }
 
 // Ensure directories exist
@@ -37,94 +194,165 @@ fn ensure_dirs() -> Result<(), String> {
     create_dir_all(VAR_DIR).map_err(|e| format!("Failed to create {}: {}", VAR_DIR, e))?;
     create_dir_all(CACHE_DIR).map_err(|e| format!("Failed to create {}: {}", CACHE_DIR, e))?;
     create_dir_all(OVERLAY_DIR).map_err(|e| format!("Failed to create {}: {}", OVERLAY_DIR, e))?;
+    dpkgdb::ensure_admindir()?;
     Ok(())
 }
 
-// Load repos from repos.json
-fn load_repos() -> Result<Vec<String>, String> {
-    let path = Path::new(REPOS_FILE);
-    if !path.exists() {
-        return Ok(Vec::new());
-    }
-    let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", REPOS_FILE, e))?;
-    let repos: Vec<String> = serde_json::from_reader(file).map_err(|e| format!("Failed to parse {}: {}", REPOS_FILE, e))?;
-    Ok(repos)
+// Function refreshing the package indexes of every repository
+fn refresh_indexes() -> Result<(), String> {
+    ensure_dirs()?;
+    timing::phase("metadata refresh", index::refresh_all)
 }
 
-// Save repos to repos.json
-fn save_repos(repos: &[String]) -> Result<(), String> {
-    let file = File::create(REPOS_FILE).map_err(|e| format!("Failed to create {}: {}", REPOS_FILE, e))?;
-    serde_json::to_writer_pretty(file, repos).map_err(|e| format!("Failed to write to {}: {}", REPOS_FILE, e))?;
-    Ok(())
+// Function to resolve the dependencies of packages and show what installing them will cost;
+// returns everything to install, dependencies first, or None when declined. Resolution
+// failures are explained as a tree, or as JSON with `json`.
+fn confirm_install(
+    packages: &[resolve::Selection],
+    assume_yes: bool,
+    force_size: bool,
+    json: bool,
+    solve: resolve::Options,
+) -> Result<Option<Vec<resolve::Selection>>, String> {
+    let (index_packages, policy) = (index::load_all_packages()?, policy::Policy::load()?);
+    let (order, plan) = timing::phase("resolution", || -> Result<_, String> {
+        let mut resolver = resolve::Resolver::new(&index_packages, &policy, solve)?;
+        resolver.hold(&load_held()?);
+        for (package, version) in packages {
+            if let Some(explanation) = resolver.add(package, version.as_deref()) {
+                resolve::print(&explanation, json)?;
+                return Err(format!("Dependency resolution failed for {}", package));
+            }
+        }
+        let order: Vec<resolve::Selection> =
+            resolver.planned().into_iter().map(|(name, version)| (name, Some(version))).collect();
+        let plan = transaction::plan(&order, &index_packages, &policy)?;
+        Ok((order, plan))
+    })?;
+    println!("The following packages will be installed:");
+    transaction::print_summary(&plan);
+    transaction::check_limits(&plan, &config::load_config()?, force_size)?;
+    Ok(transaction::confirm(assume_yes)?.then_some(order))
 }
 
-// Create temporary sources.list from repos
-fn create_temp_sources_list() -> Result<NamedTempFile, String> {
-    let repos = load_repos()?;
-    let mut temp_file = NamedTempFile::new().map_err(|e| format!("Failed to create temp file: {}", e))?;
-    for repo in repos {
-        writeln!(temp_file, "{}", repo).map_err(|e| format!("Failed to write to temp file: {}", e))?;
+// Function returning the installed version of a package already layered at the requested
+// version, or at its candidate when none is requested
+fn layered_and_current(package: &str, version: Option<&str>) -> Result<Option<String>, String> {
+    if !load_installed_packages()?.iter().any(|installed| installed == package) {
+        return Ok(None);
+    }
+    let current = match dpkgdb::installed_version(package)? {
+        Some(current) => current,
+        None => return Ok(None),
+    };
+    if let Some(version) = version {
+        return Ok(Some(current).filter(|current| current == version));
     }
-    Ok(temp_file)
+    let packages = index::load_all_packages()?;
+    let policy = policy::Policy::load()?;
+    Ok(policy
+        .candidate(package, &packages)
+        .filter(|candidate| candidate.version() == current)
+        .map(|_| current))
 }
 
-// Function to update APT cache using custom sources
-fn apt_update() -> Result<(), String> {
-    ensure_dirs()?;
-    let temp_sources = create_temp_sources_list()?;
-    let sources_path = temp_sources.path().to_str().ok_or_else(|| "Failed to get temp file path".to_string())?;
-    let cache_dir = format!("Dir::Cache={}", CACHE_DIR);
-    let source_list = format!("Dir::Etc::SourceList={}", sources_path);
+// Function making sure a repository has a package, at the requested version if given
+fn check_available_from(package: &str, version: Option<&str>, repo: &str) -> Result<(), String> {
+    let available = index::load_all_packages()?
+        .iter()
+        .any(|pkg| pkg.repo == repo && pkg.name() == package && version.is_none_or(|version| pkg.version() == version));
+    if available {
+        return Ok(());
+    }
+    Err(match version {
+        Some(version) => format!("{} {} is not available from {}", package, version, repo),
+        None => format!("{} is not available from {}", package, repo),
+    })
+}
 
-    let update_args = vec![
-        "update",
-        "-o", &cache_dir,
-        "-o", &source_list,
-        "-o", "Dir::Etc::SourceParts=-", // Disable source parts
-    ];
-    run_command("apt-get", &update_args)?;
-    Ok(())
+// Function to download a .deb of a package into the cache, returning its path; the
+// candidate unless a version is given
+fn download_package(package: &str, version: Option<&str>) -> Result<String, String> {
+    let mut paths = download::debs(&[(package.to_string(), version.map(str::to_string))])?;
+    Ok(paths.remove(0))
 }
 
-// Function to install a package
-fn install_package(package: &str) -> Result<(), String> {
-    ensure_dirs()?;
-    apt_update()?; // Ensure cache is updated
-
-    let temp_sources = create_temp_sources_list()?;
-    let sources_path = temp_sources.path().to_str().ok_or_else(|| "Failed to get temp file path".to_string())?;
-    let cache_dir = format!("Dir::Cache={}", CACHE_DIR);
-    let source_list = format!("Dir::Etc::SourceList={}", sources_path);
-
-    // Download package
-    let download_args = vec![
-        "download",
-        package,
-        "-o", &cache_dir,
-        "-o", &source_list,
-        "-o", "Dir::Etc::SourceParts=-",
-    ];
-    run_command("apt-get", &download_args)?;
-
-    // Find the downloaded .deb file
-    let deb_pattern = format!("{}/{}_*.deb", CACHE_DIR, package);
-    let ls_output = run_command("ls", &[&deb_pattern])?;
-    let deb_files: Vec<&str> = ls_output.trim().split('\n').collect();
-    if deb_files.is_empty() || deb_files[0].is_empty() {
-        return Err(format!("No .deb file found for {}", package));
-    }
-    let deb_path = deb_files[0];
-
-    // Install to overlay
-    let install_args = vec![
-        "--instdir",
-        OVERLAY_DIR,
-        "--force-not-root",
-        "--force-overwrite",
+// Function to install a package, at a specific version if given
+fn install_package(package: &str, version: Option<&str>) -> Result<(), String> {
+    let deb_path = download_package(package, version)?;
+    install_deb(package, &deb_path)
+}
+
+// Function to install resolved packages in order. New ones not in `manual` are recorded as
+// automatically installed; packages in `manual` are marked as explicitly wanted.
+fn install_packages(order: &[resolve::Selection], manual: &[String]) -> Result<(), String> {
+    let previously = load_installed_packages()?;
+    let debs = download::debs(order)?;
+    for (i, ((package, _), deb_path)) in order.iter().zip(&debs).enumerate() {
+        install_deb(package, deb_path)?;
+        transaction::progress("unpack", i + 1, order.len());
+    }
+    let mut auto = load_auto_installed()?;
+    auto.retain(|p| !manual.contains(p));
+    for (package, _) in order {
+        if !manual.contains(package) && !previously.contains(package) && !auto.contains(package) {
+            auto.push(package.clone());
+        }
+    }
+    save_auto_installed(&auto)
+}
+
+// Function reinstalling, once, the packages layered before the overlay had its own dpkg
+// database, so dpkg knows their files and versions like those of every later install
+fn migrate_to_admindir() -> Result<(), String> {
+    if !dpkgdb::needs_migration() {
+        return Ok(());
+    }
+    let registered: Vec<String> = dpkgdb::installed_versions()?.into_iter().map(|(name, _)| name).collect();
+    let missing: Vec<resolve::Selection> = load_installed_packages()?
+        .into_iter()
+        .filter(|package| !registered.contains(package))
+        .map(|package| (package, None))
+        .collect();
+    if !missing.is_empty() {
+        println!("Registering {} packages layered before the overlay had its own dpkg database", missing.len());
+        let debs = download::debs(&missing)?;
+        for ((package, _), deb_path) in missing.iter().zip(&debs) {
+            install_deb(package, deb_path)?;
+        }
+    }
+    dpkgdb::mark_migrated()
+}
+
+// Function to install an already downloaded .deb of a package into the overlay
+fn install_deb(package: &str, deb_path: &str) -> Result<(), String> {
+    // Install to overlay, tracked in its own dpkg database. Dependencies provided by
+    // the base image live in the base database, which this one deliberately can't see.
+    let target_args = dpkgdb::dpkg_target_args();
+    let force_args = force::install_args()?;
+    let mut install_args: Vec<&str> = target_args.iter().chain(&force_args).map(String::as_str).collect();
+    install_args.extend([
+        // Keep conffiles the user edited; their new versions are set aside for review
+        "--force-confold",
+        "--force-confdef",
         "-i",
         deb_path,
-    ];
-    run_command("dpkg", &install_args)?;
+    ]);
+    let edited = conffiles::edited(package)?;
+    timing::phase("extraction", || transaction::run_dpkg(&install_args)).map_err(|e| match force::hint(&e) {
+        Some(hint) => format!("{}\nCan't install {}: {}", e.trim_end(), package, hint),
+        None => e,
+    })?;
+    fault::point("extraction")?;
+    let set_aside = conffiles::set_aside(package, &edited)?;
+    if set_aside > 0 {
+        println!(
+            "Kept your version of {} configuration files of {}; review the new ones with 'hacker-ostree conffiles'",
+            set_aside, package
+        );
+    }
+    filelists::record_package_files(package, deb_path)?;
+    transaction::run_post_actions(package)?;
 
     // Record installed package if not already there
     let mut installed = load_installed_packages()?;
@@ -132,87 +360,1369 @@ fn install_package(package: &str) -> Result<(), String> {
         installed.push(package.to_string());
         save_installed_packages(&installed)?;
     }
-
-    Ok(())
+
+    Ok(())
+}
+
+// Function listing the paths installing a package would add (A), replace (R) or remove (D),
+// and base image files it would shadow (C), without changing anything
+fn preview_files(package: &str, version: Option<&str>) -> Result<(), String> {
+    let deb_path = download_package(package, version)?;
+    let new_files = filelists::deb_files(&deb_path)?;
+    let root = storage::overlay_root()?;
+    let in_overlay = |file: &str| std::fs::symlink_metadata(format!("{}{}", root, file)).is_ok();
+    let old_files: Vec<String> = filelists::load_package_files(package)?
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|file| in_overlay(file) && !Path::new(&format!("{}{}", root, file)).is_dir())
+        .collect();
+    let (mut added, mut replaced, mut conflicts) = (0, 0, 0);
+    for file in &new_files {
+        if old_files.contains(file) {
+            replaced += 1;
+            println!("R {}", file);
+        } else if in_overlay(file) {
+            replaced += 1;
+            let (layered, _) = filelists::find_owners(file);
+            if layered.is_empty() {
+                println!("R {} (unowned overlay file)", file);
+            } else {
+                println!("R {} (owned by {})", file, layered.join(", "));
+            }
+        } else if Path::new(file).exists() {
+            conflicts += 1;
+            let (_, base) = filelists::find_owners(file);
+            if base.is_empty() {
+                println!("C {} (base image file)", file);
+            } else {
+                println!("C {} (base image, owned by {})", file, base.join(", "));
+            }
+        } else {
+            added += 1;
+            println!("A {}", file);
+        }
+    }
+    let removed: Vec<&String> = old_files.iter().filter(|file| !new_files.contains(file)).collect();
+    for file in &removed {
+        println!("D {}", file);
+    }
+    println!(
+        "{} added, {} replaced, {} removed, {} shadowing base image files",
+        added,
+        replaced,
+        removed.len(),
+        conflicts
+    );
+    Ok(())
+}
+
+// Function returning every name install could be given: indexed packages, the names they
+// provide, and packages of the base image and the overlay
+fn known_packages() -> Result<BTreeSet<String>, String> {
+    let mut known: BTreeSet<String> = load_installed_packages()?.into_iter().collect();
+    known.extend(dpkgdb::installed_with_provides(true)?.into_iter().map(|(name, _, _)| name));
+    for pkg in index::load_all_packages()? {
+        for provided in resolve::relations(pkg.field("Provides")).iter().flatten() {
+            known.insert(resolve::parse_relation(provided).0.to_string());
+        }
+        known.insert(pkg.name().to_string());
+    }
+    Ok(known)
+}
+
+// Function to remove a package
+fn remove_package(package: &str) -> Result<(), String> {
+    if dpkgdb::installed_version(package)?.is_none() {
+        return Err(format!("{} is not installed in the overlay", package));
+    }
+
+    // Remove from overlay
+    let target_args = dpkgdb::dpkg_target_args();
+    let force_args = force::essential_args();
+    let mut remove_args: Vec<&str> = target_args.iter().chain(&force_args).map(String::as_str).collect();
+    remove_args.extend(["-r", package]);
+    timing::phase("removal", || transaction::run_dpkg(&remove_args))?;
+    fault::point("removal")?;
+    filelists::remove_package_files(package)?;
+
+    // Remove from installed list
+    let mut installed = load_installed_packages()?;
+    installed.retain(|p| p != package);
+    save_installed_packages(&installed)?;
+    let mut auto = load_auto_installed()?;
+    if auto.iter().any(|p| p == package) {
+        auto.retain(|p| p != package);
+        save_auto_installed(&auto)?;
+    }
+    let mut held = load_held()?;
+    if held.iter().any(|p| p == package) {
+        held.retain(|p| p != package);
+        save_held(&held)?;
+    }
+
+    Ok(())
+}
+
+// Function removing automatically installed packages that no explicitly installed package
+// needs anymore, directly or through other dependencies
+fn autoremove() -> Result<(), String> {
+    let auto = load_auto_installed()?;
+    let layered = dpkgdb::installed_with_provides(false)?;
+    let mut needed: HashSet<String> = layered
+        .iter()
+        .map(|(name, _, _)| name.clone())
+        .filter(|name| !auto.contains(name))
+        .collect();
+    let mut pending: Vec<String> = needed.iter().cloned().collect();
+    while let Some(package) = pending.pop() {
+        for dependency in dpkgdb::dependencies(&package)? {
+            let satisfying = layered
+                .iter()
+                .filter(|(name, _, provides)| *name == dependency || provides.contains(&dependency));
+            for (name, _, _) in satisfying {
+                if needed.insert(name.clone()) {
+                    pending.push(name.clone());
+                }
+            }
+        }
+    }
+    for (name, _, _) in &layered {
+        if auto.contains(name) && !needed.contains(name) {
+            println!("Removing {}, which is no longer needed", name);
+            remove_package(name)?;
+        }
+    }
+    Ok(())
+}
+
+// Function to list installed packages with their versions
+fn list_packages() -> Result<Vec<(String, String)>, String> {
+    dpkgdb::installed_versions()
+}
+
+// Function to unpack a package's payload into a directory without installing it
+fn extract_package(package: &str, dir: &str) -> Result<(), String> {
+    refresh_indexes()?;
+    let deb_path = download_package(package, None)?;
+    std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir, e))?;
+    run_command("dpkg-deb", &["-x", &deb_path, dir])?;
+    println!("Extracted {} into {}", package, dir);
+    Ok(())
+}
+
+// Function to print the files a layered or base package owns
+fn show_files(package: &str, missing_only: bool) -> Result<(), String> {
+    let (files, root) = match filelists::load_package_files(package)? {
+        Some(files) => (files, storage::overlay_root()?),
+        None => match filelists::load_base_package_files(package)? {
+            Some(files) => (files, String::new()),
+            None => return Err(format!("{} is neither layered nor part of the base image", package)),
+        },
+    };
+    let mut missing = 0;
+    for file in &files {
+        let present = std::fs::symlink_metadata(format!("{}{}", root, file)).is_ok();
+        if !present {
+            missing += 1;
+        }
+        if !missing_only || !present {
+            println!("{}", file);
+        }
+    }
+    if missing_only && missing > 0 {
+        return Err(format!("{} of {} files are missing", missing, files.len()));
+    }
+    Ok(())
+}
+
+// Function to print which packages own a path
+fn show_owners(path: &str) -> Result<(), String> {
+    let mut candidates = vec![path.to_string()];
+    // With merged /usr, dpkg may have recorded the path through the /bin, /sbin or /lib symlinks
+    if let Ok(resolved) = std::fs::canonicalize(path) {
+        candidates.push(resolved.display().to_string());
+    }
+    let aliases: Vec<String> = candidates
+        .iter()
+        .filter_map(|candidate| candidate.strip_prefix("/usr"))
+        .filter(|rest| ["/bin/", "/sbin/", "/lib"].iter().any(|dir| rest.starts_with(dir)))
+        .map(str::to_string)
+        .collect();
+    candidates.extend(aliases);
+    candidates.dedup();
+    for candidate in &candidates {
+        let (layered, base) = filelists::find_owners(candidate);
+        if layered.is_empty() && base.is_empty() {
+            continue;
+        }
+        for package in &layered {
+            println!("{}: {} (layered)", package, candidate);
+        }
+        for package in &base {
+            println!("{}: {} (base)", package, candidate);
+        }
+        return Ok(());
+    }
+    Err(format!("No package owns {}", path))
+}
+
+// Function to report which package and transaction introduced a file or systemd unit
+fn blame(target: &str) -> Result<(), String> {
+    let paths: Vec<String> = if target.starts_with('/') {
+        vec![target.to_string()]
+    } else {
+        ["/usr/lib/systemd/system", "/lib/systemd/system", "/etc/systemd/system", "/usr/lib/systemd/user"]
+            .iter()
+            .map(|dir| format!("{}/{}", dir, target))
+            .collect()
+    };
+    let entries = history::load()?;
+    let mut found = false;
+    for path in &paths {
+        let (layered, base) = filelists::find_owners(path);
+        for package in &layered {
+            found = true;
+            let version = dpkgdb::installed_version(package)?.unwrap_or_else(|| "?".to_string());
+            println!("{}", path);
+            println!("  Package: {} {} (layered)", package, version);
+            match history::last_install_of(&entries, package) {
+                Some(entry) => {
+                    println!(
+                        "  Transaction: #{} {} at {} by {}",
+                        entry.id,
+                        entry.command,
+                        history::format_time(entry.finished),
+                        if entry.user.is_empty() { "unknown" } else { &entry.user }
+                    );
+                    if !entry.command_line.is_empty() {
+                        println!("  Command line: {}", entry.command_line);
+                    }
+                }
+                None => println!("  Transaction: not recorded (installed before history was kept)"),
+            }
+        }
+        for package in &base {
+            found = true;
+            println!("{}", path);
+            println!("  Package: {} (base image)", package);
+            if let Some(deployment) = ostree::deployments().ok().and_then(|d| d.into_iter().next()) {
+                println!("  Deployment: {}.{}", deployment.checksum, deployment.serial);
+            }
+        }
+    }
+    if !found {
+        return Err(format!("No package owns {}", target));
+    }
+    Ok(())
+}
+
+// Function to verify overlay files against the overlay dpkg database
+fn verify_packages(package: Option<&str>) -> Result<(), String> {
+    if let Some(package) = package {
+        if dpkgdb::installed_version(package)?.is_none() {
+            return Err(format!("{} is not installed in the overlay", package));
+        }
+    }
+    let problems = dpkgdb::verify(package)?;
+    if problems.is_empty() {
+        println!("All overlay files match the package database");
+        return Ok(());
+    }
+    for line in &problems {
+        println!("{}", line);
+    }
+    Err(format!("{} files differ from the package database", problems.len()))
+}
+
+// Function checking the whole system for compliance reports: the booted deployment against
+// its commit, overlay files against the package database and the stored repository
+// signatures, each passing or failing, and failing as a whole if any of them does
+fn verify_system() -> Result<(), String> {
+    let mut checks: Vec<(String, Vec<String>)> = Vec::new();
+    match ostree::current()? {
+        Some(deployment) => {
+            let name = format!("Deployment {}.{}", deployment.checksum, deployment.serial);
+            checks.push((name, ostree::verify_deployment(&deployment)?));
+        }
+        None => println!("SKIP  Deployment: not OSTree-booted"),
+    }
+    checks.push(("Overlay files".to_string(), dpkgdb::verify(None)?));
+    let signatures: Vec<String> = repos::load_repos()?
+        .iter()
+        .map(|repo| (repo.name.clone(), index::signature_status(repo)))
+        .filter(|(_, status)| status.starts_with("BAD"))
+        .map(|(name, status)| format!("{}: {}", name, status))
+        .collect();
+    checks.push(("Repository signatures".to_string(), signatures));
+
+    let mut failed = 0;
+    for (name, problems) in &checks {
+        println!("{}  {}", if problems.is_empty() { "PASS" } else { "FAIL" }, name);
+        for problem in problems {
+            println!("      {}", problem);
+        }
+        failed += usize::from(!problems.is_empty());
+    }
+    if failed > 0 {
+        return Err(format!("System verification failed: {} of {} checks failed", failed, checks.len()));
+    }
+    println!("System verification passed");
+    Ok(())
+}
+
+// Function blocking until no transaction is running or queued with the daemon, and with
+// `staged` until no staged deployment is waiting to be finalized, so provisioning scripts
+// can sequence against background updates. Fails once `timeout` seconds have passed.
+fn wait_idle(timeout: Option<u64>, staged: bool) -> Result<(), String> {
+    let started = Instant::now();
+    let mut waiting_for = String::new();
+    loop {
+        let jobs = daemon::pending_jobs();
+        let busy = if let Some(owner) = transaction::lock_owner() {
+            format!("the running transaction (pid {})", owner)
+        } else if jobs > 0 {
+            format!("{} queued daemon jobs", jobs)
+        } else if staged && ostree::deployments()?.iter().any(|deployment| deployment.staged) {
+            "the staged deployment to be finalized".to_string()
+        } else {
+            return Ok(());
+        };
+        if busy != waiting_for {
+            println!("Waiting for {}", busy);
+            waiting_for = busy;
+        }
+        if let Some(timeout) = timeout {
+            if started.elapsed() >= Duration::from_secs(timeout) {
+                return Err(format!("Timed out after {} seconds waiting for {}", timeout, waiting_for));
+            }
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+// Function listing overlay files no layered package owns and, unless `dry_run`, deleting
+// them once confirmed
+fn clean_orphans(dry_run: bool) -> Result<(), String> {
+    let orphans = filelists::find_orphans(&load_installed_packages()?, &storage::overlay_root()?)?;
+    if orphans.is_empty() {
+        return Ok(());
+    }
+    if dry_run {
+        println!("{} orphaned files (dry run, nothing removed)", orphans.len());
+        return Ok(());
+    }
+    transaction::confirm_destructive("clean-orphans", "deletes the overlay files listed above")?;
+    transaction::run("clean", &[], || filelists::remove_unowned(&orphans))?;
+    println!("Removed {} orphaned files", orphans.len());
+    Ok(())
+}
+
+// Function reporting overlay files that drifted from the package database; `fix` reinstalls
+// damaged packages and deletes files no package owns
+fn diff_overlay(fix: bool) -> Result<(), String> {
+    let installed = load_installed_packages()?;
+    let drift = drift::scan(&installed, &storage::overlay_root()?)?;
+    drift::print(&drift);
+    if drift.is_clean() {
+        return Ok(());
+    }
+    if !fix {
+        return Err("Overlay differs from the package database; run with --fix to reconcile".to_string());
+    }
+    transaction::confirm_destructive("diff-fix", "deletes the unowned files and reinstalls the packages listed above")?;
+    let damaged = drift.damaged_packages();
+    transaction::run("diff-fix", &damaged, || {
+        filelists::remove_unowned(&drift.unowned)?;
+        if !damaged.is_empty() {
+            refresh_indexes()?;
+        }
+        for package in &damaged {
+            install_package(package, None)?;
+        }
+        Ok(())
+    })?;
+    println!("Reinstalled {} packages and removed {} unowned files", damaged.len(), drift.unowned.len());
+    Ok(())
+}
+
+// Function to search packages in the index database
+fn search_package(query: &str) -> Result<String, String> {
+    let packages = index::load_all_packages()?;
+    if packages.is_empty() {
+        return Err("Package index is empty; run 'hacker-ostree update' first".to_string());
+    }
+    let languages = index::configured_languages(&config::load_config()?);
+    let translations = index::load_translations(&languages)?;
+    let query = query.to_lowercase();
+
+    let mut seen = HashSet::new();
+    let mut output = String::new();
+    for pkg in &packages {
+        let description = index::description(pkg, &translations);
+        let summary = description.lines().next().unwrap_or_default();
+        let matches = pkg.name().to_lowercase().contains(&query) || description.to_lowercase().contains(&query);
+        if matches && seen.insert(pkg.name().to_string()) {
+            output.push_str(&format!("{} - {}\n", pkg.name(), summary));
+        }
+    }
+    Ok(output)
+}
+
+// Function to show package details from the index database
+fn show_package(name: &str, fix_typo: bool) -> Result<(), String> {
+    let languages = index::configured_languages(&config::load_config()?);
+    let translations = index::load_translations(&languages)?;
+    let policy = policy::Policy::load()?;
+    let all = index::load_all_packages()?;
+    let name = &suggest::resolve(name, &all.iter().map(|pkg| pkg.name().to_string()).collect(), "not found", fix_typo, false)?;
+    let packages: Vec<index::Package> = all
+    .into_iter()
+    .filter(|pkg| pkg.name() == name)
+    .collect();
+    if let Some(candidate) = policy.candidate(name, &packages) {
+        println!("Candidate: {} from {} (priority {})\n", candidate.version(), candidate.repo, policy.priority(candidate));
+    }
+    for pkg in &packages {
+        for field in ["Package", "Version", "Architecture", "Installed-Size", "Depends", "Section"] {
+            if let Some(value) = pkg.field(field) {
+                println!("{}: {}", field, value);
+            }
+        }
+        println!("Repository: {}", pkg.repo);
+        println!("Pin-Priority: {}", policy.priority(pkg));
+        let description = index::description(pkg, &translations);
+        let mut lines = description.lines();
+        println!("Description: {}", lines.next().unwrap_or_default());
+        for line in lines {
+            println!(" {}", line);
+        }
+        println!();
+    }
+    Ok(())
+}
+
+// Function showing side by side where versions of a package come from: the base image, the
+// overlay and the newest version in each configured repo, marking the candidate
+fn compare_package(name: &str) -> Result<(), String> {
+    let base = dpkgdb::installed_with_provides(true)?
+        .into_iter()
+        .find(|(installed, _, _)| installed == name)
+        .map(|(_, version, _)| version);
+    let layered = if load_installed_packages()?.iter().any(|installed| installed == name) {
+        dpkgdb::installed_version(name)?
+    } else {
+        None
+    };
+    let policy = policy::Policy::load()?;
+    let packages: Vec<index::Package> = index::load_all_packages()?.into_iter().filter(|pkg| pkg.name() == name).collect();
+    if base.is_none() && layered.is_none() && packages.is_empty() {
+        return Err(format!("Package {} not found", name));
+    }
+    let candidate = policy.candidate(name, &packages);
+    let searched = policy.searched();
+    let width = searched.iter().map(|(repo, _)| repo.len()).max().unwrap_or(0).max("overlay".len());
+    println!("{}:", name);
+    println!("  {:width$}  {}", "base", base.as_deref().unwrap_or("-"));
+    println!("  {:width$}  {}", "overlay", layered.as_deref().unwrap_or("-"));
+    let mut candidate_shown = false;
+    for (repo, indexed) in searched {
+        let newest = packages
+            .iter()
+            .filter(|pkg| pkg.repo == repo)
+            .max_by(|a, b| version::compare_versions(a.version(), b.version()));
+        let text = match newest {
+            Some(pkg) => {
+                let chosen = candidate.is_some_and(|candidate| std::ptr::eq(candidate, pkg));
+                candidate_shown |= chosen;
+                format!("{} (priority {}){}", pkg.version(), policy.priority(pkg), if chosen { ", candidate" } else { "" })
+            }
+            None if !indexed => "- (not indexed; run 'hacker-ostree update')".to_string(),
+            None => "-".to_string(),
+        };
+        println!("  {:width$}  {}", repo, text);
+    }
+    // The candidate isn't always a repo's newest version, e.g. when pinned to an older one
+    if let Some(candidate) = candidate.filter(|_| !candidate_shown) {
+        println!("Candidate: {} from {} (priority {})", candidate.version(), candidate.repo, policy.priority(candidate));
+    }
+    Ok(())
+}
+
+// Function to upgrade all installed packages in overlay, along with any new dependencies
+fn upgrade_packages(order: &[resolve::Selection]) -> Result<(), String> {
+    install_packages(order, &[])
+}
+
+// Function to update system (OSTree pull and deploy)
+fn system_update(pull_opts: &ostree::PullOptions, allow_downgrade: bool, major: bool, unlayer: &[String]) -> Result<(), String> {
+    // Assuming OSTree remote 'origin' and ref 'main'
+    let config = config::load_config()?;
+    let booted = match booted_checksum() {
+        Ok(booted) => Some(layering::base_of(&booted)?),
+        Err(_) => None,
+    };
+    timing::phase("ostree pull", || ostree::pull("origin", "main", pull_opts, &config))?;
+    if let Some(booted) = booted {
+        let pulled = ostree::rev_parse("origin:main")?;
+        if pulled != booted {
+            changes::print_report(&booted, &pulled, &load_installed_packages()?)?;
+        }
+    }
+    deploy_and_resync(allow_downgrade, major, unlayer)
+}
+
+// Function reporting what a system update would bring from the commit metadata alone,
+// without fetching its content: version, age and message of the new commit, its package
+// changes and how much pulling it would download
+fn check_system_update(pull_opts: &ostree::PullOptions) -> Result<(), String> {
+    let config = config::load_config()?;
+    let target = pull_opts.target("main");
+    let Some(latest) = check_base_update(&target, &config)? else {
+        return Ok(());
+    };
+    let committed = ostree::commit_timestamp(&latest)?;
+    let age = (history::now() as i64 - committed).max(0) / 86400;
+    println!("Committed: {} ({} days ago)", history::format_time(committed.max(0) as u64), age);
+    let message = ostree::commit_message(&latest)?;
+    if !message.is_empty() {
+        println!("Message:");
+        for line in message {
+            println!("  {}", line);
+        }
+    }
+    match ostree::estimate_pull("origin", &target)? {
+        Some((download, unpacked)) => println!("Download size: {} ({} unpacked)", download, unpacked),
+        None => println!("Download size: unknown, the remote publishes no static delta for this update"),
+    }
+    Ok(())
+}
+
+// Function moving to the release series `series`: pulls the newest base, makes sure it is
+// of that series, runs the release migration hooks and deploys it with the overlay
+fn upgrade_release(series: &str, pull_opts: &ostree::PullOptions, unlayer: &[String]) -> Result<(), String> {
+    let config = config::load_config()?;
+    let booted = compose::CommitMetadata::load(&layering::base_of(&booted_checksum()?)?)?;
+    let current = booted.series.as_deref().unwrap_or("unknown");
+    if booted.series.as_deref() == Some(series) {
+        println!("Already on release series {}; use system-update for updates within it", series);
+        return Ok(());
+    }
+    timing::phase("ostree pull", || ostree::pull("origin", "main", pull_opts, &config))?;
+    let pulled = ostree::rev_parse("origin:main")?;
+    let target = compose::CommitMetadata::load(&pulled)?;
+    if target.series.as_deref() != Some(series) {
+        return Err(format!(
+            "The newest base image (version {}) belongs to release series {}, not {}",
+            target.version_label(),
+            target.series.as_deref().unwrap_or("unknown"),
+            series
+        ));
+    }
+    println!("Upgrading from release series {} to {} (version {})", current, series, target.version_label());
+    release::run_hooks(current, series, &pulled)?;
+    deploy_and_resync(false, true, unlayer)
+}
+
+// Function checking the configured channel for a newer release of hacker-ostree and, unless
+// only checking, installing its package like any other. Whether it takes effect now or with
+// the next deployment follows the configured layering mode.
+fn self_update(check_only: bool) -> Result<(), String> {
+    let channel = config::load_config()?
+        .self_update_channel
+        .ok_or_else(|| "No self-update-channel is configured in /etc/hacker-ostree/config.json".to_string())?;
+    let (release, verified) = selfupdate::latest(&channel)?;
+    let running = env!("CARGO_PKG_VERSION");
+    if version::compare_versions(&release.version, running) != std::cmp::Ordering::Greater {
+        println!("hacker-ostree {} is the newest release on {}", running, channel);
+        return Ok(());
+    }
+    println!("hacker-ostree {} is available (running {})", release.version, running);
+    if check_only {
+        return Ok(());
+    }
+    // Already staged for a deployment that isn't booted yet, so this binary is still the old one
+    if dpkgdb::installed_version("hacker-ostree")?.as_deref() == Some(release.version.as_str()) {
+        println!("hacker-ostree {} is already installed in the overlay", release.version);
+        return Ok(());
+    }
+    ensure_dirs()?;
+    let path = selfupdate::download(&channel, &release, verified)?;
+    transaction::run("self-update", &["hacker-ostree".to_string()], || install_deb("hacker-ostree", &path))?;
+    if layering::enabled()? {
+        println!("Staged hacker-ostree {} for the next deployment; reboot to use it", release.version);
+    } else {
+        println!("Installed hacker-ostree {} into the overlay", release.version);
+    }
+    Ok(())
+}
+
+// Function pulling the next base commit and downloading the layered packages that will be
+// reapplied on top of it, so applying the update later is just deploy and reboot
+fn prewarm(pull_opts: &ostree::PullOptions) -> Result<(), String> {
+    let config = config::load_config()?;
+    timing::phase("ostree pull", || ostree::pull("origin", "main", pull_opts, &config))?;
+    let pulled = match &pull_opts.commit {
+        Some(commit) => commit.clone(),
+        None => ostree::rev_parse("origin:main")?,
+    };
+    refresh_indexes()?;
+    let installed = load_installed_packages()?;
+    let rebuild = stale_packages(&installed, Some(pulled.clone()))?;
+    let order: Vec<resolve::Selection> = rebuild.iter().map(|(pkg, _)| (pkg.clone(), None)).collect();
+    download::debs(&order)?;
+    println!("Downloaded {} and {} of {} layered packages to reapply on it", pulled, rebuild.len(), installed.len());
+    Ok(())
+}
+
+// Function starting `system-update --download-only` detached from this process
+fn prewarm_in_background() -> Result<(), String> {
+    let exe = std::env::current_exe().map_err(|e| format!("Failed to locate executable: {}", e))?;
+    ProcessCommand::new(&exe)
+        .args(["system-update", "--download-only"])
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .map_err(|e| format!("Failed to execute {}: {}", exe.display(), e))?;
+    Ok(())
+}
+
+// Function to deploy the newest local origin:main commit. When layering into deployments
+// it becomes the base the transaction's deployment is composed on instead.
+fn deploy_base(allow_downgrade: bool, major: bool) -> Result<(), String> {
+    check_downgrade("origin:main", allow_downgrade)?;
+    check_series("origin:main", major)?;
+    if layering::enabled()? {
+        layering::set_base(&ostree::rev_parse("origin:main")?);
+        return Ok(());
+    }
+    timing::phase("ostree deploy", || ostree::deploy("origin:main"))?;
+    Ok(())
+}
+
+// Function refusing to deploy a base commit older than the booted one, unless allowed
+// explicitly or the downgrade-protection policy is turned off
+fn check_downgrade(target: &str, allow_downgrade: bool) -> Result<(), String> {
+    if allow_downgrade || !config::load_config()?.downgrade_protection {
+        return Ok(());
+    }
+    let booted = match ostree::current()? {
+        Some(deployment) => deployment.checksum,
+        None => return Ok(()),
+    };
+    let target = ostree::rev_parse(target)?;
+    if target == booted {
+        return Ok(());
+    }
+    let booted_meta = compose::CommitMetadata::load(&booted)?;
+    let target_meta = compose::CommitMetadata::load(&target)?;
+    // Compare compose versions when both commits carry one, commit dates otherwise
+    let older = match (&booted_meta.version, &target_meta.version) {
+        (Some(current), Some(candidate)) => version::compare_versions(candidate, current) == std::cmp::Ordering::Less,
+        _ => ostree::commit_timestamp(&target)? < ostree::commit_timestamp(&booted)?,
+    };
+    if older {
+        return Err(format!(
+            "Refusing to deploy {} (version {}): it is older than the booted {} (version {}); use --allow-downgrade to deploy it anyway",
+            target,
+            target_meta.version_label(),
+            booted,
+            booted_meta.version_label()
+        ));
+    }
+    Ok(())
+}
+
+// Function refusing to deploy a base commit of another release series than the booted one,
+// unless `major` is set
+fn check_series(target: &str, major: bool) -> Result<(), String> {
+    let booted = match ostree::current()? {
+        Some(deployment) => layering::base_of(&deployment.checksum)?,
+        None => return Ok(()),
+    };
+    let target = ostree::rev_parse(target)?;
+    if target == booted {
+        return Ok(());
+    }
+    release::check_series(&compose::CommitMetadata::load(&booted)?, &compose::CommitMetadata::load(&target)?, major)
+}
+
+// Function choosing, before a base update starts, the layered packages to remove because the
+// base it pulls ships them at the same or a newer version, as the unlayer-absorbed policy says.
+// Only the commit metadata is fetched here, so the question never waits inside a transaction
+fn absorbed_to_unlayer(pull_opts: &ostree::PullOptions, assume_yes: bool) -> Result<Vec<String>, String> {
+    let config = config::load_config()?;
+    if config.unlayer_absorbed == config::UnlayerPolicy::Never {
+        return Ok(Vec::new());
+    }
+    timing::phase("ostree pull", || ostree::pull_metadata("origin", &pull_opts.target("main"), &config))?;
+    let base = compose::CommitMetadata::load(&ostree::rev_parse("origin:main")?)?;
+    let absorbed = changes::absorbed_packages(&load_installed_packages()?, &base)?;
+    if absorbed.is_empty() {
+        return Ok(Vec::new());
+    }
+    println!("The new base ships these layered packages, so the overlay copies can be removed:");
+    for (name, layered, shipped) in &absorbed {
+        println!("  {} {} (base has {})", name, layered, shipped);
+    }
+    let remove = match config.unlayer_absorbed {
+        config::UnlayerPolicy::Auto => true,
+        _ => (assume_yes || std::io::stdin().is_terminal()) && transaction::confirm(assume_yes)?,
+    };
+    if !remove {
+        println!("Keeping them layered; remove them with 'hacker-ostree remove PACKAGE'");
+        return Ok(Vec::new());
+    }
+    Ok(absorbed.into_iter().map(|(name, _, _)| name).collect())
+}
+
+// Function to deploy the pulled base commit and reapply the overlay on top, without the
+// layered packages in `unlayer`
+fn deploy_and_resync(allow_downgrade: bool, major: bool, unlayer: &[String]) -> Result<(), String> {
+    deploy_base(allow_downgrade, major)?;
+    let installed = load_installed_packages()?;
+    for name in unlayer.iter().filter(|name| installed.contains(name)) {
+        remove_package(name)?;
+    }
+
+    // Resync overlay
+    resync_overlay(false)?;
+
+    Ok(())
+}
+
+// Function for unattended updates: skipped while power or network conditions say so
+// Everything is downloaded right away, but only applied inside a maintenance window
+// unless `now` is set. While someone is using the desktop the update stays staged,
+// or with `when_idle` waits until the session goes idle
+fn auto_update(now: bool, when_idle: bool) -> Result<(), String> {
+    let config = config::load_config()?;
+    if let Some(reason) = power::deferral_reason(&config) {
+        println!("Deferring automatic update: {}", reason);
+        return Ok(());
+    }
+    if !now {
+        let delay = schedule::jitter(config.auto_update_jitter_secs);
+        if delay > 0 {
+            println!("Waiting {}s, this machine's slot, before fetching the update", delay);
+            thread::sleep(Duration::from_secs(delay));
+        }
+    }
+    let in_window = schedule::in_window(&config.maintenance_windows)?;
+
+    // Stage: fetch the base commit and the overlay packages into the caches
+    let pull_opts = ostree::PullOptions { depth: config.pull_depth, commit: None };
+    prewarm(&pull_opts)?;
+    let installed = load_installed_packages()?;
+
+    if !now && !in_window {
+        let upgradable: Vec<String> = upgradable_packages(&installed)?
+            .into_iter()
+            .map(|(name, current, candidate)| format!("{} {} -> {}", name, current, candidate))
+            .collect();
+        if !upgradable.is_empty() {
+            notify::send(
+                "updates-available",
+                &format!("{} overlay updates staged", upgradable.len()),
+                &format!("Staged for the next maintenance window:\n{}", upgradable.join("\n")),
+            );
+        }
+        println!(
+            "Update staged; it will be applied in the next maintenance window ({})",
+            config.maintenance_windows.join(", ")
+        );
+        return Ok(());
+    }
+    if !now {
+        if when_idle {
+            session::wait_until_idle();
+        } else if let Some(session) = session::busy_desktop() {
+            session::notify_user(
+                &session,
+                "System update ready",
+                "An update was downloaded and will be applied once you are away, or run 'hacker-ostree auto-update --now'.",
+            );
+            println!("Update staged; {} is using the desktop, so it was not applied", session.user);
+            return Ok(());
+        }
+    }
+    let unlayer = absorbed_to_unlayer(&pull_opts, false)?;
+    transaction::run("auto-update", &installed, || deploy_and_resync(false, false, &unlayer))
+}
+
+// Function listing installed overlay packages with a newer candidate, as "name old -> new"
+fn upgradable_packages(installed: &[String]) -> Result<Vec<(String, String, String)>, String> {
+    let packages = index::load_all_packages()?;
+    let policy = policy::Policy::load()?;
+    let held = load_held()?;
+    let mut upgradable = Vec::new();
+    for name in installed.iter().filter(|name| !held.contains(name)) {
+        let (current, candidate) = match (dpkgdb::installed_version(name)?, policy.candidate(name, &packages)) {
+            (Some(current), Some(candidate)) => (current, candidate),
+            _ => continue,
+        };
+        if version::compare_versions(candidate.version(), &current) == std::cmp::Ordering::Greater {
+            upgradable.push((name.clone(), current, candidate.version().to_string()));
+        }
+    }
+    Ok(upgradable)
+}
+
+// Function converging this node to a state manifest
+fn apply_manifest(path: &str, assume_yes: bool) -> Result<(), String> {
+    let manifest = fleet::load_manifest(path)?;
+    let config = config::load_config()?;
+    let installed: Vec<String> = list_packages()?.into_iter().map(|(name, _)| name).collect();
+    let missing: Vec<String> = manifest.packages.iter().filter(|p| !installed.contains(p)).cloned().collect();
+    let unwanted: Vec<String> = manifest.remove.iter().filter(|p| installed.contains(p)).cloned().collect();
+    if !manifest.system_update && missing.is_empty() && unwanted.is_empty() {
+        println!("Already in the requested state, nothing to do");
+        return Ok(());
+    }
+    if !missing.is_empty() {
+        refresh_indexes()?;
+    }
+    let order = if missing.is_empty() {
+        Vec::new()
+    } else {
+        let missing: Vec<resolve::Selection> = missing.iter().map(|name| (name.clone(), None)).collect();
+        match confirm_install(&missing, assume_yes, false, false, resolve::Options::default())? {
+            Some(order) => order,
+            None => return Ok(()),
+        }
+    };
+    let pull_opts = ostree::PullOptions { depth: config.pull_depth, commit: None };
+    let unlayer = if manifest.system_update { absorbed_to_unlayer(&pull_opts, assume_yes)? } else { Vec::new() };
+    let mut touched: Vec<String> = order.iter().map(|(name, _)| name.clone()).collect();
+    touched.extend(unwanted.iter().cloned());
+    transaction::run("apply", &touched, || {
+        if manifest.system_update {
+            system_update(&pull_opts, false, false, &unlayer)?;
+        }
+        for package in &unwanted {
+            remove_package(package)?;
+        }
+        install_packages(&order, &missing)
+    })
+}
+
+// Function applying an offline bundle: base delta and .debs, without network access
+fn apply_bundle(file: &str, keyring: Option<&str>, allow_unsigned: bool, allow_downgrade: bool) -> Result<(), String> {
+    let (dir, info) = bundle::open(file, keyring, allow_unsigned)?;
+    let root = dir.path().display().to_string();
+    let installed: Vec<String> = list_packages()?.into_iter().map(|(name, _)| name).collect();
+    let mut touched: Vec<String> = info.debs.iter().map(|(package, _)| package.clone()).collect();
+    touched.extend(info.manifest.remove.iter().filter(|p| installed.contains(p)).cloned());
+    transaction::run("bundle-apply", &touched, || {
+        if bundle::import_base(dir.path(), &info)? {
+            deploy_base(allow_downgrade, false)?;
+        }
+        for package in info.manifest.remove.iter().filter(|p| installed.contains(p)) {
+            remove_package(package)?;
+        }
+        for (package, deb) in &info.debs {
+            install_deb(package, &format!("{}/{}", root, deb))?;
+        }
+        Ok(())
+    })
+}
+
+// Function showing deployments with their base commit, layered packages and the metadata
+// compose embedded in their commits
+fn show_status(json: bool, verbose: bool) -> Result<(), String> {
+    keys::warn_expiring();
+    let deployments = ostree::all_deployments()?;
+    let stateroots = ostree::stateroots(&deployments);
+    if json {
+        let mut listing = Vec::new();
+        for stateroot in &stateroots {
+            let deployments: Vec<&ostree::Deployment> = deployments.iter().filter(|d| &d.stateroot == stateroot).collect();
+            for (index, deployment) in deployments.iter().enumerate() {
+                let (metadata, flags, layered) = deployment_state(&deployments, index)?;
+                let layered: serde_json::Map<String, serde_json::Value> =
+                    layered.into_iter().map(|(name, version)| (name, version.into())).collect();
+                listing.push(serde_json::json!({
+                    "stateroot": stateroot,
+                    "index": index,
+                    "checksum": deployment.checksum,
+                    "serial": deployment.serial,
+                    "flags": flags,
+                    "version": metadata.version,
+                    "series": metadata.series,
+                    "base": metadata.base.as_deref().unwrap_or(&deployment.checksum),
+                    "packages": metadata.packages.len(),
+                    "layered": layered,
+                    "advisories": metadata.advisories.iter().map(|a| a.id.as_str()).collect::<Vec<_>>(),
+                    "note": notes::note(deployment)?,
+                }));
+                if verbose {
+                    let provenance = ostree::provenance(deployment);
+                    let signatures: Vec<serde_json::Value> = provenance
+                        .signatures
+                        .iter()
+                        .map(|(key, good)| serde_json::json!({"key": key, "good": good}))
+                        .collect();
+                    listing.last_mut().unwrap()["provenance"] = serde_json::json!({
+                        "refspec": provenance.refspec,
+                        "remote": provenance.remote,
+                        "url": provenance.url,
+                        "parent": provenance.parent,
+                        "content_checksum": provenance.content_checksum,
+                        "signatures": signatures,
+                        "origin": provenance.origin,
+                    });
+                }
+            }
+        }
+        let text = serde_json::to_string_pretty(&listing).map_err(|e| format!("Failed to serialize status: {}", e))?;
+        println!("{}", text);
+        return Ok(());
+    }
+    if deployments.is_empty() {
+        println!("No deployments");
+        return Ok(());
+    }
+    for stateroot in &stateroots {
+        let deployments: Vec<&ostree::Deployment> = deployments.iter().filter(|d| &d.stateroot == stateroot).collect();
+        let booted = deployments.iter().any(|d| d.booted);
+        // Only worth a heading when there is more than one OS to tell apart
+        if stateroots.len() > 1 {
+            println!("Stateroot {}{}:", stateroot, if booted { " (booted)" } else { "" });
+        }
+        show_deployments(&deployments, verbose)?;
+    }
+    Ok(())
+}
+
+// A deployment's compose metadata, status flags and layered packages
+type DeploymentState = (compose::CommitMetadata, Vec<&'static str>, Vec<(String, String)>);
+
+// Function returning the state of a deployment; its layered packages are the live overlay's
+// for the booted deployment when layering live
+fn deployment_state(deployments: &[&ostree::Deployment], index: usize) -> Result<DeploymentState, String> {
+    let deployment = deployments[index];
+    let metadata = compose::CommitMetadata::load(&deployment.checksum)?;
+    let booted_index = deployments.iter().position(|deployment| deployment.booted);
+    let mut flags = Vec::new();
+    if deployment.booted {
+        flags.push("booted");
+    }
+    // Deployments listed above the booted one take effect on the next boot
+    if booted_index.is_some_and(|booted| index < booted) {
+        flags.push(if index == 0 { "pending" } else { "staged" });
+    }
+    if deployment.pinned {
+        flags.push("pinned");
+    }
+    let layered = if deployment.booted && config::load_config()?.layering == config::Layering::Live {
+        flags.push("live");
+        dpkgdb::installed_versions()?
+    } else {
+        metadata.layered.iter().map(|(name, version)| (name.clone(), version.clone())).collect()
+    };
+    Ok((metadata, flags, layered))
+}
+
+// Function printing the deployments of one stateroot, numbered as --stateroot operations see them
+fn show_deployments(deployments: &[&ostree::Deployment], verbose: bool) -> Result<(), String> {
+    for (index, deployment) in deployments.iter().enumerate() {
+        let (metadata, mut flags, layered) = deployment_state(deployments, index)?;
+        let live = flags.contains(&"live");
+        flags.retain(|flag| *flag != "live");
+        let flags = if flags.is_empty() { String::new() } else { format!(" ({})", flags.join(", ")) };
+        println!("{} {}: {}.{}{}", if deployment.booted { "*" } else { " " }, index, deployment.checksum, deployment.serial, flags);
+        println!("    Version: {}", metadata.version_label());
+        if let Some(note) = notes::note(deployment)? {
+            println!("    Note: {}", note);
+        }
+        println!("    Base: {}", metadata.base.as_deref().unwrap_or(&deployment.checksum));
+        if !metadata.packages.is_empty() {
+            println!("    Packages: {}", metadata.packages.len());
+        }
+        let layered: Vec<String> = layered.iter().map(|(name, version)| format!("{} {}", name, version)).collect();
+        let label = if live { "Layered (live overlay)" } else { "Layered" };
+        if layered.is_empty() {
+            println!("    {}: none", label);
+        } else {
+            println!("    {}: {}", label, layered.join(", "));
+        }
+        for advisory in &metadata.advisories {
+            println!("    Advisory: {} ({} {})", advisory.id, advisory.package, advisory.fixed_version);
+        }
+        if verbose {
+            show_provenance(&ostree::provenance(deployment));
+        }
+    }
+    Ok(())
+}
+
+// Function printing where a deployment's commit came from, for status --verbose
+fn show_provenance(provenance: &ostree::Provenance) {
+    let unknown = |value: &Option<String>| value.clone().unwrap_or_else(|| "unknown".to_string());
+    println!("    Refspec: {}", unknown(&provenance.refspec));
+    match (&provenance.remote, &provenance.url) {
+        (Some(remote), Some(url)) => println!("    Remote: {} ({})", remote, url),
+        (remote, _) => println!("    Remote: {}", unknown(remote)),
+    }
+    println!("    Parent: {}", provenance.parent.as_deref().unwrap_or("none in the repository"));
+    println!("    Content checksum: {}", unknown(&provenance.content_checksum));
+    if provenance.signatures.is_empty() {
+        println!("    Signatures: none verified");
+    }
+    for (key, good) in &provenance.signatures {
+        println!("    Signed by: {} ({})", key, if *good { "good signature" } else { "not verified" });
+    }
+    if let Some(origin) = &provenance.origin {
+        println!("    Origin file:");
+        for line in origin.lines().filter(|line| !line.trim().is_empty()) {
+            println!("      {}", line);
+        }
+    }
+}
+
+// Function returning the checksum of the booted deployment, or the default one of a targeted
+// stateroot that isn't booted
+fn booted_checksum() -> Result<String, String> {
+    ostree::current()?
+        .map(|deployment| deployment.checksum)
+        .ok_or_else(|| "No booted deployment found".to_string())
+}
+
+// Function comparing the package manifests of two base commits
+// (the booted deployment and the pulled origin:main by default)
+fn diff_commits(from: Option<&str>, to: Option<&str>) -> Result<(), String> {
+    let from = match from {
+        Some(rev) => ostree::rev_parse(rev)?,
+        None => booted_checksum()?,
+    };
+    let to = ostree::rev_parse(to.unwrap_or("origin:main"))?;
+    println!("Comparing {} -> {}", from, to);
+    compose::print_diff(&compose::CommitMetadata::load(&from)?, &compose::CommitMetadata::load(&to)?);
+    Ok(())
+}
+
+// Function pulling the metadata of `target` on origin and comparing it with the booted base:
+// reports that the base is up to date, or the update and the package changes it brings and
+// returns the new commit
+fn check_base_update(target: &str, config: &config::Config) -> Result<Option<String>, String> {
+    timing::phase("ostree pull", || ostree::pull_metadata("origin", target, config))?;
+    let booted = layering::base_of(&booted_checksum()?)?;
+    let latest = ostree::rev_parse("origin:main")?;
+    if booted == latest {
+        let metadata = compose::CommitMetadata::load(&booted)?;
+        println!("Base image is up to date (version {})", metadata.version_label());
+        return Ok(None);
+    }
+    println!("Base image update available: {} -> {}", booted, latest);
+    compose::print_diff(&compose::CommitMetadata::load(&booted)?, &compose::CommitMetadata::load(&latest)?);
+    Ok(Some(latest))
+}
+
+// Function reporting available base and overlay updates without applying anything
+fn check_update() -> Result<(), String> {
+    let config = config::load_config()?;
+    if check_base_update("main", &config)?.is_some() && config.prewarm_updates {
+        prewarm_in_background()?;
+        println!("Downloading it in the background");
+    }
+
+    refresh_indexes()?;
+    let upgradable = upgradable_packages(&load_installed_packages()?)?;
+    if upgradable.is_empty() {
+        println!("Overlay packages are up to date");
+    } else {
+        println!("Overlay updates:");
+        for (name, current, candidate) in &upgradable {
+            println!("  {} {} -> {}", name, current, candidate);
+        }
+    }
+    keys::warn_expiring();
+    let (_, token, updates) = summary_lines()?;
+    save_status(&token, updates)
 }
 
-// Function to remove a package
-fn remove_package(package: &str) -> Result<(), String> {
-    // Remove from overlay
-    let remove_args = vec![
-        "--instdir",
-        OVERLAY_DIR,
-        "--force-not-root",
-        "-r",
-        package,
-    ];
-    run_command("dpkg", &remove_args)?;
+// Function summarizing how current the system is from local state only, without pulling or
+// refreshing anything: image version, updates already known to be available, whether a
+// reboot is pending, and when it was last updated. Also returns the matching prompt token
+// and the number of updates available.
+fn summary_lines() -> Result<(Vec<String>, String, usize), String> {
+    let deployments = ostree::deployments()?;
+    let booted_index = deployments.iter().position(|d| d.booted).unwrap_or(0);
+    let booted = deployments.get(booted_index).ok_or("No deployments found")?;
+    let base = layering::base_of(&booted.checksum)?;
+    let version = compose::CommitMetadata::load(&base)?.version;
+    let mut lines = vec![format!(
+        "HackerOS {} ({})",
+        version.as_deref().unwrap_or("unknown version"),
+        &booted.checksum[..booted.checksum.len().min(12)]
+    )];
+    let mut updates = 0;
 
-    // Remove from installed list
-    let mut installed = load_installed_packages()?;
-    installed.retain(|p| p != package);
-    save_installed_packages(&installed)?;
+    // origin:main is whatever the last check-update or update timer pulled
+    match ostree::rev_parse("origin:main") {
+        Ok(latest) if latest != base => {
+            let latest_version = compose::CommitMetadata::load(&latest)?.version;
+            lines.push(format!("Base image update available: {}", latest_version.as_deref().unwrap_or(&latest)));
+            updates += 1;
+        }
+        Ok(_) => lines.push("Base image is up to date".to_string()),
+        Err(_) => lines.push("Base image updates unknown; run 'hacker-ostree check-update'".to_string()),
+    }
+    let installed = load_installed_packages()?;
+    if !installed.is_empty() {
+        let overlay = match upgradable_packages(&installed) {
+            Ok(upgradable) if upgradable.is_empty() => "up to date".to_string(),
+            Ok(upgradable) => {
+                updates += upgradable.len();
+                format!("{} update{} available", upgradable.len(), if upgradable.len() == 1 { "" } else { "s" })
+            }
+            Err(_) => "updates unknown".to_string(),
+        };
+        lines.push(format!("Layered packages: {}, {}", installed.len(), overlay));
+    }
+    let reboot = booted_index > 0;
+    if reboot {
+        lines.push("Reboot required: a new deployment is staged".to_string());
+    }
+    let last = history::load()?
+        .into_iter()
+        .rev()
+        .find(|entry| entry.success && ["upgrade", "system-update", "auto-update"].contains(&entry.command.as_str()));
+    lines.push(match last {
+        Some(entry) => format!("Last updated: {} ({})", history::format_time(entry.finished), entry.command),
+        None => "Last updated: never".to_string(),
+    });
 
-    Ok(())
+    let mut token = String::new();
+    if updates > 0 {
+        token.push_str(&format!("\u{2b06}{}", updates));
+    }
+    if reboot {
+        token.push('\u{27f2}');
+    }
+    Ok((lines, token, updates))
 }
 
-// Function to list installed packages
-fn list_packages() -> Result<Vec<String>, String> {
-    load_installed_packages()
+// Function caching the prompt token for prompt-status and the number of updates for the
+// status file
+fn save_status(token: &str, updates: usize) -> Result<(), String> {
+    statusfile::update(|status| status.updates = Some(updates));
+    if let Some(parent) = Path::new(PROMPT_STATUS_FILE).parent() {
+        create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    std::fs::write(PROMPT_STATUS_FILE, token).map_err(|e| format!("Failed to write {}: {}", PROMPT_STATUS_FILE, e))
 }
 
-// Function to search packages in APT
-fn search_package(query: &str) -> Result<String, String> {
-    let temp_sources = create_temp_sources_list()?;
-    let sources_path = temp_sources.path().to_str().ok_or_else(|| "Failed to get temp file path".to_string())?;
-    let source_list = format!("Dir::Etc::SourceList={}", sources_path);
-
-    let search_args = vec![
-        "search",
-        "-o", &source_list,
-        "-o", "Dir::Etc::SourceParts=-",
-        query,
-    ];
-    run_command("apt-cache", &search_args)
-}
-
-// Function to upgrade all installed packages in overlay
-fn upgrade_packages() -> Result<(), String> {
-    apt_update()?;
-    let installed = load_installed_packages()?;
-    for pkg in installed {
-        install_package(&pkg)?;
+// Function refreshing the cached prompt token and update count once a transaction or a
+// rollback changed what is pending; on failure the previous token stays
+fn refresh_status() {
+    if let Err(e) = summary_lines().and_then(|(_, token, updates)| save_status(&token, updates)) {
+        eprintln!("Warning: failed to refresh the prompt status: {}", e);
     }
-    Ok(())
 }
 
-// Function to update system (OSTree pull and deploy)
-fn system_update() -> Result<(), String> {
-    // Assuming OSTree remote 'origin' and ref 'main'
-    run_command("ostree", &["pull", "origin", "main"])?;
-
-    // Deploy the new commit
-    run_command("ostree", &["admin", "deploy", "origin:main"])?;
+// Function printing the summary, or writing it as the login message fragment
+fn summary(motd: bool) -> Result<(), String> {
+    let (lines, token, updates) = summary_lines()?;
+    save_status(&token, updates)?;
+    let text = lines.join("\n") + "\n";
+    if !motd {
+        print!("{}", text);
+        return Ok(());
+    }
+    if let Some(parent) = Path::new(MOTD_FILE).parent() {
+        create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    std::fs::write(MOTD_FILE, text).map_err(|e| format!("Failed to write {}: {}", MOTD_FILE, e))
+}
 
-    // Resync overlay
-    resync_overlay()?;
+// Function printing the cached prompt token: pending updates and a needed reboot, or
+// nothing. It only reads one file, so a prompt can call it on every line.
+fn prompt_status() {
+    if let Ok(token) = std::fs::read_to_string(PROMPT_STATUS_FILE) {
+        println!("{}", token);
+    }
+}
 
-    Ok(())
+// Function reverting the package changes of a transaction: packages it added are removed, and
+// those it removed or replaced are installed again at the versions it found
+fn undo(id: Option<u64>, assume_yes: bool) -> Result<(), String> {
+    let entries = history::load()?;
+    let entry = match id {
+        Some(id) => entries.iter().find(|entry| entry.id == id).ok_or_else(|| format!("No transaction {} in the history", id))?,
+        None => entries
+            .iter()
+            .rev()
+            .find(|entry| entry.success && (!entry.added.is_empty() || !entry.removed.is_empty()))
+            .ok_or("No transaction in the history changed any packages")?,
+    };
+    if !entry.success {
+        return Err(format!("Transaction {} failed and was rolled back; there is nothing to undo", entry.id));
+    }
+    if entry.added.is_empty() && entry.removed.is_empty() {
+        return Err(format!("Transaction {} recorded no package changes to undo", entry.id));
+    }
+    let current: std::collections::BTreeMap<String, String> = list_packages()?.into_iter().collect();
+    let unwanted: Vec<String> = entry
+        .added
+        .keys()
+        .filter(|name| !entry.removed.contains_key(*name) && current.contains_key(*name))
+        .cloned()
+        .collect();
+    let restore: Vec<resolve::Selection> = entry
+        .removed
+        .iter()
+        .filter(|(name, version)| current.get(*name) != Some(*version))
+        .map(|(name, version)| (name.clone(), Some(version.clone())))
+        .collect();
+    if unwanted.is_empty() && restore.is_empty() {
+        println!("The changes of transaction {} ({}) are already undone", entry.id, entry.command);
+        return Ok(());
+    }
+    println!("Undoing transaction {} ({}, {}):", entry.id, entry.command, history::format_time(entry.started));
+    for name in &unwanted {
+        println!("  remove {} {}", name, current[name]);
+    }
+    for (name, version) in &restore {
+        let from = current.get(name).map(|v| format!(" (now {})", v)).unwrap_or_default();
+        println!("  install {} {}{}", name, version.as_deref().unwrap_or_default(), from);
+    }
+    if !transaction::confirm(assume_yes)? {
+        return Ok(());
+    }
+    if !restore.is_empty() {
+        refresh_indexes()?;
+    }
+    let mut touched = unwanted.clone();
+    touched.extend(restore.iter().map(|(name, _)| name.clone()));
+    // Packages put back from scratch count as asked for; replaced ones keep their marking
+    let manual: Vec<String> = restore.iter().map(|(name, _)| name.clone()).filter(|name| !current.contains_key(name)).collect();
+    transaction::run("undo", &touched, || {
+        for name in &unwanted {
+            remove_package(name)?;
+        }
+        if !restore.is_empty() {
+            install_packages(&restore, &manual)?;
+        }
+        Ok(())
+    })
 }
 
 // Function to rollback
 fn rollback() -> Result<(), String> {
-    run_command("ostree", &["admin", "undeploy", "0"])?;
+    transaction::confirm_destructive("rollback", "makes the previous deployment the default again")?;
+    let _lock = transaction::Lock::acquire()?;
+    if layering::enabled()? {
+        let target = layering::rollback()?;
+        println!("{} is the default deployment again; reboot to use it", target);
+        notify::send("rollback", "rolled back to the previous deployment", &format!("Deployment {} was made the default with 'hacker-ostree rollback'.", target));
+        statusfile::update(|_| {});
+        refresh_status();
+        return Ok(());
+    }
+    ostree::undeploy(0)?;
+    statusfile::update(|_| {});
+    refresh_status();
+    notify::send("rollback", "rolled back to the previous deployment", "The newest deployment was removed with 'hacker-ostree rollback'.");
+    Ok(())
+}
+
+// Function attaching a note to a deployment of the targeted stateroot, or removing it
+fn set_note(index: usize, note: Option<&str>) -> Result<(), String> {
+    let deployments = ostree::deployments()?;
+    let deployment = deployments
+        .get(index)
+        .ok_or_else(|| format!("No deployment {}; 'hacker-ostree status' lists them", index))?;
+    notes::set(deployment, note)?;
+    match note {
+        Some(_) => println!("Noted deployment {} ({}.{})", index, deployment.checksum, deployment.serial),
+        None => println!("Removed the note of deployment {} ({}.{})", index, deployment.checksum, deployment.serial),
+    }
     Ok(())
 }
 
-// Function to resync overlay after rootfs update
-fn resync_overlay() -> Result<(), String> {
+// Function to resync overlay after rootfs update, reapplying only the packages that need it
+// unless `full` is set
+fn resync_overlay(full: bool) -> Result<(), String> {
+    refresh_indexes()?;
     let installed = load_installed_packages()?;
+    let stale = if full {
+        installed.iter().map(|pkg| (pkg.clone(), "full resync requested".to_string())).collect()
+    } else {
+        stale_packages(&installed, layering::target_base()?)?
+    };
+    println!("Reapplying {} of {} layered packages", stale.len(), installed.len());
+    for (pkg, reason) in &stale {
+        println!("  {}: {}", pkg, reason);
+    }
+    let order: Vec<resolve::Selection> = stale.into_iter().map(|(pkg, _)| (pkg, None)).collect();
+    install_packages(&order, &[])
+}
+
+// Function deciding which layered packages have to be reapplied on `new_base`, with the reason
+// for each: a newer candidate, missing overlay content, or a base change touching their files
+// or dependencies
+fn stale_packages(installed: &[String], new_base: Option<String>) -> Result<Vec<(String, String)>, String> {
+    // Compare base commits: layered deployments also contain the overlay itself
+    let booted = match ostree::current()? {
+        Some(deployment) => Some(layering::base_of(&deployment.checksum)?),
+        None => None,
+    };
+    let pending = new_base;
+    let mut changed_packages = HashSet::new();
+    let mut changed_files = HashSet::new();
+    if let (Some(old), Some(new)) = (booted, pending) {
+        if old != new {
+            let old_meta = compose::CommitMetadata::load(&old)?;
+            let new_meta = compose::CommitMetadata::load(&new)?;
+            if old_meta.packages.is_empty() || new_meta.packages.is_empty() {
+                // Without both manifests there is no telling what the base change affected
+                return Ok(installed.iter().map(|pkg| (pkg.clone(), "base manifest unavailable".to_string())).collect());
+            }
+            for (name, version) in &new_meta.packages {
+                if old_meta.packages.get(name) != Some(version) {
+                    changed_packages.insert(name.clone());
+                }
+            }
+            changed_packages.extend(old_meta.packages.keys().filter(|name| !new_meta.packages.contains_key(*name)).cloned());
+            changed_files.extend(ostree::diff_paths(&old, &new)?.into_iter().map(|(_, path)| path));
+        }
+    }
+
+    let packages = index::load_all_packages()?;
+    let policy = policy::Policy::load()?;
+    let root = storage::overlay_root()?;
+    let mut stale = Vec::new();
     for pkg in installed {
-        install_package(&pkg)?;
+        let current = dpkgdb::installed_version(pkg)?;
+        let candidate = policy.candidate(pkg, &packages).map(|candidate| candidate.version().to_string());
+        let files = filelists::load_package_files(pkg)?;
+        let reason = if current.is_none() {
+            Some("not in the overlay database".to_string())
+        } else if candidate.is_some() && candidate != current {
+            Some(format!("{} -> {}", current.unwrap_or_default(), candidate.unwrap_or_default()))
+        } else if let Some(dependency) = dpkgdb::dependencies(pkg)?.into_iter().find(|d| changed_packages.contains(d)) {
+            Some(format!("dependency {} changed in the base", dependency))
+        } else {
+            match files {
+                None => Some("no file list recorded".to_string()),
+                Some(files) => files
+                    .iter()
+                    .find(|file| changed_files.contains(*file) && !Path::new(&format!("{}{}", root, file)).is_dir())
+                    .map(|file| format!("base changed {}", file))
+                    .or_else(|| {
+                        files
+                            .iter()
+                            .find(|file| std::fs::symlink_metadata(format!("{}{}", root, file)).is_err())
+                            .map(|file| format!("{} is missing from the overlay", file))
+                    }),
+            }
+        };
+        if let Some(reason) = reason {
+            stale.push((pkg.clone(), reason));
+        }
     }
-    Ok(())
+    Ok(stale)
 }
 
 // Load installed packages from file
@@ -242,73 +1752,885 @@ fn save_installed_packages(packages: &[String]) -> Result<(), String> {
     Ok(())
 }
 
+// Load the packages installed only as dependencies
+fn load_auto_installed() -> Result<Vec<String>, String> {
+    match std::fs::read_to_string(AUTO_INSTALLED_FILE) {
+        Ok(text) => Ok(text.lines().map(str::trim).filter(|l| !l.is_empty()).map(str::to_string).collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(format!("Failed to read {}: {}", AUTO_INSTALLED_FILE, e)),
+    }
+}
+
+fn save_auto_installed(packages: &[String]) -> Result<(), String> {
+    let mut text = packages.join("\n");
+    text.push('\n');
+    std::fs::write(AUTO_INSTALLED_FILE, text).map_err(|e| format!("Failed to write {}: {}", AUTO_INSTALLED_FILE, e))
+}
+
+fn load_held() -> Result<Vec<String>, String> {
+    match std::fs::read_to_string(HELD_FILE) {
+        Ok(text) => Ok(text.lines().map(str::trim).filter(|l| !l.is_empty()).map(str::to_string).collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(format!("Failed to read {}: {}", HELD_FILE, e)),
+    }
+}
+
+fn save_held(packages: &[String]) -> Result<(), String> {
+    let mut text = packages.join("\n");
+    text.push('\n');
+    std::fs::write(HELD_FILE, text).map_err(|e| format!("Failed to write {}: {}", HELD_FILE, e))
+}
+
+// Function expanding package names and glob patterns such as 'libfoo-*' against `known`,
+// packages `set` describes. What patterns matched is shown and confirmed before going on;
+// plain names that aren't known get suggestions instead.
+fn select_packages(patterns: &[String], known: &BTreeSet<String>, set: &str, fix_typo: bool, assume_yes: bool) -> Result<Vec<String>, String> {
+    let mut selected: Vec<String> = Vec::new();
+    let mut expanded = false;
+    for pattern in patterns {
+        if !pattern.contains(['*', '?']) {
+            let name = suggest::resolve(pattern, known, &format!("is not {}", set), fix_typo, assume_yes)?;
+            if !selected.contains(&name) {
+                selected.push(name);
+            }
+            continue;
+        }
+        let matched: Vec<&String> = known.iter().filter(|name| policy::glob_match(pattern, name)).collect();
+        if matched.is_empty() {
+            return Err(format!("No package {} matches {}", set, pattern));
+        }
+        println!("{} matches: {}", pattern, matched.iter().map(|name| name.as_str()).collect::<Vec<_>>().join(", "));
+        for name in matched {
+            if !selected.contains(name) {
+                selected.push(name.clone());
+            }
+        }
+        expanded = true;
+    }
+    if expanded && !transaction::confirm(assume_yes)? {
+        return Err("Aborted".to_string());
+    }
+    Ok(selected)
+}
+
+// Function holding layered packages at their installed version, or listing the held ones
+// without patterns
+fn hold_packages(patterns: &[String], assume_yes: bool) -> Result<(), String> {
+    let mut held = load_held()?;
+    if patterns.is_empty() {
+        if held.is_empty() {
+            println!("No packages are held");
+        }
+        for name in &held {
+            println!("{}", name);
+        }
+        return Ok(());
+    }
+    let installed: BTreeSet<String> = load_installed_packages()?.into_iter().collect();
+    for name in select_packages(patterns, &installed, "installed in the overlay", false, assume_yes)? {
+        if held.contains(&name) {
+            println!("{} is already held", name);
+        } else {
+            println!("Holding {}", name);
+            held.push(name);
+        }
+    }
+    held.sort();
+    save_held(&held)
+}
+
+// Function releasing held packages so upgrade updates them again
+fn unhold_packages(patterns: &[String], assume_yes: bool) -> Result<(), String> {
+    let mut held = load_held()?;
+    let known: BTreeSet<String> = held.iter().cloned().collect();
+    for name in select_packages(patterns, &known, "held", false, assume_yes)? {
+        println!("Releasing {}", name);
+        held.retain(|held| *held != name);
+    }
+    save_held(&held)
+}
+
 // Function to clean cache
 fn clean_cache() -> Result<(), String> {
-    run_command("rm", &["-rf", &format!("{}/archives/*", CACHE_DIR)])?;
+    let archives = format!("{}/archives", CACHE_DIR);
+    let entries = match std::fs::read_dir(&archives) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(()),
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let removed = if path.is_dir() { std::fs::remove_dir_all(&path) } else { std::fs::remove_file(&path) };
+        removed.map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+    }
     Ok(())
 }
 
 // Function to add repo
-fn add_repo(repo_line: &str) -> Result<(), String> {
-    let mut repos = load_repos()?;
-    repos.push(repo_line.to_string());
-    save_repos(&repos)?;
+fn add_repo(repo_line: &str, name: Option<&str>, suite: Option<&str>) -> Result<(), String> {
+    let mut repos = repos::load_repos()?;
+    let mut repo = match repos::expand_shorthand(repo_line, suite)? {
+        Some(repo) => repo,
+        None => repos::parse_line(repo_line, name)?,
+    };
+    if let Some(name) = name {
+        repo.name = name.to_string();
+    }
+    if name.is_some() && repos.iter().any(|r| r.name == repo.name) {
+        return Err(format!("A repository named {} already exists", repo.name));
+    }
+    repo.name = repos::unique_name(&repos, &repo.name);
+    repo.validate().map_err(|e| e.to_string())?;
+    println!("Added repository {}", repo.name);
+    repos.push(repo);
+    repos::save_repos(&repos)?;
     Ok(())
 }
 
 // Function to remove repo
-fn remove_repo(index: usize) -> Result<(), String> {
-    let mut repos = load_repos()?;
-    if index < repos.len() {
-        repos.remove(index);
-        save_repos(&repos)?;
-        Ok(())
+fn remove_repo(selector: &str) -> Result<(), String> {
+    let mut repos = repos::load_repos()?;
+    let index = repos::find_repo(&repos, selector)?;
+    let removed = repos.remove(index);
+    repos::save_repos(&repos)?;
+    // Drop its cached metadata so it no longer contributes candidates
+    index::remove_repo_index(&removed);
+    Ok(())
+}
+
+// Function to show a repo's configuration
+fn show_repo(selector: &str) -> Result<(), String> {
+    let repos = repos::load_repos()?;
+    let repo = &repos[repos::find_repo(&repos, selector)?];
+    println!("Name: {}", repo.name);
+    println!("Type: {}", repo.kind);
+    println!("URI: {}", repo.uri);
+    if let Some(snapshot) = &repo.snapshot {
+        println!("Snapshot: {} (fetched from {})", snapshot, repos::snapshot_uri(&repo.uri, snapshot)?);
+    }
+    println!("Suite: {}", repo.suite);
+    println!("Components: {}", repo.components.join(" "));
+    if !repo.options.is_empty() {
+        println!("Options: {}", repo.options.join(" "));
+    }
+    if !repo.mirrors.is_empty() {
+        let health = mirrors::load_health();
+        println!("Mirrors (in order of preference):");
+        for uri in mirrors::ordered_uris(repo) {
+            match health.get(&uri) {
+                Some(h) if h.failures > 0 => println!("  {} ({} recent failures)", uri, h.failures),
+                _ => println!("  {}", uri),
+            }
+        }
+    }
+    println!("Source line: {}", repo.to_line());
+
+    let record = match index::load_record(repo)? {
+        Some(record) => record,
+        None => {
+            println!("Metadata: not refreshed yet; run 'hacker-ostree update'");
+            return Ok(());
+        }
+    };
+    println!("Metadata:");
+    for (label, value) in [
+        ("Origin", &record.origin),
+        ("Label", &record.label),
+        ("Suite", &record.suite),
+        ("Codename", &record.codename),
+        ("Date", &record.date),
+    ] {
+        if let Some(value) = value {
+            println!("  {}: {}", label, value);
+        }
+    }
+    if let Some(valid_until) = &record.valid_until {
+        let expired = run_command("date", &["-d", valid_until, "+%s"])
+            .ok()
+            .and_then(|secs| secs.trim().parse::<u64>().ok())
+            .is_some_and(|secs| secs < history::now());
+        println!("  Valid-Until: {}{}", valid_until, if expired { " (EXPIRED)" } else { "" });
+    }
+    if record.not_automatic {
+        println!("  NotAutomatic: yes{}", if record.but_automatic_upgrades { " (ButAutomaticUpgrades)" } else { "" });
+    }
+    let health = mirrors::load_health();
+    let last_success = mirrors::ordered_uris(repo)
+        .iter()
+        .filter_map(|uri| health.get(uri).map(|h| h.last_success))
+        .max()
+        .unwrap_or(0)
+        .max(record.fetched_at);
+    println!("  Last successful refresh: {}", history::format_time(last_success));
+    println!("  Indexes last changed: {}", history::format_time(record.fetched_at));
+    println!("  Signature: {}", index::signature_status(repo));
+    println!("  Packages: {}", index::load_packages(repo)?.len());
+    Ok(())
+}
+
+// Function to add or remove a mirror of a repo
+fn edit_repo_mirror(selector: &str, uri: &str, add: bool) -> Result<(), String> {
+    let mut repos = repos::load_repos()?;
+    let index = repos::find_repo(&repos, selector)?;
+    let repo = &mut repos[index];
+    if add {
+        if repo.uri == uri || repo.mirrors.iter().any(|m| m == uri) {
+            return Err(format!("{} is already a mirror of {}", uri, repo.name));
+        }
+        repo.mirrors.push(uri.to_string());
+        repo.validate().map_err(|e| e.to_string())?;
     } else {
-        Err("Invalid index".to_string())
+        let before = repo.mirrors.len();
+        repo.mirrors.retain(|m| m != uri);
+        if repo.mirrors.len() == before {
+            return Err(format!("{} is not a mirror of {}", uri, repo.name));
+        }
+    }
+    repos::save_repos(&repos)
+}
+
+// Function measuring the mirrors of repositories (all of them without a selector) and, with
+// `apply`, making the fastest one each repository's preferred URI
+fn benchmark_repos(selector: Option<&str>, apply: bool, max_age_days: Option<u64>) -> Result<(), String> {
+    let mut repos = repos::load_repos()?;
+    let indexes: Vec<usize> = match selector {
+        Some(selector) => vec![repos::find_repo(&repos, selector)?],
+        None => (0..repos.len()).collect(),
+    };
+    for index in indexes {
+        let repo = &mut repos[index];
+        let results = mirrors::benchmark(repo, max_age_days.map(|days| days * 86400))?;
+        println!("{}:", repo.name);
+        for (uri, result) in &results {
+            match result {
+                Ok(b) => println!(
+                    "  {}: {} ms latency, {}/s (measured {})",
+                    uri,
+                    b.latency_ms,
+                    transaction::format_size(b.throughput),
+                    history::format_time(b.measured_at)
+                ),
+                Err(e) => println!("  {}: unreachable ({})", uri, e.trim().lines().last().unwrap_or_default()),
+            }
+        }
+        if !apply || repo.mirrors.is_empty() {
+            continue;
+        }
+        if repo.snapshot.is_some() {
+            println!("  Frozen to a snapshot; mirror order left unchanged");
+            continue;
+        }
+        // Stable, so equally fast mirrors keep their configured order
+        let mut ranked: Vec<(String, u64)> = results
+            .into_iter()
+            .map(|(uri, result)| (uri, result.map(|b| b.throughput).unwrap_or(0)))
+            .collect();
+        ranked.sort_by_key(|(_, throughput)| std::cmp::Reverse(*throughput));
+        let mut uris = ranked.into_iter().map(|(uri, _)| uri);
+        if let Some(fastest) = uris.next() {
+            repo.uri = fastest;
+            repo.mirrors = uris.collect();
+            println!("  Preferred: {}", repo.uri);
+        }
+    }
+    if apply {
+        repos::save_repos(&repos)?;
+    }
+    Ok(())
+}
+
+// Function pinning repositories to a snapshot timestamp (all of them without a selector),
+// or unpinning them when `timestamp` is None
+fn set_repo_snapshot(selector: Option<&str>, timestamp: Option<&str>) -> Result<(), String> {
+    let mut repos = repos::load_repos()?;
+    let indexes: Vec<usize> = match selector {
+        Some(selector) => vec![repos::find_repo(&repos, selector)?],
+        None => (0..repos.len()).collect(),
+    };
+    if let Some(timestamp) = timestamp {
+        if !repos::is_snapshot_timestamp(timestamp) {
+            return Err(format!("Invalid snapshot timestamp {}: expected something like 20240101T000000Z", timestamp));
+        }
+    }
+    for index in indexes {
+        let repo = &mut repos[index];
+        match timestamp {
+            Some(timestamp) => {
+                // Fails for repos without a known snapshot service
+                repos::snapshot_uri(&repo.uri, timestamp)?;
+                repo.snapshot = Some(timestamp.to_string());
+                println!("Froze {} at {}", repo.name, timestamp);
+            }
+            None => {
+                if repo.snapshot.take().is_some() {
+                    println!("Thawed {}", repo.name);
+                }
+            }
+        }
+        repo.validate().map_err(|e| e.to_string())?;
     }
+    repos::save_repos(&repos)
 }
 
 // Function to list repos
-fn list_repos() -> Result<Vec<String>, String> {
-    load_repos()
+fn list_repos() -> Result<Vec<repos::Repo>, String> {
+    repos::load_repos()
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let matches = Command::new("hacker-ostree")
+// Command line definition, shared by argument parsing and shell completion
+fn build_cli() -> Command {
+    Command::new("hacker-ostree")
     .version("0.3.0")
     .author("Your Name")
     .about("Custom package manager for atomic systems with APT overlay")
+    .arg(Arg::new("timing")
+    .long("timing")
+    .global(true)
+    .action(ArgAction::SetTrue)
+    .help("Print how long each phase took"))
+    .arg(Arg::new("host")
+    .long("host")
+    .value_name("USER@MACHINE")
+    .global(true)
+    .help("Run the command on another node over SSH"))
+    .arg(Arg::new("stateroot")
+    .long("stateroot")
+    .value_name("NAME")
+    .global(true)
+    .help("Operate on another OS installed side by side (an OSTree stateroot) instead of the booted one"))
+    .arg(Arg::new("no-attach")
+    .long("no-attach")
+    .global(true)
+    .action(ArgAction::SetTrue)
+    .help("Fail when a daemon transaction is in progress instead of following it"))
+    .arg(Arg::new("target-release")
+    .short('t')
+    .long("target-release")
+    .value_name("SUITE")
+    .global(true)
+    .help("Prefer versions from this suite or codename, e.g. bookworm-backports, over the configured default-release"))
+    .arg(Arg::new("note")
+    .long("note")
+    .value_name("TEXT")
+    .global(true)
+    .help("Attach a note to the deployment this command creates, shown by status"))
+    .arg(Arg::new("allow-unsigned")
+    .long("allow-unsigned")
+    .global(true)
+    .action(ArgAction::SetTrue)
+    .help("Accept unsigned repositories, packages without a verifiable hash and unsigned bundles"))
+    .arg(Arg::new("i-know-what-im-doing")
+    .long("i-know-what-im-doing")
+    .global(true)
+    .action(ArgAction::SetTrue)
+    .help("Skip typing the confirmation token of operations listed in confirm-tokens, e.g. in scripts"))
+    .arg(Arg::new("force")
+    .long("force")
+    .value_name("OPTION")
+    .global(true)
+    .action(ArgAction::Append)
+    .value_parser(force::names())
+    .help("Pass a dpkg force option for this run, e.g. overwrite to replace files another layered package owns"))
+    .arg(Arg::new("inject-fault")
+    .long("inject-fault")
+    .value_name("POINT")
+    .global(true)
+    .hide(true)
+    .action(ArgAction::Append)
+    .value_parser(fault::names())
+    .help("Fail the transaction at POINT, to test rollback (needs HACKER_OSTREE_FAULT_INJECTION=1)"))
     .subcommand(Command::new("update")
-    .about("Update APT cache"))
+    .about("Refresh repository indexes"))
     .subcommand(Command::new("upgrade")
-    .about("Upgrade all installed packages in overlay"))
+    .about("Upgrade all installed packages in overlay")
+    .arg(Arg::new("yes")
+    .short('y')
+    .long("yes")
+    .action(ArgAction::SetTrue)
+    .help("Do not ask for confirmation"))
+    .arg(Arg::new("force-size")
+    .long("force-size")
+    .action(ArgAction::SetTrue)
+    .help("Proceed even when the configured size limits are exceeded")))
     .subcommand(Command::new("system-update")
-    .about("Update the system via OSTree pull and deploy"))
-    .subcommand(Command::new("system-upgrade")
-    .about("Alias for system-update"))
+    .about("Update the system via OSTree pull and deploy")
+    .visible_alias("system-upgrade")
+    .arg(Arg::new("depth")
+    .long("depth")
+    .value_name("N")
+    .allow_negative_numbers(true)
+    .value_parser(clap::value_parser!(i32))
+    .help("Parent commits to fetch (0 = newest only, -1 = full history)"))
+    .arg(Arg::new("commit")
+    .long("commit")
+    .value_name("CHECKSUM")
+    .help("Pull and deploy this exact commit instead of the newest one"))
+    .arg(Arg::new("allow-downgrade")
+    .long("allow-downgrade")
+    .action(ArgAction::SetTrue)
+    .help("Deploy the commit even if it is older than the booted one"))
+    .arg(Arg::new("major")
+    .long("major")
+    .action(ArgAction::SetTrue)
+    .help("Deploy the commit even if it belongs to another release series, without running migration hooks"))
+    .arg(Arg::new("download-only")
+    .long("download-only")
+    .action(ArgAction::SetTrue)
+    .help("Pull the commit and download the layered packages to reapply, without deploying"))
+    .arg(Arg::new("check-only")
+    .long("check-only")
+    .action(ArgAction::SetTrue)
+    .conflicts_with("download-only")
+    .help("Only fetch the commit metadata and report the version, age, changes and download size of the update")))
+    .subcommand(Command::new("upgrade-release")
+    .about("Move to another release series of the base image, running its migration hooks")
+    .arg(Arg::new("SERIES")
+    .required(true)
+    .index(1)
+    .help("Release series the newest base image must belong to"))
+    .arg(Arg::new("depth")
+    .long("depth")
+    .value_name("N")
+    .allow_negative_numbers(true)
+    .value_parser(clap::value_parser!(i32))
+    .help("Parent commits to fetch (0 = newest only, -1 = full history)")))
+    .subcommand(Command::new("auto-update")
+    .about("Update base and overlay unattended, deferring on low battery or metered connections")
+    .arg(Arg::new("now")
+    .long("now")
+    .action(ArgAction::SetTrue)
+    .help("Apply immediately instead of waiting for a maintenance window or an idle desktop"))
+    .arg(Arg::new("when-idle")
+    .long("when-idle")
+    .action(ArgAction::SetTrue)
+    .conflicts_with("now")
+    .help("Wait for the desktop session to go idle instead of leaving the update staged")))
     .subcommand(Command::new("install")
-    .about("Install a DEB package to overlay")
+    .about("Install DEB packages to overlay")
     .arg(Arg::new("PACKAGE")
     .required(true)
-    .index(1)))
+    .num_args(1..)
+    .help("Package names, or NAME=VERSION for a specific version; all are installed in one transaction"))
+    .arg(Arg::new("from")
+    .long("from")
+    .value_name("REPO")
+    .help("Take the package from this repository, and its dependencies too where it has them"))
+    .arg(Arg::new("fix-typo")
+    .long("fix-typo")
+    .action(ArgAction::SetTrue)
+    .help("If PACKAGE isn't known, continue with the closest known name once confirmed"))
+    .arg(Arg::new("yes")
+    .short('y')
+    .long("yes")
+    .action(ArgAction::SetTrue)
+    .help("Do not ask for confirmation"))
+    .arg(Arg::new("force-size")
+    .long("force-size")
+    .action(ArgAction::SetTrue)
+    .help("Proceed even when the configured size limits are exceeded"))
+    .arg(Arg::new("force-reinstall")
+    .long("force-reinstall")
+    .action(ArgAction::SetTrue)
+    .help("Download and reinstall even if the newest version is already installed"))
+    .arg(Arg::new("preview-files")
+    .long("preview-files")
+    .action(ArgAction::SetTrue)
+    .help("List the paths the installation would add, replace or remove, and change nothing"))
+    .arg(Arg::new("json")
+    .long("json")
+    .action(ArgAction::SetTrue)
+    .help("Explain dependency resolution failures as JSON"))
+    .arg(Arg::new("no-upgrade-existing")
+    .long("no-upgrade-existing")
+    .action(ArgAction::SetTrue)
+    .help("Fail instead of replacing layered packages that don't satisfy new dependencies"))
+    .arg(Arg::new("prefer-installed-versions")
+    .long("prefer-installed-versions")
+    .action(ArgAction::SetTrue)
+    .help("Satisfy dependencies with installed packages first and change layered ones as little as possible"))
+    .arg(Arg::new("solve-strict")
+    .long("solve-strict")
+    .action(ArgAction::SetTrue)
+    .help("Use only candidate versions and refuse conflicting packages instead of relaxing")))
     .subcommand(Command::new("remove")
     .about("Remove a DEB package from overlay")
     .arg(Arg::new("PACKAGE")
     .required(true)
+    .num_args(1..)
+    .help("Package names or glob patterns such as 'libfoo-*', matched against the layered packages"))
+    .arg(Arg::new("yes")
+    .short('y')
+    .long("yes")
+    .action(ArgAction::SetTrue)
+    .help("Do not ask to confirm what patterns matched"))
+    .arg(Arg::new("fix-typo")
+    .long("fix-typo")
+    .action(ArgAction::SetTrue)
+    .help("If PACKAGE isn't known, continue with the closest known name once confirmed")))
+    .subcommand(Command::new("hold")
+    .about("Keep layered packages at their installed version during upgrade")
+    .arg(Arg::new("PACKAGE")
+    .num_args(0..)
+    .help("Package names or glob patterns such as '*-dbgsym'; lists the held packages without any"))
+    .arg(Arg::new("yes")
+    .short('y')
+    .long("yes")
+    .action(ArgAction::SetTrue)
+    .help("Do not ask to confirm what patterns matched")))
+    .subcommand(Command::new("unhold")
+    .about("Let upgrade update held packages again")
+    .arg(Arg::new("PACKAGE")
+    .required(true)
+    .num_args(1..)
+    .help("Package names or glob patterns"))
+    .arg(Arg::new("yes")
+    .short('y')
+    .long("yes")
+    .action(ArgAction::SetTrue)
+    .help("Do not ask to confirm what patterns matched")))
+    .subcommand(Command::new("divert")
+    .about("Manage dpkg diversions in the overlay")
+    .subcommand(Command::new("add")
+    .about("Divert other packages' versions of a file so they don't replace it")
+    .arg(Arg::new("PATH")
+    .required(true)
+    .index(1))
+    .arg(Arg::new("to")
+    .long("to")
+    .value_name("PATH")
+    .help("Where the diverted versions go (PATH.distrib by default)"))
+    .arg(Arg::new("package")
+    .long("package")
+    .value_name("PACKAGE")
+    .help("Package whose own version is not diverted (every package's is by default)")))
+    .subcommand(Command::new("remove")
+    .about("Remove a diversion and move the diverted file back")
+    .arg(Arg::new("PATH")
+    .required(true)
     .index(1)))
     .subcommand(Command::new("list")
-    .about("List installed packages"))
+    .about("List the diversions packages and you made in the overlay")))
+    .subcommand(Command::new("list")
+    .about("List installed packages")
+    .arg(Arg::new("json")
+    .long("json")
+    .action(ArgAction::SetTrue)
+    .help("Print JSON (schema: hacker-ostree schema list)")))
+    .subcommand(Command::new("extract")
+    .about("Download a package and unpack it into a directory without installing it")
+    .arg(Arg::new("PACKAGE")
+    .required(true)
+    .index(1))
+    .arg(Arg::new("DIR")
+    .required(true)
+    .index(2)))
+    .subcommand(Command::new("files")
+    .about("List the files a package owns")
+    .arg(Arg::new("PACKAGE")
+    .required(true)
+    .index(1))
+    .arg(Arg::new("missing")
+    .long("missing")
+    .action(ArgAction::SetTrue)
+    .help("Only show files that are not present on disk")))
+    .subcommand(Command::new("owns")
+    .about("Show which package owns a path")
+    .arg(Arg::new("PATH")
+    .required(true)
+    .index(1)))
+    .subcommand(Command::new("blame")
+    .about("Show which package and transaction introduced a file or systemd unit")
+    .arg(Arg::new("TARGET")
+    .required(true)
+    .index(1)))
+    .subcommand(Command::new("verify")
+    .about("Verify overlay files against the package database")
+    .arg(Arg::new("PACKAGE")
+    .index(1)))
+    .subcommand(Command::new("verify-system")
+    .about("Check the booted deployment, overlay files and repository signatures in one pass/fail report"))
+    .subcommand(Command::new("diff")
+    .about("Compare the overlay on disk against the package database")
+    .arg(Arg::new("overlay")
+    .long("overlay")
+    .required(true)
+    .action(ArgAction::SetTrue)
+    .help("Report modified, missing and unowned overlay files"))
+    .arg(Arg::new("fix")
+    .long("fix")
+    .action(ArgAction::SetTrue)
+    .help("Reinstall damaged packages and delete unowned files")))
     .subcommand(Command::new("search")
     .about("Search for packages in APT repositories")
     .arg(Arg::new("QUERY")
     .required(true)
     .index(1)))
+    .subcommand(Command::new("show")
+    .about("Show package details from APT repositories")
+    .arg(Arg::new("PACKAGE")
+    .required(true)
+    .index(1))
+    .arg(Arg::new("fix-typo")
+    .long("fix-typo")
+    .action(ArgAction::SetTrue)
+    .help("If PACKAGE isn't known, continue with the closest known name once confirmed")))
+    .subcommand(Command::new("compare")
+    .about("Compare a package's versions in the base image, the overlay and each repository")
+    .arg(Arg::new("PACKAGE")
+    .required(true)
+    .index(1)))
+    .subcommand(Command::new("apply")
+    .about("Converge this node to a state manifest")
+    .arg(Arg::new("manifest")
+    .long("manifest")
+    .value_name("FILE")
+    .required(true)
+    .help("Manifest file, or - for stdin"))
+    .arg(Arg::new("yes")
+    .short('y')
+    .long("yes")
+    .action(ArgAction::SetTrue)
+    .help("Do not ask for confirmation")))
+    .subcommand(Command::new("fleet")
+    .about("Manage many nodes at once")
+    .subcommand(Command::new("apply")
+    .about("Apply a state manifest across hosts over SSH")
+    .arg(Arg::new("hosts")
+    .long("hosts")
+    .value_name("FILE")
+    .required(true)
+    .help("File with one user@machine per line"))
+    .arg(Arg::new("manifest")
+    .long("manifest")
+    .value_name("FILE")
+    .required(true)
+    .help("State manifest to apply"))
+    .arg(Arg::new("rolling")
+    .long("rolling")
+    .value_name("N|PERCENT")
+    .help("Hosts per batch, e.g. 5 or 20%; a failing batch stops the rollout"))
+    .arg(Arg::new("report")
+    .long("report")
+    .value_name("FILE")
+    .help("Write the JSON report to a file instead of stdout"))))
+    .subcommand(Command::new("bundle")
+    .about("Move updates to machines without network access")
+    .subcommand(Command::new("create")
+    .about("Pack a base delta and the packages of a state manifest into a tar file")
+    .arg(Arg::new("OUT")
+    .required(true)
+    .index(1))
+    .arg(Arg::new("manifest")
+    .long("manifest")
+    .value_name("FILE")
+    .required(true)
+    .help("State manifest describing the target"))
+    .arg(Arg::new("from")
+    .long("from")
+    .value_name("COMMIT")
+    .help("Base commit the offline node has; omit for a full base image"))
+    .arg(Arg::new("sign")
+    .long("sign")
+    .value_name("KEY")
+    .help("GPG key to sign the bundle with")))
+    .subcommand(Command::new("apply")
+    .about("Verify and apply a bundle without network access")
+    .arg(Arg::new("FILE")
+    .required(true)
+    .index(1))
+    .arg(Arg::new("keyring")
+    .long("keyring")
+    .value_name("FILE")
+    .help("Keyring to verify the signature with"))
+    .arg(Arg::new("allow-downgrade")
+    .long("allow-downgrade")
+    .action(ArgAction::SetTrue)
+    .help("Deploy the bundled base even if it is older than the booted one"))))
+    .subcommand(Command::new("status")
+    .about("Show deployments with their base version, packages and advisories")
+    .arg(Arg::new("json")
+    .long("json")
+    .action(ArgAction::SetTrue)
+    .help("Print JSON (schema: hacker-ostree schema status)"))
+    .arg(Arg::new("verbose")
+    .short('v')
+    .long("verbose")
+    .action(ArgAction::SetTrue)
+    .help("Also show each commit's provenance: remote, parent, content checksum, signatures and origin file")))
+    .subcommand(Command::new("check-update")
+    .about("Report available base and overlay updates without applying them"))
+    .subcommand(Command::new("summary")
+    .about("Summarize image version, known updates, pending reboot and last update")
+    .arg(Arg::new("motd")
+    .long("motd")
+    .action(ArgAction::SetTrue)
+    .help("Write the summary to /run/motd.d to be shown at login instead of printing it")))
+    .subcommand(Command::new("prompt-status")
+    .about("Print a short cached status for shell prompts: \u{2b06}N pending updates, \u{27f2} reboot needed"))
+    .subcommand(Command::new("db")
+    .about("Inspect package databases of base commits")
+    .subcommand(Command::new("diff")
+    .about("Compare the packages of two base commits")
+    .arg(Arg::new("FROM")
+    .index(1)
+    .help("Commit or ref to compare from (the booted deployment by default)"))
+    .arg(Arg::new("TO")
+    .index(2)
+    .help("Commit or ref to compare to (origin:main by default)"))))
+    .subcommand(Command::new("compose")
+    .about("Build base images")
+    .subcommand(Command::new("tree")
+    .about("Bootstrap a treefile and commit it with version, package and advisory metadata")
+    .arg(Arg::new("TREEFILE")
+    .required(true)
+    .index(1))
+    .arg(Arg::new("repo")
+    .long("repo")
+    .value_name("PATH")
+    .help("OSTree repository to commit to (the system repository by default)"))
+    .arg(Arg::new("lockfile")
+    .long("lockfile")
+    .value_name("FILE")
+    .help("Build exactly the repos, versions and hashes of a lockfile, failing on any drift"))
+    .arg(Arg::new("no-cache")
+    .long("no-cache")
+    .action(ArgAction::SetTrue)
+    .help("Bootstrap from scratch, downloading every package again, instead of building on the previous build")))
+    .subcommand(Command::new("container")
+    .about("Compose a treefile and push it as an OSTree-native container image")
+    .arg(Arg::new("TREEFILE")
+    .required(true)
+    .index(1))
+    .arg(Arg::new("tag")
+    .long("tag")
+    .value_name("IMAGE")
+    .required(true)
+    .help("Image to push, e.g. registry.example.com/hackeros:dev, or with a transport such as oci:DIR"))
+    .arg(Arg::new("repo")
+    .long("repo")
+    .value_name("PATH")
+    .help("OSTree repository to commit to before pushing (the system repository by default)"))
+    .arg(Arg::new("lockfile")
+    .long("lockfile")
+    .value_name("FILE")
+    .help("Build exactly the repos, versions and hashes of a lockfile, failing on any drift"))
+    .arg(Arg::new("no-cache")
+    .long("no-cache")
+    .action(ArgAction::SetTrue)
+    .help("Bootstrap from scratch, downloading every package again, instead of building on the previous build")))
+    .subcommand(Command::new("lockfile")
+    .about("Resolve a treefile against snapshots of its repos and write a lockfile")
+    .arg(Arg::new("TREEFILE")
+    .required(true)
+    .index(1))
+    .arg(Arg::new("output")
+    .short('o')
+    .long("output")
+    .value_name("FILE")
+    .help("Where to write the lockfile (TREEFILE with a .lock.json extension by default)"))))
+    .subcommand(Command::new("conffiles")
+    .about("Review new package versions of configuration files you edited")
+    .subcommand(Command::new("list")
+    .about("List pending configuration updates"))
+    .subcommand(Command::new("diff")
+    .about("Show how the new versions differ from yours")
+    .arg(Arg::new("PATH")
+    .index(1)))
+    .subcommand(Command::new("keep")
+    .about("Keep your version and discard the new one")
+    .arg(Arg::new("PATH")
+    .required(true)
+    .index(1)))
+    .subcommand(Command::new("replace")
+    .about("Install the new version, saving yours as .dpkg-old")
+    .arg(Arg::new("PATH")
+    .required(true)
+    .index(1))))
+    .subcommand(Command::new("history")
+    .about("Show the transactions recorded in the history")
+    .arg(Arg::new("json")
+    .long("json")
+    .action(ArgAction::SetTrue)
+    .help("Print JSON (schema: hacker-ostree schema history)"))
+    .subcommand(Command::new("diff")
+    .about("Show how the layered packages after one transaction differ from those after another")
+    .arg(Arg::new("FROM")
+    .required(true)
+    .index(1)
+    .value_parser(clap::value_parser!(u64))
+    .help("Transaction to compare from; 0 for before the first one"))
+    .arg(Arg::new("TO")
+    .index(2)
+    .value_parser(clap::value_parser!(u64))
+    .help("Transaction to compare to (the newest one by default)"))
+    .arg(Arg::new("json")
+    .long("json")
+    .action(ArgAction::SetTrue)
+    .help("Print JSON (schema: hacker-ostree schema history-diff)"))))
+    .subcommand(Command::new("undo")
+    .about("Revert the package changes of a transaction")
+    .arg(Arg::new("ID")
+    .index(1)
+    .value_parser(clap::value_parser!(u64))
+    .help("Transaction to revert (the newest one that changed packages when omitted)"))
+    .arg(Arg::new("yes")
+    .short('y')
+    .long("yes")
+    .action(ArgAction::SetTrue)
+    .help("Do not ask for confirmation")))
     .subcommand(Command::new("rollback")
     .about("Rollback to previous OSTree commit"))
+    .subcommand(Command::new("note")
+    .about("Attach a note to a deployment, e.g. why it is pinned")
+    .arg(Arg::new("INDEX")
+    .required(true)
+    .index(1)
+    .value_parser(clap::value_parser!(usize))
+    .help("Deployment number as shown by status"))
+    .arg(Arg::new("TEXT")
+    .index(2)
+    .required_unless_present("clear"))
+    .arg(Arg::new("clear")
+    .long("clear")
+    .action(ArgAction::SetTrue)
+    .conflicts_with("TEXT")
+    .help("Remove the deployment's note")))
     .subcommand(Command::new("resync")
-    .about("Resync overlay with installed packages"))
+    .about("Resync overlay with installed packages")
+    .arg(Arg::new("full")
+    .long("full")
+    .action(ArgAction::SetTrue)
+    .help("Reapply every layered package instead of only those affected by changes")))
     .subcommand(Command::new("clean")
-    .about("Clean APT cache"))
+    .about("Clean the package download cache")
+    .arg(Arg::new("orphans")
+    .long("orphans")
+    .action(ArgAction::SetTrue)
+    .help("Remove overlay files not owned by any layered package instead"))
+    .arg(Arg::new("dry-run")
+    .long("dry-run")
+    .action(ArgAction::SetTrue)
+    .requires("orphans")
+    .help("Only report orphaned files")))
+    .subcommand(Command::new("cache")
+    .about("Inspect the package download cache")
+    .subcommand(Command::new("verify")
+    .about("Check cached packages against the hashes recorded at download, evicting corrupt ones")))
+    .subcommand(Command::new("generations")
+    .about("List committed overlay generation images"))
+    .subcommand(Command::new("prune")
+    .about("Prune unreachable objects from the OSTree repository")
+    .arg(Arg::new("keep-younger-than")
+    .long("keep-younger-than")
+    .value_name("DATE")
+    .help("Keep commits younger than the given date, e.g. \"2 weeks ago\""))
+    .arg(Arg::new("depth")
+    .long("depth")
+    .value_name("N")
+    .help("Only keep N commits of history per ref"))
+    .arg(Arg::new("dry-run")
+    .long("dry-run")
+    .action(ArgAction::SetTrue)
+    .help("Report what would be pruned without deleting anything")))
     .subcommand(Command::new("repo")
     .about("Manage repositories")
     .subcommand(Command::new("list")
@@ -317,68 +2639,671 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     .about("Add a repository")
     .arg(Arg::new("REPO_LINE")
     .required(true)
+    .index(1)
+    .help("Source line, or a shorthand like ppa:user/name or debian:bookworm-backports"))
+    .arg(Arg::new("suite")
+    .long("suite")
+    .value_name("SUITE")
+    .help("Distribution series for ppa: shorthands"))
+    .arg(Arg::new("name")
+    .long("name")
+    .value_name("NAME")
+    .help("Name to refer to the repository by (derived from the URI by default)")))
+    .subcommand(Command::new("freeze")
+    .about("Pin repositories to an archive snapshot so overlays can be rebuilt identically")
+    .arg(Arg::new("REPO")
+    .index(1)
+    .help("Repository name or index (all repositories by default)"))
+    .arg(Arg::new("at")
+    .long("at")
+    .value_name("TIMESTAMP")
+    .help("Snapshot timestamp like 20240101T000000Z (now by default)")))
+    .subcommand(Command::new("thaw")
+    .about("Unpin repositories from their snapshot")
+    .arg(Arg::new("REPO")
+    .index(1)
+    .help("Repository name or index (all repositories by default)")))
+    .subcommand(Command::new("remove")
+    .about("Remove a repository by name or index")
+    .arg(Arg::new("REPO")
+    .required(true)
+    .index(1)))
+    .subcommand(Command::new("show")
+    .about("Show a repository's configuration")
+    .arg(Arg::new("REPO")
+    .required(true)
     .index(1)))
+    .subcommand(Command::new("benchmark")
+    .about("Measure the latency and throughput of repository mirrors")
+    .arg(Arg::new("REPO")
+    .index(1)
+    .help("Repository name or index (all repositories by default)"))
+    .arg(Arg::new("apply")
+    .long("apply")
+    .action(ArgAction::SetTrue)
+    .help("Reorder each repository's mirrors fastest first"))
+    .arg(Arg::new("max-age")
+    .long("max-age")
+    .value_name("DAYS")
+    .value_parser(clap::value_parser!(u64))
+    .help("Reuse measurements younger than this instead of measuring again")))
+    .subcommand(Command::new("add-key")
+    .about("Trust a signing key for repositories")
+    .arg(Arg::new("KEY")
+    .required(true)
+    .index(1)
+    .help("Key file or URL, armored or binary"))
+    .arg(Arg::new("name")
+    .long("name")
+    .value_name("NAME")
+    .help("Name of the keyring to store it as (the key id by default)")))
+    .subcommand(Command::new("list-keys")
+    .about("List the trusted signing keys"))
+    .subcommand(Command::new("remove-key")
+    .about("Stop trusting a signing key")
+    .arg(Arg::new("KEY")
+    .required(true)
+    .index(1)
+    .help("Keyring name or key fingerprint")))
+    .subcommand(Command::new("refresh-keys")
+    .about("Fetch updated versions of the trusted signing keys and those of OSTree remotes"))
+    .subcommand(Command::new("mirror")
+    .about("Manage a repository's failover mirrors")
+    .subcommand(Command::new("add")
+    .about("Append a mirror to a repository")
+    .arg(Arg::new("REPO")
+    .required(true)
+    .index(1))
+    .arg(Arg::new("URI")
+    .required(true)
+    .index(2)))
     .subcommand(Command::new("remove")
-    .about("Remove a repository by index")
-    .arg(Arg::new("INDEX")
+    .about("Remove a mirror from a repository")
+    .arg(Arg::new("REPO")
     .required(true)
-    .index(1))))
-    .get_matches();
+    .index(1))
+    .arg(Arg::new("URI")
+    .required(true)
+    .index(2)))))
+    .subcommand(Command::new("daemon")
+    .about("Run the transaction daemon serving queued requests on its socket and as org.hackeros.OSTree1 on the system bus (for the systemd service)"))
+    .subcommand(Command::new("queue")
+    .about("Submit, list and cancel transactions queued with the daemon")
+    .subcommand(Command::new("submit")
+    .about("Queue a command with the daemon and follow its output")
+    .arg(Arg::new("ARGS")
+    .required(true)
+    .num_args(1..)
+    .trailing_var_arg(true)
+    .allow_hyphen_values(true)))
+    .subcommand(Command::new("list")
+    .about("List the running and queued transactions"))
+    .subcommand(Command::new("cancel")
+    .about("Cancel a queued transaction")
+    .arg(Arg::new("ID")
+    .required(true)
+    .index(1)
+    .value_parser(clap::value_parser!(u64))))
+    .subcommand(Command::new("attach")
+    .about("Follow the output of a transaction, the running one by default")
+    .arg(Arg::new("ID")
+    .index(1)
+    .value_parser(clap::value_parser!(u64)))))
+    .subcommand(Command::new("ex")
+    .about("Experimental commands, enabled with \"experimental\": true in config.json; they may change or go away")
+    .subcommand(Command::new("live-apply")
+    .about("Use the packages layered into the pending deployment right away, before rebooting into it")))
+    .subcommand(Command::new("wait")
+    .about("Block until no transaction is running or queued, for scripts sequencing against background updates")
+    .arg(Arg::new("timeout")
+    .long("timeout")
+    .value_name("SECONDS")
+    .value_parser(clap::value_parser!(u64))
+    .help("Give up and fail after this many seconds"))
+    .arg(Arg::new("staged")
+    .long("staged")
+    .action(ArgAction::SetTrue)
+    .help("Also wait until no staged deployment is waiting to be finalized")))
+    .subcommand(Command::new("schema")
+    .about("Print the JSON schema of a machine-readable format")
+    .arg(Arg::new("NAME")
+    .index(1)
+    .value_parser(schema::NAMES.map(|(name, _)| name))
+    .help("Format to describe (lists them when omitted)")))
+    .subcommand(Command::new("completions")
+    .about("Print a shell completion script")
+    .arg(Arg::new("SHELL")
+    .required(true)
+    .value_parser(["bash", "zsh", "fish"])
+    .index(1)))
+    .subcommand(Command::new("self-update")
+    .about("Install a newer signed release of hacker-ostree from the configured channel into the overlay")
+    .arg(Arg::new("check")
+    .long("check")
+    .action(ArgAction::SetTrue)
+    .help("Only report whether a newer release is available")))
+    .subcommand(Command::new("__complete")
+    .hide(true)
+    .arg(Arg::new("WORDS")
+    .num_args(0..)
+    .trailing_var_arg(true)
+    .allow_hyphen_values(true)))
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let matches = build_cli().get_matches();
+    if let Some(code) = enter_root()? {
+        std::process::exit(code);
+    }
+    exec::init()?;
+    if let Some(host) = matches.get_one::<String>("host") {
+        remote::run_on_host(host)?;
+        return Ok(());
+    }
+    if !ostree::is_booted() {
+        if let Some(command) = matches.subcommand_name().filter(|name| OSTREE_COMMANDS.contains(name)) {
+            return Err(format!(
+                "{} needs an OSTree-booted system and this host isn't one; only overlay package commands work here",
+                command
+            )
+            .into());
+        }
+        if matches.get_one::<String>("stateroot").is_some() {
+            return Err("--stateroot needs an OSTree-booted system and this host isn't one".into());
+        }
+        ostree::set_overlay_only(true);
+    }
+    let _timing = timing::Report::start(matches.get_flag("timing"));
+    transaction::set_attach(!matches.get_flag("no-attach"));
+    transaction::set_skip_tokens(matches.get_flag("i-know-what-im-doing"));
+    keys::set_allow_unsigned(matches.get_flag("allow-unsigned"));
+    policy::set_target_release(matches.get_one::<String>("target-release").cloned());
+    notes::set_pending(matches.get_one::<String>("note").cloned());
+    force::set_requested(matches.get_many::<String>("force").map(|o| o.cloned().collect()).unwrap_or_default());
+    fault::set_injected(matches.get_many::<String>("inject-fault").map(|p| p.cloned().collect()).unwrap_or_default())?;
+    if let Some(stateroot) = matches.get_one::<String>("stateroot") {
+        ostree::set_stateroot(Some(stateroot.clone()));
+        if let Some(code) = enter_stateroot()? {
+            std::process::exit(code);
+        }
+    }
 
     match matches.subcommand() {
-        Some(("update", _)) => apt_update()?,
-        Some(("upgrade", _)) => upgrade_packages()?,
-        Some(("system-update", _)) | Some(("system-upgrade", _)) => system_update()?,
-        Some(("install", sub_m)) => install_package(sub_m.get_one::<String>("PACKAGE").unwrap())?,
-        Some(("remove", sub_m)) => remove_package(sub_m.get_one::<String>("PACKAGE").unwrap())?,
-        Some(("list", _)) => {
+        Some(("update", _)) => refresh_indexes()?,
+        Some(("self-update", sub_m)) => self_update(sub_m.get_flag("check"))?,
+        Some(("completions", sub_m)) => print!("{}", completion::script(sub_m.get_one::<String>("SHELL").unwrap())),
+        Some(("__complete", sub_m)) => {
+            let words: Vec<String> = sub_m.get_many::<String>("WORDS").map(|w| w.cloned().collect()).unwrap_or_default();
+            for candidate in completion::complete(build_cli(), &words) {
+                println!("{}", candidate);
+            }
+        }
+        Some(("upgrade", sub_m)) => {
+            refresh_indexes()?;
+            let upgradable: Vec<resolve::Selection> = upgradable_packages(&load_installed_packages()?)?
+                .into_iter()
+                .map(|(name, _, candidate)| (name, Some(candidate)))
+                .collect();
+            if upgradable.is_empty() {
+                println!("Overlay packages are up to date");
+            } else if let Some(order) = confirm_install(
+                &upgradable,
+                sub_m.get_flag("yes"),
+                sub_m.get_flag("force-size"),
+                false,
+                resolve::Options::default(),
+            )? {
+                let names: Vec<String> = order.iter().map(|(name, _)| name.clone()).collect();
+                transaction::run("upgrade", &names, || upgrade_packages(&order))?
+            }
+        }
+        Some(("system-update", sub_m)) => {
+            let pull_opts = ostree::PullOptions {
+                depth: match sub_m.get_one::<i32>("depth") {
+                    Some(depth) => *depth,
+                    None => config::load_config()?.pull_depth,
+                },
+                commit: sub_m.get_one::<String>("commit").cloned(),
+            };
+            if sub_m.get_flag("check-only") {
+                check_system_update(&pull_opts)?
+            } else if sub_m.get_flag("download-only") {
+                let _lock = transaction::Lock::acquire()?;
+                prewarm(&pull_opts)?
+            } else {
+                let unlayer = absorbed_to_unlayer(&pull_opts, false)?;
+                transaction::run("system-update", &[], || {
+                    system_update(&pull_opts, sub_m.get_flag("allow-downgrade"), sub_m.get_flag("major"), &unlayer)
+                })?
+            }
+        }
+        Some(("upgrade-release", sub_m)) => {
+            let pull_opts = ostree::PullOptions {
+                depth: match sub_m.get_one::<i32>("depth") {
+                    Some(depth) => *depth,
+                    None => config::load_config()?.pull_depth,
+                },
+                commit: None,
+            };
+            let series = sub_m.get_one::<String>("SERIES").unwrap();
+            let unlayer = absorbed_to_unlayer(&pull_opts, false)?;
+            transaction::run("upgrade-release", &[], || upgrade_release(series, &pull_opts, &unlayer))?
+        }
+        Some(("auto-update", sub_m)) => auto_update(sub_m.get_flag("now"), sub_m.get_flag("when-idle"))?,
+        Some(("install", sub_m)) => {
+            let from = match sub_m.get_one::<String>("from") {
+                Some(selector) => {
+                    let repos = repos::load_repos()?;
+                    Some(repos[repos::find_repo(&repos, selector)?].name.clone())
+                }
+                None => None,
+            };
+            policy::set_target(from.clone());
+            refresh_indexes()?;
+            let mut requested: Vec<resolve::Selection> = Vec::new();
+            for spec in sub_m.get_many::<String>("PACKAGE").unwrap() {
+                let (package, version) = match spec.split_once('=') {
+                    Some((name, version)) => (name.to_string(), Some(version.to_string())),
+                    None => (spec.clone(), None),
+                };
+                let package = suggest::resolve(
+                    &package,
+                    &known_packages()?,
+                    "is not available from any repository",
+                    sub_m.get_flag("fix-typo"),
+                    sub_m.get_flag("yes"),
+                )?;
+                if let Some(repo) = &from {
+                    check_available_from(&package, version.as_deref(), repo)?;
+                }
+                let current = if sub_m.get_flag("force-reinstall") { None } else { layered_and_current(&package, version.as_deref())? };
+                if sub_m.get_flag("preview-files") {
+                    preview_files(&package, version.as_deref())?;
+                } else if let Some(version) = current {
+                    let mut auto = load_auto_installed()?;
+                    if auto.contains(&package) {
+                        auto.retain(|p| *p != package);
+                        save_auto_installed(&auto)?;
+                        println!("{} {} is already installed, marked as manually installed", package, version);
+                    } else {
+                        println!("{} {} is already installed, nothing to do", package, version);
+                    }
+                } else {
+                    requested.push((package, version));
+                }
+            }
+            let order = if requested.is_empty() {
+                None
+            } else {
+                confirm_install(
+                    &requested,
+                    sub_m.get_flag("yes"),
+                    sub_m.get_flag("force-size"),
+                    sub_m.get_flag("json"),
+                    resolve::Options {
+                        upgrade_existing: !sub_m.get_flag("no-upgrade-existing"),
+                        prefer_installed: sub_m.get_flag("prefer-installed-versions"),
+                        strict: sub_m.get_flag("solve-strict"),
+                    },
+                )?
+            };
+            if let Some(order) = order {
+                let names: Vec<String> = order.iter().map(|(name, _)| name.clone()).collect();
+                let manual: Vec<String> = requested.into_iter().map(|(name, _)| name).collect();
+                transaction::run("install", &names, || install_packages(&order, &manual))?
+            }
+        }
+        Some(("remove", sub_m)) => {
+            let installed: BTreeSet<String> = load_installed_packages()?.into_iter().collect();
+            let patterns: Vec<String> = sub_m.get_many::<String>("PACKAGE").unwrap().cloned().collect();
+            let packages = select_packages(
+                &patterns,
+                &installed,
+                "installed in the overlay",
+                sub_m.get_flag("fix-typo"),
+                sub_m.get_flag("yes"),
+            )?;
+            transaction::run("remove", &packages, || {
+                for package in &packages {
+                    remove_package(package)?;
+                }
+                autoremove()
+            })?
+        }
+        Some(("hold", sub_m)) => {
+            let patterns: Vec<String> = sub_m.get_many::<String>("PACKAGE").map(|p| p.cloned().collect()).unwrap_or_default();
+            hold_packages(&patterns, sub_m.get_flag("yes"))?
+        }
+        Some(("unhold", sub_m)) => {
+            let patterns: Vec<String> = sub_m.get_many::<String>("PACKAGE").unwrap().cloned().collect();
+            unhold_packages(&patterns, sub_m.get_flag("yes"))?
+        }
+        Some(("divert", divert_m)) => match divert_m.subcommand() {
+            Some(("add", sub_m)) => {
+                let path = sub_m.get_one::<String>("PATH").unwrap();
+                transaction::run("divert-add", &[], || {
+                    diversions::add(
+                        path,
+                        sub_m.get_one::<String>("to").map(String::as_str),
+                        sub_m.get_one::<String>("package").map(String::as_str),
+                    )
+                })?
+            }
+            Some(("remove", sub_m)) => {
+                let path = sub_m.get_one::<String>("PATH").unwrap();
+                transaction::run("divert-remove", &[], || diversions::remove(path))?
+            }
+            Some(("list", _)) => diversions::list()?,
+            _ => println!("Invalid divert subcommand"),
+        },
+        Some(("list", sub_m)) => {
             let pkgs = list_packages()?;
+            let auto = load_auto_installed()?;
+            if sub_m.get_flag("json") {
+                let listing: Vec<serde_json::Value> = pkgs
+                    .iter()
+                    .map(|(pkg, version)| serde_json::json!({ "name": pkg, "version": version, "automatic": auto.contains(pkg) }))
+                    .collect();
+                let text = serde_json::to_string_pretty(&listing).map_err(|e| format!("Failed to serialize packages: {}", e))?;
+                println!("{}", text);
+                return Ok(());
+            }
             println!("Installed packages:");
-            for pkg in pkgs {
-                println!("- {}", pkg);
+            for (pkg, version) in pkgs {
+                if auto.contains(&pkg) {
+                    println!("- {} {} (automatic)", pkg, version);
+                } else {
+                    println!("- {} {}", pkg, version);
+                }
             }
         }
+        Some(("extract", sub_m)) => extract_package(
+            sub_m.get_one::<String>("PACKAGE").unwrap(),
+            sub_m.get_one::<String>("DIR").unwrap(),
+        )?,
+        Some(("files", sub_m)) => show_files(sub_m.get_one::<String>("PACKAGE").unwrap(), sub_m.get_flag("missing"))?,
+        Some(("owns", sub_m)) => show_owners(sub_m.get_one::<String>("PATH").unwrap())?,
+        Some(("blame", sub_m)) => blame(sub_m.get_one::<String>("TARGET").unwrap())?,
+        Some(("verify", sub_m)) => verify_packages(sub_m.get_one::<String>("PACKAGE").map(String::as_str))?,
+        Some(("verify-system", _)) => verify_system()?,
+        Some(("diff", sub_m)) => diff_overlay(sub_m.get_flag("fix"))?,
         Some(("search", sub_m)) => {
             let output = search_package(sub_m.get_one::<String>("QUERY").unwrap())?;
             print!("{}", output);
         }
+        Some(("show", sub_m)) => show_package(sub_m.get_one::<String>("PACKAGE").unwrap(), sub_m.get_flag("fix-typo"))?,
+        Some(("compare", sub_m)) => compare_package(sub_m.get_one::<String>("PACKAGE").unwrap())?,
+        Some(("apply", sub_m)) => apply_manifest(sub_m.get_one::<String>("manifest").unwrap(), sub_m.get_flag("yes"))?,
+        Some(("fleet", fleet_m)) => match fleet_m.subcommand() {
+            Some(("apply", sub_m)) => fleet::apply(
+                sub_m.get_one::<String>("hosts").unwrap(),
+                sub_m.get_one::<String>("manifest").unwrap(),
+                sub_m.get_one::<String>("rolling").map(String::as_str),
+                sub_m.get_one::<String>("report").map(String::as_str),
+            )?,
+            _ => println!("Invalid fleet subcommand"),
+        },
+        Some(("bundle", bundle_m)) => match bundle_m.subcommand() {
+            Some(("create", sub_m)) => bundle::create(
+                sub_m.get_one::<String>("OUT").unwrap(),
+                sub_m.get_one::<String>("manifest").unwrap(),
+                sub_m.get_one::<String>("from").map(String::as_str),
+                sub_m.get_one::<String>("sign").map(String::as_str),
+            )?,
+            Some(("apply", sub_m)) => apply_bundle(
+                sub_m.get_one::<String>("FILE").unwrap(),
+                sub_m.get_one::<String>("keyring").map(String::as_str),
+                keys::allow_unsigned(),
+                sub_m.get_flag("allow-downgrade"),
+            )?,
+            _ => println!("Invalid bundle subcommand"),
+        },
+        Some(("status", sub_m)) => show_status(sub_m.get_flag("json"), sub_m.get_flag("verbose"))?,
+        Some(("check-update", _)) => check_update()?,
+        Some(("summary", sub_m)) => summary(sub_m.get_flag("motd"))?,
+        Some(("prompt-status", _)) => prompt_status(),
+        Some(("db", db_m)) => match db_m.subcommand() {
+            Some(("diff", sub_m)) => diff_commits(
+                sub_m.get_one::<String>("FROM").map(String::as_str),
+                sub_m.get_one::<String>("TO").map(String::as_str),
+            )?,
+            _ => println!("Invalid db subcommand"),
+        },
+        Some(("compose", compose_m)) => match compose_m.subcommand() {
+            Some(("tree", sub_m)) => {
+                compose::compose_tree(
+                    sub_m.get_one::<String>("TREEFILE").unwrap(),
+                    sub_m.get_one::<String>("repo").map_or(ostree::OSTREE_REPO, String::as_str),
+                    sub_m.get_one::<String>("lockfile").map(String::as_str),
+                    sub_m.get_flag("no-cache"),
+                )?;
+            }
+            Some(("container", sub_m)) => compose::compose_container(
+                sub_m.get_one::<String>("TREEFILE").unwrap(),
+                sub_m.get_one::<String>("repo").map_or(ostree::OSTREE_REPO, String::as_str),
+                sub_m.get_one::<String>("lockfile").map(String::as_str),
+                sub_m.get_flag("no-cache"),
+                sub_m.get_one::<String>("tag").unwrap(),
+            )?,
+            Some(("lockfile", sub_m)) => {
+                let treefile = sub_m.get_one::<String>("TREEFILE").unwrap();
+                let output = match sub_m.get_one::<String>("output") {
+                    Some(output) => output.clone(),
+                    None => Path::new(treefile).with_extension("lock.json").display().to_string(),
+                };
+                compose::write_lockfile(treefile, &output)?
+            }
+            _ => println!("Invalid compose subcommand"),
+        },
+        Some(("conffiles", conf_m)) => match conf_m.subcommand() {
+            Some(("diff", sub_m)) => conffiles::diff(sub_m.get_one::<String>("PATH").map(String::as_str))?,
+            Some(("keep", sub_m)) => {
+                let path = sub_m.get_one::<String>("PATH").unwrap();
+                transaction::run("conffiles-keep", &[], || conffiles::resolve(path, false))?
+            }
+            Some(("replace", sub_m)) => {
+                let path = sub_m.get_one::<String>("PATH").unwrap();
+                transaction::run("conffiles-replace", &[], || conffiles::resolve(path, true))?
+            }
+            _ => conffiles::list()?,
+        },
+        Some(("schema", sub_m)) => schema::print(sub_m.get_one::<String>("NAME").map(String::as_str))?,
+        Some(("daemon", _)) => daemon::serve()?,
+        Some(("queue", queue_m)) => match queue_m.subcommand() {
+            Some(("submit", sub_m)) => {
+                let args: Vec<String> = sub_m.get_many::<String>("ARGS").unwrap().cloned().collect();
+                daemon::submit(&args)?
+            }
+            Some(("list", _)) => daemon::list()?,
+            Some(("cancel", sub_m)) => daemon::cancel(*sub_m.get_one::<u64>("ID").unwrap())?,
+            Some(("attach", sub_m)) => daemon::attach(sub_m.get_one::<u64>("ID").copied())?,
+            _ => println!("Invalid queue subcommand"),
+        },
+        Some(("ex", ex_m)) => {
+            if !config::load_config()?.experimental {
+                return Err("Experimental commands are disabled; set \"experimental\": true in /etc/hacker-ostree/config.json to use them".into());
+            }
+            match ex_m.subcommand() {
+                Some(("live-apply", _)) => {
+                    if !layering::enabled()? {
+                        return Err("Packages are layered live already".into());
+                    }
+                    let _lock = transaction::Lock::acquire()?;
+                    storage::live_apply()?
+                }
+                _ => println!("Invalid ex subcommand"),
+            }
+        }
+        Some(("wait", sub_m)) => wait_idle(sub_m.get_one::<u64>("timeout").copied(), sub_m.get_flag("staged"))?,
+        Some(("history", sub_m)) => match sub_m.subcommand() {
+            Some(("diff", diff_m)) => {
+                let entries = history::load()?;
+                let from = *diff_m.get_one::<u64>("FROM").unwrap();
+                let to = diff_m.get_one::<u64>("TO").copied().or(entries.last().map(|entry| entry.id)).unwrap_or(0);
+                history::print_diff(&history::diff(&entries, from, to)?, diff_m.get_flag("json"))?;
+            }
+            _ => history::print(&history::load()?, sub_m.get_flag("json"))?,
+        },
+        Some(("undo", sub_m)) => undo(sub_m.get_one::<u64>("ID").copied(), sub_m.get_flag("yes"))?,
         Some(("rollback", _)) => rollback()?,
-        Some(("resync", _)) => resync_overlay()?,
+        Some(("note", sub_m)) => set_note(*sub_m.get_one::<usize>("INDEX").unwrap(), sub_m.get_one::<String>("TEXT").map(String::as_str))?,
+        Some(("resync", sub_m)) => transaction::run("resync", &[], || resync_overlay(sub_m.get_flag("full")))?,
+        Some(("clean", sub_m)) if sub_m.get_flag("orphans") => {
+            clean_orphans(sub_m.get_flag("dry-run"))?
+        }
         Some(("clean", _)) => clean_cache()?,
+        Some(("cache", cache_m)) => match cache_m.subcommand() {
+            Some(("verify", _)) => cache::verify()?,
+            _ => println!("Invalid cache subcommand"),
+        },
+        Some(("generations", _)) => storage::print_generations()?,
+        Some(("prune", sub_m)) => {
+            if !sub_m.get_flag("dry-run") {
+                transaction::confirm_destructive("prune", "permanently deletes commits no ref points to from the OSTree repository")?;
+            }
+            ostree::prune_repo(
+                sub_m.get_one::<String>("keep-younger-than").map(String::as_str),
+                sub_m.get_one::<String>("depth").map(String::as_str),
+                sub_m.get_flag("dry-run"),
+            )?
+        }
         Some(("repo", sub_m)) => match sub_m.subcommand() {
             Some(("list", _)) => {
                 let repos = list_repos()?;
                 println!("Repositories:");
                 for (i, repo) in repos.iter().enumerate() {
-                    println!("{}: {}", i, repo);
+                    match &repo.snapshot {
+                        Some(snapshot) => println!("{}: [{}] {} (frozen at {})", i, repo.name, repo.to_line(), snapshot),
+                        None => println!("{}: [{}] {}", i, repo.name, repo.to_line()),
+                    }
+                }
+            }
+            Some(("freeze", m)) => {
+                let timestamp = match m.get_one::<String>("at") {
+                    Some(at) => at.clone(),
+                    None => run_command("date", &["-u", "+%Y%m%dT%H%M%SZ"])?.trim().to_string(),
+                };
+                set_repo_snapshot(m.get_one::<String>("REPO").map(String::as_str), Some(&timestamp))?
+            }
+            Some(("thaw", m)) => set_repo_snapshot(m.get_one::<String>("REPO").map(String::as_str), None)?,
+            Some(("add", add_m)) => add_repo(
+                add_m.get_one::<String>("REPO_LINE").unwrap(),
+                add_m.get_one::<String>("name").map(String::as_str),
+                add_m.get_one::<String>("suite").map(String::as_str),
+            )?,
+            Some(("remove", rm_m)) => remove_repo(rm_m.get_one::<String>("REPO").unwrap())?,
+            Some(("show", show_m)) => show_repo(show_m.get_one::<String>("REPO").unwrap())?,
+            Some(("benchmark", m)) => benchmark_repos(
+                m.get_one::<String>("REPO").map(String::as_str),
+                m.get_flag("apply"),
+                m.get_one::<u64>("max-age").copied(),
+            )?,
+            Some(("add-key", m)) => {
+                keys::add_key(m.get_one::<String>("KEY").unwrap(), m.get_one::<String>("name").map(String::as_str))?;
+            }
+            Some(("list-keys", _)) => {
+                let keys = keys::list_keys()?;
+                if keys.is_empty() {
+                    println!("No keys in {}", repos::KEYRINGS_DIR);
+                }
+                for key in keys {
+                    let expires = match key.expires {
+                        Some(expires) => format!(", expires {}", history::format_time(expires)),
+                        None => String::new(),
+                    };
+                    println!("{}: {} {}{}", key.name, key.fingerprint, key.uids.join(", "), expires);
                 }
             }
-            Some(("add", add_m)) => add_repo(add_m.get_one::<String>("REPO_LINE").unwrap())?,
-            Some(("remove", rm_m)) => {
-                let index: usize = rm_m.get_one::<String>("INDEX").unwrap().parse()?;
-                remove_repo(index)?;
+            Some(("remove-key", m)) => {
+                let name = keys::remove_key(m.get_one::<String>("KEY").unwrap())?;
+                println!("Removed keyring {}", name);
             }
+            Some(("refresh-keys", _)) => keys::refresh_keys()?,
+            Some(("mirror", mirror_m)) => match mirror_m.subcommand() {
+                Some((action @ ("add" | "remove"), m)) => edit_repo_mirror(
+                    m.get_one::<String>("REPO").unwrap(),
+                    m.get_one::<String>("URI").unwrap(),
+                    action == "add",
+                )?,
+                _ => println!("Invalid repo mirror subcommand"),
+            },
             _ => println!("Invalid repo subcommand"),
         },
         _ => {
             println!("Usage: hacker-ostree <COMMAND>\n");
             println!("Commands:");
-            println!("  update          Update APT cache");
+            println!("  update          Refresh repository indexes");
             println!("  upgrade         Upgrade all installed packages in overlay");
             println!("  system-update   Update the system via OSTree pull and deploy");
             println!("  system-upgrade  Alias for system-update");
-            println!("  install         Install a DEB package to overlay");
+            println!("  upgrade-release Move to another release series of the base image");
+            println!("  auto-update     Unattended update honoring battery and metered connections");
+            println!("  install         Install DEB packages to overlay");
             println!("  remove          Remove a DEB package from overlay");
+            println!("  hold            Keep layered packages at their version during upgrade");
+            println!("  unhold          Let upgrade update held packages again");
+            println!("  divert          Add, remove or list dpkg diversions in the overlay");
             println!("  list            List installed packages");
+            println!("  extract         Unpack a package into a directory without installing it");
+            println!("  files           List the files a package owns");
+            println!("  owns            Show which package owns a path");
+            println!("  blame           Show which package and transaction introduced a file or unit");
+            println!("  verify          Verify overlay files against the package database");
+            println!("  verify-system   Check deployment, overlay and signatures in one pass/fail report");
+            println!("  diff --overlay  Report overlay files that drifted from the package database");
             println!("  search          Search for packages in APT repositories");
+            println!("  show            Show package details from APT repositories");
+            println!("  compare         Compare a package's versions in base, overlay and repositories");
+            println!("  apply           Converge this node to a state manifest");
+            println!("  fleet apply     Apply a state manifest across hosts over SSH");
+            println!("  bundle create   Pack updates for an offline node");
+            println!("  bundle apply    Verify and apply an offline bundle");
+            println!("  status          Show deployments with version, packages and advisories");
+            println!("  check-update    Report available base and overlay updates");
+            println!("  summary         Summarize how current the system is, or write it to the motd");
+            println!("  prompt-status   Print a short cached status for shell prompts");
+            println!("  db diff         Compare the packages of two base commits");
+            println!("  compose tree    Build and commit a base image from a treefile");
+            println!("  compose container Compose a treefile and push it as a container image");
+            println!("  compose lockfile Pin a treefile's repos, versions and hashes for repeatable builds");
+            println!("  conffiles       Review new versions of configuration files you edited");
+            println!("  history         Show the recorded transactions");
+            println!("  history diff    Compare the layered packages after two transactions");
+            println!("  undo            Revert the package changes of a transaction");
             println!("  rollback        Rollback to previous OSTree commit");
+            println!("  note            Attach a note to a deployment");
             println!("  resync          Resync overlay with installed packages");
-            println!("  clean           Clean APT cache");
+            println!("  clean           Clean the package download cache");
+            println!("  cache verify    Check cached packages for corruption and evict bad ones");
+            println!("  generations     List committed overlay generation images");
+            println!("  prune           Prune unreachable objects from the OSTree repository");
             println!("  repo list       List repositories");
             println!("  repo add        Add a repository");
-            println!("  repo remove     Remove a repository by index");
+            println!("  repo remove     Remove a repository by name or index");
+            println!("  repo show       Show a repository's configuration");
+            println!("  repo mirror     Manage a repository's failover mirrors");
+            println!("  repo benchmark  Measure mirror latency and throughput");
+            println!("  repo add-key    Trust a signing key for repositories");
+            println!("  repo list-keys  List the trusted signing keys");
+            println!("  repo remove-key Stop trusting a signing key");
+            println!("  repo refresh-keys Fetch updated signing keys before they expire");
+            println!("  repo freeze     Pin repositories to an archive snapshot");
+            println!("  repo thaw       Unpin repositories from their snapshot");
+            println!("  daemon          Run the transaction daemon");
+            println!("  queue           Submit, list, cancel or attach to daemon transactions");
+            println!("  wait            Block until no transaction is running or queued");
+            println!("  ex              Experimental commands, enabled in config.json");
+            println!("  schema          Print the JSON schema of a machine-readable format");
+            println!("  completions     Print a shell completion script");
+            println!("  self-update     Install a newer signed release of hacker-ostree into the overlay");
         }
     }
 
+    // Changes to what the system trusts and installs from are audit events
+    if let Some(("repo", sub_m)) = matches.subcommand() {
+        if let Some(action @ ("add" | "remove" | "freeze" | "thaw" | "add-key" | "remove-key" | "refresh-keys" | "mirror")) = sub_m.subcommand_name() {
+            notify::audit(&format!("repo {}", action), &std::env::args().collect::<Vec<_>>().join(" "));
+        }
+    }
     Ok(())
 }
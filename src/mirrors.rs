@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use crate::repos::Repo;
+use crate::{index, run_command};
+
+const HEALTH_FILE: &str = "/var/lib/hacker-ostree/mirror-health.json";
+// Mirrors that failed within this window are tried after the healthy ones
+const FAILURE_COOLDOWN_SECS: u64 = 3600;
+// Give up measuring a mirror after this many seconds per request
+const BENCHMARK_TIMEOUT: &str = "60";
+// Repos are refreshed concurrently; their mirrors' outcomes are recorded one at a time
+static HEALTH_LOCK: Mutex<()> = Mutex::new(());
+
+// What we remember about a mirror between runs
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct MirrorHealth {
+    pub failures: u32,
+    pub last_failure: u64,
+    pub last_success: u64,
+    // Last measurement by `repo benchmark`
+    #[serde(default)]
+    pub benchmark: Option<Benchmark>,
+}
+
+// Measured speed of a mirror
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Benchmark {
+    pub measured_at: u64,
+    // Time to the first byte of the Release file
+    pub latency_ms: u64,
+    // Bytes per second fetching the largest index, or the Release file before the first update
+    pub throughput: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+pub fn load_health() -> HashMap<String, MirrorHealth> {
+    File::open(HEALTH_FILE)
+        .ok()
+        .and_then(|file| serde_json::from_reader(file).ok())
+        .unwrap_or_default()
+}
+
+fn save_health(health: &HashMap<String, MirrorHealth>) -> Result<(), String> {
+    if let Some(parent) = Path::new(HEALTH_FILE).parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    let file = File::create(HEALTH_FILE).map_err(|e| format!("Failed to create {}: {}", HEALTH_FILE, e))?;
+    serde_json::to_writer_pretty(file, health).map_err(|e| format!("Failed to write to {}: {}", HEALTH_FILE, e))
+}
+
+fn is_cooling_down(health: &HashMap<String, MirrorHealth>, uri: &str) -> bool {
+    health
+        .get(uri)
+        .is_some_and(|h| h.last_failure > h.last_success && now().saturating_sub(h.last_failure) < FAILURE_COOLDOWN_SECS)
+}
+
+// Base URIs of a repo in the order they should be tried: configured order,
+// with mirrors that failed recently moved to the back
+pub fn ordered_uris(repo: &Repo) -> Vec<String> {
+    let repo = &repo.resolved().unwrap_or_else(|_| repo.clone());
+    let health = load_health();
+    let all: Vec<String> = std::iter::once(repo.uri.clone()).chain(repo.mirrors.iter().cloned()).collect();
+    let (healthy, cooling): (Vec<String>, Vec<String>) = all.into_iter().partition(|uri| !is_cooling_down(&health, uri));
+    healthy.into_iter().chain(cooling).collect()
+}
+
+// Remember the outcome of using a mirror
+fn record(uri: &str, ok: bool) -> Result<(), String> {
+    let _guard = HEALTH_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut health = load_health();
+    let entry = health.entry(uri.to_string()).or_default();
+    if ok {
+        entry.last_success = now();
+        entry.failures = 0;
+    } else {
+        entry.last_failure = now();
+        entry.failures += 1;
+    }
+    save_health(&health)
+}
+
+// Run an acquisition against each of a repo's mirrors until one succeeds
+pub fn with_mirrors<T, F>(repo: &Repo, mut op: F) -> Result<T, String>
+where
+    F: FnMut(&str) -> Result<T, String>,
+{
+    let mut errors = Vec::new();
+    for uri in ordered_uris(repo) {
+        match op(&uri) {
+            Ok(value) => {
+                record(&uri, true)?;
+                return Ok(value);
+            }
+            Err(e) => {
+                record(&uri, false)?;
+                if !repo.mirrors.is_empty() {
+                    eprintln!("Warning: {}: mirror {} failed, trying next", repo.name, uri);
+                }
+                errors.push(e);
+            }
+        }
+    }
+    Err(errors.join("\n"))
+}
+
+// Time to first byte in seconds and transfer rate in bytes per second of fetching a URL
+fn measure(url: &str) -> Result<(f64, f64), String> {
+    let output = run_command("curl", &[
+        "--silent",
+        "--show-error",
+        "--fail",
+        "--location",
+        "--max-time", BENCHMARK_TIMEOUT,
+        "--output", "/dev/null",
+        "--write-out", "%{time_starttransfer} %{speed_download}",
+        url,
+    ])?;
+    let mut fields = output.split_whitespace().map(str::parse::<f64>);
+    match (fields.next(), fields.next()) {
+        (Some(Ok(latency)), Some(Ok(speed))) => Ok((latency, speed)),
+        _ => Err(format!("Unexpected curl output '{}'", output.trim())),
+    }
+}
+
+// Largest index the repo's last refresh fetched, relative to its Release file
+fn largest_index(repo: &Repo) -> Option<String> {
+    let record = index::load_record(repo).ok()??;
+    let text = fs::read_to_string(format!("{}/InRelease", index::repo_index_dir(repo))).ok()?;
+    let release = index::parse_release(&text);
+    record
+        .files
+        .iter()
+        .filter_map(|file| release.sha256.iter().find(|entry| entry.path == file.source))
+        .max_by_key(|entry| entry.size)
+        .map(|entry| entry.path.clone())
+}
+
+// A mirror's URI with its measurement, or why it couldn't be measured
+pub type MirrorResult = (String, Result<Benchmark, String>);
+
+fn measure_mirror(repo: &Repo, uri: &str, sample: Option<&str>) -> Result<Benchmark, String> {
+    let dists = repo.dists_url(uri);
+    let (latency, speed) = measure(&format!("{}/InRelease", dists)).or_else(|_| measure(&format!("{}/Release", dists)))?;
+    let throughput = match sample {
+        Some(path) => measure(&format!("{}/{}", dists, path))?.1,
+        None => speed,
+    };
+    Ok(Benchmark { measured_at: now(), latency_ms: (latency * 1000.0).round() as u64, throughput: throughput as u64 })
+}
+
+// Measure every URI of a repo, reusing results younger than `max_age` seconds. Results are
+// kept with the mirror health; unreachable mirrors count as failures.
+pub fn benchmark(repo: &Repo, max_age: Option<u64>) -> Result<Vec<MirrorResult>, String> {
+    let repo = repo.resolved()?;
+    let sample = largest_index(&repo);
+    let mut results = Vec::new();
+    for uri in std::iter::once(repo.uri.clone()).chain(repo.mirrors.iter().cloned()) {
+        let previous = load_health().get(&uri).and_then(|h| h.benchmark.clone());
+        if let Some(benchmark) = previous.filter(|b| max_age.is_some_and(|age| now().saturating_sub(b.measured_at) < age)) {
+            results.push((uri, Ok(benchmark)));
+            continue;
+        }
+        let result = measure_mirror(&repo, &uri, sample.as_deref());
+        record(&uri, result.is_ok())?;
+        let mut health = load_health();
+        health.entry(uri.clone()).or_default().benchmark = result.as_ref().ok().cloned();
+        save_health(&health)?;
+        results.push((uri, result));
+    }
+    Ok(results)
+}
@@ -0,0 +1,55 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use crate::ostree::{self, Deployment};
+
+// Free-form notes by deployment ("<stateroot>/<checksum>.<serial>"). They live beside the
+// deployments rather than in their origin files, which ostree copies into every deployment
+// made from them.
+const NOTES_FILE: &str = "/var/lib/hacker-ostree/notes.json";
+
+// Note given with --note for the deployment this run creates
+static PENDING: Mutex<Option<String>> = Mutex::new(None);
+
+pub fn set_pending(note: Option<String>) {
+    *PENDING.lock().unwrap_or_else(|e| e.into_inner()) = note;
+}
+
+fn key(deployment: &Deployment) -> String {
+    format!("{}/{}.{}", deployment.stateroot, deployment.checksum, deployment.serial)
+}
+
+fn load() -> Result<BTreeMap<String, String>, String> {
+    match fs::read_to_string(NOTES_FILE) {
+        Ok(text) => serde_json::from_str(&text).map_err(|e| format!("Failed to parse {}: {}", NOTES_FILE, e)),
+        Err(_) => Ok(BTreeMap::new()),
+    }
+}
+
+pub fn note(deployment: &Deployment) -> Result<Option<String>, String> {
+    Ok(load()?.remove(&key(deployment)))
+}
+
+// Attach a note to a deployment, or remove its note with None. Notes of deployments that no
+// longer exist are dropped, so a later deployment reusing the name doesn't inherit one.
+pub fn set(deployment: &Deployment, note: Option<&str>) -> Result<(), String> {
+    let existing: Vec<String> = ostree::all_deployments()?.iter().map(key).collect();
+    let mut notes = load()?;
+    notes.retain(|name, _| existing.contains(name));
+    match note {
+        Some(note) => notes.insert(key(deployment), note.to_string()),
+        None => notes.remove(&key(deployment)),
+    };
+    if let Some(parent) = Path::new(NOTES_FILE).parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    let text = serde_json::to_string_pretty(&notes).map_err(|e| format!("Failed to serialize notes: {}", e))?;
+    fs::write(NOTES_FILE, text).map_err(|e| format!("Failed to write {}: {}", NOTES_FILE, e))
+}
+
+// Give the deployment just created the pending note, or none
+pub fn attach_pending(deployment: &Deployment) -> Result<(), String> {
+    let pending = PENDING.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    set(deployment, pending.as_deref())
+}
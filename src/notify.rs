@@ -0,0 +1,182 @@
+use std::fs;
+use std::io::Write;
+use std::process::{Command as ProcessCommand, Stdio};
+use serde_json::json;
+use tempfile::NamedTempFile;
+use crate::config::{load_config, Config};
+use crate::history;
+use crate::run_command;
+
+fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+// POST the event as JSON to the webhook
+fn post_webhook(url: &str, body: &serde_json::Value) -> Result<(), String> {
+    let mut temp = NamedTempFile::new().map_err(|e| format!("Failed to create temp file: {}", e))?;
+    temp.write_all(body.to_string().as_bytes()).map_err(|e| format!("Failed to write to temp file: {}", e))?;
+    let data = format!("@{}", temp.path().display());
+    run_command("curl", &[
+        "--silent",
+        "--show-error",
+        "--fail",
+        "--max-time", "30",
+        "-X", "POST",
+        "-H", "Content-Type: application/json",
+        "--data-binary", &data,
+        url,
+    ])?;
+    Ok(())
+}
+
+// Hand a plain text mail to the local sendmail
+fn send_mail(to: &str, subject: &str, text: &str) -> Result<(), String> {
+    let mut child = ProcessCommand::new("sendmail")
+        .args(["-t"])
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to execute sendmail: {}", e))?;
+    let message = format!("To: {}\nSubject: {}\nContent-Type: text/plain; charset=utf-8\n\n{}\n", to, subject, text);
+    child
+        .stdin
+        .take()
+        .ok_or_else(|| "Failed to open sendmail input".to_string())?
+        .write_all(message.as_bytes())
+        .map_err(|e| format!("Failed to write to sendmail: {}", e))?;
+    let status = child.wait().map_err(|e| format!("Failed to wait for sendmail: {}", e))?;
+    if !status.success() {
+        return Err(format!("Command failed: sendmail ({})", status));
+    }
+    Ok(())
+}
+
+// Port of syslog over TLS (RFC 5425)
+const SYSLOG_TLS_PORT: &str = "6514";
+// Facility "log audit" (13) in the RFC 5424 priority
+const SYSLOG_FACILITY: u32 = 13;
+// Structured data ID; 32473 is the enterprise number reserved for documentation (RFC 5612)
+const SYSLOG_SD_ID: &str = "hackerostree@32473";
+
+// Escape a structured data parameter value
+fn sd_escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace(']', "\\]")
+}
+
+// An event as one RFC 5424 message: warnings for failures and rollbacks, notices otherwise
+fn syslog_message(event: &str, host: &str, user: &str, summary: &str, details: &str) -> Result<String, String> {
+    let severity = if event == "rollback" || summary.contains("failed") { 4 } else { 5 };
+    let timestamp = run_command("date", &["-u", "+%Y-%m-%dT%H:%M:%SZ"])?;
+    let text: Vec<&str> = details.lines().filter(|line| !line.trim().is_empty()).collect();
+    Ok(format!(
+        "<{}>1 {} {} hacker-ostree {} {} [{} user=\"{}\"] {}: {}",
+        SYSLOG_FACILITY * 8 + severity,
+        timestamp.trim(),
+        host,
+        std::process::id(),
+        event,
+        SYSLOG_SD_ID,
+        sd_escape(user),
+        summary,
+        text.join("; ")
+    ))
+}
+
+// Deliver a message to a syslog server over TLS, with RFC 5425 octet-counting framing
+fn send_syslog(server: &str, ca: Option<&str>, message: &str) -> Result<(), String> {
+    let (host, port) = match server.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => (host, port),
+        _ => (server, SYSLOG_TLS_PORT),
+    };
+    let connect = format!("{}:{}", host, port);
+    let mut args = vec![
+        "30", "openssl", "s_client", "-connect", &connect, "-servername", host, "-verify_hostname", host,
+        "-verify_return_error", "-quiet", "-no_ign_eof",
+    ];
+    if let Some(ca) = ca {
+        args.extend(["-CAfile", ca]);
+    }
+    let mut child = ProcessCommand::new("timeout")
+        .args(&args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to execute openssl: {}", e))?;
+    child
+        .stdin
+        .take()
+        .ok_or_else(|| "Failed to open openssl input".to_string())?
+        .write_all(format!("{} {}", message.len(), message).as_bytes())
+        .map_err(|e| format!("Failed to write to openssl: {}", e))?;
+    let output = child.wait_with_output().map_err(|e| format!("Failed to wait for openssl: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("Failed to deliver to {}: {}", connect, stderr.lines().last().unwrap_or_default()));
+    }
+    Ok(())
+}
+
+// Forward an event to the remote syslog server and audit collector
+fn forward(config: &Config, event: &str, host: &str, summary: &str, details: &str) {
+    let user = history::invoking_user();
+    if let Some(server) = &config.syslog_server {
+        let sent = syslog_message(event, host, &user, summary, details)
+            .and_then(|message| send_syslog(server, config.syslog_ca.as_deref(), &message));
+        if let Err(e) = sent {
+            eprintln!("Warning: failed to forward to syslog server: {}", e);
+        }
+    }
+    if let Some(url) = &config.audit_collector {
+        let body = json!({
+            "event": event,
+            "host": host,
+            "user": user,
+            "timestamp": history::now(),
+            "summary": summary,
+            "details": details,
+        });
+        if let Err(e) = post_webhook(url, &body) {
+            eprintln!("Warning: failed to forward to audit collector: {}", e);
+        }
+    }
+}
+
+// Record a change to repositories or keys with the syslog server and audit collector only;
+// they aren't worth a webhook call or mail
+pub fn audit(summary: &str, details: &str) {
+    if let Ok(config) = load_config() {
+        forward(&config, "config-change", &hostname(), summary, details);
+    }
+}
+
+// Report an event to the configured webhook and mail targets, and forward it. `event` is one of
+// "transaction", "updates-available" or "rollback". Delivery problems are only warned
+// about so they never fail the operation being reported.
+pub fn send(event: &str, summary: &str, details: &str) {
+    let config = match load_config() {
+        Ok(config) => config,
+        Err(_) => return,
+    };
+    let host = hostname();
+    if let Some(url) = &config.notify_webhook {
+        let body = json!({
+            "event": event,
+            "host": host,
+            "timestamp": history::now(),
+            "summary": summary,
+            "details": details,
+        });
+        if let Err(e) = post_webhook(url, &body) {
+            eprintln!("Warning: failed to notify webhook: {}", e);
+        }
+    }
+    if let Some(to) = &config.notify_email {
+        let subject = format!("[hacker-ostree] {}: {}", host, summary);
+        if let Err(e) = send_mail(to, &subject, details) {
+            eprintln!("Warning: failed to send notification mail: {}", e);
+        }
+    }
+    forward(&config, event, &host, summary, details);
+}
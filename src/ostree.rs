@@ -0,0 +1,713 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use crate::config::Config;
+use crate::{notes, run_command, run_command_streamed, ROOT_ENV};
+
+pub const OSTREE_REPO: &str = "/ostree/repo";
+// Each stateroot's /var lives below /ostree/deploy/<stateroot>/var
+pub const DEPLOY_DIR: &str = "/ostree/deploy";
+
+// Stateroot given with --stateroot; the booted one otherwise
+static STATEROOT: Mutex<Option<String>> = Mutex::new(None);
+// Created by the initramfs when booting an OSTree deployment
+const BOOTED_MARKER: &str = "/run/ostree-booted";
+// Set on hosts that aren't OSTree-booted, where only the overlay is managed
+static OVERLAY_ONLY: AtomicBool = AtomicBool::new(false);
+
+// Whether this host was booted into an OSTree deployment. A run relocated below
+// HACKER_OSTREE_ROOT looks for the marker there, so a test decides what it runs on.
+pub fn is_booted() -> bool {
+    let root = std::env::var(ROOT_ENV).unwrap_or_default();
+    Path::new(&format!("{}{}", root.trim_end_matches('/'), BOOTED_MARKER)).exists()
+}
+
+// Manage only the overlay: there are no deployments, so nothing asks ostree about them
+pub fn set_overlay_only(enabled: bool) {
+    OVERLAY_ONLY.store(enabled, Ordering::Relaxed);
+}
+
+pub fn overlay_only() -> bool {
+    OVERLAY_ONLY.load(Ordering::Relaxed)
+}
+
+// One entry from `ostree admin status`
+#[derive(Debug, Clone, Default)]
+pub struct Deployment {
+    pub stateroot: String,
+    pub checksum: String,
+    pub serial: String,
+    pub pinned: bool,
+    pub booted: bool,
+    // Written out only when the system shuts down
+    pub staged: bool,
+}
+
+// Parse the text output of `ostree admin status` into deployments, newest first
+pub fn parse_deployments(status: &str) -> Vec<Deployment> {
+    let mut deployments: Vec<Deployment> = Vec::new();
+    for line in status.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let indented = line.starts_with("    ");
+        if !indented {
+            // Deployment lines look like "* <stateroot> <checksum>.<serial> (flags)"
+            let mut words = line.trim_start_matches('*').split_whitespace();
+            let stateroot = words.next().unwrap_or_default().to_string();
+            let (checksum, serial) = match words.next().and_then(|id| id.split_once('.')) {
+                Some((checksum, serial)) => (checksum.to_string(), serial.to_string()),
+                None => continue,
+            };
+            deployments.push(Deployment {
+                stateroot,
+                checksum,
+                serial,
+                booted: line.starts_with('*'),
+                staged: line.contains("(staged)"),
+                ..Default::default()
+            });
+            continue;
+        }
+        let current = match deployments.last_mut() {
+            Some(deployment) => deployment,
+            None => continue,
+        };
+        let (key, value) = match line.trim().split_once(':') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => continue,
+        };
+        if key == "Pinned" {
+            current.pinned = value == "yes";
+        }
+    }
+    deployments
+}
+
+// Deployments of every stateroot on this system
+pub fn all_deployments() -> Result<Vec<Deployment>, String> {
+    if overlay_only() {
+        return Ok(Vec::new());
+    }
+    let status = run_command("ostree", &["admin", "status"])?;
+    Ok(parse_deployments(&status))
+}
+
+// Stateroots with deployments, the booted one first
+pub fn stateroots(deployments: &[Deployment]) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for deployment in deployments.iter().filter(|d| d.booted).chain(deployments) {
+        if !names.contains(&deployment.stateroot) {
+            names.push(deployment.stateroot.clone());
+        }
+    }
+    names
+}
+
+// Target another stateroot than the booted one for this run
+pub fn set_stateroot(name: Option<String>) {
+    *STATEROOT.lock().unwrap_or_else(|e| e.into_inner()) = name;
+}
+
+fn target(deployments: &[Deployment]) -> Result<String, String> {
+    let names = stateroots(deployments);
+    match STATEROOT.lock().unwrap_or_else(|e| e.into_inner()).clone() {
+        Some(name) if names.contains(&name) => Ok(name),
+        Some(name) => Err(format!("No stateroot named {}; stateroots on this system: {}", name, names.join(", "))),
+        None => names.into_iter().next().ok_or_else(|| "No deployments found".to_string()),
+    }
+}
+
+// Stateroot operations apply to
+pub fn stateroot() -> Result<String, String> {
+    target(&all_deployments()?)
+}
+
+// Whether operations apply to a stateroot other than the booted one
+pub fn targets_other_stateroot() -> Result<bool, String> {
+    if overlay_only() {
+        return Ok(false);
+    }
+    let deployments = all_deployments()?;
+    let target = target(&deployments)?;
+    Ok(!deployments.iter().any(|d| d.booted && d.stateroot == target))
+}
+
+// Deployments of the targeted stateroot, newest first
+pub fn deployments() -> Result<Vec<Deployment>, String> {
+    if overlay_only() {
+        return Ok(Vec::new());
+    }
+    let deployments = all_deployments()?;
+    let target = target(&deployments)?;
+    Ok(deployments.into_iter().filter(|d| d.stateroot == target).collect())
+}
+
+// Deployment operations start from: the booted one, or the default one of a stateroot
+// that isn't booted
+pub fn current() -> Result<Option<Deployment>, String> {
+    let deployments = deployments()?;
+    Ok(deployments.iter().find(|d| d.booted).or(deployments.first()).cloned())
+}
+
+// `ostree admin` numbers deployments across all stateroots; map an index into the targeted
+// stateroot's deployments to that numbering
+fn admin_index(index: usize) -> Result<String, String> {
+    let deployments = all_deployments()?;
+    let target = target(&deployments)?;
+    deployments
+        .iter()
+        .enumerate()
+        .filter(|(_, d)| d.stateroot == target)
+        .nth(index)
+        .map(|(i, _)| i.to_string())
+        .ok_or_else(|| format!("{} has no deployment {}", target, index))
+}
+
+// Deploy a commit as the new default of the targeted stateroot, with the note given by
+// --note if any
+pub fn deploy(refspec: &str) -> Result<String, String> {
+    let os = format!("--os={}", stateroot()?);
+    let output = run_command("ostree", &["admin", "deploy", &os, refspec])?;
+    if let Some(deployment) = deployments()?.first() {
+        notes::attach_pending(deployment)?;
+    }
+    Ok(output)
+}
+
+// Remove a deployment of the targeted stateroot
+pub fn undeploy(index: usize) -> Result<String, String> {
+    run_command("ostree", &["admin", "undeploy", &admin_index(index)?])
+}
+
+// Make a deployment of the targeted stateroot the default
+pub fn set_default(index: usize) -> Result<String, String> {
+    run_command("ostree", &["admin", "set-default", &admin_index(index)?])
+}
+
+// Check a deployment for tampering: every object in the repo with fsck, then the
+// deployment's /usr checkout against its commit. Returns the problems found, one per line;
+// /etc and /var legitimately differ from the commit and aren't compared.
+pub fn verify_deployment(deployment: &Deployment) -> Result<Vec<String>, String> {
+    let repo = format!("--repo={}", OSTREE_REPO);
+    let mut problems = Vec::new();
+    if let Err(e) = run_command("ostree", &["fsck", &repo]) {
+        let errors: Vec<String> = e.lines().filter(|line| line.to_lowercase().contains("error")).map(str::to_string).collect();
+        // A failed fsck is a problem even when it explains itself in other words
+        if errors.is_empty() {
+            problems.push(e.trim().to_string());
+        } else {
+            problems.extend(errors);
+        }
+    }
+    let checkout = format!(
+        "{}/{}/deploy/{}.{}",
+        DEPLOY_DIR, deployment.stateroot, deployment.checksum, deployment.serial
+    );
+    let diff = run_command("ostree", &["diff", &repo, &deployment.checksum, &checkout])?;
+    for line in diff.lines() {
+        if let Some((kind, path)) = line.split_once(char::is_whitespace) {
+            if path.trim_start().starts_with("/usr/") {
+                let change = match kind {
+                    "M" => "modified",
+                    "A" => "added",
+                    "D" => "deleted",
+                    _ => kind,
+                };
+                problems.push(format!("{}: {}", path.trim_start(), change));
+            }
+        }
+    }
+    Ok(problems)
+}
+
+// Where a deployment's commit came from, for auditing what a machine runs
+#[derive(Debug, Default, PartialEq)]
+pub struct Provenance {
+    // Contents of the deployment's .origin file
+    pub origin: Option<String>,
+    pub refspec: Option<String>,
+    pub remote: Option<String>,
+    pub url: Option<String>,
+    pub parent: Option<String>,
+    pub content_checksum: Option<String>,
+    // Signing key and whether its signature is good, for each signature on the commit
+    pub signatures: Vec<(String, bool)>,
+}
+
+// Gather a deployment's provenance. Anything ostree can't tell, e.g. the parent of a commit
+// pulled without history, is left out rather than failing the whole report.
+pub fn provenance(deployment: &Deployment) -> Provenance {
+    let repo = format!("--repo={}", OSTREE_REPO);
+    let origin_path = format!(
+        "{}/{}/deploy/{}.{}.origin",
+        DEPLOY_DIR, deployment.stateroot, deployment.checksum, deployment.serial
+    );
+    let origin = fs::read_to_string(&origin_path).ok();
+    let refspec = origin.as_deref().and_then(|text| {
+        text.lines()
+            .find_map(|line| line.trim().strip_prefix("refspec=").map(|refspec| refspec.trim().to_string()))
+    });
+    let remote = refspec.as_deref().and_then(|refspec| refspec.split_once(':')).map(|(remote, _)| remote.to_string());
+    let url = remote
+        .as_deref()
+        .and_then(|remote| run_command("ostree", &["remote", "show-url", &repo, remote]).ok())
+        .map(|url| url.trim().to_string())
+        .filter(|url| !url.is_empty());
+    let parent = run_command("ostree", &["rev-parse", &repo, &format!("{}^", deployment.checksum)])
+        .ok()
+        .map(|parent| parent.trim().to_string())
+        .filter(|parent| !parent.is_empty());
+    let mut show = vec!["show".to_string(), repo.clone()];
+    if let Some(remote) = &remote {
+        show.push(format!("--gpg-verify-remote={}", remote));
+    }
+    show.push(deployment.checksum.clone());
+    let show: Vec<&str> = show.iter().map(String::as_str).collect();
+    let (content_checksum, signatures) = match run_command("ostree", &show) {
+        Ok(output) => parse_show(&output),
+        Err(_) => (None, Vec::new()),
+    };
+    Provenance { origin, refspec, remote, url, parent, content_checksum, signatures }
+}
+
+// Content checksum and signatures from the text of `ostree show`
+fn parse_show(output: &str) -> (Option<String>, Vec<(String, bool)>) {
+    let mut content_checksum = None;
+    let mut signatures: Vec<(String, bool)> = Vec::new();
+    for line in output.lines().map(str::trim) {
+        if let Some(checksum) = line.strip_prefix("ContentChecksum:") {
+            content_checksum = Some(checksum.trim().to_string());
+        } else if line.starts_with("Signature made") {
+            // "... using RSA key ID 1234ABCD" or, from newer gpg, "... using RSA key <fingerprint>"
+            let key = line.rsplit(' ').next().unwrap_or_default();
+            signatures.push((key.to_string(), false));
+        } else if line.starts_with("Good signature") {
+            if let Some(last) = signatures.last_mut() {
+                last.1 = true;
+            }
+        }
+    }
+    (content_checksum, signatures)
+}
+
+// History and commit selection for a base pull
+#[derive(Debug, Clone, Default)]
+pub struct PullOptions {
+    // Parent commits to fetch: 0 for only the newest, -1 for full history
+    pub depth: i32,
+    // Pull this exact commit instead of the ref's current head
+    pub commit: Option<String>,
+}
+
+impl PullOptions {
+    // What to pull of `branch`: its head, or the selected commit on it
+    pub fn target(&self, branch: &str) -> String {
+        match &self.commit {
+            Some(commit) => format!("{}@{}", branch, commit),
+            None => branch.to_string(),
+        }
+    }
+}
+
+// Pull a branch from a remote honoring depth and commit selection.
+// Metadata and objects are fetched as separate phases, each retried with backoff;
+// ostree keeps already-downloaded objects in the repo's staging area, so a retry
+// resumes where the failed attempt stopped instead of starting over.
+pub fn pull(remote: &str, branch: &str, opts: &PullOptions, config: &Config) -> Result<(), String> {
+    let repo_arg = format!("--repo={}", OSTREE_REPO);
+    let depth_arg = format!("--depth={}", opts.depth);
+    let target = opts.target(branch);
+    let phases: [(&str, Vec<&str>); 2] = [
+        ("commit metadata", vec!["pull", &repo_arg, "--commit-metadata-only", &depth_arg, remote, &target]),
+        ("objects", vec!["pull", &repo_arg, &depth_arg, remote, &target]),
+    ];
+    for (index, (phase, args)) in phases.iter().enumerate() {
+        println!("[{}/{}] Pulling {} for {}:{}", index + 1, phases.len(), phase, remote, branch);
+        with_retries(config, || run_command_streamed("ostree", args))?;
+    }
+    Ok(())
+}
+
+// Fetch only the commit objects of a branch, enough to read the metadata of what a pull would bring
+pub fn pull_metadata(remote: &str, branch: &str, config: &Config) -> Result<(), String> {
+    let repo_arg = format!("--repo={}", OSTREE_REPO);
+    with_retries(config, || run_command("ostree", &["pull", &repo_arg, "--commit-metadata-only", remote, branch]).map(|_| ()))
+}
+
+// Retry a network operation with exponential backoff
+fn with_retries<F>(config: &Config, mut op: F) -> Result<(), String>
+where
+    F: FnMut() -> Result<(), String>,
+{
+    let mut delay = config.retry_delay_secs;
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < config.network_retries => {
+                attempt += 1;
+                eprintln!("{}; retrying in {}s (attempt {}/{})", e, delay, attempt, config.network_retries);
+                thread::sleep(Duration::from_secs(delay));
+                delay = delay.saturating_mul(2);
+            }
+            Err(e) => return Err(format!("{} (gave up after {} retries)", e, attempt)),
+        }
+    }
+}
+
+// Commit checksum a ref or commit prefix points to
+pub fn rev_parse(refspec: &str) -> Result<String, String> {
+    Ok(run_command("ostree", &["rev-parse", "--repo", OSTREE_REPO, refspec])?.trim().to_string())
+}
+
+// String value of a commit metadata key, None if the commit doesn't carry it
+pub fn metadata_string(repo: &str, rev: &str, key: &str) -> Result<Option<String>, String> {
+    let repo_arg = format!("--repo={}", repo);
+    let key_arg = format!("--print-metadata-key={}", key);
+    match run_command("ostree", &["show", &repo_arg, &key_arg, rev]) {
+        Ok(output) => Ok(Some(parse_gvariant_string(output.trim()))),
+        Err(e) if e.contains("No such metadata key") => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Undo the quoting of a GVariant text-format string ('...' or "...")
+fn parse_gvariant_string(text: &str) -> String {
+    let inner = text
+        .strip_prefix('\'')
+        .and_then(|t| t.strip_suffix('\''))
+        .or_else(|| text.strip_prefix('"').and_then(|t| t.strip_suffix('"')))
+        .unwrap_or(text);
+    let mut value = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => value.push('\n'),
+            Some('t') => value.push('\t'),
+            Some('r') => value.push('\r'),
+            Some('u') => {
+                let code: String = chars.by_ref().take(4).collect();
+                if let Some(c) = u32::from_str_radix(&code, 16).ok().and_then(char::from_u32) {
+                    value.push(c);
+                }
+            }
+            Some(other) => value.push(other),
+            None => {}
+        }
+    }
+    value
+}
+
+// Commit a directory tree, or the tree of an existing commit given as "ref=<rev>", to a
+// branch with string metadata; returns the new checksum
+pub fn commit_tree(repo: &str, branch: &str, tree: &str, subject: &str, metadata: &[(&str, String)]) -> Result<String, String> {
+    let tree = if tree.starts_with("ref=") { tree.to_string() } else { format!("dir={}", tree) };
+    let mut args = vec![
+        "commit".to_string(),
+        format!("--repo={}", repo),
+        format!("--branch={}", branch),
+        format!("--tree={}", tree),
+        format!("--subject={}", subject),
+    ];
+    for (key, value) in metadata {
+        args.push(format!("--add-metadata-string={}={}", key, value));
+    }
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    Ok(run_command("ostree", &args)?.trim().to_string())
+}
+
+// Paths added (A), modified (M) or removed (D) between two commits, with /usr/etc reported as /etc
+pub fn diff_paths(from: &str, to: &str) -> Result<Vec<(char, String)>, String> {
+    let repo_arg = format!("--repo={}", OSTREE_REPO);
+    let output = run_command("ostree", &["diff", &repo_arg, from, to])?;
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let status = fields.next()?.chars().next()?;
+            let path = fields.next()?;
+            let path = match path.strip_prefix("/usr/etc") {
+                Some(rest) => format!("/etc{}", rest),
+                None => path.to_string(),
+            };
+            Some((status, path))
+        })
+        .collect())
+}
+
+// Contents of a file in a commit
+pub fn cat(rev: &str, path: &str) -> Result<String, String> {
+    run_command("ostree", &["cat", "--repo", OSTREE_REPO, rev, path])
+}
+
+// Commit date in seconds since the epoch
+pub fn commit_timestamp(rev: &str) -> Result<i64, String> {
+    let output = run_command("ostree", &["show", "--repo", OSTREE_REPO, rev])?;
+    let date = output
+        .lines()
+        .find_map(|line| line.strip_prefix("Date:"))
+        .map(str::trim)
+        .ok_or_else(|| format!("No date in commit {}", rev))?;
+    let seconds = run_command("date", &["-d", date, "+%s"])?;
+    seconds.trim().parse().map_err(|e| format!("Failed to parse date of commit {}: {}", rev, e))
+}
+
+// Subject and body of a commit, without blank lines
+pub fn commit_message(rev: &str) -> Result<Vec<String>, String> {
+    let output = run_command("ostree", &["show", "--repo", OSTREE_REPO, rev])?;
+    // The message is the only part indented by four spaces
+    Ok(output
+        .lines()
+        .filter_map(|line| line.strip_prefix("    "))
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+// Download and unpacked size of pulling a branch, as ostree formats them, estimated from the
+// static delta the remote publishes; None when it publishes none for this update
+pub fn estimate_pull(remote: &str, target: &str) -> Result<Option<(String, String)>, String> {
+    let repo_arg = format!("--repo={}", OSTREE_REPO);
+    let output = match run_command("ostree", &["pull", &repo_arg, "--dry-run", remote, target]) {
+        Ok(output) => output,
+        Err(e) if e.contains("delta") => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    // "Delta update: 0/3 parts, 0 bytes/25.1 MB, 80.0 MB total uncompressed"
+    let Some(line) = output.lines().rev().find_map(|line| line.trim().strip_prefix("Delta update:")) else {
+        return Ok(None);
+    };
+    let fields: Vec<&str> = line.split(", ").collect();
+    let download = fields.get(1).and_then(|sizes| sizes.rsplit_once('/')).map(|(_, total)| total.to_string());
+    let unpacked = fields.get(2).and_then(|size| size.strip_suffix(" total uncompressed")).map(str::to_string);
+    Ok(download.zip(unpacked))
+}
+
+// Write a static delta between two commits (from scratch without `from`) to a file
+pub fn generate_delta(from: Option<&str>, to: &str, filename: &str) -> Result<(), String> {
+    let repo_arg = format!("--repo={}", OSTREE_REPO);
+    let to_arg = format!("--to={}", to);
+    let file_arg = format!("--filename={}", filename);
+    let from_arg = match from {
+        Some(from) => format!("--from={}", from),
+        None => "--empty".to_string(),
+    };
+    run_command("ostree", &["static-delta", "generate", &repo_arg, &from_arg, &to_arg, "--inline", &file_arg])?;
+    Ok(())
+}
+
+// Import a static delta file and point a ref at the commit it produces
+pub fn apply_delta(filename: &str, refspec: &str, checksum: &str) -> Result<(), String> {
+    let repo_arg = format!("--repo={}", OSTREE_REPO);
+    run_command("ostree", &["static-delta", "apply-offline", &repo_arg, filename])?;
+    let create_arg = format!("--create={}", refspec);
+    run_command("ostree", &["refs", &repo_arg, "--force", &create_arg, checksum])?;
+    Ok(())
+}
+
+// Size of the ostree repository in bytes
+fn repo_size() -> Result<u64, String> {
+    let output = run_command("du", &["-sb", OSTREE_REPO])?;
+    output
+        .split_whitespace()
+        .next()
+        .and_then(|size| size.parse().ok())
+        .ok_or_else(|| format!("Failed to parse size of {}", OSTREE_REPO))
+}
+
+// Prune unreachable objects from the ostree repository, refusing if a deployment is unprotected
+pub fn prune_repo(keep_younger_than: Option<&str>, depth: Option<&str>, dry_run: bool) -> Result<(), String> {
+    // Pruning with --refs-only keeps everything reachable from a ref, so every
+    // deployment (pinned ones in particular) must still be referenced by one.
+    let refs = run_command("ostree", &["refs", "--repo", OSTREE_REPO])?;
+    let mut protected = HashSet::new();
+    for name in refs.lines().map(str::trim).filter(|name| !name.is_empty()) {
+        if let Ok(rev) = run_command("ostree", &["rev-parse", "--repo", OSTREE_REPO, name]) {
+            protected.insert(rev.trim().to_string());
+        }
+    }
+    for deployment in all_deployments()? {
+        if !protected.contains(&deployment.checksum) {
+            return Err(format!(
+                "Refusing to prune: deployment {}.{}{} is not referenced by any ref",
+                deployment.checksum,
+                deployment.serial,
+                if deployment.pinned { " (pinned)" } else { "" }
+            ));
+        }
+    }
+
+    let size_before = repo_size()?;
+    let repo_arg = format!("--repo={}", OSTREE_REPO);
+    let mut args = vec!["prune", repo_arg.as_str(), "--refs-only"];
+    let keep_arg;
+    if let Some(age) = keep_younger_than {
+        keep_arg = format!("--keep-younger-than={}", age);
+        args.push(&keep_arg);
+    }
+    let depth_arg;
+    if let Some(depth) = depth {
+        depth_arg = format!("--depth={}", depth);
+        args.push(&depth_arg);
+    }
+    if dry_run {
+        args.push("--no-prune");
+    }
+    let output = run_command("ostree", &args)?;
+    let size_after = repo_size()?;
+
+    for line in output.lines().filter(|line| !line.trim().is_empty()) {
+        println!("{}", line.trim());
+    }
+    println!(
+        "Repository size: {} MiB -> {} MiB ({} MiB reclaimed)",
+        size_before / (1024 * 1024),
+        size_after / (1024 * 1024),
+        size_before.saturating_sub(size_after) / (1024 * 1024)
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::testing::{call, failing, replaying};
+
+    const STATUS: &str = "  hackeros ccc333.0 (staged)
+    Version: 2024.2
+* hackeros aaa111.0
+    Version: 2024.1
+    Pinned: yes
+  testing ddd444.0
+    Version: 1.0
+";
+
+    #[test]
+    fn parses_admin_status() {
+        let deployments = parse_deployments(STATUS);
+        assert_eq!(deployments.len(), 3);
+        assert_eq!((deployments[1].checksum.as_str(), deployments[1].serial.as_str()), ("aaa111", "0"));
+        assert!(deployments[1].booted && deployments[1].pinned);
+        assert!(!deployments[0].booted && !deployments[0].pinned);
+        assert!(deployments[0].staged && !deployments[1].staged);
+        assert_eq!(stateroots(&deployments), ["hackeros", "testing"]);
+    }
+
+    #[test]
+    fn another_stateroot_uses_its_own_deployments_and_admin_index() {
+        let calls = vec![
+            call("ostree", &["admin", "status"], STATUS),
+            call("ostree", &["admin", "undeploy", "2"], ""),
+        ];
+        let (result, log) = replaying(calls, || {
+            set_stateroot(Some("testing".to_string()));
+            let result = (targets_other_stateroot(), current().map(|d| d.map(|d| d.checksum)), undeploy(0));
+            set_stateroot(None);
+            result
+        });
+        assert_eq!(result.0, Ok(true));
+        assert_eq!(result.1, Ok(Some("ddd444".to_string())));
+        assert!(result.2.is_ok());
+        assert_eq!(log.last().unwrap().args, ["admin", "undeploy", "2"]);
+    }
+
+    #[test]
+    fn unknown_stateroot_is_an_error() {
+        let (result, _) = replaying(vec![call("ostree", &["admin", "status"], STATUS)], || {
+            set_stateroot(Some("nope".to_string()));
+            let result = deployments();
+            set_stateroot(None);
+            result
+        });
+        assert_eq!(
+            result.unwrap_err(),
+            "No stateroot named nope; stateroots on this system: hackeros, testing"
+        );
+    }
+
+    #[test]
+    fn verify_deployment_reports_changes_below_usr_only() {
+        let deployment = parse_deployments(STATUS).remove(1);
+        let calls = vec![
+            call("ostree", &["fsck", "--repo=/ostree/repo"], "Validating refs...\n"),
+            call(
+                "ostree",
+                &["diff", "--repo=/ostree/repo", "aaa111", "/ostree/deploy/hackeros/deploy/aaa111.0"],
+                "M    /usr/bin/sudo\nA    /etc\nD    /usr/etc/motd\nA    /var\n",
+            ),
+        ];
+        let (result, _) = replaying(calls, || verify_deployment(&deployment));
+        assert_eq!(result, Ok(vec!["/usr/bin/sudo: modified".to_string(), "/usr/etc/motd: deleted".to_string()]));
+    }
+
+    #[test]
+    fn verify_deployment_reports_a_failed_fsck_without_error_lines() {
+        let deployment = parse_deployments(STATUS).remove(1);
+        let calls = vec![
+            failing("ostree", &["fsck", "--repo=/ostree/repo"], "Object missing: 3f9a.dirtree\n"),
+            call("ostree", &["diff", "--repo=/ostree/repo", "aaa111", "/ostree/deploy/hackeros/deploy/aaa111.0"], ""),
+        ];
+        let (result, _) = replaying(calls, || verify_deployment(&deployment));
+        assert_eq!(result, Ok(vec!["Object missing: 3f9a.dirtree".to_string()]));
+    }
+
+    #[test]
+    fn parses_content_checksum_and_signatures_from_show() {
+        let output = "commit aaa111
+ContentChecksum:  5f2e9c
+Date:  2024-05-01 10:00:00 +0000
+Version: 2024.1
+
+    HackerOS 2024.1
+
+Found 2 signatures:
+
+  Signature made Wed 01 May 2024 10:00:00 AM UTC using RSA key ID 4E1F7D2A
+  Good signature from \"HackerOS Release <release@hackeros.org>\"
+  Signature made Wed 01 May 2024 10:00:00 AM UTC using EdDSA key ID 9B3C0011
+  Can't check signature: public key not found
+";
+        let (checksum, signatures) = parse_show(output);
+        assert_eq!(checksum.as_deref(), Some("5f2e9c"));
+        assert_eq!(signatures, [("4E1F7D2A".to_string(), true), ("9B3C0011".to_string(), false)]);
+    }
+
+    #[test]
+    fn estimates_pull_size_from_the_static_delta() {
+        let calls = vec![
+            call(
+                "ostree",
+                &["pull", "--repo=/ostree/repo", "--dry-run", "origin", "main"],
+                "Delta update: 0/3 parts, 0 bytes/25.1 MB, 80.0 MB total uncompressed\n",
+            ),
+            failing("ostree", &["pull", "--repo=/ostree/repo", "--dry-run", "origin", "main"], "error: --dry-run requires static deltas"),
+        ];
+        let (result, _) = replaying(calls, || {
+            (estimate_pull("origin", "main").unwrap(), estimate_pull("origin", "main").unwrap())
+        });
+        assert_eq!(result.0, Some(("25.1 MB".to_string(), "80.0 MB".to_string())));
+        assert_eq!(result.1, None);
+    }
+
+    #[test]
+    fn missing_metadata_key_is_none() {
+        let calls = vec![
+            call("ostree", &["show", "--repo=/ostree/repo", "--print-metadata-key=version", "aaa111"], "'2024.1'\n"),
+            failing("ostree", &["show", "*", "--print-metadata-key=hackeros.base", "*"], "error: No such metadata key 'hackeros.base'"),
+        ];
+        let (result, _) = replaying(calls, || {
+            (metadata_string(OSTREE_REPO, "aaa111", "version"), metadata_string(OSTREE_REPO, "aaa111", "hackeros.base"))
+        });
+        assert_eq!(result, (Ok(Some("2024.1".to_string())), Ok(None)));
+    }
+}
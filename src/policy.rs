@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+use crate::config::load_config;
+use crate::index::{self, IndexRecord, Package};
+use crate::repos::{self, Repo};
+use crate::version::compare_versions;
+
+const PREFERENCES_DIR: &str = "/etc/hacker-ostree/preferences.d";
+
+// Priority of versions from a normal repo without pins
+const DEFAULT_PRIORITY: i32 = 500;
+// Priority of versions from NotAutomatic repos such as backports
+const NOT_AUTOMATIC_PRIORITY: i32 = 1;
+// Priority of NotAutomatic repos that still allow upgrades of packages taken from them
+const AUTOMATIC_UPGRADES_PRIORITY: i32 = 100;
+// Priority of versions from the target release or the repo given with --from
+const TARGET_PRIORITY: i32 = 990;
+
+// Repo given with --from for this invocation
+static TARGET: Mutex<Option<String>> = Mutex::new(None);
+// Suite given with -t for this invocation, overriding default-release
+static TARGET_RELEASE: Mutex<Option<String>> = Mutex::new(None);
+
+// Prefer the versions of one repo over every other for the rest of this run, without
+// touching preferences.d; pins naming specific packages still win
+pub fn set_target(repo: Option<String>) {
+    *TARGET.lock().unwrap_or_else(|e| e.into_inner()) = repo;
+}
+
+// Prefer the versions of repos whose suite or codename is `release` for the rest of this run
+pub fn set_target_release(release: Option<String>) {
+    *TARGET_RELEASE.lock().unwrap_or_else(|e| e.into_inner()) = release;
+}
+
+// What a pin stanza's Pin: line matches against
+#[derive(Debug, Clone)]
+enum PinTarget {
+    // release a=,n=,o=,l=,c=,v= constraints
+    Release(Vec<(String, String)>),
+    // origin <host>
+    Origin(String),
+    // version <glob>
+    Version(String),
+}
+
+// One stanza from preferences.d
+#[derive(Debug, Clone)]
+struct Pin {
+    packages: Vec<String>,
+    target: PinTarget,
+    priority: i32,
+}
+
+impl Pin {
+    fn is_general(&self) -> bool {
+        self.packages.iter().any(|p| p == "*")
+    }
+}
+
+// Match a shell-style glob supporting '*' and '?'
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let (p, t): (Vec<char>, Vec<char>) = (pattern.chars().collect(), text.chars().collect());
+    let (mut pi, mut ti) = (0, 0);
+    let (mut star, mut mark) = (None, 0);
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some(pi);
+            mark = ti;
+            pi += 1;
+        } else if let Some(s) = star {
+            pi = s + 1;
+            mark += 1;
+            ti = mark;
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+// Parse the value of a Pin: line
+fn parse_pin_target(value: &str) -> Option<PinTarget> {
+    let (kind, rest) = value.trim().split_once(char::is_whitespace)?;
+    let rest = rest.trim().trim_matches('"').to_string();
+    match kind {
+        "release" => {
+            let constraints = rest
+                .split(',')
+                .map(|part| match part.trim().split_once('=') {
+                    Some((key, value)) => (key.trim().to_string(), value.trim().to_string()),
+                    // "release bookworm" is shorthand for the archive
+                    None => ("a".to_string(), part.trim().to_string()),
+                })
+                .collect();
+            Some(PinTarget::Release(constraints))
+        }
+        "origin" => Some(PinTarget::Origin(rest)),
+        "version" => Some(PinTarget::Version(rest)),
+        _ => None,
+    }
+}
+
+// Whether a repo's Release names `release` as its suite or codename
+fn is_release(record: &IndexRecord, release: &str) -> bool {
+    record.suite.as_deref() == Some(release) || record.codename.as_deref() == Some(release)
+}
+
+// Load pin stanzas from preferences.d in file name order
+fn load_pins() -> Result<Vec<Pin>, String> {
+    let mut files: Vec<_> = match fs::read_dir(PREFERENCES_DIR) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect(),
+        Err(_) => return Ok(Vec::new()),
+    };
+    files.sort();
+    let mut pins = Vec::new();
+    for path in files {
+        let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        if name.starts_with('.') || (name.contains('.') && !name.ends_with(".pref")) {
+            continue;
+        }
+        let text = fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        for stanza in index::parse_deb822(&text) {
+            let (packages, pin, priority) = match (stanza.get("Package"), stanza.get("Pin"), stanza.get("Pin-Priority")) {
+                (Some(packages), Some(pin), Some(priority)) => (packages, pin, priority),
+                _ => continue,
+            };
+            if packages.split_whitespace().any(|p| p.starts_with('/')) {
+                eprintln!("Warning: {}: regular expression pins are not supported, skipping", path.display());
+                continue;
+            }
+            let target = parse_pin_target(pin)
+                .ok_or_else(|| format!("{}: invalid Pin: {}", path.display(), pin))?;
+            let priority = priority
+                .trim()
+                .parse()
+                .map_err(|_| format!("{}: invalid Pin-Priority: {}", path.display(), priority))?;
+            pins.push(Pin {
+                packages: packages.split_whitespace().map(str::to_string).collect(),
+                target,
+                priority,
+            });
+        }
+    }
+    Ok(pins)
+}
+
+// Pin rules plus the release metadata they are matched against
+pub struct Policy {
+    pins: Vec<Pin>,
+    target: Option<String>,
+    // Suite or codename from -t or default-release
+    target_release: Option<String>,
+    repos: HashMap<String, Repo>,
+    // Position of each repo in repos.json, used as a tie-breaker
+    order: HashMap<String, usize>,
+    records: HashMap<String, IndexRecord>,
+}
+
+impl Policy {
+    pub fn load() -> Result<Policy, String> {
+        let mut repos = HashMap::new();
+        let mut order = HashMap::new();
+        let mut records = HashMap::new();
+        for (position, repo) in repos::load_repos()?.into_iter().enumerate() {
+            if let Some(record) = index::load_record(&repo)? {
+                records.insert(repo.name.clone(), record);
+            }
+            order.insert(repo.name.clone(), position);
+            repos.insert(repo.name.clone(), repo);
+        }
+        let target = TARGET.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let target_release = match TARGET_RELEASE.lock().unwrap_or_else(|e| e.into_inner()).clone() {
+            Some(release) => Some(release),
+            None => load_config()?.default_release,
+        };
+        // Like APT, a target release nothing provides is a mistake; until the indexes have
+        // been fetched there is nothing to check it against
+        if let Some(release) = &target_release {
+            if !records.is_empty() && !records.values().any(|record| is_release(record, release)) {
+                let mut known: Vec<&str> = records
+                    .values()
+                    .flat_map(|record| [record.suite.as_deref(), record.codename.as_deref()])
+                    .flatten()
+                    .collect();
+                known.sort();
+                known.dedup();
+                return Err(format!("No repository provides target release {} (available: {})", release, known.join(", ")));
+            }
+        }
+        Ok(Policy { pins: load_pins()?, target, target_release, repos, order, records })
+    }
+
+    // Whether a version comes from the --from repo or the target release
+    fn is_target(&self, pkg: &Package) -> bool {
+        self.target.as_ref() == Some(&pkg.repo)
+            || self
+                .target_release
+                .as_ref()
+                .is_some_and(|release| self.records.get(&pkg.repo).is_some_and(|record| is_release(record, release)))
+    }
+
+    fn pin_matches(&self, pin: &Pin, pkg: &Package) -> bool {
+        if !pin.packages.iter().any(|p| glob_match(p, pkg.name())) {
+            return false;
+        }
+        match &pin.target {
+            PinTarget::Version(pattern) => glob_match(pattern, pkg.version()),
+            PinTarget::Origin(host) => self
+                .repos
+                .get(&pkg.repo)
+                .is_some_and(|repo| repo.uri.split("://").nth(1).unwrap_or_default().split('/').next() == Some(host.as_str())),
+            PinTarget::Release(constraints) => {
+                let record = self.records.get(&pkg.repo);
+                constraints.iter().all(|(key, pattern)| {
+                    let actual = match key.as_str() {
+                        "a" => record.and_then(|r| r.suite.clone()),
+                        "n" => record.and_then(|r| r.codename.clone()),
+                        "o" => record.and_then(|r| r.origin.clone()),
+                        "l" => record.and_then(|r| r.label.clone()),
+                        "c" => Some(pkg.component.clone()),
+                        "v" => Some(pkg.version().to_string()),
+                        "b" => pkg.field("Architecture").map(str::to_string),
+                        _ => None,
+                    };
+                    actual.is_some_and(|actual| glob_match(pattern, &actual))
+                })
+            }
+        }
+    }
+
+    // Pin priority of one available version: specific pins first, then the --from repo and
+    // target release, then general pins
+    pub fn priority(&self, pkg: &Package) -> i32 {
+        let mut specific = self.pins.iter().filter(|pin| !pin.is_general());
+        if let Some(pin) = specific.find(|pin| self.pin_matches(pin, pkg)) {
+            return pin.priority;
+        }
+        if self.is_target(pkg) {
+            return TARGET_PRIORITY;
+        }
+        if let Some(pin) = self.pins.iter().filter(|pin| pin.is_general()).find(|pin| self.pin_matches(pin, pkg)) {
+            return pin.priority;
+        }
+        match self.records.get(&pkg.repo) {
+            Some(record) if record.not_automatic && record.but_automatic_upgrades => AUTOMATIC_UPGRADES_PRIORITY,
+            Some(record) if record.not_automatic => NOT_AUTOMATIC_PRIORITY,
+            _ => DEFAULT_PRIORITY,
+        }
+    }
+
+    // Names of the configured repos in repos.json order, with whether their index is available
+    pub fn searched(&self) -> Vec<(String, bool)> {
+        let mut names: Vec<&String> = self.repos.keys().collect();
+        names.sort_by_key(|name| self.order.get(*name).copied().unwrap_or(usize::MAX));
+        names.into_iter().map(|name| (name.clone(), self.records.contains_key(name))).collect()
+    }
+
+    // Configured repo a package version comes from
+    pub fn repo(&self, pkg: &Package) -> Option<&Repo> {
+        self.repos.get(&pkg.repo)
+    }
+
+    // A specific version of a package when one is given, its candidate otherwise
+    pub fn select<'a>(&self, name: &str, version: Option<&str>, packages: &'a [Package]) -> Option<&'a Package> {
+        match version {
+            Some(version) => packages.iter().find(|pkg| pkg.name() == name && pkg.version() == version),
+            None => self.candidate(name, packages),
+        }
+    }
+
+    // Candidate version of a package. Ties are broken deterministically:
+    //   1. highest pin priority
+    //   2. the repo listed first in repos.json
+    //   3. highest version within that repo
+    // Versions pinned below zero are never selected.
+    pub fn candidate<'a>(&self, name: &str, packages: &'a [Package]) -> Option<&'a Package> {
+        let position = |pkg: &Package| self.order.get(&pkg.repo).copied().unwrap_or(usize::MAX);
+        packages
+            .iter()
+            .filter(|pkg| pkg.name() == name)
+            .map(|pkg| (self.priority(pkg), pkg))
+            .filter(|(priority, _)| *priority >= 0)
+            .max_by(|(pa, a), (pb, b)| {
+                pa.cmp(pb)
+                    .then_with(|| position(b).cmp(&position(a)))
+                    .then_with(|| compare_versions(a.version(), b.version()))
+            })
+            .map(|(_, pkg)| pkg)
+    }
+}
@@ -0,0 +1,61 @@
+use std::fs;
+use crate::config::Config;
+use crate::run_command;
+
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+// Battery charge in percent while discharging, None when on AC or without a battery
+fn battery_discharging() -> Option<u32> {
+    // upower knows about all batteries and whether the line is plugged in
+    if let Ok(devices) = run_command("upower", &["-e"]) {
+        let battery = devices.lines().find(|line| line.contains("battery_"))?;
+        let info = run_command("upower", &["-i", battery.trim()]).ok()?;
+        let value = |key: &str| {
+            info.lines()
+                .find_map(|line| line.trim().strip_prefix(key))
+                .map(|rest| rest.trim().to_string())
+        };
+        if value("state:")? != "discharging" {
+            return None;
+        }
+        return value("percentage:")?.trim_end_matches('%').parse::<f64>().ok().map(|p| p as u32);
+    }
+    // Without upower, read the kernel's view directly
+    let entries = fs::read_dir(POWER_SUPPLY_DIR).ok()?;
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let read = |name: &str| fs::read_to_string(entry.path().join(name)).map(|v| v.trim().to_string()).unwrap_or_default();
+        if read("type") == "Battery" && read("status") == "Discharging" {
+            return read("capacity").parse().ok();
+        }
+    }
+    None
+}
+
+// Whether NetworkManager considers the primary connection metered
+fn metered() -> bool {
+    let output = run_command("busctl", &[
+        "get-property",
+        "org.freedesktop.NetworkManager",
+        "/org/freedesktop/NetworkManager",
+        "org.freedesktop.NetworkManager",
+        "Metered",
+    ]);
+    // NMMetered: 1 = yes, 3 = guess-yes; no NetworkManager means unmetered
+    matches!(output.as_deref().map(str::trim), Ok("u 1") | Ok("u 3"))
+}
+
+// Why an automatic update should not download right now, if it shouldn't
+pub fn deferral_reason(config: &Config) -> Option<String> {
+    if let Some(percent) = battery_discharging() {
+        if percent < config.auto_update_min_battery {
+            return Some(format!(
+                "on battery at {}% (below auto-update-min-battery of {}%)",
+                percent, config.auto_update_min_battery
+            ));
+        }
+    }
+    if !config.auto_update_on_metered && metered() {
+        return Some("connection is metered (set auto-update-on-metered to allow)".to_string());
+    }
+    None
+}
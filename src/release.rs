@@ -0,0 +1,81 @@
+use std::fs;
+use std::path::Path;
+use crate::compose::CommitMetadata;
+use crate::{ostree, run_command, transaction};
+
+// Migration hooks the administrator adds; one with the same name as a vendor hook replaces it
+const LOCAL_HOOKS_DIR: &str = "/etc/hacker-ostree/release-hooks.d";
+// Migration hooks shipped in the image being upgraded to
+const VENDOR_HOOKS_PATH: &str = "/usr/lib/hacker-ostree/release-hooks.d";
+
+// Refuse to move to a base image of another release series unless `major` is set. Commits
+// without a series, from before images declared one, never block.
+pub fn check_series(booted: &CommitMetadata, target: &CommitMetadata, major: bool) -> Result<(), String> {
+    match (&booted.series, &target.series) {
+        (Some(current), Some(next)) if current != next && !major => Err(format!(
+            "The new base image (version {}) belongs to release series {}, not the booted {}; \
+             run 'hacker-ostree upgrade-release {}' to move to it",
+            target.version_label(),
+            next,
+            current,
+            next
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Executable hooks by file name: the target image's, overridden by local ones
+fn hooks(vendor_dir: &Path) -> Result<Vec<(String, String)>, String> {
+    let mut hooks: Vec<(String, String)> = Vec::new();
+    for dir in [vendor_dir, Path::new(LOCAL_HOOKS_DIR)] {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') {
+                continue;
+            }
+            hooks.retain(|(existing, _)| *existing != name);
+            hooks.push((name, entry.path().display().to_string()));
+        }
+    }
+    hooks.sort();
+    Ok(hooks)
+}
+
+// Run the migration hooks for moving from series `from` to `to` at `commit`, in file name
+// order. Each gets HACKEROS_FROM_SERIES, HACKEROS_TO_SERIES and HACKEROS_TARGET_COMMIT in
+// its environment; the first one failing fails the upgrade before anything is deployed.
+pub fn run_hooks(from: &str, to: &str, commit: &str) -> Result<(), String> {
+    let workdir = tempfile::Builder::new()
+        .prefix("hacker-ostree-release-")
+        .tempdir()
+        .map_err(|e| format!("Failed to create temp directory: {}", e))?;
+    let vendor_dir = workdir.path().join("hooks");
+    let subpath = format!("--subpath={}", VENDOR_HOOKS_PATH);
+    let repo = format!("--repo={}", ostree::OSTREE_REPO);
+    let vendor = vendor_dir.display().to_string();
+    // Images without hooks simply don't have the directory
+    if run_command("ostree", &["checkout", &repo, "--user-mode", &subpath, commit, &vendor]).is_err() {
+        fs::create_dir_all(&vendor_dir).map_err(|e| format!("Failed to create {}: {}", vendor, e))?;
+    }
+    let hooks = hooks(&vendor_dir)?;
+    if hooks.is_empty() {
+        return Ok(());
+    }
+    let environment = [
+        format!("HACKEROS_FROM_SERIES={}", from),
+        format!("HACKEROS_TO_SERIES={}", to),
+        format!("HACKEROS_TARGET_COMMIT={}", commit),
+    ];
+    for (name, path) in &hooks {
+        println!("Running release migration hook {}", name);
+        let mut args: Vec<&str> = environment.iter().map(String::as_str).collect();
+        args.push(path);
+        let output = transaction::run_watched("env", &args).map_err(|e| format!("Release migration hook {} failed: {}", name, e))?;
+        print!("{}", output);
+    }
+    Ok(())
+}
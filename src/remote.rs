@@ -0,0 +1,50 @@
+use std::io::IsTerminal;
+use crate::run_command_streamed;
+
+// Binary invoked on remote nodes
+const REMOTE_BINARY: &str = "hacker-ostree";
+
+// Quote a word for the remote POSIX shell
+pub fn shell_quote(word: &str) -> String {
+    if !word.is_empty() && word.chars().all(|c| c.is_ascii_alphanumeric() || "-_./=:@,+".contains(c)) {
+        return word.to_string();
+    }
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+// Our own arguments without --host, to be replayed on the remote node
+pub fn forwarded_args() -> Vec<String> {
+    let mut args = Vec::new();
+    let mut skip_value = false;
+    for arg in std::env::args().skip(1) {
+        if skip_value {
+            skip_value = false;
+        } else if arg == "--host" {
+            skip_value = true;
+        } else if !arg.starts_with("--host=") {
+            args.push(arg);
+        }
+    }
+    args
+}
+
+// ssh arguments running hacker-ostree with `args` on `host`
+pub fn ssh_args(host: &str, args: &[String], tty: bool) -> Vec<String> {
+    let mut remote = vec![REMOTE_BINARY.to_string()];
+    remote.extend(args.iter().map(|arg| shell_quote(arg)));
+    let mut ssh = Vec::new();
+    if tty {
+        // Lets the remote side ask for confirmation and show progress as it would locally
+        ssh.push("-t".to_string());
+    }
+    ssh.extend(["--".to_string(), host.to_string(), remote.join(" ")]);
+    ssh
+}
+
+// Run the current command on another node, streaming its output
+pub fn run_on_host(host: &str) -> Result<(), String> {
+    let tty = std::io::stdin().is_terminal() && std::io::stdout().is_terminal();
+    let args = ssh_args(host, &forwarded_args(), tty);
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    run_command_streamed("ssh", &args).map_err(|_| format!("Command failed on {}", host))
+}
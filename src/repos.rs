@@ -0,0 +1,405 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use crate::{fetch, run_command};
+
+const REPOS_FILE: &str = "/etc/hacker-ostree/repos.json";
+pub const KEYRINGS_DIR: &str = "/etc/hacker-ostree/keyrings";
+const DEBIAN_KEYRING: &str = "/usr/share/keyrings/debian-archive-keyring.gpg";
+const LAUNCHPAD_API: &str = "https://api.launchpad.net/1.0";
+pub const KEYSERVER: &str = "https://keyserver.ubuntu.com";
+// URI schemes apt can fetch from that we allow in generated sources
+const ALLOWED_SCHEMES: [&str; 8] = [
+    "http", "https", "file", "mirror+http", "mirror+https", "mirror+file", "tor+http", "tor+https",
+];
+// sources.list options we pass through to apt
+const ALLOWED_OPTIONS: [&str; 16] = [
+    "arch", "lang", "target", "pdiffs", "by-hash", "signed-by", "trusted", "allow-insecure",
+    "allow-weak", "allow-downgrade-to-insecure", "check-valid-until", "valid-until-min",
+    "valid-until-max", "check-date", "date-max-future", "inrelease-path",
+];
+// Characters that would let a value escape the generated sources or a shell
+const FORBIDDEN_CHARS: &str = ";&|$`<>\\\"'(){}[]*?!#";
+
+// A repo field that failed validation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    pub repo: String,
+    pub field: &'static str,
+    pub value: String,
+    pub reason: String,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid repository {}: {} \"{}\" {}", self.repo, self.field, self.value.escape_debug(), self.reason)
+    }
+}
+
+// A configured APT repository
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Repo {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,
+    pub uri: String,
+    pub suite: String,
+    #[serde(default)]
+    pub components: Vec<String>,
+    // Alternative base URIs tried in order when `uri` fails
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mirrors: Vec<String>,
+    // Pins the repo to an archive snapshot ("20240101T000000Z") served by a snapshot service
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<String>,
+}
+
+// repos.json used to hold raw sources.list lines; accept both forms
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredRepo {
+    Line(String),
+    Entry(Repo),
+}
+
+impl Repo {
+    // Render as a one-line sources.list entry
+    pub fn to_line(&self) -> String {
+        let mut line = self.kind.clone();
+        if !self.options.is_empty() {
+            line.push_str(&format!(" [{}]", self.options.join(" ")));
+        }
+        line.push_str(&format!(" {} {}", self.uri, self.suite));
+        for component in &self.components {
+            line.push(' ');
+            line.push_str(component);
+        }
+        line
+    }
+
+    // Value of a `key=value` option such as arch= or signed-by=
+    pub fn option(&self, key: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .find_map(|opt| opt.strip_prefix(key)?.strip_prefix('='))
+    }
+
+    // Check every field before the repo is stored or written into apt's sources
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let error = |field: &'static str, value: &str, reason: &str| ValidationError {
+            repo: self.name.clone(),
+            field,
+            value: value.to_string(),
+            reason: reason.to_string(),
+        };
+        let is_token = |value: &str, extra: &str| {
+            !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric() || extra.contains(c))
+        };
+
+        if !is_token(&self.name, "._-") || self.name.starts_with(['.', '-']) {
+            return Err(error("name", &self.name, "may only contain letters, digits, '.', '_' and '-'"));
+        }
+        if self.kind != "deb" && self.kind != "deb-src" {
+            return Err(error("type", &self.kind, "must be deb or deb-src"));
+        }
+        for (field, uri) in std::iter::once(("uri", &self.uri)).chain(self.mirrors.iter().map(|m| ("mirror", m))) {
+            validate_uri(uri).map_err(|reason| error(field, uri, &reason))?;
+        }
+        if !is_token(&self.suite, "._-~+/") || self.suite.split('/').any(|part| part == "..") {
+            return Err(error("suite", &self.suite, "is not a valid suite or flat repository path"));
+        }
+        if let Some(snapshot) = &self.snapshot {
+            if !is_snapshot_timestamp(snapshot) {
+                return Err(error("snapshot", snapshot, "must look like 20240101T000000Z"));
+            }
+        }
+        if self.components.is_empty() && !self.suite.ends_with('/') {
+            return Err(error("suite", &self.suite, "needs components, or a trailing '/' for a flat repository"));
+        }
+        for component in &self.components {
+            if !is_token(component, "._-+/") || component.starts_with('/') {
+                return Err(error("component", component, "is not a valid component name"));
+            }
+        }
+        for option in &self.options {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| error("option", option, "must have the form key=value"))?;
+            let key = key.trim_end_matches(['+', '-']);
+            if !ALLOWED_OPTIONS.contains(&key) {
+                return Err(error("option", option, "is not a supported sources.list option"));
+            }
+            if value.is_empty() || value.chars().any(|c| c.is_whitespace() || c.is_control() || FORBIDDEN_CHARS.contains(c)) {
+                return Err(error("option", option, "has an empty value or contains forbidden characters"));
+            }
+        }
+        Ok(())
+    }
+
+    // The repo as it is actually fetched: snapshot-pinned repos go to the snapshot
+    // service only, whose old Release files are past their Valid-Until by design
+    pub fn resolved(&self) -> Result<Repo, String> {
+        let timestamp = match &self.snapshot {
+            Some(timestamp) => timestamp,
+            None => return Ok(self.clone()),
+        };
+        let mut resolved = self.clone();
+        resolved.uri = snapshot_uri(&self.uri, timestamp)?;
+        resolved.mirrors.clear();
+        resolved.options.retain(|opt| !opt.starts_with("check-valid-until="));
+        resolved.options.push("check-valid-until=no".to_string());
+        Ok(resolved)
+    }
+
+    // Relative directory keeping this repo's cached indexes and .debs apart from other
+    // repos' and from its own for another suite: "<name>/<suite>", with flat repos' "./"
+    // as "flat"
+    pub fn cache_path(&self) -> String {
+        let suite = self.suite.trim_matches('/').replace('/', "_");
+        let suite = if suite.is_empty() || suite == "." { "flat".to_string() } else { suite };
+        format!("{}/{}", self.name, suite)
+    }
+
+    // Base URL of the Release file and indexes ("dists/<suite>" or a flat repo directory)
+    // on the given mirror
+    pub fn dists_url(&self, uri: &str) -> String {
+        let uri = uri.trim_end_matches('/');
+        if self.components.is_empty() {
+            format!("{}/{}", uri, self.suite.trim_end_matches('/'))
+        } else {
+            format!("{}/dists/{}", uri, self.suite)
+        }
+    }
+}
+
+// Snapshot timestamps as used by snapshot.debian.org: YYYYMMDDTHHMMSSZ
+pub fn is_snapshot_timestamp(value: &str) -> bool {
+    let bytes = value.as_bytes();
+    bytes.len() == 16
+        && bytes[8] == b'T'
+        && bytes[15] == b'Z'
+        && bytes.iter().enumerate().all(|(i, b)| i == 8 || i == 15 || b.is_ascii_digit())
+}
+
+// Where the archive behind `uri` is served as it was at `timestamp`
+pub fn snapshot_uri(uri: &str, timestamp: &str) -> Result<String, String> {
+    let rest = uri.split_once("://").map(|(_, rest)| rest).unwrap_or(uri);
+    let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+    let path = path.trim_end_matches('/');
+    let resolved = if host == "snapshot.debian.org" {
+        // Already a snapshot URI: swap the timestamp
+        let archive = path.strip_prefix("archive/").and_then(|p| p.split('/').next()).unwrap_or("debian");
+        format!("https://snapshot.debian.org/archive/{}/{}/", archive, timestamp)
+    } else if (host.ends_with("debian.org")) && (path == "debian" || path == "debian-security" || path == "debian-ports") {
+        format!("https://snapshot.debian.org/archive/{}/{}/", path, timestamp)
+    } else if host.ends_with("ubuntu.com") && (path == "ubuntu" || path == "ubuntu-ports") {
+        format!("https://snapshot.ubuntu.com/{}/{}/", path, timestamp)
+    } else if host == "ppa.launchpadcontent.net" {
+        format!("https://snapshot.ppa.launchpadcontent.net/{}/{}/", path, timestamp)
+    } else {
+        return Err(format!("No snapshot service is known for {}", uri));
+    };
+    Ok(resolved)
+}
+
+// Check that a repository URI uses an allowed scheme and carries nothing but the URI
+fn validate_uri(uri: &str) -> Result<(), String> {
+    if let Some(c) = uri.chars().find(|c| c.is_whitespace() || c.is_control() || FORBIDDEN_CHARS.contains(*c)) {
+        return Err(format!("contains forbidden character {:?}", c));
+    }
+    let (scheme, rest) = uri.split_once(':').ok_or_else(|| "is missing a scheme such as https://".to_string())?;
+    if !ALLOWED_SCHEMES.contains(&scheme) {
+        return Err(format!("uses scheme {} (allowed: {})", scheme, ALLOWED_SCHEMES.join(", ")));
+    }
+    if scheme.ends_with("file") {
+        // file:/path and file:///path are both accepted by apt
+        if !rest.starts_with('/') {
+            return Err("must be an absolute path".to_string());
+        }
+    } else {
+        let host = rest.strip_prefix("//").ok_or_else(|| format!("must start with {}://", scheme))?;
+        if host.is_empty() || host.starts_with('/') {
+            return Err("has no host".to_string());
+        }
+    }
+    Ok(())
+}
+
+// Parse a one-line sources.list entry, e.g. "deb [arch=amd64] http://deb.debian.org/debian bookworm main"
+pub fn parse_line(line: &str, name: Option<&str>) -> Result<Repo, String> {
+    let line = line.trim();
+    let (kind, rest) = line.split_once(char::is_whitespace).ok_or_else(|| format!("Invalid repo line: {}", line))?;
+    let mut rest = rest.trim_start();
+    let mut options = Vec::new();
+    if let Some(bracketed) = rest.strip_prefix('[') {
+        let (opts, after) = bracketed.split_once(']').ok_or_else(|| format!("Unterminated options in: {}", line))?;
+        options = opts.split_whitespace().map(str::to_string).collect();
+        rest = after.trim_start();
+    }
+    let mut fields = rest.split_whitespace();
+    let uri = fields.next().ok_or_else(|| format!("Missing URI in: {}", line))?.to_string();
+    let suite = fields.next().ok_or_else(|| format!("Missing suite in: {}", line))?.to_string();
+    let components = fields.map(str::to_string).collect();
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => default_name(&uri, &suite),
+    };
+    Ok(Repo { name, kind: kind.to_string(), options, uri, suite, components, mirrors: Vec::new(), snapshot: None })
+}
+
+// Value of a key in /etc/os-release
+fn os_release(key: &str) -> Option<String> {
+    let text = fs::read_to_string("/etc/os-release").ok()?;
+    text.lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+        .map(|value| value.trim_matches('"').to_string())
+        .filter(|value| !value.is_empty())
+}
+
+// Download an armored key and store it as a binary keyring
+fn import_key(url: &str, keyring: &str) -> Result<(), String> {
+    let armored = fetch::fetch_text(url)?;
+    if !armored.contains("BEGIN PGP PUBLIC KEY BLOCK") {
+        return Err(format!("No public key found at {}", url));
+    }
+    let mut temp = NamedTempFile::new().map_err(|e| format!("Failed to create temp file: {}", e))?;
+    temp.write_all(armored.as_bytes()).map_err(|e| format!("Failed to write to temp file: {}", e))?;
+    let temp_path = temp.path().to_str().ok_or_else(|| "Failed to get temp file path".to_string())?;
+    fs::create_dir_all(KEYRINGS_DIR).map_err(|e| format!("Failed to create {}: {}", KEYRINGS_DIR, e))?;
+    run_command("gpg", &["--batch", "--yes", "--dearmor", "-o", keyring, temp_path])?;
+    Ok(())
+}
+
+// Expand `ppa:user/name` or `debian:<suite>` into a full entry, fetching its key.
+// Returns None for anything that is not a shorthand.
+pub fn expand_shorthand(spec: &str, suite: Option<&str>) -> Result<Option<Repo>, String> {
+    if let Some(ppa) = spec.strip_prefix("ppa:") {
+        let (user, archive) = ppa.split_once('/').ok_or_else(|| format!("Invalid PPA {}, expected ppa:user/name", spec))?;
+        let series = match suite {
+            Some(suite) => suite.to_string(),
+            None => os_release("UBUNTU_CODENAME")
+                .ok_or_else(|| "Cannot determine the Ubuntu series for this PPA; pass --suite".to_string())?,
+        };
+        let api = format!("{}/~{}/+archive/ubuntu/{}", LAUNCHPAD_API, user, archive);
+        let info: serde_json::Value = serde_json::from_str(&fetch::fetch_text(&api)?)
+            .map_err(|e| format!("Failed to parse Launchpad response for {}: {}", spec, e))?;
+        let fingerprint = info["signing_key_fingerprint"]
+            .as_str()
+            .ok_or_else(|| format!("Launchpad did not report a signing key for {}", spec))?;
+        let name = format!("ppa-{}-{}", user, archive);
+        let keyring = format!("{}/{}.gpg", KEYRINGS_DIR, name);
+        import_key(&format!("{}/pks/lookup?op=get&search=0x{}", KEYSERVER, fingerprint), &keyring)?;
+        return Ok(Some(Repo {
+            name,
+            kind: "deb".to_string(),
+            options: vec![format!("signed-by={}", keyring)],
+            uri: format!("https://ppa.launchpadcontent.net/{}/{}/ubuntu", user, archive),
+            suite: series,
+            components: vec!["main".to_string()],
+            mirrors: Vec::new(),
+            snapshot: None,
+        }));
+    }
+    if let Some(suite) = spec.strip_prefix("debian:") {
+        if suite.is_empty() {
+            return Err("Invalid shorthand debian:, expected debian:<suite>".to_string());
+        }
+        if !Path::new(DEBIAN_KEYRING).exists() {
+            return Err(format!("{} is missing; install debian-archive-keyring first", DEBIAN_KEYRING));
+        }
+        let uri = if suite.ends_with("-security") {
+            "http://security.debian.org/debian-security"
+        } else {
+            "http://deb.debian.org/debian"
+        };
+        return Ok(Some(Repo {
+            name: format!("debian-{}", suite),
+            kind: "deb".to_string(),
+            options: vec![format!("signed-by={}", DEBIAN_KEYRING)],
+            uri: uri.to_string(),
+            suite: suite.to_string(),
+            components: vec!["main".to_string()],
+            mirrors: Vec::new(),
+            snapshot: None,
+        }));
+    }
+    Ok(None)
+}
+
+// Derive a name such as "deb.debian.org-bookworm" from the URI host and suite
+fn default_name(uri: &str, suite: &str) -> String {
+    let host = uri
+        .split("://")
+        .nth(1)
+        .unwrap_or(uri)
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    format!("{}-{}", host, suite)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '-' })
+        .collect::<String>()
+        .trim_matches(['-', '.'])
+        .to_string()
+}
+
+// Pick a name not yet used by any configured repo
+pub fn unique_name(repos: &[Repo], base: &str) -> String {
+    let mut name = base.to_string();
+    let mut n = 2;
+    while repos.iter().any(|r| r.name == name) {
+        name = format!("{}-{}", base, n);
+        n += 1;
+    }
+    name
+}
+
+// Find a repo by name, falling back to its position for older scripts
+pub fn find_repo(repos: &[Repo], selector: &str) -> Result<usize, String> {
+    if let Some(pos) = repos.iter().position(|r| r.name == selector) {
+        return Ok(pos);
+    }
+    if let Ok(index) = selector.parse::<usize>() {
+        if index < repos.len() {
+            return Ok(index);
+        }
+    }
+    let names: Vec<&str> = repos.iter().map(|r| r.name.as_str()).collect();
+    Err(format!("No repository named {} (available: {})", selector, names.join(", ")))
+}
+
+// Load repos from repos.json
+pub fn load_repos() -> Result<Vec<Repo>, String> {
+    let path = Path::new(REPOS_FILE);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", REPOS_FILE, e))?;
+    let stored: Vec<StoredRepo> = serde_json::from_reader(file).map_err(|e| format!("Failed to parse {}: {}", REPOS_FILE, e))?;
+    let mut repos: Vec<Repo> = Vec::new();
+    for entry in stored {
+        let repo = match entry {
+            StoredRepo::Entry(repo) => repo,
+            StoredRepo::Line(line) => {
+                let mut repo = parse_line(&line, None)?;
+                repo.name = unique_name(&repos, &repo.name);
+                repo
+            }
+        };
+        repo.validate().map_err(|e| format!("{} in {}", e, REPOS_FILE))?;
+        repos.push(repo);
+    }
+    Ok(repos)
+}
+
+// Save repos to repos.json
+pub fn save_repos(repos: &[Repo]) -> Result<(), String> {
+    let file = File::create(REPOS_FILE).map_err(|e| format!("Failed to create {}: {}", REPOS_FILE, e))?;
+    serde_json::to_writer_pretty(file, repos).map_err(|e| format!("Failed to write to {}: {}", REPOS_FILE, e))?;
+    Ok(())
+}
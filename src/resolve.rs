@@ -0,0 +1,488 @@
+use std::collections::{HashMap, HashSet};
+use serde::Serialize;
+use crate::dpkgdb;
+use crate::index::Package;
+use crate::policy::Policy;
+use crate::version::{compare_versions, satisfies};
+
+// Guard against runaway recursion on pathological dependency chains
+const MAX_DEPTH: usize = 64;
+
+// Package to install with the version chosen for it; None takes the policy candidate
+pub type Selection = (String, Option<String>);
+
+// One available version of a package and why it was passed over, if it was
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Candidate {
+    pub version: String,
+    pub repo: String,
+    pub priority: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rejected: Option<String>,
+}
+
+// A relation that can't be satisfied, with the relations below it that caused it
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Failure {
+    // Relation as written in the Depends field, e.g. "libssl3 (>= 3.0.9)"
+    pub relation: String,
+    pub reason: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub installed: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub candidates: Vec<Candidate>,
+    // Packages providing the name when it is virtual
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub providers: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub causes: Vec<Failure>,
+}
+
+// Why a requested package can't be installed
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Explanation {
+    pub package: String,
+    // Repos whose indexes were searched, in repos.json order
+    pub searched: Vec<String>,
+    // Repos skipped because their index hasn't been fetched
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub not_indexed: Vec<String>,
+    pub failure: Failure,
+}
+
+// Split "name:arch (op version)" into its name and optional constraint
+pub fn parse_relation(relation: &str) -> (&str, Option<(&str, &str)>) {
+    let relation = relation.trim();
+    let (name, constraint) = match relation.split_once('(') {
+        Some((name, rest)) => (name.trim(), Some(rest.trim_end_matches(')').trim())),
+        None => (relation, None),
+    };
+    let name = name.split(':').next().unwrap_or(name);
+    let constraint = constraint.and_then(|c| {
+        let split = c.find(|ch: char| !"<>=".contains(ch))?;
+        Some((c[..split].trim(), c[split..].trim()))
+    });
+    (name, constraint)
+}
+
+// Relations of a Depends-style field, each a list of alternatives
+pub fn relations(field: Option<&str>) -> Vec<Vec<&str>> {
+    field
+        .unwrap_or_default()
+        .split(',')
+        .map(|group| group.split('|').map(str::trim).filter(|a| !a.is_empty()).collect::<Vec<_>>())
+        .filter(|group| !group.is_empty())
+        .collect()
+}
+
+// How far the resolver may go to satisfy a new install
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    // Replace layered packages whose version doesn't satisfy a new dependency
+    pub upgrade_existing: bool,
+    // Satisfy alternatives with installed packages first and, when a layered package
+    // must change, move it to the lowest version that will do instead of the candidate
+    pub prefer_installed: bool,
+    // Use only candidate versions and refuse to plan packages that conflict with
+    // installed or planned ones, instead of relaxing both
+    pub strict: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options { upgrade_existing: true, prefer_installed: false, strict: false }
+    }
+}
+
+// Checks relations against the base image, the overlay and the indexed repos
+pub struct Resolver<'a> {
+    packages: &'a [Package],
+    policy: &'a Policy,
+    options: Options,
+    // Installed versions of each package, base and overlay
+    installed: HashMap<String, Vec<String>>,
+    // Versions installed in the base image and in the overlay
+    base: HashMap<String, String>,
+    layered: HashMap<String, String>,
+    // Installed packages providing each virtual name
+    provided: HashMap<String, Vec<String>>,
+    // Layered packages held at their installed version
+    held: HashSet<String>,
+    // Packages on the current dependency chain, so cycles are not followed
+    visiting: HashSet<String>,
+    // Candidates to install, each after the dependencies it pulled in
+    planned: Vec<&'a Package>,
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new(packages: &'a [Package], policy: &'a Policy, options: Options) -> Result<Self, String> {
+        let mut installed: HashMap<String, Vec<String>> = HashMap::new();
+        let (mut base_versions, mut layered) = (HashMap::new(), HashMap::new());
+        let mut provided: HashMap<String, Vec<String>> = HashMap::new();
+        for base in [true, false] {
+            for (name, version, provides) in dpkgdb::installed_with_provides(base)? {
+                for virtual_name in provides {
+                    provided.entry(virtual_name).or_default().push(name.clone());
+                }
+                if base {
+                    base_versions.insert(name.clone(), version.clone());
+                } else {
+                    layered.insert(name.clone(), version.clone());
+                }
+                installed.entry(name).or_default().push(version);
+            }
+        }
+        Ok(Resolver {
+            packages,
+            policy,
+            options,
+            installed,
+            base: base_versions,
+            layered,
+            provided,
+            held: HashSet::new(),
+            visiting: HashSet::new(),
+            planned: Vec::new(),
+        })
+    }
+
+    // Add a package, at a specific version if given, and the dependencies it is missing to
+    // the install set, or explain why it can't be installed. Names missing from every index
+    // may still be virtual packages something provides.
+    pub fn add(&mut self, name: &str, version: Option<&str>) -> Option<Explanation> {
+        let relation = match version {
+            Some(version) => format!("{} (= {})", name, version),
+            None => name.to_string(),
+        };
+        let planned = self.planned.len();
+        let checked = if self.packages.iter().any(|pkg| pkg.name() == name) {
+            self.check_candidate(name, &relation, version.map(|v| ("=", v)), 0)
+        } else {
+            self.check_relation(&relation, 0)
+        };
+        let failure = match checked {
+            Some(failure) => failure,
+            None => {
+                let conflicts = self.conflicts(planned);
+                if conflicts.is_empty() {
+                    return None;
+                }
+                self.planned.truncate(planned);
+                let reason = "it or packages it needs conflict with installed or planned ones".to_string();
+                Failure { relation, reason, installed: None, candidates: Vec::new(), providers: Vec::new(), causes: conflicts }
+            }
+        };
+        let (searched, not_indexed): (Vec<_>, Vec<_>) = self.policy.searched().into_iter().partition(|(_, indexed)| *indexed);
+        Some(Explanation {
+            package: name.to_string(),
+            searched: searched.into_iter().map(|(name, _)| name).collect(),
+            not_indexed: not_indexed.into_iter().map(|(name, _)| name).collect(),
+            failure,
+        })
+    }
+
+    // Keep layered packages at their installed version: anything needing another one fails
+    // instead of replacing them
+    pub fn hold(&mut self, names: &[String]) {
+        self.held.extend(names.iter().cloned());
+    }
+
+    // Packages to install in dependency order, with the versions chosen for them
+    pub fn planned(&self) -> Vec<(String, String)> {
+        self.planned.iter().map(|pkg| (pkg.name().to_string(), pkg.version().to_string())).collect()
+    }
+
+    // Conflicts and Breaks of the packages planned from position `from` on, against layered
+    // and planned packages. dpkg can't see the base image from the overlay database, so
+    // clashes with base packages are only refused when solving strictly.
+    fn conflicts(&self, from: usize) -> Vec<Failure> {
+        let mut failures = Vec::new();
+        for pkg in &self.planned[from..] {
+            for field in ["Conflicts", "Breaks"] {
+                for relation in relations(pkg.field(field)).into_iter().flatten() {
+                    let (name, constraint) = parse_relation(relation);
+                    if name == pkg.name() {
+                        continue;
+                    }
+                    let hit = |version: &str| constraint.is_none_or(|(op, wanted)| satisfies(version, op, wanted));
+                    let replaced = self.planned.iter().any(|other| other.name() == name);
+                    let base = self.base.get(name).filter(|v| self.options.strict && hit(v));
+                    let layered = self.layered.get(name).filter(|v| !replaced && hit(v));
+                    let installed = base
+                        .map(|v| format!("{} {} in the base image", name, v))
+                        .into_iter()
+                        .chain(layered.map(|v| format!("layered {} {}", name, v)));
+                    let planned = self
+                        .planned
+                        .iter()
+                        .filter(|other| other.name() == name && hit(other.version()))
+                        .map(|other| format!("planned {} {}", name, other.version()));
+                    for clash in installed.chain(planned) {
+                        failures.push(Failure {
+                            relation: format!("{}: {}", field, relation),
+                            reason: format!("{} {} clashes with {}", pkg.name(), pkg.version(), clash),
+                            installed: None,
+                            candidates: Vec::new(),
+                            providers: Vec::new(),
+                            causes: Vec::new(),
+                        });
+                    }
+                }
+            }
+        }
+        failures
+    }
+
+    // Version of a package to plan for a relation: the policy candidate, or with relaxation
+    // the best other version satisfying the constraint
+    fn choose(&self, name: &str, constraint: Option<(&str, &str)>) -> Option<&'a Package> {
+        let fits = |pkg: &&Package| constraint.is_none_or(|(op, wanted)| satisfies(pkg.version(), op, wanted));
+        let candidate = self.policy.candidate(name, self.packages);
+        let acceptable = self.packages.iter().filter(|pkg| pkg.name() == name && self.policy.priority(pkg) >= 0).filter(fits);
+        if self.options.prefer_installed && constraint.is_some() && self.layered.contains_key(name) {
+            return acceptable.min_by(|a, b| compare_versions(a.version(), b.version()));
+        }
+        if self.options.strict || candidate.is_some_and(|c| fits(&c)) {
+            return candidate;
+        }
+        acceptable.max_by(|a, b| {
+            self.policy
+                .priority(a)
+                .cmp(&self.policy.priority(b))
+                .then_with(|| compare_versions(a.version(), b.version()))
+        })
+    }
+
+    // Check one group of alternatives; fails only when none of them can be satisfied.
+    // Packages a failed alternative pulled in are dropped again.
+    fn check_group(&mut self, group: &[&str], depth: usize) -> Option<Failure> {
+        // Alternatives already installed win over pulling in the first one listed
+        if self.options.prefer_installed && group.iter().any(|alternative| self.is_installed(alternative)) {
+            return None;
+        }
+        let mut failures = Vec::new();
+        for alternative in group {
+            let planned = self.planned.len();
+            match self.check_relation(alternative, depth) {
+                None => return None,
+                Some(failure) => failures.push(failure),
+            }
+            self.planned.truncate(planned);
+        }
+        if failures.len() == 1 {
+            return failures.pop();
+        }
+        Some(Failure {
+            relation: group.join(" | "),
+            reason: "none of the alternatives can be installed".to_string(),
+            installed: None,
+            candidates: Vec::new(),
+            providers: Vec::new(),
+            causes: failures,
+        })
+    }
+
+    // Whether an installed package satisfies a relation
+    fn is_installed(&self, relation: &str) -> bool {
+        let (name, constraint) = parse_relation(relation);
+        let ok = |version: &String| constraint.is_none_or(|(op, wanted)| satisfies(version, op, wanted));
+        self.installed.get(name).is_some_and(|versions| versions.iter().any(ok))
+            || (constraint.is_none() && self.provided.contains_key(name))
+    }
+
+    fn check_relation(&mut self, relation: &str, depth: usize) -> Option<Failure> {
+        let (name, constraint) = parse_relation(relation);
+        let ok = |version: &str| constraint.is_none_or(|(op, wanted)| satisfies(version, op, wanted));
+        if self.is_installed(relation) {
+            return None;
+        }
+        if self.planned.iter().any(|pkg| pkg.name() == name && ok(pkg.version())) {
+            return None;
+        }
+        // Unversioned relations are also satisfied by anything providing the name
+        let provides = |pkg: &&Package| relations(pkg.field("Provides")).iter().flatten().any(|p| parse_relation(p).0 == name);
+        if constraint.is_none() && self.planned.iter().any(provides) {
+            return None;
+        }
+        if !self.options.upgrade_existing && self.layered.contains_key(name) {
+            return Some(Failure {
+                relation: relation.to_string(),
+                reason: "the layered version doesn't satisfy it and --no-upgrade-existing forbids replacing it".to_string(),
+                installed: self.installed_label(name),
+                candidates: Vec::new(),
+                providers: Vec::new(),
+                causes: Vec::new(),
+            });
+        }
+        if !self.packages.iter().any(|pkg| pkg.name() == name) {
+            let mut providers: Vec<String> = self
+                .packages
+                .iter()
+                .filter(provides)
+                .map(|pkg| pkg.name().to_string())
+                .collect();
+            providers.sort();
+            providers.dedup();
+            if constraint.is_none() {
+                let mut failures = Vec::new();
+                for provider in &providers {
+                    let planned = self.planned.len();
+                    match self.check_candidate(provider, provider, None, depth + 1) {
+                        None => return None,
+                        Some(failure) => failures.push(failure),
+                    }
+                    self.planned.truncate(planned);
+                }
+                if !providers.is_empty() {
+                    return Some(Failure {
+                        relation: relation.to_string(),
+                        reason: format!("{} is a virtual package and none of its providers can be installed", name),
+                        installed: None,
+                        candidates: Vec::new(),
+                        providers,
+                        causes: failures,
+                    });
+                }
+            }
+            let installed = self.installed_label(name);
+            let reason = match installed {
+                Some(_) => "installed version does not satisfy it and no searched repo has another",
+                None => "not installed and not found in any searched repo",
+            };
+            return Some(Failure {
+                relation: relation.to_string(),
+                reason: reason.to_string(),
+                installed,
+                candidates: Vec::new(),
+                providers,
+                causes: Vec::new(),
+            });
+        }
+        self.check_candidate(name, relation, constraint, depth)
+    }
+
+    fn installed_label(&self, name: &str) -> Option<String> {
+        self.installed.get(name).map(|versions| versions.join(", "))
+    }
+
+    // Failure for a package's candidate, listing every available version and why it was passed over
+    fn candidate_failure(&self, name: &str, relation: &str, constraint: Option<(&str, &str)>, reason: String, causes: Vec<Failure>) -> Failure {
+        let chosen = self.choose(name, constraint);
+        let candidates = self
+            .packages
+            .iter()
+            .filter(|pkg| pkg.name() == name)
+            .map(|pkg| {
+                let priority = self.policy.priority(pkg);
+                let rejected = if priority < 0 {
+                    Some(format!("pinned below zero (priority {})", priority))
+                } else if let Some((op, wanted)) = constraint.filter(|(op, wanted)| !satisfies(pkg.version(), op, wanted)) {
+                    Some(format!("does not satisfy {} {}", op, wanted))
+                } else if chosen.is_some_and(|c| !std::ptr::eq(c, pkg)) {
+                    Some("not chosen".to_string())
+                } else {
+                    None
+                };
+                Candidate { version: pkg.version().to_string(), repo: pkg.repo.clone(), priority, rejected }
+            })
+            .collect();
+        Failure {
+            relation: relation.to_string(),
+            reason,
+            installed: self.installed_label(name),
+            candidates,
+            providers: Vec::new(),
+            causes,
+        }
+    }
+
+    // Check that the candidate of a package satisfies a constraint and that its own
+    // dependencies can be met, planning it after them
+    fn check_candidate(&mut self, name: &str, relation: &str, constraint: Option<(&str, &str)>, depth: usize) -> Option<Failure> {
+        if let Some(layered) = self.layered.get(name).filter(|_| self.held.contains(name)) {
+            if constraint.is_none_or(|(op, wanted)| satisfies(layered, op, wanted)) {
+                return None;
+            }
+            let reason = format!("it is held at {}; 'hacker-ostree unhold {}' lets it change", layered, name);
+            return Some(self.candidate_failure(name, relation, constraint, reason, Vec::new()));
+        }
+        let candidate = match (self.choose(name, constraint), self.policy.candidate(name, self.packages)) {
+            (Some(chosen), _) if constraint.is_none_or(|(op, wanted)| satisfies(chosen.version(), op, wanted)) => chosen,
+            (_, Some(candidate)) => {
+                let reason = if self.options.strict {
+                    format!("candidate {} from {} is not acceptable and --solve-strict allows no other", candidate.version(), candidate.repo)
+                } else {
+                    format!("candidate {} from {} is not acceptable and no other version is", candidate.version(), candidate.repo)
+                };
+                return Some(self.candidate_failure(name, relation, constraint, reason, Vec::new()));
+            }
+            (_, None) => {
+                let reason = "every available version is pinned below zero".to_string();
+                return Some(self.candidate_failure(name, relation, constraint, reason, Vec::new()));
+            }
+        };
+        if self.planned.iter().any(|pkg| pkg.name() == name) || depth >= MAX_DEPTH || !self.visiting.insert(name.to_string()) {
+            return None;
+        }
+        let mut causes = Vec::new();
+        for field in ["Pre-Depends", "Depends"] {
+            for group in relations(candidate.field(field)) {
+                if let Some(cause) = self.check_group(&group, depth + 1) {
+                    causes.push(cause);
+                }
+            }
+        }
+        self.visiting.remove(name);
+        if causes.is_empty() {
+            self.planned.push(candidate);
+            return None;
+        }
+        let reason = format!("{} {} has dependencies that can't be satisfied", name, candidate.version());
+        Some(self.candidate_failure(name, relation, constraint, reason, causes))
+    }
+}
+
+fn print_failure(failure: &Failure, indent: usize) {
+    let pad = "  ".repeat(indent);
+    println!("{}{}: {}", pad, failure.relation, failure.reason);
+    if let Some(installed) = &failure.installed {
+        println!("{}  installed: {}", pad, installed);
+    }
+    for candidate in &failure.candidates {
+        match &candidate.rejected {
+            Some(rejected) => println!(
+                "{}  {} from {} (priority {}): {}",
+                pad, candidate.version, candidate.repo, candidate.priority, rejected
+            ),
+            None => println!("{}  {} from {} (priority {})", pad, candidate.version, candidate.repo, candidate.priority),
+        }
+    }
+    if !failure.providers.is_empty() {
+        println!("{}  provided by: {}", pad, failure.providers.join(", "));
+    }
+    for cause in &failure.causes {
+        print_failure(cause, indent + 1);
+    }
+}
+
+// Print an explanation as a tree, or as JSON for tooling
+pub fn print(explanation: &Explanation, json: bool) -> Result<(), String> {
+    if json {
+        let text = serde_json::to_string_pretty(explanation).map_err(|e| format!("Failed to serialize explanation: {}", e))?;
+        println!("{}", text);
+        return Ok(());
+    }
+    println!("{} can't be installed:", explanation.package);
+    print_failure(&explanation.failure, 1);
+    match explanation.searched.as_slice() {
+        [] => println!("Searched repos: none"),
+        searched => println!("Searched repos: {}", searched.join(", ")),
+    }
+    if !explanation.not_indexed.is_empty() {
+        println!("Not indexed (run an update): {}", explanation.not_indexed.join(", "));
+    }
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use std::fs;
+use crate::{fetch, run_command};
+
+// Stable and unique per installation, so delays derived from it are too
+const MACHINE_ID: &str = "/etc/machine-id";
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+// A weekly time range such as "Mon-Fri 02:00-05:00"
+struct Window {
+    // Indexed Monday = 0
+    days: [bool; 7],
+    // Minutes after midnight; end <= start wraps past midnight
+    start: u32,
+    end: u32,
+}
+
+fn parse_day(day: &str) -> Result<usize, String> {
+    let day = day.to_lowercase();
+    DAYS.iter()
+        .position(|d| day.starts_with(d))
+        .ok_or_else(|| format!("Unknown day {}", day))
+}
+
+fn parse_time(time: &str) -> Result<u32, String> {
+    let (hours, minutes) = time.split_once(':').ok_or_else(|| format!("Invalid time {}, expected HH:MM", time))?;
+    let hours: u32 = hours.parse().map_err(|_| format!("Invalid time {}", time))?;
+    let minutes: u32 = minutes.parse().map_err(|_| format!("Invalid time {}", time))?;
+    if hours > 24 || minutes > 59 || (hours == 24 && minutes > 0) {
+        return Err(format!("Invalid time {}", time));
+    }
+    Ok(hours * 60 + minutes)
+}
+
+// Parse "[DAYS ]HH:MM-HH:MM" where DAYS is a comma separated list of days or day ranges
+fn parse_window(spec: &str) -> Result<Window, String> {
+    let context = |e: String| format!("Invalid maintenance window \"{}\": {}", spec, e);
+    let mut parts = spec.split_whitespace().collect::<Vec<_>>();
+    let times = parts.pop().ok_or_else(|| context("empty".to_string()))?;
+    let mut days = [parts.is_empty(); 7];
+    for group in parts.iter().flat_map(|part| part.split(',')).filter(|g| !g.is_empty()) {
+        match group.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (parse_day(first).map_err(context)?, parse_day(last).map_err(context)?);
+                let mut day = first;
+                loop {
+                    days[day] = true;
+                    if day == last {
+                        break;
+                    }
+                    day = (day + 1) % 7;
+                }
+            }
+            None => days[parse_day(group).map_err(context)?] = true,
+        }
+    }
+    let (start, end) = times.split_once('-').ok_or_else(|| context("expected HH:MM-HH:MM".to_string()))?;
+    Ok(Window {
+        days,
+        start: parse_time(start).map_err(context)?,
+        end: parse_time(end).map_err(context)?,
+    })
+}
+
+impl Window {
+    fn contains(&self, day: usize, minute: u32) -> bool {
+        if self.start < self.end {
+            return self.days[day] && minute >= self.start && minute < self.end;
+        }
+        // Wraps past midnight: the part after midnight belongs to the previous day's window
+        (self.days[day] && minute >= self.start) || (self.days[(day + 6) % 7] && minute < self.end)
+    }
+}
+
+// Whether the local time falls inside one of the configured windows; no windows means always
+pub fn in_window(windows: &[String]) -> Result<bool, String> {
+    if windows.is_empty() {
+        return Ok(true);
+    }
+    let parsed = windows.iter().map(|w| parse_window(w)).collect::<Result<Vec<_>, _>>()?;
+    // %u: 1 = Monday
+    let now = run_command("date", &["+%u %H:%M"])?;
+    let (day, time) = now.trim().split_once(' ').ok_or_else(|| format!("Unexpected date output: {}", now))?;
+    let day = day.parse::<usize>().map_err(|_| format!("Unexpected date output: {}", now))? - 1;
+    let minute = parse_time(time)?;
+    Ok(parsed.iter().any(|w| w.contains(day, minute)))
+}
+
+// Delay within `window` seconds picked by a SHA256 hex digest
+fn slot(digest: &str, window: u64) -> u64 {
+    digest.get(..16).and_then(|prefix| u64::from_str_radix(prefix, 16).ok()).map_or(0, |value| value % window)
+}
+
+// This machine's delay before automatic updates fetch anything, within `window` seconds. It
+// is derived from the machine id, so it stays the same from run to run while a fleet tracking
+// the same channel spreads out instead of hitting the mirror in the same minute. Machines
+// without an initialized id don't wait.
+pub fn jitter(window: u64) -> u64 {
+    if window == 0 {
+        return 0;
+    }
+    let id = fs::read_to_string(MACHINE_ID).unwrap_or_default();
+    if id.trim().is_empty() || id.trim() == "uninitialized" {
+        return 0;
+    }
+    fetch::sha256_file(MACHINE_ID).map_or(0, |digest| slot(&digest, window))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slot_is_stable_and_within_the_window() {
+        let digest = "00000000000012c1e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934c";
+        assert_eq!(slot(digest, 3600), 0x12c1 % 3600);
+        assert_eq!(slot(digest, 3600), slot(digest, 3600));
+        assert!(slot("ffffffffffffffff", 1800) < 1800);
+        assert_eq!(slot("not hex", 1800), 0);
+    }
+}
@@ -0,0 +1,336 @@
+use serde_json::{json, Value};
+
+// Bumped whenever a published format changes incompatibly
+const SCHEMA_VERSION: u32 = 1;
+const DIALECT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+// Machine-readable formats with a published schema: (name, description)
+pub const NAMES: [(&str, &str); 12] = [
+    ("apply", "State manifest read by apply, fleet apply and bundle create"),
+    ("fleet-apply", "Report printed or written by fleet apply"),
+    ("bundle", "bundle.json at the root of an offline bundle"),
+    ("lockfile", "Lockfile written by compose lockfile and read by compose tree --lockfile"),
+    ("explain", "Dependency resolution failure printed by install --json"),
+    ("history", "Transaction history in /var/lib/hacker-ostree/history.json, printed by history --json"),
+    ("history-diff", "Package changes between two transactions printed by history diff --json"),
+    ("list", "Layered packages printed by list --json"),
+    ("status", "Deployments printed by status --json"),
+    ("queue", "Requests and replies on the daemon socket, one JSON object per line"),
+    ("webhook", "Body POSTed to notify-webhook"),
+    ("status-file", "/run/hacker-ostree/status.json, replaced atomically on every state change"),
+];
+
+fn manifest() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "system-update": { "type": "boolean", "default": false, "description": "Update the base image before converging the overlay" },
+            "packages": { "type": "array", "items": { "type": "string" }, "default": [], "description": "Packages that must be layered" },
+            "remove": { "type": "array", "items": { "type": "string" }, "default": [], "description": "Packages that must not be layered" }
+        },
+        "additionalProperties": false
+    })
+}
+
+fn body(name: &str) -> Option<Value> {
+    let schema = match name {
+        "apply" => manifest(),
+        "fleet-apply" => json!({
+            "type": "object",
+            "required": ["manifest", "batch_size", "hosts", "summary"],
+            "properties": {
+                "manifest": manifest(),
+                "batch_size": { "type": "integer", "minimum": 1 },
+                "hosts": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["host", "status", "exit_code", "duration_secs", "output"],
+                        "properties": {
+                            "host": { "type": "string" },
+                            "status": { "enum": ["ok", "failed", "skipped"] },
+                            "exit_code": { "type": ["integer", "null"] },
+                            "duration_secs": { "type": "number" },
+                            "output": { "type": "string" }
+                        }
+                    }
+                },
+                "summary": {
+                    "type": "object",
+                    "required": ["ok", "failed", "skipped"],
+                    "properties": {
+                        "ok": { "type": "integer" },
+                        "failed": { "type": "integer" },
+                        "skipped": { "type": "integer" }
+                    }
+                }
+            }
+        }),
+        "bundle" => json!({
+            "type": "object",
+            "required": ["created", "manifest", "base_from", "base_to", "debs"],
+            "properties": {
+                "created": { "type": "integer", "description": "Unix timestamp" },
+                "manifest": manifest(),
+                "base_from": { "type": ["string", "null"], "description": "Commit the base delta starts from, null for a full delta" },
+                "base_to": { "type": ["string", "null"], "description": "Commit the base delta yields, null without a base update" },
+                "debs": {
+                    "type": "array",
+                    "items": { "type": "array", "prefixItems": [{ "type": "string" }, { "type": "string" }], "items": false },
+                    "description": "Package names with their .deb below debs/"
+                }
+            }
+        }),
+        "explain" => json!({
+            "type": "object",
+            "required": ["package", "searched", "failure"],
+            "properties": {
+                "package": { "type": "string" },
+                "searched": { "type": "array", "items": { "type": "string" }, "description": "Repos whose indexes were searched" },
+                "not-indexed": { "type": "array", "items": { "type": "string" }, "description": "Repos skipped because their index hasn't been fetched" },
+                "failure": { "$ref": "#/$defs/failure" }
+            },
+            "$defs": {
+                "failure": {
+                    "type": "object",
+                    "required": ["relation", "reason"],
+                    "properties": {
+                        "relation": { "type": "string", "description": "Relation as written in the Depends field" },
+                        "reason": { "type": "string" },
+                        "installed": { "type": "string", "description": "Installed versions, comma separated" },
+                        "candidates": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "required": ["version", "repo", "priority"],
+                                "properties": {
+                                    "version": { "type": "string" },
+                                    "repo": { "type": "string" },
+                                    "priority": { "type": "integer" },
+                                    "rejected": { "type": "string", "description": "Why this version was passed over" }
+                                }
+                            }
+                        },
+                        "providers": { "type": "array", "items": { "type": "string" } },
+                        "causes": { "type": "array", "items": { "$ref": "#/$defs/failure" } }
+                    }
+                }
+            }
+        }),
+        "history" => json!({
+            "type": "array",
+            "items": {
+                "type": "object",
+                "required": ["id", "started", "finished", "command", "packages", "success"],
+                "properties": {
+                    "id": { "type": "integer" },
+                    "started": { "type": "integer", "description": "Unix timestamp" },
+                    "finished": { "type": "integer", "description": "Unix timestamp" },
+                    "command": { "type": "string" },
+                    "command_line": { "type": "string" },
+                    "user": { "type": "string" },
+                    "packages": { "type": "array", "items": { "type": "string" } },
+                    "added": { "type": "object", "additionalProperties": { "type": "string" }, "description": "Layered packages added, with versions" },
+                    "removed": { "type": "object", "additionalProperties": { "type": "string" }, "description": "Layered packages removed, with versions; upgrades appear in both" },
+                    "base": { "type": "string", "description": "Deployment checksum when the transaction started" },
+                    "success": { "type": "boolean" },
+                    "error": { "type": "string" }
+                }
+            }
+        }),
+        "lockfile" => json!({
+            "type": "object",
+            "required": ["ref", "suite", "repos", "packages"],
+            "properties": {
+                "ref": { "type": "string", "description": "Branch of the treefile it was resolved from" },
+                "suite": { "type": "string" },
+                "repos": { "type": "array", "items": { "type": "string" }, "description": "Source lines, pinned to archive snapshots where possible" },
+                "packages": {
+                    "type": "object",
+                    "additionalProperties": {
+                        "type": "object",
+                        "required": ["version", "sha256"],
+                        "properties": {
+                            "version": { "type": "string" },
+                            "sha256": { "type": "string" }
+                        }
+                    }
+                }
+            }
+        }),
+        "history-diff" => json!({
+            "type": "object",
+            "required": ["from", "to", "added", "removed", "untracked"],
+            "properties": {
+                "from": { "type": "integer", "description": "Transaction compared from; 0 is before the first one" },
+                "to": { "type": "integer" },
+                "added": { "type": "object", "additionalProperties": { "type": "string" }, "description": "Packages layered at 'to' but not at 'from', with versions" },
+                "removed": { "type": "object", "additionalProperties": { "type": "string" }, "description": "Packages layered at 'from' but not at 'to', with versions; version changes appear in both" },
+                "untracked": { "type": "array", "items": { "type": "integer" }, "description": "Transactions in between that recorded no package versions" }
+            }
+        }),
+        "list" => json!({
+            "type": "array",
+            "items": {
+                "type": "object",
+                "required": ["name", "version", "automatic"],
+                "properties": {
+                    "name": { "type": "string" },
+                    "version": { "type": "string" },
+                    "automatic": { "type": "boolean", "description": "Installed only as a dependency" }
+                }
+            }
+        }),
+        "status" => json!({
+            "type": "array",
+            "items": {
+                "type": "object",
+                "required": ["stateroot", "index", "checksum", "serial", "flags", "version", "series", "base", "packages", "layered", "advisories", "note"],
+                "properties": {
+                    "stateroot": { "type": "string" },
+                    "index": { "type": "integer", "description": "Position among the stateroot's deployments, newest first" },
+                    "checksum": { "type": "string" },
+                    "serial": { "type": "string" },
+                    "flags": { "type": "array", "items": { "enum": ["booted", "pending", "staged", "pinned", "live"] } },
+                    "version": { "type": ["string", "null"] },
+                    "series": { "type": ["string", "null"] },
+                    "base": { "type": "string" },
+                    "packages": { "type": "integer", "description": "Packages in the base manifest" },
+                    "layered": { "type": "object", "additionalProperties": { "type": "string" } },
+                    "advisories": { "type": "array", "items": { "type": "string" } },
+                    "note": { "type": ["string", "null"], "description": "Set with --note or the note command" },
+                    "provenance": {
+                        "type": "object",
+                        "description": "Only with --verbose",
+                        "required": ["refspec", "remote", "url", "parent", "content_checksum", "signatures", "origin"],
+                        "properties": {
+                            "refspec": { "type": ["string", "null"] },
+                            "remote": { "type": ["string", "null"] },
+                            "url": { "type": ["string", "null"] },
+                            "parent": { "type": ["string", "null"] },
+                            "content_checksum": { "type": ["string", "null"] },
+                            "signatures": {
+                                "type": "array",
+                                "items": {
+                                    "type": "object",
+                                    "required": ["key", "good"],
+                                    "properties": { "key": { "type": "string" }, "good": { "type": "boolean" } }
+                                }
+                            },
+                            "origin": { "type": ["string", "null"], "description": "Contents of the deployment's .origin file" }
+                        }
+                    }
+                }
+            }
+        }),
+        "queue" => json!({
+            "oneOf": [
+                {
+                    "title": "Request",
+                    "type": "object",
+                    "required": ["op"],
+                    "properties": {
+                        "op": { "enum": ["submit", "list", "cancel", "attach"] },
+                        "args": { "type": "array", "items": { "type": "string" }, "description": "submit: command line without the program name" },
+                        "id": { "type": ["integer", "null"], "description": "cancel, attach: job id; attach without one follows the running job" }
+                    }
+                },
+                {
+                    "title": "Reply",
+                    "type": "object",
+                    "required": ["type"],
+                    "properties": {
+                        "type": { "enum": ["queued", "output", "progress", "finished", "cancelled", "jobs", "error"] },
+                        "id": { "type": "integer" },
+                        "position": { "type": "integer", "description": "queued: jobs ahead of this one" },
+                        "line": { "type": "string", "description": "output: one line of the job's output" },
+                        "phase": { "enum": ["download", "unpack"], "description": "progress: step of the job being reported" },
+                        "percent": { "type": "integer", "minimum": 0, "maximum": 100 },
+                        "success": { "type": "boolean" },
+                        "jobs": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "required": ["id", "command", "running", "submitted"],
+                                "properties": {
+                                    "id": { "type": "integer" },
+                                    "command": { "type": "string" },
+                                    "running": { "type": "boolean" },
+                                    "submitted": { "type": "integer", "description": "Unix timestamp" }
+                                }
+                            }
+                        },
+                        "message": { "type": "string" }
+                    }
+                }
+            ]
+        }),
+        "webhook" => json!({
+            "type": "object",
+            "required": ["event", "host", "timestamp", "summary", "details"],
+            "properties": {
+                "event": { "enum": ["transaction", "updates-available", "rollback"] },
+                "host": { "type": "string" },
+                "timestamp": { "type": "integer", "description": "Unix timestamp" },
+                "summary": { "type": "string" },
+                "details": { "type": "string" }
+            }
+        }),
+        "status-file" => json!({
+            "type": "object",
+            "required": ["running", "packages", "started", "last_transaction", "updates", "reboot_required", "updated"],
+            "properties": {
+                "running": { "type": ["string", "null"], "description": "Command of the transaction in progress, null when idle" },
+                "packages": { "type": "array", "items": { "type": "string" }, "description": "Packages the running transaction was given" },
+                "started": { "type": ["integer", "null"], "description": "Unix timestamp the running transaction started at" },
+                "last_transaction": {
+                    "type": ["object", "null"],
+                    "required": ["id", "command", "success", "finished"],
+                    "properties": {
+                        "id": { "type": "integer", "description": "History id" },
+                        "command": { "type": "string" },
+                        "success": { "type": "boolean" },
+                        "finished": { "type": "integer", "description": "Unix timestamp" }
+                    }
+                },
+                "updates": { "type": ["integer", "null"], "description": "Updates found by the last check-update or summary, null before either ran" },
+                "reboot_required": { "type": "boolean" },
+                "updated": { "type": "integer", "description": "Unix timestamp of the last change" }
+            }
+        }),
+        _ => return None,
+    };
+    Some(schema)
+}
+
+// Full schema document for a format, with its version in the id
+pub fn schema(name: &str) -> Result<Value, String> {
+    let mut schema = body(name).ok_or_else(|| {
+        let names: Vec<&str> = NAMES.iter().map(|(name, _)| *name).collect();
+        format!("No schema named {}; available: {}", name, names.join(", "))
+    })?;
+    let description = NAMES.iter().find(|(n, _)| *n == name).map(|(_, d)| *d).unwrap_or_default();
+    if let Value::Object(map) = &mut schema {
+        map.insert("$schema".to_string(), json!(DIALECT));
+        map.insert("$id".to_string(), json!(format!("urn:hacker-ostree:schema:{}:v{}", name, SCHEMA_VERSION)));
+        map.insert("title".to_string(), json!(description));
+    }
+    Ok(schema)
+}
+
+// Print one schema, or the list of available ones without a name
+pub fn print(name: Option<&str>) -> Result<(), String> {
+    match name {
+        Some(name) => {
+            let text = serde_json::to_string_pretty(&schema(name)?).map_err(|e| format!("Failed to serialize schema: {}", e))?;
+            println!("{}", text);
+        }
+        None => {
+            println!("Schemas (version {}):", SCHEMA_VERSION);
+            for (name, description) in NAMES {
+                println!("  {:<12} {}", name, description);
+            }
+        }
+    }
+    Ok(())
+}
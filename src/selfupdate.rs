@@ -0,0 +1,79 @@
+use std::collections::BTreeMap;
+use std::fs;
+use serde::Deserialize;
+use crate::fetch::{self, Download};
+use crate::{keys, run_command, CACHE_DIR};
+
+// Keys release manifests of the self-update channel must be signed with
+const SELF_UPDATE_KEYRING: &str = "/etc/hacker-ostree/keyrings/self-update.gpg";
+// Manifest at the root of a channel and its detached signature next to it
+const MANIFEST: &str = "release.json";
+const SIGNATURE: &str = "release.json.sig";
+
+// The newest release a channel offers
+#[derive(Deserialize)]
+pub struct Release {
+    pub version: String,
+    // Package by Debian architecture, "all" serving any
+    pub packages: BTreeMap<String, ReleaseFile>,
+}
+
+#[derive(Deserialize)]
+pub struct ReleaseFile {
+    // Path relative to the channel URL
+    pub file: String,
+    pub sha256: String,
+}
+
+// Fetch the channel's manifest and check its signature. Whether it was verified is returned
+// alongside; an unsigned or badly signed one is only accepted with --allow-unsigned.
+pub fn latest(channel: &str) -> Result<(Release, bool), String> {
+    let workdir = tempfile::Builder::new()
+        .prefix("hacker-ostree-self-update-")
+        .tempdir()
+        .map_err(|e| format!("Failed to create temp directory: {}", e))?;
+    let channel = channel.trim_end_matches('/');
+    let manifest = workdir.path().join(MANIFEST).display().to_string();
+    let signature = workdir.path().join(SIGNATURE).display().to_string();
+    fetch::fetch_all(&[Download { url: format!("{}/{}", channel, MANIFEST), dest: manifest.clone() }], false)
+        .map_err(|e| format!("Failed to fetch the release manifest from {}: {}", channel, e))?;
+    let checked = fetch::fetch_all(&[Download { url: format!("{}/{}", channel, SIGNATURE), dest: signature.clone() }], false)
+        .and_then(|_| run_command("gpgv", &["--keyring", SELF_UPDATE_KEYRING, &signature, &manifest]));
+    let verified = match checked {
+        Ok(_) => true,
+        Err(e) if keys::allow_unsigned() => {
+            eprintln!("Warning: using an unverified release manifest from {}: {}", channel, e.trim());
+            false
+        }
+        Err(e) => {
+            return Err(format!(
+                "Release manifest signature verification failed against {}: {}; pass --allow-unsigned to use it anyway",
+                SELF_UPDATE_KEYRING,
+                e.trim()
+            ))
+        }
+    };
+    let text = fs::read_to_string(&manifest).map_err(|e| format!("Failed to read {}: {}", MANIFEST, e))?;
+    let release = serde_json::from_str(&text).map_err(|e| format!("Failed to parse {} from {}: {}", MANIFEST, channel, e))?;
+    Ok((release, verified))
+}
+
+// Download the release's package for this machine into the cache and check it against the
+// manifest's hash, returning its path
+pub fn download(channel: &str, release: &Release, verified: bool) -> Result<String, String> {
+    let arch = run_command("dpkg", &["--print-architecture"])?.trim().to_string();
+    let package = release
+        .packages
+        .get(&arch)
+        .or_else(|| release.packages.get("all"))
+        .ok_or_else(|| format!("Release {} has no package for {}", release.version, arch))?;
+    let name = package.file.rsplit('/').next().unwrap_or_default();
+    if name.is_empty() || package.file.split('/').any(|part| part == "..") {
+        return Err(format!("Refusing suspicious path {} in {}", package.file, MANIFEST));
+    }
+    let path = format!("{}/self-update/{}", CACHE_DIR, name);
+    let url = format!("{}/{}", channel.trim_end_matches('/'), package.file);
+    fetch::fetch_all(&[Download { url, dest: path.clone() }], false)?;
+    keys::verify_deb(&path, Some(&package.sha256), verified)?;
+    Ok(path)
+}
@@ -0,0 +1,68 @@
+use std::thread;
+use std::time::Duration;
+use crate::run_command;
+
+// How often --when-idle checks whether the desktop became idle
+const IDLE_POLL_SECS: u64 = 60;
+
+// Active graphical login session as reported by logind
+pub struct Session {
+    pub user: String,
+    uid: String,
+    idle: bool,
+}
+
+// Properties of a logind session, from `loginctl show-session`
+fn session_properties(id: &str) -> Option<Vec<(String, String)>> {
+    let output = run_command("loginctl", &["show-session", id, "-p", "Type", "-p", "Active", "-p", "IdleHint", "-p", "Name", "-p", "User"]).ok()?;
+    Some(
+        output
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.trim().to_string()))
+            .collect(),
+    )
+}
+
+// The active X11 or Wayland session, if someone is using the desktop
+pub fn active_graphical() -> Option<Session> {
+    // Without logind there is no way to tell, so behave like a headless machine
+    let sessions = run_command("loginctl", &["list-sessions", "--no-legend"]).ok()?;
+    for id in sessions.lines().filter_map(|line| line.split_whitespace().next()) {
+        let properties = match session_properties(id) {
+            Some(properties) => properties,
+            None => continue,
+        };
+        let value = |key: &str| properties.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str()).unwrap_or("");
+        if matches!(value("Type"), "x11" | "wayland" | "mir") && value("Active") == "yes" {
+            return Some(Session {
+                user: value("Name").to_string(),
+                uid: value("User").to_string(),
+                idle: value("IdleHint") == "yes",
+            });
+        }
+    }
+    None
+}
+
+// Active graphical session whose user is not idle; disruptive steps wait while there is one
+pub fn busy_desktop() -> Option<Session> {
+    active_graphical().filter(|session| !session.idle)
+}
+
+// Block until no graphical session is in active use
+pub fn wait_until_idle() {
+    while let Some(session) = busy_desktop() {
+        println!("Waiting for {}'s desktop session to become idle", session.user);
+        thread::sleep(Duration::from_secs(IDLE_POLL_SECS));
+    }
+}
+
+// Show a desktop notification in the user's session; failures are only warnings
+pub fn notify_user(session: &Session, summary: &str, body: &str) {
+    let bus = format!("DBUS_SESSION_BUS_ADDRESS=unix:path=/run/user/{}/bus", session.uid);
+    let result = run_command("runuser", &["-u", &session.user, "--", "env", &bus, "notify-send", "--app-name=hacker-ostree", summary, body]);
+    if let Err(e) = result {
+        eprintln!("Warning: failed to notify {}: {}", session.user, e);
+    }
+}
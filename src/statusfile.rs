@@ -0,0 +1,85 @@
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use crate::{history, ostree};
+
+// Rewritten whole, by rename, on every state change so desktop widgets can watch it with
+// inotify instead of polling the CLI or talking D-Bus
+pub const STATUS_FILE: &str = "/run/hacker-ostree/status.json";
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Status {
+    // Command of the transaction in progress, None when idle
+    pub running: Option<String>,
+    pub packages: Vec<String>,
+    pub started: Option<u64>,
+    pub last_transaction: Option<LastTransaction>,
+    // Base and overlay updates found by the last check-update or summary
+    pub updates: Option<usize>,
+    // A deployment other than the booted one will be booted next
+    pub reboot_required: bool,
+    pub updated: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LastTransaction {
+    pub id: u64,
+    pub command: String,
+    pub success: bool,
+    pub finished: u64,
+}
+
+fn load() -> Status {
+    fs::read_to_string(STATUS_FILE)
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+fn save(status: &Status) -> Result<(), String> {
+    let dir = Path::new(STATUS_FILE).parent().ok_or("Status file has no parent directory")?;
+    fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    let mut file = NamedTempFile::new_in(dir).map_err(|e| format!("Failed to create temp file: {}", e))?;
+    let text = serde_json::to_string_pretty(status).map_err(|e| format!("Failed to serialize status: {}", e))?;
+    file.write_all(text.as_bytes()).map_err(|e| format!("Failed to write to temp file: {}", e))?;
+    // Readable by the unprivileged session the widget runs in
+    fs::set_permissions(file.path(), fs::Permissions::from_mode(0o644))
+        .map_err(|e| format!("Failed to set permissions of {}: {}", file.path().display(), e))?;
+    file.persist(STATUS_FILE).map_err(|e| format!("Failed to write {}: {}", STATUS_FILE, e))?;
+    Ok(())
+}
+
+// Apply a change to the status file. It is only a notification channel, so failing to write
+// it is a warning rather than a reason to fail what changed the state.
+pub fn update<F: FnOnce(&mut Status)>(change: F) {
+    let mut status = load();
+    change(&mut status);
+    if let Ok(deployments) = ostree::deployments() {
+        status.reboot_required = deployments.iter().position(|d| d.booted).is_some_and(|booted| booted > 0);
+    }
+    status.updated = history::now();
+    if let Err(e) = save(&status) {
+        eprintln!("Warning: {}", e);
+    }
+}
+
+pub fn transaction_started(command: &str, packages: &[String]) {
+    update(|status| {
+        status.running = Some(command.to_string());
+        status.packages = packages.to_vec();
+        status.started = Some(history::now());
+    });
+}
+
+pub fn transaction_finished(last: LastTransaction) {
+    update(|status| {
+        status.running = None;
+        status.packages = Vec::new();
+        status.started = None;
+        status.last_transaction = Some(last);
+    });
+}
@@ -0,0 +1,281 @@
+use std::collections::HashSet;
+use std::fs::{self, create_dir_all};
+use std::path::Path;
+use crate::config::{load_config, Config, Layering, StorageBackend};
+use crate::{ostree, run_command, timing, OVERLAY_DIR};
+
+const GENERATIONS_DIR: &str = "/var/lib/hacker-ostree/generations";
+const OBJECTS_DIR: &str = "/var/lib/hacker-ostree/objects";
+const GENERATION_MOUNT: &str = "/run/hacker-ostree/generation";
+// Source name used for our mounts so they can be told apart in /proc/self/mounts
+const MOUNT_SOURCE: &str = "hacker-ostree";
+
+// Run a mutating operation against the overlay, committing a new generation afterwards
+pub fn with_overlay<T, F>(op: F) -> Result<T, String>
+where
+    F: FnOnce() -> Result<T, String>,
+{
+    let config = load_config()?;
+    let mounted = timing::phase("overlay restore", || prepare_overlay(&config))?;
+    let result = match op() {
+        Ok(result) => result,
+        Err(e) => {
+            if let Err(cleanup) = discard_overlay(&config, mounted) {
+                eprintln!("Warning: {}", cleanup);
+            }
+            return Err(e);
+        }
+    };
+    timing::phase("generation commit", || commit_generation(&config))?;
+    Ok(result)
+}
+
+// Drop the overlay prepare_overlay() unpacked for an operation that failed, leaving the newest
+// image the source of truth again, and unmount the image if it mounted it
+fn discard_overlay(config: &Config, mounted: bool) -> Result<(), String> {
+    // Without an image yet the unpacked tree is all there is
+    match image_extension(config.storage_backend) {
+        Some(ext) if !list_generations(ext)?.is_empty() => {}
+        _ => return Ok(()),
+    }
+    fs::remove_dir_all(OVERLAY_DIR).map_err(|e| format!("Failed to clear {}: {}", OVERLAY_DIR, e))?;
+    create_dir_all(OVERLAY_DIR).map_err(|e| format!("Failed to create {}: {}", OVERLAY_DIR, e))?;
+    if mounted {
+        run_command("umount", &["-l", GENERATION_MOUNT])?;
+    }
+    Ok(())
+}
+
+// Directory where the current overlay content can be read
+pub fn overlay_root() -> Result<String, String> {
+    let config = load_config()?;
+    if config.storage_backend != StorageBackend::Directory && is_mounted(GENERATION_MOUNT)? {
+        return Ok(GENERATION_MOUNT.to_string());
+    }
+    Ok(OVERLAY_DIR.to_string())
+}
+
+// File extension of generation images for image-based backends
+fn image_extension(backend: StorageBackend) -> Option<&'static str> {
+    match backend {
+        StorageBackend::Directory => None,
+        StorageBackend::Squashfs => Some("squashfs"),
+        StorageBackend::Composefs => Some("cfs"),
+    }
+}
+
+// Restore the working overlay tree from the newest image before a transaction; true when
+// it mounted the image to do so
+fn prepare_overlay(config: &Config) -> Result<bool, String> {
+    let ext = match image_extension(config.storage_backend) {
+        Some(ext) => ext,
+        None => return Ok(false),
+    };
+    let latest = match list_generations(ext)?.last() {
+        Some(gen) => generation_path(*gen, ext),
+        None => return Ok(false),
+    };
+    let is_empty = fs::read_dir(OVERLAY_DIR)
+        .map(|mut entries| entries.next().is_none())
+        .unwrap_or(true);
+    if !is_empty {
+        return Ok(false);
+    }
+    let mut mounted = false;
+    match config.storage_backend {
+        StorageBackend::Squashfs => {
+            run_command("unsquashfs", &["-f", "-q", "-d", OVERLAY_DIR, &latest])?;
+        }
+        StorageBackend::Composefs => {
+            // composefs images only hold metadata, so copy the content out of the mounted tree
+            if !is_mounted(GENERATION_MOUNT)? {
+                mount_image(config, &latest)?;
+                mounted = true;
+            }
+            run_command("cp", &["-a", &format!("{}/.", GENERATION_MOUNT), OVERLAY_DIR])?;
+        }
+        StorageBackend::Directory => {}
+    }
+    Ok(mounted)
+}
+
+// Pack the overlay into a new generation image and mount it
+fn commit_generation(config: &Config) -> Result<(), String> {
+    let ext = match image_extension(config.storage_backend) {
+        Some(ext) => ext,
+        None => return Ok(()),
+    };
+    create_dir_all(GENERATIONS_DIR).map_err(|e| format!("Failed to create {}: {}", GENERATIONS_DIR, e))?;
+
+    let next = list_generations(ext)?.last().map_or(1, |gen| gen + 1);
+    let image = generation_path(next, ext);
+    let partial = format!("{}.partial", image);
+    match config.storage_backend {
+        StorageBackend::Squashfs => {
+            run_command("mksquashfs", &[
+                OVERLAY_DIR,
+                &partial,
+                "-noappend",
+                "-quiet",
+                "-comp", &config.squashfs_compression,
+            ])?;
+        }
+        StorageBackend::Composefs => {
+            create_dir_all(OBJECTS_DIR).map_err(|e| format!("Failed to create {}: {}", OBJECTS_DIR, e))?;
+            let digest_store = format!("--digest-store={}", OBJECTS_DIR);
+            run_command("mkcomposefs", &[&digest_store, OVERLAY_DIR, &partial])?;
+        }
+        StorageBackend::Directory => {}
+    }
+    fs::rename(&partial, &image).map_err(|e| format!("Failed to rename {}: {}", partial, e))?;
+
+    activate_generation(config, &image)?;
+    prune_generations(config, ext)?;
+
+    // The image is now the source of truth; drop the unpacked copy to save space
+    fs::remove_dir_all(OVERLAY_DIR).map_err(|e| format!("Failed to clear {}: {}", OVERLAY_DIR, e))?;
+    create_dir_all(OVERLAY_DIR).map_err(|e| format!("Failed to create {}: {}", OVERLAY_DIR, e))?;
+    Ok(())
+}
+
+// Mount a generation image on the generation mount point
+fn mount_image(config: &Config, image: &str) -> Result<(), String> {
+    create_dir_all(GENERATION_MOUNT).map_err(|e| format!("Failed to create {}: {}", GENERATION_MOUNT, e))?;
+    match config.storage_backend {
+        StorageBackend::Composefs => {
+            let mut options = format!("basedir={}", OBJECTS_DIR);
+            if config.composefs_verity {
+                options.push_str(",verity");
+            }
+            run_command("mount", &["-t", "composefs", "-o", &options, image, GENERATION_MOUNT])?;
+        }
+        _ => {
+            run_command("mount", &["-t", "squashfs", "-o", "loop,ro", image, GENERATION_MOUNT])?;
+        }
+    }
+    Ok(())
+}
+
+// Mount an image and, when layering live, stack its /usr as a read-only overlayfs lower
+// layer over the base /usr
+fn activate_generation(config: &Config, image: &str) -> Result<(), String> {
+    if is_mounted("/usr")? {
+        run_command("umount", &["-l", "/usr"])?;
+    }
+    if is_mounted(GENERATION_MOUNT)? {
+        run_command("umount", &["-l", GENERATION_MOUNT])?;
+    }
+    mount_image(config, image)?;
+
+    let layer = format!("{}/usr", GENERATION_MOUNT);
+    if config.layering == Layering::Live && !ostree::targets_other_stateroot()? && Path::new(&layer).is_dir() {
+        let options = format!("ro,lowerdir={}:/usr", layer);
+        run_command("mount", &["-t", "overlay", MOUNT_SOURCE, "-o", &options, "/usr"])?;
+    }
+    Ok(())
+}
+
+// Experimental: when layering into deployments, make the layered packages usable before the
+// reboot by stacking the overlay's /usr read-only over the running one, as layering live
+// does. /etc and services of the new packages still only take effect after the reboot.
+pub fn live_apply() -> Result<(), String> {
+    let layer = format!("{}/usr", overlay_root()?);
+    if !Path::new(&layer).is_dir() {
+        return Err("No layered files under /usr to apply".to_string());
+    }
+    if is_mounted("/usr")? {
+        run_command("umount", &["-l", "/usr"])?;
+    }
+    let options = format!("ro,lowerdir={}:/usr", layer);
+    run_command("mount", &["-t", "overlay", MOUNT_SOURCE, "-o", &options, "/usr"])?;
+    println!("Layered packages are usable now; /etc and services follow after the reboot");
+    Ok(())
+}
+
+// Check whether something is mounted on the target; /usr only counts if it is our overlay
+fn is_mounted(target: &str) -> Result<bool, String> {
+    let mounts = fs::read_to_string("/proc/self/mounts").map_err(|e| format!("Failed to read mounts: {}", e))?;
+    Ok(mounts.lines().any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        fields.len() > 1 && fields[1] == target && (target != "/usr" || fields[0] == MOUNT_SOURCE)
+    }))
+}
+
+// Delete all but the newest `keep-generations` images
+fn prune_generations(config: &Config, ext: &str) -> Result<(), String> {
+    let generations = list_generations(ext)?;
+    let excess = generations.len().saturating_sub(config.keep_generations.max(1));
+    for gen in &generations[..excess] {
+        let path = generation_path(*gen, ext);
+        fs::remove_file(&path).map_err(|e| format!("Failed to remove {}: {}", path, e))?;
+    }
+    if config.storage_backend == StorageBackend::Composefs && excess > 0 {
+        collect_objects(&generations[excess..])?;
+    }
+    Ok(())
+}
+
+// Remove objects from the composefs store that no remaining image references
+fn collect_objects(kept: &[u64]) -> Result<(), String> {
+    let mut referenced = HashSet::new();
+    for gen in kept {
+        let output = run_command("composefs-info", &["objects", &generation_path(*gen, "cfs")])?;
+        referenced.extend(output.lines().map(|line| line.trim().to_string()));
+    }
+    let buckets = fs::read_dir(OBJECTS_DIR).map_err(|e| format!("Failed to read {}: {}", OBJECTS_DIR, e))?;
+    for bucket in buckets.filter_map(|entry| entry.ok()) {
+        let bucket_name = bucket.file_name().to_string_lossy().to_string();
+        let objects = match fs::read_dir(bucket.path()) {
+            Ok(objects) => objects,
+            Err(_) => continue,
+        };
+        for object in objects.filter_map(|entry| entry.ok()) {
+            let relative = format!("{}/{}", bucket_name, object.file_name().to_string_lossy());
+            if !referenced.contains(&relative) {
+                fs::remove_file(object.path()).map_err(|e| format!("Failed to remove {}: {}", relative, e))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn generation_path(gen: u64, ext: &str) -> String {
+    format!("{}/gen-{}.{}", GENERATIONS_DIR, gen, ext)
+}
+
+// Generation numbers of all committed images with the given extension, oldest first
+fn list_generations(ext: &str) -> Result<Vec<u64>, String> {
+    let entries = match fs::read_dir(GENERATIONS_DIR) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let suffix = format!(".{}", ext);
+    let mut generations: Vec<u64> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            name.strip_prefix("gen-")?.strip_suffix(suffix.as_str())?.parse().ok()
+        })
+        .collect();
+    generations.sort_unstable();
+    Ok(generations)
+}
+
+// Print committed generations with their image sizes
+pub fn print_generations() -> Result<(), String> {
+    let config = load_config()?;
+    let ext = match image_extension(config.storage_backend) {
+        Some(ext) => ext,
+        None => {
+            println!("No overlay generations committed (storage backend: directory)");
+            return Ok(());
+        }
+    };
+    println!("Overlay generations:");
+    for gen in list_generations(ext)? {
+        let path = generation_path(gen, ext);
+        let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        println!("{}: {} ({} KiB)", gen, path, size / 1024);
+    }
+    Ok(())
+}
@@ -0,0 +1,75 @@
+use std::collections::BTreeSet;
+use crate::transaction;
+
+// Suggestions offered for a name that wasn't found
+const MAX_SUGGESTIONS: usize = 3;
+
+// Edit distance between two names, counting a swap of adjacent characters as one edit
+fn distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d: Vec<Vec<usize>> = (0..=a.len()).map(|i| (0..=b.len()).map(|j| if i == 0 { j } else { i }).collect()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1).min(d[i][j - 1] + 1).min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+// Known names close enough to `name` to be what was meant, closest first. Longer names
+// tolerate more typos: one edit per four characters, at least one.
+pub fn closest<'a>(name: &str, known: &'a BTreeSet<String>) -> Vec<&'a str> {
+    let allowed = (name.chars().count() / 4).max(1);
+    let mut close: Vec<(usize, &str)> = known
+        .iter()
+        .map(|candidate| (distance(name, candidate), candidate.as_str()))
+        .filter(|(distance, _)| *distance <= allowed)
+        .collect();
+    close.sort();
+    close.into_iter().take(MAX_SUGGESTIONS).map(|(_, candidate)| candidate).collect()
+}
+
+// Return `name` if it is known. Otherwise fail with the closest known names as suggestions,
+// or with `fix_typo` offer the closest one and continue with it once confirmed.
+pub fn resolve(name: &str, known: &BTreeSet<String>, what: &str, fix_typo: bool, assume_yes: bool) -> Result<String, String> {
+    if known.contains(name) {
+        return Ok(name.to_string());
+    }
+    let close = closest(name, known);
+    let Some(best) = close.first() else {
+        return Err(format!("Package {} {}", name, what));
+    };
+    if !fix_typo {
+        return Err(format!(
+            "Package {} {}; did you mean {}? Pass --fix-typo to use {}",
+            name,
+            what,
+            close.join(", "),
+            best
+        ));
+    }
+    println!("Package {} {}; using {} instead", name, what, best);
+    if !transaction::confirm(assume_yes)? {
+        return Err("Aborted".to_string());
+    }
+    Ok(best.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suggests_names_within_the_allowed_distance_closest_first() {
+        let known: BTreeSet<String> = ["firefox-esr", "firefox", "fierfox-tools", "neovim", "vim", "vis"].map(String::from).into();
+        assert_eq!(closest("firefx", &known), ["firefox"]);
+        assert_eq!(closest("vmi", &known), ["vim"]);
+        assert_eq!(closest("vi", &known), ["vim", "vis"]);
+        assert!(closest("emacs", &known).is_empty());
+    }
+}
@@ -0,0 +1,54 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+// Accumulated time per phase, in the order phases first ran
+static PHASES: Mutex<Vec<(&'static str, Duration)>> = Mutex::new(Vec::new());
+
+// Run `op` and add its duration to the named phase
+pub fn phase<T, F>(name: &'static str, op: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    if !ENABLED.load(Ordering::Relaxed) {
+        return op();
+    }
+    let started = Instant::now();
+    let result = op();
+    let elapsed = started.elapsed();
+    if let Ok(mut phases) = PHASES.lock() {
+        match phases.iter_mut().find(|(phase, _)| *phase == name) {
+            Some((_, total)) => *total += elapsed,
+            None => phases.push((name, elapsed)),
+        }
+    }
+    result
+}
+
+// Prints the per-phase breakdown when dropped, so failed commands are reported too
+pub struct Report {
+    started: Instant,
+}
+
+impl Report {
+    pub fn start(enabled: bool) -> Option<Report> {
+        ENABLED.store(enabled, Ordering::Relaxed);
+        enabled.then(|| Report { started: Instant::now() })
+    }
+}
+
+impl Drop for Report {
+    fn drop(&mut self) {
+        let total = self.started.elapsed();
+        let phases = PHASES.lock().map(|phases| phases.clone()).unwrap_or_default();
+        let accounted: Duration = phases.iter().map(|(_, d)| *d).sum();
+        eprintln!("Timing:");
+        for (name, duration) in &phases {
+            let share = duration.as_secs_f64() / total.as_secs_f64().max(f64::EPSILON) * 100.0;
+            eprintln!("  {:<20} {:>8.2}s {:>5.1}%", name, duration.as_secs_f64(), share);
+        }
+        eprintln!("  {:<20} {:>8.2}s", "other", total.saturating_sub(accounted).as_secs_f64());
+        eprintln!("  {:<20} {:>8.2}s", "total", total.as_secs_f64());
+    }
+}
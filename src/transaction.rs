@@ -0,0 +1,642 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::path::Path;
+use std::process::{Command as ProcessCommand, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::config::{load_config, Config, PromptPolicy};
+use crate::history::{self, Entry};
+use crate::index::Package;
+use crate::policy::{glob_match, Policy};
+use crate::{appstream, conffiles, daemon, dpkgdb, etcfiles, exec, fault, fetch, filelists, layering, limits, notify, ostree, resolve, run_command, statusfile, storage, AUTO_INSTALLED_FILE, HELD_FILE, INSTALLED_PKGS_FILE, OVERLAY_DIR, VAR_DIR};
+
+// Held for the duration of a transaction; contains the owner's pid
+const LOCK_FILE: &str = "/run/hacker-ostree/lock";
+// Copy of the overlay state taken before a transaction, restored if it fails
+const SNAPSHOT_DIR: &str = "/var/lib/hacker-ostree/rollback";
+// What the transaction changed in /etc, kept with the snapshot
+const ETC_JOURNAL: &str = "/var/lib/hacker-ostree/rollback/etc";
+// Follow a daemon transaction holding the lock instead of failing right away
+static ATTACH: AtomicBool = AtomicBool::new(true);
+// --i-know-what-im-doing: skip the confirmation tokens of destructive operations
+static SKIP_TOKENS: AtomicBool = AtomicBool::new(false);
+// Letters typed with the same key on QWERTY, QWERTZ and AZERTY layouts and not easily
+// misread, so confirmation tokens can be typed whatever the keyboard and locale
+const TOKEN_LETTERS: &[u8] = b"bcdefghjknprstuvx";
+const TOKEN_LENGTH: usize = 6;
+// Output left unterminated this long by a running command is checked for a prompt
+const PROMPT_IDLE_SECS: u64 = 3;
+// End of a command's output kept for recognizing prompts
+const TAIL_BYTES: usize = 1024;
+// Set by the daemon for its jobs, which then print progress lines for it to relay
+pub const PROGRESS_ENV: &str = "HACKER_OSTREE_PROGRESS";
+// Progress lines look like "@progress download 40"
+pub const PROGRESS_PREFIX: &str = "@progress ";
+
+// What installing one package is expected to cost
+pub struct PlannedPackage {
+    pub name: String,
+    pub version: String,
+    // Bytes to download, from the Packages "Size" field
+    pub download_size: u64,
+    // Bytes the package occupies once unpacked ("Installed-Size" is in KiB)
+    pub installed_size: u64,
+    // Bytes used by the version currently in the overlay
+    pub previous_size: u64,
+}
+
+// Size estimate for a set of package installations
+pub struct Plan {
+    pub packages: Vec<PlannedPackage>,
+    // Requested packages missing from the index, whose sizes are unknown
+    pub unknown: Vec<String>,
+}
+
+impl Plan {
+    pub fn download_size(&self) -> u64 {
+        self.packages.iter().map(|p| p.download_size).sum()
+    }
+
+    pub fn installed_delta(&self) -> i64 {
+        self.packages
+            .iter()
+            .map(|p| p.installed_size as i64 - p.previous_size as i64)
+            .sum()
+    }
+}
+
+// Estimate sizes for installing `names` at the chosen versions, or their policy candidates
+pub fn plan(names: &[resolve::Selection], packages: &[Package], policy: &Policy) -> Result<Plan, String> {
+    let mut plan = Plan { packages: Vec::new(), unknown: Vec::new() };
+    for (name, version) in names {
+        let candidate = match policy.select(name, version.as_deref(), packages) {
+            Some(candidate) => candidate,
+            None => {
+                plan.unknown.push(name.clone());
+                continue;
+            }
+        };
+        let number = |field: Option<&str>| field.and_then(|v| v.trim().parse::<u64>().ok()).unwrap_or(0);
+        plan.packages.push(PlannedPackage {
+            name: name.clone(),
+            version: candidate.version().to_string(),
+            download_size: number(candidate.field("Size")),
+            installed_size: number(candidate.field("Installed-Size")) * 1024,
+            previous_size: dpkgdb::installed_size(name)?.unwrap_or(0) * 1024,
+        });
+    }
+    Ok(plan)
+}
+
+// Human readable byte count
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+// Print what a plan will download and how much the overlay grows
+pub fn print_summary(plan: &Plan) {
+    for package in &plan.packages {
+        println!("  {} {} ({})", package.name, package.version, format_size(package.download_size));
+    }
+    for name in &plan.unknown {
+        println!("  {} (not in index, size unknown)", name);
+    }
+    let download = plan.download_size();
+    println!("Download size: {}", format_size(download));
+    let delta = plan.installed_delta();
+    let sign = if delta < 0 { "-" } else { "+" };
+    println!("Overlay size change: {}{}", sign, format_size(delta.unsigned_abs()));
+    if let Some(rate) = fetch::measured_throughput() {
+        let secs = (download as f64 / rate).ceil() as u64;
+        println!("Estimated download time: {}s at {}/s", secs, format_size(rate as u64));
+    }
+}
+
+// Bytes available to unprivileged writers on the filesystem holding `path`
+fn free_space(path: &str) -> Result<u64, String> {
+    let output = run_command("df", &["--output=avail", "-B1", path])?;
+    output
+        .lines()
+        .nth(1)
+        .and_then(|line| line.trim().parse().ok())
+        .ok_or_else(|| format!("Failed to parse free space of {}", path))
+}
+
+// Refuse plans exceeding the configured download and disk limits unless forced
+pub fn check_limits(plan: &Plan, config: &Config, force: bool) -> Result<(), String> {
+    let mut problems = Vec::new();
+    let download = plan.download_size();
+    if config.max_download_size > 0 && download > config.max_download_size {
+        problems.push(format!(
+            "download of {} exceeds max-download-size of {}",
+            format_size(download),
+            format_size(config.max_download_size)
+        ));
+    }
+    if config.min_free_space_after > 0 {
+        // The cache and the overlay both live under VAR_DIR
+        let needed = download as i64 + plan.installed_delta().max(0);
+        let after = free_space(VAR_DIR)? as i64 - needed;
+        if after < config.min_free_space_after as i64 {
+            problems.push(format!(
+                "only {} would remain free, below min-free-space-after of {}",
+                format_size(after.max(0) as u64),
+                format_size(config.min_free_space_after)
+            ));
+        }
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+    if force {
+        for problem in &problems {
+            eprintln!("Warning: {} (continuing because of --force-size)", problem);
+        }
+        return Ok(());
+    }
+    Err(format!("Transaction refused: {}; pass --force-size to override", problems.join("; ")))
+}
+
+// Ask before continuing; non-interactive runs and --yes proceed without asking
+pub fn confirm(assume_yes: bool) -> Result<bool, String> {
+    if assume_yes || !io::stdin().is_terminal() {
+        return Ok(true);
+    }
+    print!("Do you want to continue? [Y/n] ");
+    io::stdout().flush().map_err(|e| format!("Failed to write prompt: {}", e))?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer).map_err(|e| format!("Failed to read answer: {}", e))?;
+    let answer = answer.trim().to_lowercase();
+    Ok(answer.is_empty() || answer == "y" || answer == "yes")
+}
+
+pub fn set_skip_tokens(enabled: bool) {
+    SKIP_TOKENS.store(enabled, Ordering::Relaxed);
+}
+
+fn confirmation_token() -> Result<String, String> {
+    let mut bytes = [0u8; TOKEN_LENGTH];
+    File::open("/dev/urandom")
+        .and_then(|mut random| random.read_exact(&mut bytes))
+        .map_err(|e| format!("Failed to read /dev/urandom: {}", e))?;
+    Ok(bytes.iter().map(|b| TOKEN_LETTERS[*b as usize % TOKEN_LETTERS.len()] as char).collect())
+}
+
+// Before an operation that is hard to undo and listed in confirm-tokens, make the user type a
+// fresh token. Unlike confirm(), a run without a terminal fails instead of proceeding unless
+// --i-know-what-im-doing is given, so a copy-pasted or fleet-wide command can't do it by accident.
+pub fn confirm_destructive(operation: &str, consequence: &str) -> Result<(), String> {
+    if SKIP_TOKENS.load(Ordering::Relaxed) || !load_config()?.confirm_tokens.iter().any(|name| name == operation) {
+        return Ok(());
+    }
+    if !io::stdin().is_terminal() {
+        return Err(format!(
+            "{} {}; run it from a terminal to confirm, or pass --i-know-what-im-doing",
+            operation, consequence
+        ));
+    }
+    let token = confirmation_token()?;
+    print!("This {}. Type {} to continue: ", consequence, token);
+    io::stdout().flush().map_err(|e| format!("Failed to write prompt: {}", e))?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer).map_err(|e| format!("Failed to read answer: {}", e))?;
+    if answer.trim().to_ascii_lowercase() != token {
+        return Err("The confirmation token didn't match; nothing was changed".to_string());
+    }
+    Ok(())
+}
+
+// Exclusive transaction lock, released when dropped
+pub struct Lock;
+
+// Pid of the process holding the transaction lock, None when it is free or its holder died
+pub fn lock_owner() -> Option<String> {
+    let owner = fs::read_to_string(LOCK_FILE).ok()?.trim().to_string();
+    if !owner.is_empty() && Path::new(&format!("/proc/{}", owner)).exists() {
+        Some(owner)
+    } else {
+        None
+    }
+}
+
+impl Lock {
+    pub fn acquire() -> Result<Lock, String> {
+        if let Some(parent) = Path::new(LOCK_FILE).parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+        }
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(LOCK_FILE) {
+                Ok(mut file) => {
+                    write!(file, "{}", std::process::id()).map_err(|e| format!("Failed to write {}: {}", LOCK_FILE, e))?;
+                    return Ok(Lock);
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    if let Some(owner) = lock_owner() {
+                        return Err(format!("Another transaction is running (pid {})", owner));
+                    }
+                    // Left behind by a process that no longer exists
+                    fs::remove_file(LOCK_FILE).map_err(|e| format!("Failed to remove stale {}: {}", LOCK_FILE, e))?;
+                }
+                Err(e) => return Err(format!("Failed to create {}: {}", LOCK_FILE, e)),
+            }
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(LOCK_FILE);
+    }
+}
+
+// Everything a transaction may change outside the OSTree deployment
+fn state_paths() -> [&'static str; 8] {
+    [
+        OVERLAY_DIR,
+        dpkgdb::ADMIN_DIR,
+        filelists::FILELISTS_DIR,
+        conffiles::CONFFILES_DIR,
+        INSTALLED_PKGS_FILE,
+        AUTO_INSTALLED_FILE,
+        HELD_FILE,
+        etcfiles::TRACKING_FILE,
+    ]
+}
+
+fn saved_path(dir: &str, path: &str) -> String {
+    format!("{}/{}", dir, path.trim_start_matches('/').replace('/', "_"))
+}
+
+// Copy the overlay state into `dir`
+pub fn save_state(dir: &str) -> Result<(), String> {
+    if Path::new(dir).exists() {
+        fs::remove_dir_all(dir).map_err(|e| format!("Failed to remove {}: {}", dir, e))?;
+    }
+    fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir, e))?;
+    for path in state_paths() {
+        if Path::new(path).exists() {
+            run_command("cp", &["-a", "--reflink=auto", path, &saved_path(dir, path)])?;
+        }
+    }
+    Ok(())
+}
+
+// Replace the overlay state with the copy in `dir`, moving it there when `consume` is set.
+// Paths that did not exist when the copy was saved are removed.
+pub fn restore_state(dir: &str, consume: bool) -> Result<(), String> {
+    for path in state_paths() {
+        let saved = saved_path(dir, path);
+        let target = Path::new(path);
+        if target.is_dir() {
+            fs::remove_dir_all(target).map_err(|e| format!("Failed to remove {}: {}", path, e))?;
+        } else if target.exists() {
+            fs::remove_file(target).map_err(|e| format!("Failed to remove {}: {}", path, e))?;
+        }
+        if !Path::new(&saved).exists() {
+            continue;
+        }
+        if consume {
+            fs::rename(&saved, target).map_err(|e| format!("Failed to restore {}: {}", path, e))?;
+        } else {
+            run_command("cp", &["-a", "--reflink=auto", &saved, path])?;
+        }
+    }
+    Ok(())
+}
+
+// Copy the overlay state aside before a transaction
+fn take_snapshot() -> Result<(), String> {
+    save_state(SNAPSHOT_DIR)
+}
+
+// Put the state copied by take_snapshot back in place, and /etc the way it was. A failure
+// restoring one doesn't stop restoring the other; the snapshot is only discarded when both worked
+fn restore_snapshot() -> Result<(), String> {
+    let etc = etcfiles::undo(ETC_JOURNAL);
+    let state = restore_state(SNAPSHOT_DIR, true);
+    match (etc, state) {
+        (Ok(()), Ok(())) => discard_snapshot(),
+        (Err(e), Ok(())) | (Ok(()), Err(e)) => Err(e),
+        (Err(etc), Err(state)) => Err(format!("{}; {}", etc, state)),
+    }
+}
+
+fn discard_snapshot() -> Result<(), String> {
+    if Path::new(SNAPSHOT_DIR).exists() {
+        fs::remove_dir_all(SNAPSHOT_DIR).map_err(|e| format!("Failed to remove {}: {}", SNAPSHOT_DIR, e))?;
+    }
+    Ok(())
+}
+
+// Report that `done` of `total` steps of a phase are complete when running under the daemon
+pub fn progress(phase: &str, done: usize, total: usize) {
+    if total > 0 && std::env::var_os(PROGRESS_ENV).is_some() {
+        println!("{}{} {}", PROGRESS_PREFIX, phase, done * 100 / total);
+    }
+}
+
+pub fn set_attach(enabled: bool) {
+    ATTACH.store(enabled, Ordering::Relaxed);
+}
+
+// When the daemon holds the lock, stream its job until it is done and take the lock after it
+fn wait_for_daemon(busy: String) -> Result<Lock, String> {
+    let (id, command) = match daemon::running_job() {
+        Some(job) => job,
+        None => return Err(busy),
+    };
+    if !ATTACH.load(Ordering::Relaxed) {
+        return Err(format!("{}: daemon job {} ({}) is in progress", busy, id, command));
+    }
+    println!("Daemon job {} ({}) is in progress; following its output", id, command);
+    // Its outcome is reported by the job itself; this command only needs the lock afterwards
+    if let Err(e) = daemon::attach(Some(id)) {
+        eprintln!("{}", e);
+    }
+    Lock::acquire()
+}
+
+// Run a mutating operation as a transaction: take the lock, apply it to the overlay,
+// roll the overlay state and any deployment it staged back if it fails, and record the
+// outcome in the history
+pub fn run<T, F>(command: &str, packages: &[String], op: F) -> Result<T, String>
+where
+    F: FnOnce() -> Result<T, String>,
+{
+    let _lock = match Lock::acquire() {
+        Ok(lock) => lock,
+        Err(busy) => wait_for_daemon(busy)?,
+    };
+    limits::apply(&load_config()?)?;
+    let started = history::now();
+    let base = ostree::current().ok().flatten().map(|deployment| deployment.checksum);
+    let before = dpkgdb::installed_versions()?;
+    take_snapshot()?;
+    statusfile::transaction_started(command, packages);
+    // The new deployment is composed before image backends pack the overlay away
+    let mut staged = None;
+    let result = storage::with_overlay(|| {
+        let inputs = layering::inputs()?;
+        fault::point("snapshot")?;
+        crate::migrate_to_admindir()?;
+        let value = op()?;
+        appstream::generate()?;
+        etcfiles::sync(ETC_JOURNAL)?;
+        fault::point("stage")?;
+        staged = layering::stage(inputs)?;
+        fault::point("commit")?;
+        Ok(value)
+    });
+    let mut discarded = Ok(());
+    let error = match &result {
+        Ok(_) => {
+            discarded = discard_snapshot();
+            None
+        }
+        Err(e) => {
+            eprintln!("Transaction failed, rolling back overlay changes");
+            if let Some(staged) = &staged {
+                if let Err(undo) = layering::unstage(staged) {
+                    eprintln!("Warning: failed to remove the staged deployment: {}", undo);
+                }
+            }
+            if let Err(restore_error) = restore_snapshot() {
+                eprintln!("Warning: rollback incomplete: {}", restore_error);
+            }
+            Some(e.clone())
+        }
+    };
+    let summary = match &error {
+        None => format!("{} succeeded", command),
+        Some(_) => format!("{} failed and was rolled back", command),
+    };
+    let mut details = format!("Command: {}\nPackages: {}", command, packages.join(" "));
+    if let Some(e) = &error {
+        details.push_str(&format!("\nError: {}", e));
+    }
+    let success = error.is_none();
+    let finished = history::now();
+    let recorded = record(command, packages, started, finished, base, &before, error);
+    // Whether or not recording worked, the transaction is no longer running
+    let id = recorded.as_ref().copied().unwrap_or_default();
+    statusfile::transaction_finished(statusfile::LastTransaction { id, command: command.to_string(), success, finished });
+    crate::refresh_status();
+    notify::send("transaction", &summary, &details);
+    discarded?;
+    recorded?;
+    result
+}
+
+// Note a transaction in the history, returning its id; `before` are the overlay packages it
+// started with
+fn record(
+    command: &str,
+    packages: &[String],
+    started: u64,
+    finished: u64,
+    base: Option<String>,
+    before: &[(String, String)],
+    error: Option<String>,
+) -> Result<u64, String> {
+    // A failed transaction was rolled back, so only a successful one changed anything
+    let (added, removed) = match error {
+        None => history::changes(before, &dpkgdb::installed_versions()?),
+        Some(_) => Default::default(),
+    };
+    history::record(Entry {
+        id: 0,
+        started,
+        finished,
+        command: command.to_string(),
+        command_line: std::env::args().collect::<Vec<_>>().join(" "),
+        user: history::invoking_user(),
+        packages: packages.to_vec(),
+        added,
+        removed,
+        base,
+        success: error.is_none(),
+        error,
+    })
+}
+
+// Run the configured post-install actions matching a freshly installed package
+pub fn run_post_actions(package: &str) -> Result<(), String> {
+    let config = load_config()?;
+    for action in config.post_install.iter().filter(|a| glob_match(&a.package, package)) {
+        let args: Vec<String> = action
+            .run
+            .iter()
+            .map(|arg| arg.replace("{root}", OVERLAY_DIR).replace("{package}", package))
+            .collect();
+        let (cmd, rest) = args
+            .split_first()
+            .ok_or_else(|| format!("Empty post-install action for {}", action.package))?;
+        println!("Running post-install action for {}: {}", package, args.join(" "));
+        let rest: Vec<&str> = rest.iter().map(String::as_str).collect();
+        run_watched(cmd, &rest).map_err(|e| format!("Post-install action for {} failed: {}", package, e))?;
+    }
+    Ok(())
+}
+
+// Read a child's output stream, noting the time of every chunk as progress and keeping the
+// end of the output of both streams in `tail`
+fn drain<R: Read + Send + 'static>(
+    mut stream: R,
+    progress: Arc<AtomicU64>,
+    started: Instant,
+    tail: Arc<Mutex<Vec<u8>>>,
+) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut collected = Vec::new();
+        let mut buf = [0u8; 4096];
+        while let Ok(n) = stream.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            collected.extend_from_slice(&buf[..n]);
+            let mut tail = tail.lock().unwrap_or_else(|e| e.into_inner());
+            tail.extend_from_slice(&buf[..n]);
+            let excess = tail.len().saturating_sub(TAIL_BYTES);
+            tail.drain(..excess);
+            progress.store(started.elapsed().as_secs(), Ordering::Relaxed);
+        }
+        collected
+    })
+}
+
+// The question a command is waiting on: the last line of its output when it was left
+// unterminated and ends like a prompt ("[Y/n] ?", "Choice:"), not like progress ("...")
+fn pending_prompt(output: &str) -> Option<&str> {
+    let line = output.rsplit('\n').next()?.trim();
+    line.ends_with(['?', ':', ']', ')', '>']).then_some(line)
+}
+
+// Answer a prompt from prompt-answers, or with its default under the default prompt policy;
+// None when it must not be answered
+fn prompt_answer<'a>(prompt: &str, config: &'a Config) -> Option<&'a str> {
+    match config.prompt_answers.iter().find(|answer| glob_match(&answer.prompt, prompt)) {
+        Some(answer) => Some(&answer.answer),
+        None if config.prompt_policy == PromptPolicy::Default => Some(""),
+        None => None,
+    }
+}
+
+// Like run_command, but kill the command if it produces no output for the configured
+// stall-timeout-secs (a hung maintainer script, for example). Its input is /dev/null, so
+// anything reading it gets end of file instead of hanging.
+pub fn run_watched(cmd: &str, args: &[&str]) -> Result<String, String> {
+    watch(cmd, args, false)
+}
+
+// Run dpkg like run_watched. When prompt-answers or the default prompt policy can answer
+// them, its input is a pipe nobody types into and prompts are answered through it, or abort
+// it with the question instead of hanging unseen. Otherwise prompts get end of file and dpkg
+// fails on them right away.
+pub fn run_dpkg(args: &[&str]) -> Result<String, String> {
+    watch("dpkg", args, true)
+}
+
+// Run a command watched for stalls, with its prompts answered when `prompts` is set and the
+// configuration answers any
+fn watch(cmd: &str, args: &[&str], prompts: bool) -> Result<String, String> {
+    if let Some(result) = exec::replayed(cmd, args) {
+        return result;
+    }
+    let config = load_config()?;
+    let timeout = config.stall_timeout_secs;
+    let prompts = prompts && (!config.prompt_answers.is_empty() || config.prompt_policy == PromptPolicy::Default);
+    let mut command = ProcessCommand::new(cmd);
+    command.args(args).stdout(Stdio::piped()).stderr(Stdio::piped());
+    command.stdin(if prompts { Stdio::piped() } else { Stdio::null() });
+    // debconf takes the questions' defaults instead of asking
+    if cmd == "dpkg" && std::env::var_os("DEBIAN_FRONTEND").is_none() {
+        command.env("DEBIAN_FRONTEND", "noninteractive");
+    }
+    let mut child = command.spawn().map_err(|e| format!("Failed to execute {}: {}", cmd, e))?;
+    let mut stdin = child.stdin.take();
+    let started = Instant::now();
+    let progress = Arc::new(AtomicU64::new(0));
+    let tail = Arc::new(Mutex::new(Vec::new()));
+    let stdout = drain(child.stdout.take().ok_or("Failed to capture stdout")?, progress.clone(), started, tail.clone());
+    let stderr = drain(child.stderr.take().ok_or("Failed to capture stderr")?, progress.clone(), started, tail.clone());
+
+    // Output position of the last prompt answered, so it isn't answered twice
+    let mut answered = None;
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(|e| format!("Failed to wait for {}: {}", cmd, e))? {
+            break status;
+        }
+        let idle = started.elapsed().as_secs().saturating_sub(progress.load(Ordering::Relaxed));
+        if let Some(input) = stdin.as_mut().filter(|_| idle >= PROMPT_IDLE_SECS) {
+            let output = String::from_utf8_lossy(&tail.lock().unwrap_or_else(|e| e.into_inner())).to_string();
+            if let Some(prompt) = pending_prompt(&output).filter(|_| answered != Some(progress.load(Ordering::Relaxed))) {
+                match prompt_answer(prompt, &config) {
+                    Some(answer) => {
+                        println!("Answering \"{}\" with \"{}\"", prompt, answer);
+                        let _ = writeln!(input, "{}", answer);
+                        answered = Some(progress.load(Ordering::Relaxed));
+                    }
+                    None => {
+                        let _ = child.kill();
+                        let _ = child.wait();
+                        return Err(format!(
+                            "{} needs interactive input and was aborted: \"{}\"; answer it with a prompt-answers entry in config.json, or set prompt-policy to \"default\"",
+                            cmd, prompt
+                        ));
+                    }
+                }
+            }
+        }
+        if timeout > 0 && idle >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("{} made no progress for {}s and was aborted", cmd, timeout));
+        }
+        thread::sleep(Duration::from_millis(200));
+    };
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    let result = if status.success() {
+        Ok(String::from_utf8_lossy(&stdout).to_string())
+    } else {
+        Err(format!("Command failed: {}\nStderr: {}", cmd, String::from_utf8_lossy(&stderr)))
+    };
+    exec::record(cmd, args, &result);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PromptAnswer;
+
+    #[test]
+    fn recognizes_prompts_and_answers_them_per_policy() {
+        let conffile = "Configuration file '/etc/foo.conf'\n ==> Modified (by you or by a script) since installation.\n*** foo.conf (Y/I/N/O/D/Z) [default=N] ?";
+        assert_eq!(pending_prompt(conffile), Some("*** foo.conf (Y/I/N/O/D/Z) [default=N] ?"));
+        assert_eq!(pending_prompt("(Reading database ... "), None);
+        assert_eq!(pending_prompt("Setting up foo (1.0) ...\n"), None);
+
+        let mut config = Config {
+            prompt_answers: vec![PromptAnswer { prompt: "*Restart services*".to_string(), answer: "yes".to_string() }],
+            ..Config::default()
+        };
+        assert_eq!(prompt_answer("Restart services during package upgrades? [yes/no]", &config), Some("yes"));
+        assert_eq!(prompt_answer("Choice:", &config), None);
+        config.prompt_policy = PromptPolicy::Default;
+        assert_eq!(prompt_answer("Choice:", &config), Some(""));
+    }
+}
@@ -0,0 +1,110 @@
+use std::cmp::Ordering;
+
+// Split a Debian version into (epoch, upstream, revision)
+fn split_version(version: &str) -> (u64, &str, &str) {
+    let (epoch, rest) = match version.split_once(':') {
+        Some((epoch, rest)) => (epoch.parse().unwrap_or(0), rest),
+        None => (0, version),
+    };
+    match rest.rsplit_once('-') {
+        Some((upstream, revision)) => (epoch, upstream, revision),
+        None => (epoch, rest, ""),
+    }
+}
+
+// Sort weight of a character in the non-digit parts, as defined by dpkg
+fn order(c: Option<u8>) -> i32 {
+    match c {
+        None => 0,
+        Some(c) if c.is_ascii_digit() => 0,
+        Some(c) if c.is_ascii_alphabetic() => c as i32,
+        Some(b'~') => -1,
+        Some(c) => c as i32 + 256,
+    }
+}
+
+// dpkg's verrevcmp: alternate between non-digit and digit runs
+fn compare_part(a: &str, b: &str) -> Ordering {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (mut i, mut j) = (0, 0);
+    let is_digit = |s: &[u8], k: usize| s.get(k).is_some_and(u8::is_ascii_digit);
+    while i < a.len() || j < b.len() {
+        while (i < a.len() && !is_digit(a, i)) || (j < b.len() && !is_digit(b, j)) {
+            let (ac, bc) = (order(a.get(i).copied()), order(b.get(j).copied()));
+            if ac != bc {
+                return ac.cmp(&bc);
+            }
+            i += 1;
+            j += 1;
+        }
+        while a.get(i) == Some(&b'0') {
+            i += 1;
+        }
+        while b.get(j) == Some(&b'0') {
+            j += 1;
+        }
+        let mut first_diff = Ordering::Equal;
+        while is_digit(a, i) && is_digit(b, j) {
+            if first_diff == Ordering::Equal {
+                first_diff = a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        }
+        if is_digit(a, i) {
+            return Ordering::Greater;
+        }
+        if is_digit(b, j) {
+            return Ordering::Less;
+        }
+        if first_diff != Ordering::Equal {
+            return first_diff;
+        }
+    }
+    Ordering::Equal
+}
+
+// Compare two Debian package versions
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (epoch_a, upstream_a, revision_a) = split_version(a);
+    let (epoch_b, upstream_b, revision_b) = split_version(b);
+    epoch_a
+        .cmp(&epoch_b)
+        .then_with(|| compare_part(upstream_a, upstream_b))
+        .then_with(|| compare_part(revision_a, revision_b))
+}
+
+// Whether a version satisfies a Depends-style constraint such as (">=", "1.2-1")
+pub fn satisfies(version: &str, op: &str, wanted: &str) -> bool {
+    let ordering = compare_versions(version, wanted);
+    match op {
+        "<<" | "<" => ordering == Ordering::Less,
+        "<=" => ordering != Ordering::Greater,
+        "=" => ordering == Ordering::Equal,
+        ">=" => ordering != Ordering::Less,
+        ">>" | ">" => ordering == Ordering::Greater,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_debian_versions() {
+        assert_eq!(compare_versions("1.0", "1.0"), Ordering::Equal);
+        assert_eq!(compare_versions("1.0~rc1", "1.0"), Ordering::Less);
+        assert_eq!(compare_versions("2.12~bpo12+1", "2.12"), Ordering::Less);
+        assert_eq!(compare_versions("1:0.9", "2.0"), Ordering::Greater);
+        assert_eq!(compare_versions("1.0-10", "1.0-9"), Ordering::Greater);
+        assert_eq!(compare_versions("1.0a", "1.0"), Ordering::Greater);
+    }
+
+    #[test]
+    fn checks_relations() {
+        assert!(satisfies("2.0", ">=", "1.5"));
+        assert!(satisfies("1.5", "<<", "2.0"));
+        assert!(!satisfies("2.0", "=", "2.0-1"));
+    }
+}
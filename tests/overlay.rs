@@ -0,0 +1,413 @@
+// Install and remove real packages from a local repository with all state kept in a
+// temporary root (HACKER_OSTREE_ROOT) and ostree answered from a recording
+// (HACKER_OSTREE_REPLAY), so this runs without root and without an OSTree system.
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::{Command, Output};
+use tempfile::TempDir;
+
+const STATUS: &str = "* hackeros aaa111.0\n    Version: 2024.1\n";
+
+// Stand-in for ostree when layering into deployments: keeps the deployments in a status file
+// next to it, one line each, and composes every layered commit as bbb222 on aaa111
+const FAKE_OSTREE: &str = r#"#!/bin/sh
+status="$(dirname "$0")/status"
+case "$1 $2" in
+"admin status") cat "$status" ;;
+"admin deploy") printf '  hackeros %s.%s\n' "$4" "$(wc -l < "$status")" | cat - "$status" > "$status.new" && mv "$status.new" "$status" ;;
+"admin undeploy") sed -i "$(($3 + 1))d" "$status" ;;
+checkout*) mkdir -p "$4" ;;
+commit*) echo bbb222 ;;
+"show --repo=/ostree/repo") [ "$3 $4" = "--print-metadata-key=hackeros.base bbb222" ] && echo "'aaa111'" || { echo "error: No such metadata key" >&2; exit 1; } ;;
+*) echo "error: No such metadata key" >&2; exit 1 ;;
+esac
+"#;
+
+fn available(cmd: &str, args: &[&str]) -> bool {
+    Command::new(cmd).args(args).output().is_ok_and(|output| output.status.success())
+}
+
+// The relocated root needs unprivileged user namespaces, and packages need dpkg
+fn supported() -> bool {
+    let supported = available("unshare", &["--user", "--map-root-user", "--mount", "true"])
+        && available("dpkg-deb", &["--version"]);
+    if !supported {
+        eprintln!("skipping: needs dpkg and unprivileged user namespaces");
+    }
+    supported
+}
+
+fn sha256(path: &Path) -> String {
+    let output = Command::new("sha256sum").arg(path).output().unwrap();
+    String::from_utf8_lossy(&output.stdout).split_whitespace().next().unwrap().to_string()
+}
+
+// Build a package shipping /usr/share/<name>/README and AppStream metadata
+fn build_deb(dir: &Path, name: &str, version: &str, depends: Option<&str>) -> String {
+    let tree = dir.join(format!("{}-{}", name, version));
+    fs::create_dir_all(tree.join("DEBIAN")).unwrap();
+    fs::create_dir_all(tree.join("usr/share").join(name)).unwrap();
+    fs::write(tree.join("usr/share").join(name).join("README"), version).unwrap();
+    fs::create_dir_all(tree.join("usr/share/metainfo")).unwrap();
+    let metainfo = format!("<?xml version=\"1.0\"?>\n<component type=\"generic\">\n  <id>org.example.{}</id>\n</component>\n", name);
+    fs::write(tree.join(format!("usr/share/metainfo/org.example.{}.metainfo.xml", name)), metainfo).unwrap();
+    let mut control = format!("Package: {}\nVersion: {}\nArchitecture: all\nMaintainer: Test <test@example.org>\n", name, version);
+    if let Some(depends) = depends {
+        control.push_str(&format!("Depends: {}\n", depends));
+    }
+    control.push_str(&format!("Description: {} test package\n", name));
+    fs::write(tree.join("DEBIAN/control"), &control).unwrap();
+    let file = format!("{}_{}_all.deb", name, version);
+    let built = Command::new("dpkg-deb").args(["--root-owner-group", "--build"]).arg(&tree).arg(dir.join(&file)).output().unwrap();
+    assert!(built.status.success(), "{}", String::from_utf8_lossy(&built.stderr));
+    let deb = dir.join(&file);
+    let size = fs::metadata(&deb).unwrap().len();
+    format!("{}Filename: {}\nSize: {}\nSHA256: {}\n", control, file, size, sha256(&deb))
+}
+
+struct Sandbox {
+    dir: TempDir,
+}
+
+impl Sandbox {
+    // A flat repository with app depending on lib, and a root configured to use it
+    fn new() -> Sandbox {
+        let dir = TempDir::new().unwrap();
+        let config = dir.path().join("root/etc/hacker-ostree");
+        fs::create_dir_all(&config).unwrap();
+        fs::write(config.join("config.json"), r#"{"layering": "live"}"#).unwrap();
+        fs::write(config.join("repos.json"), "[]").unwrap();
+        // Booted into the deployment the recording's ostree reports
+        fs::create_dir_all(dir.path().join("root/run")).unwrap();
+        fs::write(dir.path().join("root/run/ostree-booted"), "").unwrap();
+
+        // The live overlay is mounted over /usr of the test's own mount namespace
+        let recording = serde_json::json!([
+            {"command": "ostree", "args": ["admin", "status"], "stdout": STATUS},
+            {"command": "ostree", "args": ["show", "--repo=/ostree/repo", "*", "aaa111"], "error": "error: No such metadata key"},
+            {"command": "mount", "args": ["-t", "overlay", "*", "-o", "*", "/usr"]},
+            {"command": "umount", "args": ["*"]},
+        ]);
+        fs::write(dir.path().join("recording.json"), recording.to_string()).unwrap();
+        let sandbox = Sandbox { dir };
+        sandbox.add_repo("local", &[("lib", "1.0", None), ("app", "1.0", Some("lib (>= 1.0)"))]);
+        sandbox
+    }
+
+    // Layering into deployments, with ostree played by FAKE_OSTREE instead of the recording
+    fn with_deployments() -> Sandbox {
+        let sandbox = Sandbox::new();
+        let root = sandbox.dir.path();
+        fs::write(root.join("root/etc/hacker-ostree/config.json"), r#"{"layering": "deployment"}"#).unwrap();
+        fs::create_dir_all(root.join("bin")).unwrap();
+        fs::write(root.join("bin/ostree"), FAKE_OSTREE).unwrap();
+        fs::set_permissions(root.join("bin/ostree"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(root.join("bin/status"), "* hackeros aaa111.0\n").unwrap();
+        let recording: Vec<serde_json::Value> = serde_json::from_str(&fs::read_to_string(root.join("recording.json")).unwrap()).unwrap();
+        let recording: Vec<serde_json::Value> = recording.into_iter().filter(|call| call["command"] != "ostree").collect();
+        fs::write(root.join("recording.json"), serde_json::to_string(&recording).unwrap()).unwrap();
+        sandbox
+    }
+
+    // Build a flat repository of (name, version, depends) packages and configure it last
+    fn add_repo(&self, name: &str, debs: &[(&str, &str, Option<&str>)]) {
+        let repo = self.dir.path().join(name);
+        fs::create_dir_all(&repo).unwrap();
+        let packages: Vec<String> = debs.iter().map(|(deb, version, depends)| build_deb(&repo, deb, version, *depends)).collect();
+        let packages = packages.join("\n");
+        fs::write(repo.join("Packages"), &packages).unwrap();
+        let release = format!(
+            "Suite: ./\nSHA256:\n {} {} Packages\n",
+            sha256(&repo.join("Packages")),
+            packages.len()
+        );
+        fs::write(repo.join("Release"), release).unwrap();
+
+        let path = self.dir.path().join("root/etc/hacker-ostree/repos.json");
+        let mut repos: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        repos.as_array_mut().unwrap().push(serde_json::json!({
+            "name": name,
+            "type": "deb",
+            "options": ["trusted=yes"],
+            "uri": format!("file://{}", repo.display()),
+            "suite": "./",
+        }));
+        fs::write(path, repos.to_string()).unwrap();
+    }
+
+    fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_hacker-ostree"));
+        command
+            .args(args)
+            .env("HACKER_OSTREE_ROOT", self.dir.path().join("root"))
+            .env("HACKER_OSTREE_REPLAY", self.dir.path().join("recording.json"));
+        let bin = self.dir.path().join("bin");
+        if bin.exists() {
+            command.env("PATH", format!("{}:{}", bin.display(), std::env::var("PATH").unwrap_or_default()));
+        }
+        command
+    }
+
+    fn run(&self, args: &[&str]) -> Output {
+        let output = self.command(args).output().unwrap();
+        assert!(output.status.success(), "{:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+        output
+    }
+
+    // Run with a fault injected at `point`, which must make it fail
+    fn run_failing_at(&self, point: &str, args: &[&str]) -> String {
+        let output = self
+            .command(args)
+            .args(["--inject-fault", point])
+            .env("HACKER_OSTREE_FAULT_INJECTION", "1")
+            .output()
+            .unwrap();
+        assert!(!output.status.success(), "{:?} succeeded despite a fault at {}", args, point);
+        String::from_utf8_lossy(&output.stderr).to_string()
+    }
+
+    fn list(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.run(&["list", "--json"]).stdout).unwrap()
+    }
+
+    fn overlay_file(&self, path: &str) -> std::path::PathBuf {
+        self.dir.path().join("root/var/lib/hacker-ostree/overlay").join(path)
+    }
+
+    fn dpkg_status(&self) -> String {
+        fs::read_to_string(self.dir.path().join("root/var/lib/hacker-ostree/dpkg/status")).unwrap_or_default()
+    }
+
+    // Deployments known to FAKE_OSTREE
+    fn deployments(&self) -> String {
+        fs::read_to_string(self.dir.path().join("bin/status")).unwrap()
+    }
+}
+
+#[test]
+fn install_and_remove_with_dependencies() {
+    if !supported() {
+        return;
+    }
+    let sandbox = Sandbox::new();
+    sandbox.run(&["update"]);
+    sandbox.run(&["install", "app", "-y"]);
+    assert_eq!(
+        sandbox.list(),
+        serde_json::json!([
+            {"name": "app", "version": "1.0", "automatic": false},
+            {"name": "lib", "version": "1.0", "automatic": true},
+        ])
+    );
+    assert!(sandbox.overlay_file("usr/share/app/README").exists());
+    let catalog = fs::read_to_string(sandbox.overlay_file("usr/share/swcatalog/xml/hacker-ostree.xml")).unwrap();
+    assert!(catalog.contains("<id>org.example.app</id>\n  <pkgname>app</pkgname>"));
+    assert!(catalog.contains("<pkgname>lib</pkgname>"));
+
+    sandbox.run(&["remove", "app"]);
+    assert_eq!(sandbox.list(), serde_json::json!([]));
+    assert!(!sandbox.overlay_file("usr/share/lib/README").exists());
+    assert!(!sandbox.overlay_file("usr/share/swcatalog/xml/hacker-ostree.xml").exists());
+
+    let history: serde_json::Value = serde_json::from_slice(&sandbox.run(&["history", "--json"]).stdout).unwrap();
+    assert_eq!(history[0]["added"], serde_json::json!({"app": "1.0", "lib": "1.0"}));
+    assert_eq!(history[1]["command"], "remove");
+    assert_eq!(history[1]["removed"], serde_json::json!({"app": "1.0", "lib": "1.0"}));
+
+    let status = fs::read_to_string(sandbox.dir.path().join("root/run/hacker-ostree/status.json")).unwrap();
+    let status: serde_json::Value = serde_json::from_str(&status).unwrap();
+    assert_eq!(status["running"], serde_json::Value::Null);
+    assert_eq!(status["last_transaction"]["id"], 2);
+    assert_eq!(status["last_transaction"]["command"], "remove");
+    assert_eq!(status["last_transaction"]["success"], true);
+    assert_eq!(status["updates"], 0);
+    assert_eq!(fs::read_to_string(sandbox.dir.path().join("root/run/hacker-ostree/prompt-status")).unwrap(), "");
+}
+
+#[test]
+fn undo_reinstalls_removed_packages() {
+    if !supported() {
+        return;
+    }
+    let sandbox = Sandbox::new();
+    sandbox.run(&["update"]);
+    sandbox.run(&["install", "lib", "app", "-y"]);
+    sandbox.run(&["remove", "app", "lib"]);
+    sandbox.run(&["undo", "-y"]);
+    assert_eq!(
+        sandbox.list(),
+        serde_json::json!([
+            {"name": "app", "version": "1.0", "automatic": false},
+            {"name": "lib", "version": "1.0", "automatic": false},
+        ])
+    );
+}
+
+// Fail an install of app at every point and check nothing of it is left behind
+fn check_faults_roll_back(sandbox: &Sandbox) {
+    sandbox.run(&["update"]);
+    sandbox.run(&["install", "lib", "-y"]);
+    let list = sandbox.list();
+    let status = sandbox.dpkg_status();
+
+    for point in ["snapshot", "download", "extraction", "stage", "commit"] {
+        let stderr = sandbox.run_failing_at(point, &["install", "app", "-y"]);
+        assert!(stderr.contains(&format!("Injected fault at {}", point)), "{}", stderr);
+        assert_eq!(sandbox.list(), list, "after a fault at {}", point);
+        assert_eq!(sandbox.dpkg_status(), status, "after a fault at {}", point);
+        assert!(!sandbox.overlay_file("usr/share/app/README").exists(), "after a fault at {}", point);
+    }
+    sandbox.run_failing_at("removal", &["remove", "lib"]);
+    assert_eq!(sandbox.list(), list);
+    assert_eq!(sandbox.dpkg_status(), status);
+    assert!(sandbox.overlay_file("usr/share/lib/README").exists());
+
+    // Every failure was recorded; the packages still install once nothing is injected
+    let history: serde_json::Value = serde_json::from_slice(&sandbox.run(&["history", "--json"]).stdout).unwrap();
+    assert_eq!(history.as_array().unwrap().iter().filter(|entry| entry["success"] == false).count(), 6);
+    sandbox.run(&["install", "app", "-y"]);
+    assert!(sandbox.overlay_file("usr/share/app/README").exists());
+}
+
+#[test]
+fn faults_at_every_point_roll_back_completely() {
+    if !supported() {
+        return;
+    }
+    check_faults_roll_back(&Sandbox::new());
+}
+
+#[test]
+fn faults_after_staging_remove_the_new_deployment() {
+    if !supported() {
+        return;
+    }
+    let sandbox = Sandbox::with_deployments();
+    check_faults_roll_back(&sandbox);
+    // Only the successful install of lib and that of app staged a deployment
+    let deployments = "  hackeros bbb222.2\n  hackeros bbb222.1\n* hackeros aaa111.0\n";
+    assert_eq!(sandbox.deployments(), deployments);
+    // A transaction changing nothing layered stages nothing
+    sandbox.run(&["resync"]);
+    assert_eq!(sandbox.deployments(), deployments);
+}
+
+#[test]
+fn fault_injection_needs_the_environment_variable() {
+    if !supported() {
+        return;
+    }
+    let sandbox = Sandbox::new();
+    let output = sandbox.command(&["list", "--inject-fault", "stage"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("HACKER_OSTREE_FAULT_INJECTION=1"));
+}
+
+#[test]
+fn install_from_takes_the_package_from_that_repo() {
+    if !supported() {
+        return;
+    }
+    let sandbox = Sandbox::new();
+    sandbox.add_repo("backports", &[("lib", "2.0", None), ("app", "2.0", Some("lib (>= 2.0)")), ("tool", "1.0", None)]);
+    sandbox.run(&["update"]);
+    sandbox.run(&["install", "lib", "-y"]);
+    assert_eq!(sandbox.list(), serde_json::json!([{"name": "lib", "version": "1.0", "automatic": false}]));
+
+    sandbox.run(&["install", "app", "--from", "backports", "-y"]);
+    assert_eq!(
+        sandbox.list(),
+        serde_json::json!([
+            {"name": "app", "version": "2.0", "automatic": false},
+            {"name": "lib", "version": "2.0", "automatic": false},
+        ])
+    );
+    // A held dependency isn't replaced to satisfy a new package
+    sandbox.run(&["remove", "app", "-y"]);
+    sandbox.run(&["install", "lib=1.0", "-y"]);
+    sandbox.run(&["hold", "lib"]);
+    let output = sandbox.command(&["install", "app", "--from", "backports", "-y"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("it is held at 1.0"));
+    assert_eq!(sandbox.list(), serde_json::json!([{"name": "lib", "version": "1.0", "automatic": false}]));
+
+    let output = sandbox.command(&["install", "tool", "--from", "local", "-y"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("tool is not available from local"));
+}
+
+#[test]
+fn cache_verify_evicts_packages_that_changed() {
+    if !supported() {
+        return;
+    }
+    let sandbox = Sandbox::new();
+    sandbox.run(&["update"]);
+    sandbox.run(&["install", "app", "-y"]);
+    sandbox.run(&["cache", "verify"]);
+
+    let cached = sandbox.dir.path().join("root/var/lib/hacker-ostree/apt-cache/archives/local/flat/all/lib_1.0_all.deb");
+    fs::write(&cached, "tampered").unwrap();
+    let output = sandbox.command(&["cache", "verify"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("corrupt, evicted"));
+    assert!(!cached.exists());
+    sandbox.run(&["cache", "verify"]);
+}
+
+#[test]
+fn remove_and_hold_expand_glob_patterns() {
+    if !supported() {
+        return;
+    }
+    let sandbox = Sandbox::new();
+    sandbox.run(&["update"]);
+    sandbox.run(&["install", "app", "-y"]);
+    let output = sandbox.run(&["hold", "l*", "-y"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("l* matches: lib"));
+    assert_eq!(String::from_utf8_lossy(&sandbox.run(&["hold"]).stdout), "lib\n");
+
+    sandbox.run(&["remove", "a?p", "l*", "-y"]);
+    assert_eq!(sandbox.list(), serde_json::json!([]));
+    assert_eq!(String::from_utf8_lossy(&sandbox.run(&["hold"]).stdout), "No packages are held\n");
+    let output = sandbox.command(&["remove", "x*"]).output().unwrap();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No package installed in the overlay matches x*"));
+}
+
+#[test]
+fn diverted_files_are_tracked_where_they_went() {
+    if !supported() {
+        return;
+    }
+    let sandbox = Sandbox::new();
+    sandbox.run(&["update"]);
+    sandbox.run(&["install", "app", "-y"]);
+    sandbox.run(&["divert", "add", "/usr/share/app/README", "--to", "/usr/share/app/README.orig"]);
+    assert!(sandbox.overlay_file("usr/share/app/README.orig").exists());
+    assert!(!sandbox.overlay_file("usr/share/app/README").exists());
+    let output = sandbox.run(&["divert", "list"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("/usr/share/app/README -> /usr/share/app/README.orig (local)"));
+    sandbox.run(&["diff", "--overlay"]);
+
+    sandbox.run(&["divert", "remove", "/usr/share/app/README"]);
+    assert!(sandbox.overlay_file("usr/share/app/README").exists());
+    sandbox.run(&["diff", "--overlay"]);
+}
+
+#[test]
+fn packages_layered_before_the_dpkg_database_are_registered_in_it() {
+    if !supported() {
+        return;
+    }
+    let sandbox = Sandbox::new();
+    sandbox.run(&["update"]);
+    fs::write(sandbox.dir.path().join("root/var/lib/hacker-ostree/installed_packages.txt"), "lib\n").unwrap();
+    // Registered before resync looks at it, so nothing is left to reapply
+    let output = sandbox.run(&["resync"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Reapplying 0 of 1 layered packages"));
+    assert!(sandbox.dpkg_status().contains("Package: lib\n"));
+    assert!(sandbox.overlay_file("usr/share/lib/README").exists());
+    assert_eq!(sandbox.list(), serde_json::json!([{"name": "lib", "version": "1.0", "automatic": false}]));
+}